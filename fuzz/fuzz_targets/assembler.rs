@@ -0,0 +1,57 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+extern crate proto;
+use proto::fuzzing::{Assembler, Bytes};
+
+#[derive(Arbitrary, Debug)]
+enum Operation {
+    Insert { offset: u16, len: u16 },
+    Read { max_length: u16, ordered: bool },
+}
+
+/// The byte found at `offset` in the stream being reassembled
+fn expected(offset: u64) -> u8 {
+    offset as u8
+}
+
+fuzz_target!(|operations: Vec<Operation>| {
+    let mut assembler = Assembler::new();
+    let mut unordered = false;
+    let mut bytes_read = 0;
+
+    for operation in operations {
+        match operation {
+            Operation::Insert { offset, len } => {
+                let offset = u64::from(offset);
+                let bytes = (offset..offset + u64::from(len))
+                    .map(expected)
+                    .collect::<Bytes>();
+                assembler.insert(offset, bytes, len.into());
+            }
+            Operation::Read {
+                max_length,
+                ordered,
+            } => {
+                match assembler.read(max_length.into(), ordered) {
+                    Ok(Some(chunk)) => {
+                        assert!(chunk.bytes.len() <= max_length.into());
+                        for (i, &byte) in chunk.bytes.iter().enumerate() {
+                            assert_eq!(byte, expected(chunk.offset + i as u64));
+                        }
+                        if ordered {
+                            assert_eq!(chunk.offset, bytes_read);
+                        }
+                        bytes_read += chunk.bytes.len() as u64;
+                    }
+                    Ok(None) => {}
+                    // Ordered reads are only illegal once an unordered read has happened
+                    Err(_) => assert!(ordered && unordered),
+                }
+                unordered |= !ordered;
+            }
+        }
+    }
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use proto::fuzzing::{Bytes, FrameIter};
+extern crate proto;
+
+fuzz_target!(|data: &[u8]| {
+    // Decoding must terminate without panicking, however malformed the payload
+    for _ in FrameIter::new(Bytes::copy_from_slice(data)) {}
+});
@@ -38,6 +38,7 @@ fuzz_target!(|input: (StreamParams, Vec<Operation>)| {
         params.send_window.into(),
         params.receive_window.into(),
         params.stream_receive_window.into(),
+        None,
     );
 
     for operation in operations {
@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use proto::fuzzing::TransportParameters;
+use proto::Side;
+extern crate proto;
+
+fuzz_target!(|input: (Side, Vec<u8>)| {
+    let (side, data) = input;
+    let params = match TransportParameters::read(side, &mut &data[..]) {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+    // Anything we accept must survive a round trip
+    let mut buf = Vec::new();
+    params.write(&mut buf);
+    assert_eq!(TransportParameters::read(side, &mut &buf[..]), Ok(params));
+});
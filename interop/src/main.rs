@@ -377,10 +377,9 @@ impl State {
         transport
             .max_idle_timeout(Some(Duration::from_secs(1)))
             .unwrap();
-        let client_config = quinn::ClientConfig {
-            crypto: Arc::new(tls_config),
-            transport: Arc::new(transport),
-        };
+        let mut client_config = quinn::ClientConfig::default();
+        client_config.crypto = Arc::new(tls_config);
+        client_config.transport = Arc::new(transport);
 
         let mut endpoint = quinn::Endpoint::builder();
         endpoint.default_client_config(client_config.clone());
@@ -425,14 +424,10 @@ impl State {
         new_conn.connection.close(0u32.into(), b"done");
 
         let saw_cert = Arc::new(Mutex::new(false));
-        let quinn::ClientConfig {
-            mut crypto,
-            transport,
-        } = self.client_config.clone();
-        Arc::make_mut(&mut crypto)
+        let mut client_config = self.client_config.clone();
+        Arc::make_mut(&mut client_config.crypto)
             .dangerous()
             .set_certificate_verifier(Arc::new(InteropVerifier(saw_cert.clone())));
-        let client_config = quinn::ClientConfig { crypto, transport };
 
         let conn = match self
             .endpoint
@@ -689,14 +684,10 @@ impl State {
         conn.close();
 
         let saw_cert = Arc::new(Mutex::new(false));
-        let quinn::ClientConfig {
-            mut crypto,
-            transport,
-        } = self.client_config.clone();
-        Arc::make_mut(&mut crypto)
+        let mut client_config = self.client_config.clone();
+        Arc::make_mut(&mut client_config.crypto)
             .dangerous()
             .set_certificate_verifier(Arc::new(InteropVerifier(saw_cert.clone())));
-        let client_config = quinn::ClientConfig { crypto, transport };
 
         let conn = match self
             .h3_client
@@ -0,0 +1,462 @@
+//! Endpoint for the [QUIC interop runner](https://github.com/marten-seemann/quic-interop-runner)
+//!
+//! Configured entirely through the environment variables set by the runner: `ROLE` selects the
+//! client or server, `TESTCASE` the scenario to exercise, and `REQUESTS` the space-separated URLs
+//! the client downloads. The server serves files from `/www` with the certificate in `/certs`, and
+//! the client saves downloads to `/downloads`. Keys are logged to `SSLKEYLOGFILE` and qlog traces
+//! written to `QLOGDIR` when set. Unsupported test cases exit with status 127, as the runner
+//! expects.
+//!
+//! Besides hq-interop, both roles speak the perf protocol under the `perf` test case: the client
+//! opens a stream, sends the number of bytes it wants back as a big-endian `u64` followed by
+//! `PERF_UPLOAD` bytes, and the server responds with the requested amount, `PERF_DOWNLOAD` bytes
+//! by default.
+
+use std::{
+    env, fs, io,
+    net::{SocketAddr, ToSocketAddrs},
+    path::{Component, Path, PathBuf},
+    process, str,
+    sync::Arc,
+    time::Instant,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::{future, StreamExt};
+use tracing::{error, info, info_span};
+use tracing_futures::Instrument as _;
+
+const HQ_ALPN: &[&[u8]] = &[b"hq-interop", b"hq-29"];
+const PERF_ALPN: &[u8] = b"perf";
+
+const WWW: &str = "/www";
+const DOWNLOADS: &str = "/downloads";
+const CERTS: &str = "/certs";
+
+/// Scenarios defined by the interop runner, plus the perf protocol
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum TestCase {
+    Handshake,
+    Transfer,
+    Retry,
+    Resumption,
+    ZeroRtt,
+    KeyUpdate,
+    ChaCha20,
+    MultiConnect,
+    Perf,
+}
+
+impl TestCase {
+    fn from_name(name: &str) -> Option<Self> {
+        use TestCase::*;
+        Some(match name {
+            "handshake" => Handshake,
+            "transfer" => Transfer,
+            "retry" => Retry,
+            "resumption" => Resumption,
+            "zerortt" => ZeroRtt,
+            "keyupdate" => KeyUpdate,
+            "chacha20" => ChaCha20,
+            "multiconnect" => MultiConnect,
+            "perf" => Perf,
+            _ => return None,
+        })
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    if env::var("RUST_LOG").is_err() {
+        env::set_var("RUST_LOG", "info");
+    }
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::FmtSubscriber::builder()
+            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+            .finish(),
+    )
+    .unwrap();
+
+    let name = env::var("TESTCASE").unwrap_or_default();
+    let testcase = match TestCase::from_name(&name) {
+        Some(x) => x,
+        None => {
+            error!(testcase = %name, "unsupported test case");
+            process::exit(127);
+        }
+    };
+    let result = match env::var("ROLE").as_deref() {
+        Ok("client") => client(testcase).await,
+        Ok("server") => server(testcase).await,
+        _ => Err(anyhow!("ROLE must be either \"client\" or \"server\"")),
+    };
+    if let Err(e) = result {
+        error!("{:?}", e);
+        process::exit(1);
+    }
+}
+
+fn transport_config() -> quinn::TransportConfig {
+    let mut transport = quinn::TransportConfig::default();
+    transport.send_window(1024 * 1024 * 8);
+    transport.receive_window(1024 * 1024 * 8).unwrap();
+    transport.stream_receive_window(1024 * 1024 * 8).unwrap();
+    if let Some(dir) = env::var_os("QLOGDIR") {
+        let dir = PathBuf::from(dir);
+        transport.qlog_factory(Some(Arc::new(
+            move |side: quinn::Side, odcid: &quinn::ConnectionId| {
+                let file = fs::File::create(dir.join(format!("{}-{:?}.qlog", odcid, side)));
+                Some(Box::new(file.ok()?) as Box<dyn io::Write + Send>)
+            },
+        )));
+    }
+    transport
+}
+
+async fn server(testcase: TestCase) -> Result<()> {
+    let certs = Path::new(CERTS);
+    let key = fs::read(certs.join("priv.key")).context("failed to read private key")?;
+    let key = quinn::PrivateKey::from_pem(&key)?;
+    let cert_chain = fs::read(certs.join("cert.pem")).context("failed to read certificate")?;
+    let cert_chain = quinn::CertificateChain::from_pem(&cert_chain)?;
+
+    let mut server_config = quinn::ServerConfigBuilder::default();
+    server_config.certificate(cert_chain, key)?;
+    let mut protocols = HQ_ALPN.to_vec();
+    protocols.push(PERF_ALPN);
+    server_config.protocols(&protocols);
+    server_config.use_stateless_retry(testcase == TestCase::Retry);
+    if env::var_os("SSLKEYLOGFILE").is_some() {
+        server_config.enable_keylog();
+    }
+    let mut server_config = server_config.build();
+    server_config.transport = Arc::new(transport_config());
+
+    let mut endpoint = quinn::Endpoint::builder();
+    endpoint.listen(server_config);
+    let (_, mut incoming) = endpoint.bind(&"[::]:443".parse().unwrap())?;
+    info!("listening");
+    while let Some(incoming) = incoming.next().await {
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(incoming).await {
+                error!("connection failed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn handle_connection(incoming: quinn::IncomingConnection) -> Result<()> {
+    let quinn::NewConnection {
+        connection,
+        mut bi_streams,
+        ..
+    } = match incoming.accept()?.into_0rtt() {
+        Ok((x, _)) => x,
+        Err(connecting) => connecting.await?,
+    };
+    let protocol = connection
+        .handshake_data()
+        .and_then(|x| x.protocol)
+        .unwrap_or_default();
+    let perf = protocol == PERF_ALPN;
+    let span = info_span!(
+        "connection",
+        remote = %connection.remote_address(),
+        protocol = %String::from_utf8_lossy(&protocol)
+    );
+    async {
+        info!("established");
+        // Each stream initiated by the client constitutes a new request
+        while let Some(stream) = bi_streams.next().await {
+            let stream = match stream {
+                Err(quinn::ConnectionError::ApplicationClosed { .. }) => break,
+                Err(e) => return Err(e.into()),
+                Ok(x) => x,
+            };
+            tokio::spawn(
+                async move {
+                    let result = match perf {
+                        true => perf_respond(stream).await,
+                        false => hq_respond(stream).await,
+                    };
+                    if let Err(e) = result {
+                        error!("request failed: {}", e);
+                    }
+                }
+                .instrument(info_span!("request")),
+            );
+        }
+        info!("closed");
+        Ok(())
+    }
+    .instrument(span)
+    .await
+}
+
+async fn hq_respond((mut send, recv): (quinn::SendStream, quinn::RecvStream)) -> Result<()> {
+    let request = recv
+        .read_to_end(64 * 1024)
+        .await
+        .map_err(|e| anyhow!("failed reading request: {}", e))?;
+    let path = str::from_utf8(&request)
+        .ok()
+        .and_then(|x| x.strip_prefix("GET "))
+        .ok_or_else(|| anyhow!("malformed request"))?
+        .trim_end();
+    info!(path);
+    let data = fs::read(local_path(WWW, path)?).context("failed to read file")?;
+    send.write_all(&data)
+        .await
+        .map_err(|e| anyhow!("failed to send response: {}", e))?;
+    send.finish()
+        .await
+        .map_err(|e| anyhow!("failed to shutdown stream: {}", e))?;
+    Ok(())
+}
+
+async fn perf_respond((mut send, mut recv): (quinn::SendStream, quinn::RecvStream)) -> Result<()> {
+    let mut header = [0; 8];
+    recv.read_exact(&mut header)
+        .await
+        .map_err(|e| anyhow!("failed reading request: {}", e))?;
+    let len = u64::from_be_bytes(header);
+    let mut uploaded = 0;
+    while let Some(chunk) = recv
+        .read_chunk(usize::MAX, false)
+        .await
+        .map_err(|e| anyhow!("failed reading upload: {}", e))?
+    {
+        uploaded += chunk.bytes.len();
+    }
+    info!(uploaded, requested = len);
+    send_zeroes(&mut send, len).await?;
+    send.finish()
+        .await
+        .map_err(|e| anyhow!("failed to shutdown stream: {}", e))?;
+    Ok(())
+}
+
+async fn client(testcase: TestCase) -> Result<()> {
+    let requests = env::var("REQUESTS").context("REQUESTS must be set")?;
+    let urls = requests
+        .split_whitespace()
+        .map(|x| x.parse::<http::Uri>())
+        .collect::<Result<Vec<_>, _>>()?;
+    let authority = urls
+        .first()
+        .and_then(|x| x.authority())
+        .ok_or_else(|| anyhow!("no requests to make"))?;
+    let host = authority.host();
+    let remote = (host, authority.port_u16().unwrap_or(443))
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("couldn't resolve to an address"))?;
+
+    let mut tls_config = rustls::ClientConfig::new();
+    tls_config.versions = vec![rustls::ProtocolVersion::TLSv1_3];
+    tls_config.enable_early_data = true;
+    tls_config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(NoVerification));
+    tls_config.alpn_protocols = match testcase {
+        TestCase::Perf => vec![PERF_ALPN.into()],
+        _ => HQ_ALPN.iter().map(|&x| x.into()).collect(),
+    };
+    if testcase == TestCase::ChaCha20 {
+        tls_config.ciphersuites = vec![&rustls::ciphersuite::TLS13_CHACHA20_POLY1305_SHA256];
+    }
+    if env::var_os("SSLKEYLOGFILE").is_some() {
+        tls_config.key_log = Arc::new(rustls::KeyLogFile::new());
+    }
+    let mut client_config = quinn::ClientConfig::default();
+    client_config.crypto = Arc::new(tls_config);
+    client_config.transport = Arc::new(transport_config());
+
+    let mut endpoint = quinn::Endpoint::builder();
+    endpoint.default_client_config(client_config);
+    let (endpoint, _) = endpoint.bind(&"[::]:0".parse().unwrap())?;
+    let client = Client {
+        endpoint,
+        remote,
+        host: host.into(),
+    };
+
+    match testcase {
+        TestCase::Handshake | TestCase::Transfer | TestCase::Retry | TestCase::ChaCha20 => {
+            let conn = client.connect().await?;
+            download_all(&conn, &urls).await?;
+            conn.close(0u32.into(), b"done");
+        }
+        TestCase::KeyUpdate => {
+            let conn = client.connect().await?;
+            conn.force_key_update();
+            download_all(&conn, &urls).await?;
+            conn.close(0u32.into(), b"done");
+        }
+        TestCase::MultiConnect => {
+            for url in &urls {
+                let conn = client.connect().await?;
+                download_all(&conn, std::slice::from_ref(url)).await?;
+                conn.close(0u32.into(), b"done");
+            }
+        }
+        TestCase::Resumption | TestCase::ZeroRtt => {
+            let (first, rest) = urls.split_first().unwrap();
+            let conn = client.connect().await?;
+            download_all(&conn, std::slice::from_ref(first)).await?;
+            conn.close(0u32.into(), b"done");
+            // Make sure the session ticket has been processed before reconnecting
+            client.endpoint.wait_idle().await;
+
+            let connecting = client.endpoint.connect(&client.remote, &client.host)?;
+            let conn = match testcase {
+                TestCase::ZeroRtt => match connecting.into_0rtt() {
+                    Ok((new_conn, _)) => new_conn.connection,
+                    Err(_) => bail!("0-RTT unavailable"),
+                },
+                _ => connecting.await?.connection,
+            };
+            download_all(&conn, rest).await?;
+            conn.close(0u32.into(), b"done");
+        }
+        TestCase::Perf => {
+            let upload = env_bytes("PERF_UPLOAD", 0)?;
+            let download = env_bytes("PERF_DOWNLOAD", 10 * 1024 * 1024)?;
+            let conn = client.connect().await?;
+            perf_request(&conn, upload, download).await?;
+            conn.close(0u32.into(), b"done");
+        }
+    }
+
+    client.endpoint.wait_idle().await;
+    Ok(())
+}
+
+struct Client {
+    endpoint: quinn::Endpoint,
+    remote: SocketAddr,
+    host: String,
+}
+
+impl Client {
+    async fn connect(&self) -> Result<quinn::Connection> {
+        let new_conn = self
+            .endpoint
+            .connect(&self.remote, &self.host)?
+            .await
+            .map_err(|e| anyhow!("failed to connect: {}", e))?;
+        Ok(new_conn.connection)
+    }
+}
+
+async fn download_all(conn: &quinn::Connection, urls: &[http::Uri]) -> Result<()> {
+    future::try_join_all(urls.iter().map(|url| download(conn, url))).await?;
+    Ok(())
+}
+
+async fn download(conn: &quinn::Connection, url: &http::Uri) -> Result<()> {
+    let (mut send, recv) = conn
+        .open_bi()
+        .await
+        .map_err(|e| anyhow!("failed to open stream: {}", e))?;
+    send.write_all(format!("GET {}\r\n", url.path()).as_bytes())
+        .await
+        .map_err(|e| anyhow!("failed to send request: {}", e))?;
+    send.finish()
+        .await
+        .map_err(|e| anyhow!("failed to shutdown stream: {}", e))?;
+    let data = recv
+        .read_to_end(usize::max_value())
+        .await
+        .map_err(|e| anyhow!("failed to read response: {}", e))?;
+    fs::write(local_path(DOWNLOADS, url.path())?, data).context("failed to save download")?;
+    Ok(())
+}
+
+async fn perf_request(conn: &quinn::Connection, upload: u64, download: u64) -> Result<()> {
+    let start = Instant::now();
+    let (mut send, mut recv) = conn
+        .open_bi()
+        .await
+        .map_err(|e| anyhow!("failed to open stream: {}", e))?;
+    let sender = async move {
+        send.write_all(&download.to_be_bytes())
+            .await
+            .map_err(|e| anyhow!("failed to send request: {}", e))?;
+        send_zeroes(&mut send, upload).await?;
+        send.finish()
+            .await
+            .map_err(|e| anyhow!("failed to shutdown stream: {}", e))
+    };
+    let receiver = async move {
+        let mut received = 0;
+        while let Some(chunk) = recv
+            .read_chunk(usize::MAX, false)
+            .await
+            .map_err(|e| anyhow!("failed to read response: {}", e))?
+        {
+            received += chunk.bytes.len() as u64;
+        }
+        Ok(received)
+    };
+    let ((), received) = future::try_join(sender, receiver).await?;
+    if received != download {
+        bail!("requested {} bytes but received {}", download, received);
+    }
+    let elapsed = start.elapsed();
+    info!(
+        "uploaded {} and downloaded {} bytes in {:?} ({:.2} MiB/s)",
+        upload,
+        download,
+        elapsed,
+        (upload + download) as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0)
+    );
+    Ok(())
+}
+
+async fn send_zeroes(send: &mut quinn::SendStream, mut len: u64) -> Result<()> {
+    const ZEROES: [u8; 64 * 1024] = [0; 64 * 1024];
+    while len > 0 {
+        let n = len.min(ZEROES.len() as u64) as usize;
+        send.write_all(&ZEROES[..n])
+            .await
+            .map_err(|e| anyhow!("failed to send data: {}", e))?;
+        len -= n as u64;
+    }
+    Ok(())
+}
+
+/// Resolve a request path within `root`, rejecting anything that could escape it
+fn local_path(root: &str, path: &str) -> Result<PathBuf> {
+    let relative = Path::new(path.trim_start_matches('/'));
+    if relative
+        .components()
+        .any(|x| !matches!(x, Component::Normal(_)))
+    {
+        bail!("invalid path {:?}", path);
+    }
+    Ok(Path::new(root).join(relative))
+}
+
+fn env_bytes(name: &str, default: u64) -> Result<u64> {
+    match env::var(name) {
+        Ok(x) => x.parse().with_context(|| format!("invalid {}", name)),
+        Err(_) => Ok(default),
+    }
+}
+
+/// The runner uses self-signed certificates, so the client accepts anything
+struct NoVerification;
+
+impl rustls::ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        _presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> std::result::Result<rustls::ServerCertVerified, rustls::TLSError> {
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
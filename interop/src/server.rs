@@ -100,8 +100,12 @@ async fn server(server_config: quinn::ServerConfigBuilder, addr: SocketAddr) ->
     let (_, mut incoming) = endpoint_builder.bind(&addr)?;
 
     println!("server listening on {}", addr);
-    while let Some(mut connecting) = incoming.next().await {
+    while let Some(incoming) = incoming.next().await {
         tokio::spawn(async move {
+            let mut connecting = match incoming.accept() {
+                Err(_) => return,
+                Ok(x) => x,
+            };
             let proto = match connecting.handshake_data().await {
                 Err(_) => return,
                 Ok(x) => x.protocol.unwrap(),
@@ -374,6 +374,25 @@ impl Connecting {
             }
         }
     }
+
+    /// Speak HTTP/3 over a QUIC connection attempt made outside of a [`Client`]
+    ///
+    /// Lets an application share one QUIC endpoint between HTTP/3 and other protocols, or tune
+    /// the endpoint itself. `connecting` must have been initiated with a client configuration
+    /// offering the [`quinn_h3::ALPN`] protocol.
+    ///
+    /// Note that [`From<quinn::Connecting>`] is also available if default settings suit
+    /// your needs.
+    ///
+    /// [`Client`]: struct.Client.html
+    /// [`quinn_h3::ALPN`]: ../constant.ALPN.html
+    /// [`From<quinn::Connecting>`]: #method.from
+    pub fn from_quic(connecting: quinn::Connecting, settings: Settings) -> Self {
+        Self {
+            connecting,
+            settings,
+        }
+    }
 }
 
 impl Future for Connecting {
@@ -398,6 +417,15 @@ impl Future for Connecting {
     }
 }
 
+impl From<quinn::Connecting> for Connecting {
+    fn from(connecting: quinn::Connecting) -> Self {
+        Self {
+            connecting,
+            settings: Settings::new(),
+        }
+    }
+}
+
 /// Established HTTP/3 connection
 ///
 /// This enables you to send requests to the server with [`send_request()`] and [`close()`]
@@ -285,12 +285,19 @@ impl Stream for IncomingConnection {
     type Item = Connecting;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        Poll::Ready(
-            ready!(Pin::new(&mut self.incoming).poll_next(cx)).map(|c| Connecting {
-                connecting: c,
-                settings: self.settings.clone(),
-            }),
-        )
+        loop {
+            let incoming = match ready!(Pin::new(&mut self.incoming).poll_next(cx)) {
+                Some(x) => x,
+                None => return Poll::Ready(None),
+            };
+            // Attempts the endpoint can't take on have already been refused
+            if let Ok(connecting) = incoming.accept() {
+                return Poll::Ready(Some(Connecting {
+                    connecting,
+                    settings: self.settings.clone(),
+                }));
+            }
+        }
     }
 }
 
@@ -418,7 +425,11 @@ impl Connecting {
     /// let (_, mut incoming) =
     ///     quic_builder.bind(&"[::]:443".parse().unwrap()).unwrap();
     ///
-    /// while let Some(mut connecting) = incoming.next().await {
+    /// while let Some(incoming_connection) = incoming.next().await {
+    ///     let mut connecting = match incoming_connection.accept() {
+    ///         Err(_) => continue,
+    ///         Ok(x) => x,
+    ///     };
     ///     let data = match connecting.handshake_data().await {
     ///         Err(_) => continue,
     ///         Ok(x) => x,
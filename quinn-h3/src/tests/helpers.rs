@@ -50,7 +50,7 @@ pub struct Helper {
 impl Helper {
     pub fn new() -> Self {
         let _ = tracing_subscriber::FmtSubscriber::builder()
-            .with_env_filter("quinn=trace,quinn-h3=trace")
+            .with_env_filter("quinn=trace,quinn_h3=trace")
             .with_writer(|| TestWriter)
             .try_init();
 
@@ -0,0 +1,97 @@
+use std::{
+    ops::RangeInclusive,
+    time::{Duration, Instant},
+};
+
+use bencher::{benchmark_group, benchmark_main, Bencher};
+
+use quinn_proto::bench::SentPackets;
+
+benchmark_group!(
+    benches,
+    contiguous,
+    every_other,
+    stale_ranges,
+    reordered_loss_scan
+);
+benchmark_main!(benches);
+
+/// Number of packets outstanding at the start of each iteration
+const PACKETS: u64 = 10_000;
+/// Maximum number of ranges in an ACK frame sent by quinn; other peers may send more
+const MAX_RANGES: u64 = 64;
+
+/// One ACK per packet, acknowledging everything received so far
+fn contiguous(bench: &mut Bencher) {
+    let now = Instant::now();
+    bench.iter(|| {
+        let mut sent = sent(now);
+        for n in 0..PACKETS {
+            sent.ack(&[0..=n]);
+        }
+        assert!(sent.is_empty());
+    });
+}
+
+/// Every other packet is received, so each ACK carries as many ranges as the peer can fit
+fn every_other(bench: &mut Bencher) {
+    let now = Instant::now();
+    bench.iter(|| {
+        let mut sent = sent(now);
+        for n in (0..PACKETS).step_by(2) {
+            sent.ack(&alternating(n));
+        }
+        assert_eq!(sent.len() as u64, PACKETS / 2);
+    });
+}
+
+/// A peer repeats a long history of ranges that no longer refer to outstanding packets
+fn stale_ranges(bench: &mut Bencher) {
+    const HISTORY: u64 = 1024;
+    let now = Instant::now();
+    bench.iter(|| {
+        let mut sent = SentPackets::new();
+        for n in 2 * HISTORY..2 * HISTORY + PACKETS {
+            sent.send(n, now);
+        }
+        let mut ranges = vec![0..=0];
+        ranges.extend((0..HISTORY).rev().map(|i| 2 * i..=2 * i));
+        for n in 2 * HISTORY..2 * HISTORY + PACKETS {
+            ranges[0] = 2 * HISTORY..=n;
+            sent.ack(&ranges);
+        }
+        assert!(sent.is_empty());
+    });
+}
+
+/// Packets are reordered beyond the packet threshold, so loss detection relies on time while
+/// many packets below the largest acknowledged remain outstanding
+fn reordered_loss_scan(bench: &mut Bencher) {
+    let now = Instant::now();
+    let lost_send_time = now - Duration::from_secs(1);
+    bench.iter(|| {
+        let mut sent = sent(now);
+        for n in (0..PACKETS).step_by(2) {
+            sent.ack(&[n..=n]);
+            sent.detect_lost(n, u64::MAX / 2, lost_send_time);
+        }
+        assert_eq!(sent.len() as u64, PACKETS / 2);
+    });
+}
+
+fn sent(now: Instant) -> SentPackets {
+    let mut sent = SentPackets::new();
+    for n in 0..PACKETS {
+        sent.send(n, now);
+    }
+    sent
+}
+
+/// Ranges of an ACK frame for the even packets up to `largest`
+fn alternating(largest: u64) -> Vec<RangeInclusive<u64>> {
+    (0..=largest / 2)
+        .rev()
+        .take(MAX_RANGES as usize)
+        .map(|i| 2 * i..=2 * i)
+        .collect()
+}
@@ -0,0 +1,106 @@
+use bencher::{benchmark_group, benchmark_main, Bencher};
+use bytes::Bytes;
+
+use quinn_proto::bench::Assembler;
+
+benchmark_group!(
+    benches,
+    in_order,
+    reverse_order,
+    retransmitted,
+    tiny_then_covering,
+    sliding_overlap
+);
+benchmark_main!(benches);
+
+/// Size of the stream reassembled by each iteration
+const STREAM_LEN: usize = 1024 * 1024;
+/// Frame size of a well-behaved peer
+const FRAME_LEN: usize = 1200;
+
+fn in_order(bench: &mut Bencher) {
+    let data = Bytes::from(vec![0; STREAM_LEN]);
+    bench.bytes = STREAM_LEN as u64;
+    bench.iter(|| {
+        let mut x = Assembler::new();
+        for offset in (0..STREAM_LEN).step_by(FRAME_LEN) {
+            let end = (offset + FRAME_LEN).min(STREAM_LEN);
+            x.insert(offset as u64, data.slice(offset..end), FRAME_LEN);
+            drain(&mut x);
+        }
+    });
+}
+
+fn reverse_order(bench: &mut Bencher) {
+    let data = Bytes::from(vec![0; STREAM_LEN]);
+    bench.bytes = STREAM_LEN as u64;
+    bench.iter(|| {
+        let mut x = Assembler::new();
+        for offset in (0..STREAM_LEN).step_by(FRAME_LEN).rev() {
+            let end = (offset + FRAME_LEN).min(STREAM_LEN);
+            x.insert(offset as u64, data.slice(offset..end), FRAME_LEN);
+        }
+        drain(&mut x);
+    });
+}
+
+/// Every frame arrives twice before any data is read
+fn retransmitted(bench: &mut Bencher) {
+    let data = Bytes::from(vec![0; STREAM_LEN]);
+    bench.bytes = STREAM_LEN as u64;
+    bench.iter(|| {
+        let mut x = Assembler::new();
+        for _ in 0..2 {
+            for offset in (FRAME_LEN..STREAM_LEN).step_by(FRAME_LEN) {
+                let end = (offset + FRAME_LEN).min(STREAM_LEN);
+                x.insert(offset as u64, data.slice(offset..end), FRAME_LEN);
+            }
+        }
+        x.insert(0, data.slice(0..FRAME_LEN), FRAME_LEN);
+        drain(&mut x);
+    });
+}
+
+/// A peer sends single-byte frames with gaps, then frames covering all of them
+fn tiny_then_covering(bench: &mut Bencher) {
+    const LEN: usize = 64 * 1024;
+    let data = Bytes::from(vec![0; LEN]);
+    bench.bytes = LEN as u64;
+    bench.iter(|| {
+        let mut x = Assembler::new();
+        for offset in (1..LEN).step_by(2) {
+            x.insert(offset as u64, data.slice(offset..offset + 1), FRAME_LEN);
+        }
+        for offset in (0..LEN).step_by(FRAME_LEN) {
+            let end = (offset + FRAME_LEN).min(LEN);
+            x.insert(offset as u64, data.slice(offset..end), FRAME_LEN);
+        }
+        drain(&mut x);
+    });
+}
+
+/// Each frame overlaps most of its predecessor, as from a peer re-sending with a small stride
+fn sliding_overlap(bench: &mut Bencher) {
+    const LEN: usize = 256 * 1024;
+    const STRIDE: usize = 16;
+    let data = Bytes::from(vec![0; LEN + FRAME_LEN]);
+    bench.bytes = LEN as u64;
+    bench.iter(|| {
+        let mut x = Assembler::new();
+        for offset in (STRIDE..LEN).step_by(STRIDE) {
+            x.insert(
+                offset as u64,
+                data.slice(offset..offset + FRAME_LEN),
+                FRAME_LEN,
+            );
+        }
+        x.insert(0, data.slice(0..STRIDE), FRAME_LEN);
+        drain(&mut x);
+    });
+}
+
+fn drain(x: &mut Assembler) {
+    while let Some(chunk) = x.read(usize::MAX, true).unwrap() {
+        bencher::black_box(chunk);
+    }
+}
@@ -0,0 +1,111 @@
+use bencher::{benchmark_group, benchmark_main, Bencher};
+use bytes::BytesMut;
+
+use quinn_proto::{
+    bench::{decode_short, encode_short},
+    crypto::{self, rustls::TlsSession, Session as _},
+    ConnectionId, Side,
+};
+
+benchmark_group!(benches, encode, decode, decode_corrupt);
+benchmark_main!(benches);
+
+/// Payload size of a full-sized 1-RTT packet
+const PAYLOAD_LEN: usize = 1200;
+/// Number of packets protected by each iteration
+const PACKETS: u64 = 1000;
+
+fn encode(bench: &mut Bencher) {
+    let (client, _) = keys();
+    let cid = ConnectionId::new(&[0xab; 8]);
+    let payload = [0; PAYLOAD_LEN];
+    let mut buf = Vec::with_capacity(2 * PAYLOAD_LEN);
+    bench.bytes = PACKETS * PAYLOAD_LEN as u64;
+    bench.iter(|| {
+        for number in 0..PACKETS {
+            buf.clear();
+            encode_short(
+                &cid,
+                number,
+                &payload,
+                &client.header.local,
+                &client.packet.local,
+                &mut buf,
+            );
+        }
+        bencher::black_box(&buf);
+    });
+}
+
+fn decode(bench: &mut Bencher) {
+    let packets = encoded(0..PACKETS);
+    let (_, server) = keys();
+    bench.bytes = PACKETS * PAYLOAD_LEN as u64;
+    bench.iter(|| {
+        for (number, packet) in (0..PACKETS).zip(&packets) {
+            let payload = decode_short(
+                BytesMut::from(&packet[..]),
+                8,
+                number,
+                &server.header.remote,
+                &server.packet.remote,
+            );
+            assert_eq!(payload.map(|x| x.len()), Some(PAYLOAD_LEN));
+        }
+    });
+}
+
+/// Packets fail authentication, as when an attacker injects datagrams under a valid header
+fn decode_corrupt(bench: &mut Bencher) {
+    let mut packets = encoded(0..PACKETS);
+    for packet in &mut packets {
+        *packet.last_mut().unwrap() ^= 1;
+    }
+    let (_, server) = keys();
+    bench.bytes = PACKETS * PAYLOAD_LEN as u64;
+    bench.iter(|| {
+        for (number, packet) in (0..PACKETS).zip(&packets) {
+            let payload = decode_short(
+                BytesMut::from(&packet[..]),
+                8,
+                number,
+                &server.header.remote,
+                &server.packet.remote,
+            );
+            assert!(payload.is_none());
+        }
+    });
+}
+
+/// Packets sent by the client with the given numbers
+fn encoded(numbers: std::ops::Range<u64>) -> Vec<Vec<u8>> {
+    let (client, _) = keys();
+    let cid = ConnectionId::new(&[0xab; 8]);
+    let payload = [0; PAYLOAD_LEN];
+    numbers
+        .map(|number| {
+            let mut buf = Vec::new();
+            encode_short(
+                &cid,
+                number,
+                &payload,
+                &client.header.local,
+                &client.packet.local,
+                &mut buf,
+            );
+            buf
+        })
+        .collect()
+}
+
+/// Matching client and server keys
+///
+/// Initial keys are derived from the connection ID alone, and use the same algorithms as the
+/// most common 1-RTT cipher suite.
+fn keys() -> (crypto::Keys<TlsSession>, crypto::Keys<TlsSession>) {
+    let cid = ConnectionId::new(&[0xcd; 8]);
+    (
+        TlsSession::initial_keys(&cid, Side::Client),
+        TlsSession::initial_keys(&cid, Side::Server),
+    )
+}
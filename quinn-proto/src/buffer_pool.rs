@@ -0,0 +1,83 @@
+use std::sync::{Arc, Mutex};
+
+/// Maximum number of idle buffers retained by a pool
+///
+/// Bounds the memory held by an endpoint after a burst of transmits, while comfortably covering the
+/// number of datagrams in flight between `poll_transmit` and the socket in a typical I/O loop.
+const MAX_POOLED: usize = 256;
+
+/// Pool of reusable datagram buffers shared by an endpoint and its connections
+///
+/// Buffers handed out in a `Transmit` can be returned with `Endpoint::recycle` once the datagram
+/// has been sent, saving an allocation per outgoing datagram.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BufferPool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl BufferPool {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get an empty buffer with room for at least `capacity` bytes
+    pub(crate) fn get(&self, capacity: usize) -> Vec<u8> {
+        let buf = self.buffers.lock().unwrap().pop();
+        match buf {
+            Some(mut buf) => {
+                buf.reserve(capacity);
+                buf
+            }
+            None => Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Return a buffer for future use
+    pub(crate) fn put(&self, mut buf: Vec<u8>) {
+        if buf.capacity() == 0 {
+            return;
+        }
+        buf.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < MAX_POOLED {
+            buffers.push(buf);
+        }
+    }
+
+    /// Number of idle buffers in the pool
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse() {
+        let pool = BufferPool::new();
+        let mut buf = pool.get(1200);
+        assert!(buf.capacity() >= 1200);
+        buf.extend_from_slice(b"hello");
+        let ptr = buf.as_ptr();
+        pool.put(buf);
+        assert_eq!(pool.len(), 1);
+        let buf = pool.get(1200);
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    fn bounded() {
+        let pool = BufferPool::new();
+        for _ in 0..MAX_POOLED + 10 {
+            pool.put(Vec::with_capacity(16));
+        }
+        assert_eq!(pool.len(), MAX_POOLED);
+        pool.put(Vec::new());
+        assert_eq!(pool.len(), MAX_POOLED);
+    }
+}
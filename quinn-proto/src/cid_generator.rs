@@ -1,7 +1,8 @@
-use std::time::Duration;
+use std::{fmt, sync::Arc, time::Duration};
 
-use rand::RngCore;
+use rand::Rng;
 
+use crate::random::{OsRandom, RandomSource};
 use crate::shared::ConnectionId;
 use crate::MAX_CID_SIZE;
 
@@ -20,13 +21,24 @@ pub trait ConnectionIdGenerator: Send {
     ///
     /// Connection IDs will be retired after the returned `Duration`, if any. Assumed to be constant.
     fn cid_lifetime(&self) -> Option<Duration>;
+    /// Whether the endpoint may overwrite the first 8 bytes of each generated CID
+    ///
+    /// If so, the endpoint replaces them with an encrypted encoding of its internal index for the
+    /// connection, allowing packets to be routed without a hash table lookup. Each encoding is
+    /// derived from the first 4 bytes generated, which should be random for CIDs to remain
+    /// unlinkable. Generators which embed information of their own, e.g. for load balancers,
+    /// should return `false`, the default.
+    fn allow_handle_embedding(&self) -> bool {
+        false
+    }
 }
 
 /// Generates purely random connection IDs of a certain length
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone)]
 pub struct RandomConnectionIdGenerator {
     cid_len: usize,
     lifetime: Option<Duration>,
+    random_source: Arc<dyn RandomSource>,
 }
 
 impl Default for RandomConnectionIdGenerator {
@@ -34,6 +46,7 @@ impl Default for RandomConnectionIdGenerator {
         Self {
             cid_len: 8,
             lifetime: None,
+            random_source: Arc::new(OsRandom),
         }
     }
 }
@@ -55,12 +68,28 @@ impl RandomConnectionIdGenerator {
         self.lifetime = Some(d);
         self
     }
+
+    /// Set where the bytes of the CIDs are drawn from, [`OsRandom`] by default
+    pub fn set_random_source(&mut self, source: Arc<dyn RandomSource>) -> &mut Self {
+        self.random_source = source;
+        self
+    }
+}
+
+impl fmt::Debug for RandomConnectionIdGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RandomConnectionIdGenerator")
+            .field("cid_len", &self.cid_len)
+            .field("lifetime", &self.lifetime)
+            .finish()
+    }
 }
 
 impl ConnectionIdGenerator for RandomConnectionIdGenerator {
     fn generate_cid(&mut self) -> ConnectionId {
         let mut bytes_arr = [0; MAX_CID_SIZE];
-        rand::thread_rng().fill_bytes(&mut bytes_arr[..self.cid_len]);
+        self.random_source
+            .fill_bytes(&mut bytes_arr[..self.cid_len]);
 
         ConnectionId::new(&bytes_arr[..self.cid_len])
     }
@@ -73,4 +102,100 @@ impl ConnectionIdGenerator for RandomConnectionIdGenerator {
     fn cid_lifetime(&self) -> Option<Duration> {
         self.lifetime
     }
+
+    fn allow_handle_embedding(&self) -> bool {
+        self.cid_len >= HandleCodec::LEN
+    }
+}
+
+/// Encrypts connection indices into and out of the leading bytes of local CIDs
+///
+/// The index is paired with 32 random bits taken from the CID and passed through a small keyed
+/// Feistel network, so that distinct CIDs for the same connection share no visible structure.
+#[derive(Debug, Clone)]
+pub(crate) struct HandleCodec {
+    keys: [u32; 4],
+}
+
+impl HandleCodec {
+    /// Number of leading CID bytes occupied by the encoding
+    pub(crate) const LEN: usize = 8;
+
+    pub(crate) fn new<R: Rng>(rng: &mut R) -> Self {
+        Self { keys: rng.gen() }
+    }
+
+    /// Overwrite the leading bytes of `cid` with an encoding of `index`
+    pub(crate) fn encode(&self, cid: &mut ConnectionId, index: usize) {
+        debug_assert!(index <= u32::MAX as usize);
+        let mut nonce = [0; 4];
+        nonce.copy_from_slice(&cid[..4]);
+        let (mut l, mut r) = (u32::from_be_bytes(nonce), index as u32);
+        for &key in &self.keys {
+            let next = l ^ round(key, r);
+            l = r;
+            r = next;
+        }
+        cid[..4].copy_from_slice(&l.to_be_bytes());
+        cid[4..8].copy_from_slice(&r.to_be_bytes());
+    }
+
+    /// Recover the index embedded by `encode`, if `cid` is long enough to contain one
+    ///
+    /// Garbage is returned for CIDs which weren't produced by `encode` with the same key, so the
+    /// result must be validated.
+    pub(crate) fn decode(&self, cid: &[u8]) -> Option<usize> {
+        if cid.len() < Self::LEN {
+            return None;
+        }
+        let mut half = [0; 4];
+        half.copy_from_slice(&cid[..4]);
+        let mut l = u32::from_be_bytes(half);
+        half.copy_from_slice(&cid[4..8]);
+        let mut r = u32::from_be_bytes(half);
+        for &key in self.keys.iter().rev() {
+            let prev = r ^ round(key, l);
+            r = l;
+            l = prev;
+        }
+        Some(r as usize)
+    }
+}
+
+fn round(key: u32, x: u32) -> u32 {
+    let x = (x ^ key).wrapping_mul(0x9e37_79b1);
+    x ^ x.rotate_left(13) ^ (x >> 7)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn handle_roundtrip() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let codec = HandleCodec::new(&mut rng);
+        let mut generator = RandomConnectionIdGenerator::new(MAX_CID_SIZE);
+        for &index in &[0, 1, 1234, u32::MAX as usize] {
+            let mut cid = generator.generate_cid();
+            let tail = cid[HandleCodec::LEN..].to_vec();
+            codec.encode(&mut cid, index);
+            assert_eq!(codec.decode(&cid), Some(index));
+            assert_eq!(&cid[HandleCodec::LEN..], &tail[..]);
+        }
+        assert_eq!(codec.decode(&[0; 4]), None);
+    }
+
+    #[test]
+    fn handle_unlinkable() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let codec = HandleCodec::new(&mut rng);
+        let mut a = ConnectionId::new(&[1, 2, 3, 4, 0, 0, 0, 0]);
+        let mut b = ConnectionId::new(&[1, 2, 3, 5, 0, 0, 0, 0]);
+        codec.encode(&mut a, 7);
+        codec.encode(&mut b, 7);
+        assert_ne!(a[..4], b[..4]);
+        assert_ne!(a[4..8], b[4..8]);
+    }
 }
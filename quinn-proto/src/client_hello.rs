@@ -0,0 +1,159 @@
+//! Best-effort inspection of a TLS ClientHello, ahead of the cryptographic handshake
+//!
+//! Lets a server triage an incoming connection attempt by the name and protocols the client asks
+//! for before committing any per-connection state to it. Only the first CRYPTO frame of the first
+//! Initial packet is examined, so a ClientHello split across several packets yields nothing.
+
+use bytes::{Buf, Bytes};
+
+use crate::frame::{self, Frame};
+
+/// Fields of interest from a ClientHello
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ClientHello {
+    /// The host name from the server_name extension
+    pub(crate) server_name: Option<String>,
+    /// Protocols offered in the application_layer_protocol_negotiation extension
+    pub(crate) alpn_protocols: Option<Vec<Vec<u8>>>,
+}
+
+impl ClientHello {
+    /// Extract what can be found from the frames of a decrypted Initial packet
+    pub(crate) fn peek(payload: Bytes) -> Self {
+        for frame in frame::Iter::new(payload) {
+            match frame {
+                Frame::Crypto(frame::Crypto { offset: 0, data }) => {
+                    return Self::parse(data).unwrap_or_default();
+                }
+                Frame::Invalid { .. } => break,
+                _ => {}
+            }
+        }
+        Self::default()
+    }
+
+    /// Parse a ClientHello handshake message, which may be truncated
+    ///
+    /// Returns `None` if not even the extensions are reached.
+    fn parse(mut data: Bytes) -> Option<Self> {
+        const CLIENT_HELLO: u8 = 1;
+        const SERVER_NAME: u16 = 0;
+        const ALPN: u16 = 16;
+
+        if take_u8(&mut data)? != CLIENT_HELLO {
+            return None;
+        }
+        // Handshake message length, legacy version and random
+        skip(&mut data, 3 + 2 + 32)?;
+        let len = take_u8(&mut data)? as usize;
+        skip(&mut data, len)?; // legacy_session_id
+        let len = take_u16(&mut data)? as usize;
+        skip(&mut data, len)?; // cipher_suites
+        let len = take_u8(&mut data)? as usize;
+        skip(&mut data, len)?; // legacy_compression_methods
+        let len = (take_u16(&mut data)? as usize).min(data.len());
+        let mut extensions = take(&mut data, len)?;
+
+        let mut result = Self::default();
+        while extensions.has_remaining() {
+            let ty = take_u16(&mut extensions)?;
+            let len = take_u16(&mut extensions)? as usize;
+            let body = match take(&mut extensions, len) {
+                Some(x) => x,
+                // Truncated by the end of the CRYPTO frame
+                None => break,
+            };
+            match ty {
+                SERVER_NAME => result.server_name = parse_server_name(body),
+                ALPN => result.alpn_protocols = parse_alpn(body),
+                _ => {}
+            }
+        }
+        Some(result)
+    }
+}
+
+fn parse_server_name(mut body: Bytes) -> Option<String> {
+    const HOST_NAME: u8 = 0;
+    let len = take_u16(&mut body)? as usize;
+    let mut list = take(&mut body, len)?;
+    while list.has_remaining() {
+        let ty = take_u8(&mut list)?;
+        let len = take_u16(&mut list)? as usize;
+        let name = take(&mut list, len)?;
+        if ty == HOST_NAME {
+            return String::from_utf8(name.to_vec()).ok();
+        }
+    }
+    None
+}
+
+fn parse_alpn(mut body: Bytes) -> Option<Vec<Vec<u8>>> {
+    let len = take_u16(&mut body)? as usize;
+    let mut list = take(&mut body, len)?;
+    let mut protocols = Vec::new();
+    while list.has_remaining() {
+        let len = take_u8(&mut list)? as usize;
+        protocols.push(take(&mut list, len)?.to_vec());
+    }
+    Some(protocols)
+}
+
+fn take_u8(data: &mut Bytes) -> Option<u8> {
+    if data.remaining() < 1 {
+        return None;
+    }
+    Some(data.get_u8())
+}
+
+fn take_u16(data: &mut Bytes) -> Option<u16> {
+    if data.remaining() < 2 {
+        return None;
+    }
+    Some(data.get_u16())
+}
+
+fn take(data: &mut Bytes, len: usize) -> Option<Bytes> {
+    if data.remaining() < len {
+        return None;
+    }
+    Some(data.split_to(len))
+}
+
+fn skip(data: &mut Bytes, len: usize) -> Option<()> {
+    take(data, len).map(|_| ())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex_literal::hex;
+
+    #[test]
+    fn server_name_and_alpn() {
+        let mut hello = Vec::new();
+        hello.extend_from_slice(&hex!("01 000000 0303"));
+        hello.extend_from_slice(&[0; 32]); // random
+        hello.extend_from_slice(&hex!("00 0002 1301 01 00"));
+        let extensions = hex!(
+            "0000 0010 000e 00 000b 6578616d706c652e636f6d"
+            "0010 000b 0009 02 6833 05 68332d3239"
+            "002b 0003 02 0304"
+        );
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let result = ClientHello::parse(Bytes::from(hello.clone())).unwrap();
+        assert_eq!(result.server_name.as_deref(), Some("example.com"));
+        assert_eq!(
+            result.alpn_protocols,
+            Some(vec![b"h3".to_vec(), b"h3-29".to_vec()])
+        );
+
+        // Truncated in the middle of the ALPN extension
+        hello.truncate(hello.len() - 12);
+        let result = ClientHello::parse(Bytes::from(hello)).unwrap();
+        assert_eq!(result.server_name.as_deref(), Some("example.com"));
+        assert_eq!(result.alpn_protocols, None);
+    }
+}
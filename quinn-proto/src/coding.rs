@@ -1,3 +1,25 @@
+//! Coding of the primitive types found in QUIC's wire format
+//!
+//! Protocols layered on QUIC, such as HTTP/3, and extensions to QUIC itself encode their messages
+//! with the same primitives, chiefly the [variable-length integer](crate::VarInt). The
+//! [`Codec`] trait reads and writes them from any [`Buf`] or [`BufMut`], and [`BufExt`] and
+//! [`BufMutExt`] do so with a little less ceremony.
+//!
+//! ```
+//! # use quinn_proto::{coding::{BufExt, BufMutExt, Codec}, VarInt};
+//! let mut buf = Vec::new();
+//! VarInt::from_u32(494_878_333).encode(&mut buf);
+//! buf.write_var(37);
+//! buf.write(0x1234u16);
+//! assert_eq!(buf, [0x9d, 0x7f, 0x3e, 0x7d, 0x25, 0x12, 0x34]);
+//!
+//! let mut r = bytes::Bytes::from(buf);
+//! assert_eq!(VarInt::decode(&mut r).unwrap(), VarInt::from_u32(494_878_333));
+//! assert_eq!(r.get_var().unwrap(), 37);
+//! assert_eq!(r.get::<u16>().unwrap(), 0x1234);
+//! assert!(r.get::<u8>().is_err());
+//! ```
+
 use std::net::{Ipv4Addr, Ipv6Addr};
 
 use bytes::{Buf, BufMut};
@@ -5,14 +27,21 @@ use thiserror::Error;
 
 use crate::VarInt;
 
+/// Error indicating that the buffer ended before the value being decoded
 #[derive(Error, Debug, Copy, Clone, Eq, PartialEq)]
 #[error("unexpected end of buffer")]
 pub struct UnexpectedEnd;
 
+/// Result of decoding a value
 pub type Result<T> = ::std::result::Result<T, UnexpectedEnd>;
 
+/// Types with a fixed encoding on the wire
+///
+/// Integers other than [`VarInt`] are encoded in network byte order.
 pub trait Codec: Sized {
+    /// Decode a value from the start of `buf`, consuming its encoding
     fn decode<B: Buf>(buf: &mut B) -> Result<Self>;
+    /// Append the encoding of the value to `buf`
     fn encode<B: BufMut>(&self, buf: &mut B);
 }
 
@@ -92,8 +121,11 @@ impl Codec for Ipv6Addr {
     }
 }
 
+/// Decoding of [`Codec`] values from any [`Buf`]
 pub trait BufExt {
+    /// Decode a `T`, consuming its encoding
     fn get<T: Codec>(&mut self) -> Result<T>;
+    /// Decode a variable-length integer, consuming its encoding
     fn get_var(&mut self) -> Result<u64>;
 }
 
@@ -107,8 +139,16 @@ impl<T: Buf> BufExt for T {
     }
 }
 
+/// Encoding of [`Codec`] values into any [`BufMut`]
 pub trait BufMutExt {
+    /// Append the encoding of `x`
     fn write<T: Codec>(&mut self, x: T);
+    /// Append the encoding of `x` as a variable-length integer
+    ///
+    /// # Panics
+    ///
+    /// If `x` is 2^62 or more, which can't be encoded. Use [`write()`](Self::write) with a
+    /// [`VarInt`] to exclude that statically.
     fn write_var(&mut self, x: u64);
 }
 
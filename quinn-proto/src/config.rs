@@ -1,15 +1,26 @@
-use std::{convert::TryInto, fmt, num::TryFromIntError, sync::Arc, time::Duration};
+use std::{
+    convert::TryInto,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    num::TryFromIntError,
+    sync::Arc,
+    time::Duration,
+};
 
-use rand::RngCore;
 use thiserror::Error;
 
 #[cfg(feature = "rustls")]
 use crate::crypto::types::{Certificate, CertificateChain, PrivateKey};
+#[cfg(feature = "tap")]
+use crate::tap::PacketTap;
 use crate::{
     cid_generator::{ConnectionIdGenerator, RandomConnectionIdGenerator},
     congestion,
     crypto::{self, ClientConfig as _, HandshakeTokenKey as _, HmacKey as _, ServerConfig as _},
-    VarInt, VarIntBoundsExceeded,
+    random::{OsRandom, RandomSource},
+    resumption::ResumptionStore,
+    time::{default_clock, Clock},
+    QlogFactory, VarInt, VarIntBoundsExceeded,
 };
 
 /// Parameters governing the core QUIC state machine
@@ -29,6 +40,7 @@ pub struct TransportConfig {
     pub(crate) max_concurrent_uni_streams: VarInt,
     pub(crate) max_idle_timeout: Option<Duration>,
     pub(crate) stream_receive_window: VarInt,
+    pub(crate) stream_reassembly_window: Option<u64>,
     pub(crate) receive_window: VarInt,
     pub(crate) send_window: u64,
 
@@ -39,12 +51,19 @@ pub struct TransportConfig {
 
     pub(crate) persistent_congestion_threshold: u32,
     pub(crate) keep_alive_interval: Option<Duration>,
+    pub(crate) keep_alive_only_when_active: bool,
+    pub(crate) mtu_discovery_interval: Option<Duration>,
+    pub(crate) local_path_filter: Arc<LocalPathFilter>,
+    pub(crate) timer_slack: Duration,
     pub(crate) crypto_buffer_size: usize,
     pub(crate) allow_spin: bool,
     pub(crate) datagram_receive_buffer_size: Option<usize>,
     pub(crate) datagram_send_buffer_size: usize,
 
     pub(crate) congestion_controller_factory: Box<dyn congestion::ControllerFactory + Send + Sync>,
+    pub(crate) qlog_factory: Option<Arc<dyn QlogFactory>>,
+    #[cfg(feature = "tap")]
+    pub(crate) packet_tap: Option<Arc<dyn PacketTap>>,
 }
 
 impl TransportConfig {
@@ -93,6 +112,21 @@ impl TransportConfig {
         Ok(self)
     }
 
+    /// Maximum distance past the first gap in a stream's received data at which data is buffered
+    ///
+    /// If the peer sends data for a stream beyond this distance, even if flow control would permit
+    /// it, the stream is stopped with error code 0 on the application's behalf, discarding what was
+    /// buffered, and further reads fail with `ReadError::UnknownStream`. This bounds the memory a
+    /// peer can pin by deliberately leaving gaps in a stream, independent of
+    /// `stream_receive_window`. Data received without gaps doesn't count toward the limit, so a
+    /// peer outpacing the application is held back by flow control alone.
+    ///
+    /// `None` to rely on flow control alone, which is the default.
+    pub fn stream_reassembly_window(&mut self, value: Option<u64>) -> &mut Self {
+        self.stream_reassembly_window = value;
+        self
+    }
+
     /// Maximum number of bytes the peer may transmit across all streams of a connection before
     /// becoming blocked.
     ///
@@ -159,6 +193,69 @@ impl TransportConfig {
         self
     }
 
+    /// Whether to send keep-alive packets only while the application has activity pending
+    ///
+    /// Activity is pending while any stream is open, or while the application waits for the peer
+    /// in some other way, as reported by [`Connection::set_awaiting_peer()`]. A connection without
+    /// either is left to time out once the idle timeout elapses, instead of being kept alive
+    /// indefinitely by `keep_alive_interval`. `false` by default.
+    ///
+    /// [`Connection::set_awaiting_peer()`]: crate::generic::Connection::set_awaiting_peer
+    pub fn keep_alive_only_when_active(&mut self, value: bool) -> &mut Self {
+        self.keep_alive_only_when_active = value;
+        self
+    }
+
+    /// Period after which to search again for a larger path MTU, or `None` to never search
+    ///
+    /// Packets are no larger than 1232 bytes, which any QUIC path must carry, until the path is
+    /// shown to carry larger ones. Once the handshake is confirmed, search probes send packets up
+    /// to the smaller of the endpoint's and the peer's `max_udp_payload_size`, and only sizes
+    /// whose probes are acknowledged are used. A lost probe doesn't count as congestion. If the
+    /// path stops delivering packets altogether, the MTU falls back to 1232 bytes, and the search
+    /// resumes after this period, as it does after every search to detect paths that improved.
+    ///
+    /// A migration to a new address starts over from 1232 bytes. `None` by default. Local paths
+    /// are treated differently; see [`local_path_filter()`](Self::local_path_filter).
+    pub fn mtu_discovery_interval(&mut self, value: Option<Duration>) -> &mut Self {
+        self.mtu_discovery_interval = value;
+        self
+    }
+
+    /// Decide which peer addresses are reached over a local path, such as loopback
+    ///
+    /// A local path is assumed to carry large datagrams, so once the peer's transport parameters
+    /// are known, packets are as large as its `max_udp_payload_size` allows, up to 65527 bytes,
+    /// without searching for the MTU first. Endpoints meant for loopback use, e.g. in tests or
+    /// sidecar setups, should raise `max_udp_payload_size` well above its default to benefit.
+    /// Should the path stop delivering packets that large, it falls back on 1232 bytes and is
+    /// searched every minute, or every `mtu_discovery_interval` if set. Packets on a local path
+    /// are still paced, lest a burst overflow the receiver's socket buffer, but at a rate of four
+    /// congestion windows per RTT rather than 1.25, as there are no router queues to spare.
+    ///
+    /// By default, exactly the loopback addresses are local. `filter` replaces that, e.g. to also
+    /// trust a peer on the same host or a directly attached network, or, with `|_| false`, to
+    /// treat every path alike.
+    pub fn local_path_filter<F>(&mut self, filter: F) -> &mut Self
+    where
+        F: Fn(&SocketAddr) -> bool + Send + Sync + 'static,
+    {
+        self.local_path_filter = Arc::new(filter);
+        self
+    }
+
+    /// Maximum delay allowed when coalescing timers
+    ///
+    /// Timers which don't drive loss recovery, pacing or keep-alives, such as the idle and key
+    /// discard timers, may fire up to this much later than scheduled if that allows them to be
+    /// handled together. Coalescing is per connection: it saves wakeups when one connection has
+    /// several timers due close together, but timers of different connections aren't aligned with
+    /// each other. Zero, the default, never delays a timer.
+    pub fn timer_slack(&mut self, value: Duration) -> &mut Self {
+        self.timer_slack = value;
+        self
+    }
+
     /// Maximum quantity of out-of-order crypto layer data to buffer
     pub fn crypto_buffer_size(&mut self, value: usize) -> &mut Self {
         self.crypto_buffer_size = value;
@@ -214,6 +311,34 @@ impl TransportConfig {
         self.congestion_controller_factory = Box::new(factory);
         self
     }
+
+    /// How to obtain sinks for connection traces in the qlog format
+    ///
+    /// Each connection using this configuration calls the factory once, when it is created, and
+    /// writes its trace to the result. Untraced by default.
+    ///
+    /// # Example
+    /// ```
+    /// # use quinn_proto::*; use std::{fs::File, io, sync::Arc};
+    /// let mut config = TransportConfig::default();
+    /// config.qlog_factory(Some(Arc::new(|side: Side, odcid: &ConnectionId| {
+    ///     let file = File::create(format!("{}-{:?}.sqlog", odcid, side)).ok()?;
+    ///     Some(Box::new(io::BufWriter::new(file)) as Box<dyn io::Write + Send>)
+    /// })));
+    /// ```
+    pub fn qlog_factory(&mut self, factory: Option<Arc<dyn QlogFactory>>) -> &mut Self {
+        self.qlog_factory = factory;
+        self
+    }
+
+    /// Observer of the decrypted contents of packets sent and received by each connection
+    ///
+    /// See the [`tap`](crate::tap) module. `None` by default.
+    #[cfg(feature = "tap")]
+    pub fn packet_tap(&mut self, tap: Option<Arc<dyn PacketTap>>) -> &mut Self {
+        self.packet_tap = tap;
+        self
+    }
 }
 
 impl Default for TransportConfig {
@@ -229,6 +354,7 @@ impl Default for TransportConfig {
             max_concurrent_uni_streams: 100u32.into(),
             max_idle_timeout: Some(Duration::from_millis(10_000)),
             stream_receive_window: STREAM_RWND.into(),
+            stream_reassembly_window: None,
             receive_window: VarInt::MAX,
             send_window: (8 * STREAM_RWND).into(),
 
@@ -239,52 +365,110 @@ impl Default for TransportConfig {
 
             persistent_congestion_threshold: 3,
             keep_alive_interval: None,
+            keep_alive_only_when_active: false,
+            mtu_discovery_interval: None,
+            local_path_filter: Arc::new(is_loopback),
+            timer_slack: Duration::from_millis(0),
             crypto_buffer_size: 16 * 1024,
             allow_spin: true,
             datagram_receive_buffer_size: Some(STREAM_RWND as usize),
             datagram_send_buffer_size: 1024 * 1024,
 
             congestion_controller_factory: Box::new(Arc::new(congestion::NewRenoConfig::default())),
+            qlog_factory: None,
+            #[cfg(feature = "tap")]
+            packet_tap: None,
         }
     }
 }
 
 impl fmt::Debug for TransportConfig {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt.debug_struct("TranportConfig")
-            .field(
-                "max_concurrent_bidi_streams",
-                &self.max_concurrent_bidi_streams,
-            )
-            .field(
-                "max_concurrent_uni_streams",
-                &self.max_concurrent_uni_streams,
-            )
-            .field("max_idle_timeout", &self.max_idle_timeout)
-            .field("stream_receive_window", &self.stream_receive_window)
-            .field("receive_window", &self.receive_window)
-            .field("send_window", &self.send_window)
-            .field("max_tlps", &self.max_tlps)
-            .field("packet_threshold", &self.packet_threshold)
-            .field("time_threshold", &self.time_threshold)
-            .field("initial_rtt", &self.initial_rtt)
-            .field(
-                "persistent_congestion_threshold",
-                &self.persistent_congestion_threshold,
-            )
-            .field("keep_alive_interval", &self.keep_alive_interval)
-            .field("crypto_buffer_size", &self.crypto_buffer_size)
-            .field("allow_wpin", &self.allow_spin)
-            .field(
-                "datagram_receive_buffer_size",
-                &self.datagram_receive_buffer_size,
-            )
-            .field("datagram_send_buffer_size", &self.datagram_send_buffer_size)
-            .field("congestion_controller_factory", &"[ opaque ]")
-            .finish()
+        let mut fmt = fmt.debug_struct("TranportConfig");
+        fmt.field(
+            "max_concurrent_bidi_streams",
+            &self.max_concurrent_bidi_streams,
+        )
+        .field(
+            "max_concurrent_uni_streams",
+            &self.max_concurrent_uni_streams,
+        )
+        .field("max_idle_timeout", &self.max_idle_timeout)
+        .field("stream_receive_window", &self.stream_receive_window)
+        .field("stream_reassembly_window", &self.stream_reassembly_window)
+        .field("receive_window", &self.receive_window)
+        .field("send_window", &self.send_window)
+        .field("max_tlps", &self.max_tlps)
+        .field("packet_threshold", &self.packet_threshold)
+        .field("time_threshold", &self.time_threshold)
+        .field("initial_rtt", &self.initial_rtt)
+        .field(
+            "persistent_congestion_threshold",
+            &self.persistent_congestion_threshold,
+        )
+        .field("keep_alive_interval", &self.keep_alive_interval)
+        .field(
+            "keep_alive_only_when_active",
+            &self.keep_alive_only_when_active,
+        )
+        .field("mtu_discovery_interval", &self.mtu_discovery_interval)
+        .field("local_path_filter", &"[ opaque ]")
+        .field("timer_slack", &self.timer_slack)
+        .field("crypto_buffer_size", &self.crypto_buffer_size)
+        .field("allow_wpin", &self.allow_spin)
+        .field(
+            "datagram_receive_buffer_size",
+            &self.datagram_receive_buffer_size,
+        )
+        .field("datagram_send_buffer_size", &self.datagram_send_buffer_size)
+        .field("congestion_controller_factory", &"[ opaque ]")
+        .field(
+            "qlog_factory",
+            &self.qlog_factory.as_ref().map(|_| "[ opaque ]"),
+        );
+        #[cfg(feature = "tap")]
+        fmt.field(
+            "packet_tap",
+            &self.packet_tap.as_ref().map(|_| "[ opaque ]"),
+        );
+        fmt.finish()
+    }
+}
+
+impl TransportConfig {
+    /// Whether `remote` is reached over a local path
+    pub(crate) fn is_local_path(&self, remote: &SocketAddr) -> bool {
+        (self.local_path_filter)(remote)
+    }
+
+    /// Period after which to search again for the MTU of a path, if it's to be searched at all
+    pub(crate) fn mtu_discovery_interval_for(&self, local: bool) -> Option<Duration> {
+        match self.mtu_discovery_interval {
+            None if local => Some(LOCAL_MTU_DISCOVERY_INTERVAL),
+            x => x,
+        }
     }
 }
 
+/// Decides whether a peer address is reached over a local path
+pub(crate) type LocalPathFilter = dyn Fn(&SocketAddr) -> bool + Send + Sync;
+
+/// Whether `addr` is a loopback address, including IPv4 loopback addresses mapped to IPv6
+fn is_loopback(addr: &SocketAddr) -> bool {
+    match addr.ip() {
+        IpAddr::V4(ip) => ip.is_loopback(),
+        IpAddr::V6(ip) => match ip.segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, _] => hi >> 8 == 127,
+            _ => ip.is_loopback(),
+        },
+    }
+}
+
+/// Period after which to search the MTU of a local path again, absent `mtu_discovery_interval`
+///
+/// Searching is cheap on a local path, whose round trips take microseconds.
+const LOCAL_MTU_DISCOVERY_INTERVAL: Duration = Duration::from_secs(60);
+
 /// Global configuration for the endpoint, affecting all connections
 ///
 /// Default values should be suitable for most internet applications.
@@ -299,6 +483,8 @@ where
     /// Create a cid generator for local cid in Endpoint struct
     pub(crate) connection_id_generator_factory:
         Arc<dyn Fn() -> Box<dyn ConnectionIdGenerator> + Send + Sync>,
+    pub(crate) random_source: Arc<dyn RandomSource>,
+    pub(crate) clock: Arc<dyn Clock>,
 }
 
 impl<S> EndpointConfig<S>
@@ -313,6 +499,8 @@ where
             reset_key: Arc::new(reset_key),
             max_udp_payload_size: 1480u32.into(), // Typical internet MTU minus IPv4 and UDP overhead, rounded up to a multiple of 8
             connection_id_generator_factory: Arc::new(cid_factory),
+            random_source: Arc::new(OsRandom),
+            clock: default_clock(),
         }
     }
 
@@ -334,6 +522,30 @@ where
         self
     }
 
+    /// Source of randomness for each `Endpoint` constructed from this configuration
+    ///
+    /// Connections draw their randomness from their endpoint, so a [`SeededRandom`], together with
+    /// fixed keys, a CID generator using the same source (see
+    /// [`RandomConnectionIdGenerator::set_random_source()`]), and deterministic cryptography, makes
+    /// an endpoint's behavior a pure function of its inputs. This is useful for reproducible
+    /// simulations and fuzzing. Defaults to [`OsRandom`].
+    ///
+    /// [`SeededRandom`]: crate::SeededRandom
+    pub fn random_source(&mut self, source: Arc<dyn RandomSource>) -> &mut Self {
+        self.random_source = source;
+        self
+    }
+
+    /// Clock read by [`Endpoint::connect()`](crate::generic::Endpoint::connect)
+    ///
+    /// Every other operation takes the time from its caller. Defaults to the host's monotonic
+    /// clock, except on `wasm32-unknown-unknown`, where there is none and a clock must be supplied
+    /// before connecting.
+    pub fn clock(&mut self, clock: Arc<dyn Clock>) -> &mut Self {
+        self.clock = clock;
+        self
+    }
+
     /// Private key used to send authenticated connection resets to peers who were
     /// communicating with a previous instance of this endpoint.
     pub fn reset_key(&mut self, value: &[u8]) -> Result<&mut Self, ConfigError> {
@@ -345,6 +557,9 @@ where
     ///
     /// The default is suitable for typical internet applications. Applications which expect to run
     /// on networks supporting Ethernet jumbo frames or similar should set this appropriately.
+    ///
+    /// Also bounds the size of packets sent, which only exceed 1232 bytes once the path is shown to
+    /// carry them, as enabled by [`TransportConfig::mtu_discovery_interval()`].
     pub fn max_udp_payload_size(&mut self, value: u64) -> Result<&mut Self, ConfigError> {
         self.max_udp_payload_size = value.try_into()?;
         Ok(self)
@@ -370,6 +585,8 @@ impl<S: crypto::Session> fmt::Debug for EndpointConfig<S> {
             .field("reset_key", &"[ elided ]")
             .field("max_udp_payload_size", &self.max_udp_payload_size)
             .field("cid_generator_factory", &"[ elided ]")
+            .field("random_source", &"[ elided ]")
+            .field("clock", &"[ elided ]")
             .finish()
     }
 }
@@ -377,7 +594,7 @@ impl<S: crypto::Session> fmt::Debug for EndpointConfig<S> {
 impl<S: crypto::Session> Default for EndpointConfig<S> {
     fn default() -> Self {
         let mut reset_key = vec![0; S::HmacKey::KEY_LEN];
-        rand::thread_rng().fill_bytes(&mut reset_key);
+        OsRandom.fill_bytes(&mut reset_key);
         Self::new(
             S::HmacKey::new(&reset_key)
                 .expect("HMAC key rejected random bytes; use EndpointConfig::new instead"),
@@ -391,6 +608,8 @@ impl<S: crypto::Session> Clone for EndpointConfig<S> {
             reset_key: self.reset_key.clone(),
             max_udp_payload_size: self.max_udp_payload_size,
             connection_id_generator_factory: self.connection_id_generator_factory.clone(),
+            random_source: self.random_source.clone(),
+            clock: self.clock.clone(),
         }
     }
 }
@@ -428,8 +647,14 @@ where
     /// Improves behavior for clients that move between different internet connections or suffer NAT
     /// rebinding. Enabled by default.
     pub(crate) migration: bool,
+
+    /// Chooses the application protocol of each connection
+    pub(crate) alpn_selector: Option<Arc<AlpnSelector>>,
 }
 
+/// Callback choosing a connection's application protocol from the server name and offered list
+pub(crate) type AlpnSelector = dyn Fn(Option<&str>, &[Vec<u8>]) -> Option<Vec<u8>> + Send + Sync;
+
 impl<S> ServerConfig<S>
 where
     S: crypto::Session,
@@ -447,6 +672,8 @@ where
             concurrent_connections: 100_000,
 
             migration: true,
+
+            alpn_selector: None,
         }
     }
 
@@ -470,9 +697,11 @@ where
         self
     }
 
-    /// Maximum number of incoming connections to buffer.
+    /// Maximum number of concurrent connections, including attempts awaiting a decision
     ///
-    /// Accepting a connection removes it from the buffer, so this does not need to be large.
+    /// Further connection attempts are refused. Attempts yielded as
+    /// [`DatagramEvent::NewConnection`](crate::DatagramEvent::NewConnection) count until
+    /// they are accepted, refused, retried or ignored.
     pub fn concurrent_connections(&mut self, value: u32) -> &mut Self {
         self.concurrent_connections = value;
         self
@@ -486,6 +715,25 @@ where
         self.migration = value;
         self
     }
+
+    /// Choose the application protocol of each connection by calling `selector`
+    ///
+    /// `selector` is passed the server name the client asked for, if any, and the protocols it
+    /// offers in its order of preference. It returns the protocol to negotiate, which must be one
+    /// of those offered, or `None` to refuse the connection. This takes the place of the preference
+    /// list in the TLS configuration, letting an endpoint serving several protocols decide for
+    /// each client.
+    ///
+    /// The list is read from the client's first packet. In the rare case that it doesn't fit
+    /// there, or if the client offers no protocols at all, `selector` isn't called and the TLS
+    /// configuration applies as usual.
+    pub fn alpn_selector<F>(&mut self, selector: F) -> &mut Self
+    where
+        F: Fn(Option<&str>, &[Vec<u8>]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    {
+        self.alpn_selector = Some(Arc::new(selector));
+        self
+    }
 }
 
 #[cfg(feature = "rustls")]
@@ -514,6 +762,7 @@ where
             .field("retry_token_lifetime", &self.retry_token_lifetime)
             .field("concurrent_connections", &self.concurrent_connections)
             .field("migration", &self.migration)
+            .field("alpn_selector", &self.alpn_selector.is_some())
             .finish()
     }
 }
@@ -523,10 +772,8 @@ where
     S: crypto::Session,
 {
     fn default() -> Self {
-        let rng = &mut rand::thread_rng();
-
         let mut master_key = [0u8; 64];
-        rng.fill_bytes(&mut master_key);
+        OsRandom.fill_bytes(&mut master_key);
 
         Self::new(S::HandshakeTokenKey::from_secret(&master_key))
     }
@@ -546,6 +793,7 @@ where
             retry_token_lifetime: self.retry_token_lifetime,
             concurrent_connections: self.concurrent_connections,
             migration: self.migration,
+            alpn_selector: self.alpn_selector.clone(),
         }
     }
 }
@@ -562,10 +810,22 @@ where
 
     /// Cryptographic configuration to use
     pub crypto: S::ClientConfig,
+
+    /// Where to find and record what's learned about servers to speed up later connections
+    ///
+    /// Session tickets are stored separately, by the cryptographic configuration.
+    pub(crate) resumption: Option<Arc<ResumptionStore>>,
 }
 
 #[cfg(feature = "rustls")]
 impl ClientConfig<crypto::rustls::TlsSession> {
+    /// Keep all resumption state, including TLS session tickets, in `store`
+    pub fn resumption_store(&mut self, store: Arc<ResumptionStore>) -> &mut Self {
+        Arc::make_mut(&mut self.crypto).session_persistence = store.clone();
+        self.resumption = Some(store);
+        self
+    }
+
     /// Add a trusted certificate authority
     pub fn add_certificate_authority(
         &mut self,
@@ -587,6 +847,7 @@ where
         Self {
             transport: Default::default(),
             crypto: S::ClientConfig::new(),
+            resumption: None,
         }
     }
 }
@@ -600,6 +861,7 @@ where
         Self {
             transport: self.transport.clone(),
             crypto: self.crypto.clone(),
+            resumption: self.resumption.clone(),
         }
     }
 }
@@ -612,6 +874,7 @@ where
         fmt.debug_struct("ClientConfig<T>")
             .field("transport", &self.transport)
             .field("crypto", &"ClientConfig { elided }")
+            .field("resumption", &self.resumption)
             .finish()
     }
 }
@@ -0,0 +1,443 @@
+//! Checks that an endpoint rejects protocol violations committed by its peer
+//!
+//! A [`Harness`] connects a client and a server over a simulated [`Network`] and lets either
+//! side misbehave: [`Harness::inject()`] sends arbitrary [`Frames`] past all local validation,
+//! and [`Harness::replay()`] puts previously sent datagrams back on the wire. The outcome is
+//! observed through [`Harness::error()`].
+//!
+//! [`checks()`] provides a suite of violations that any QUIC server must detect, so that
+//! modified versions of this crate can verify they still do:
+//!
+//! ```ignore
+//! for (name, result) in conformance::run_all(|| make_harness()) {
+//!     assert!(result.is_ok(), "{}: {}", name, result.unwrap_err());
+//! }
+//! ```
+
+use bytes::{BufMut, Bytes, BytesMut};
+use thiserror::Error;
+
+use crate::{
+    coding::BufMutExt,
+    config::ClientConfig,
+    connection::{Connection, ConnectionError, Event},
+    crypto,
+    endpoint::Endpoint,
+    frame::{self, StreamMeta},
+    range_set::RangeSet,
+    simulation::{Network, NodeId},
+    ConnectError, ConnectionHandle, Dir, Side, StreamId, TransportErrorCode,
+};
+
+/// A client and server connected over a simulated network
+pub struct Harness<S>
+where
+    S: crypto::Session,
+{
+    network: Network<S>,
+    client: NodeId,
+    server: NodeId,
+    client_ch: ConnectionHandle,
+    server_ch: Option<ConnectionHandle>,
+    /// Per side, whether the connection has been established
+    connected: [bool; 2],
+    /// Per side, the reason the connection was lost, if it has been
+    lost: [Option<ConnectionError>; 2],
+}
+
+impl<S> Harness<S>
+where
+    S: crypto::Session,
+{
+    /// Begin connecting `client` to `server`
+    ///
+    /// The network's random decisions are derived from `seed`. Its links are perfect unless
+    /// reconfigured through [`network_mut()`](Self::network_mut).
+    pub fn new(
+        seed: u64,
+        client: Endpoint<S>,
+        server: Endpoint<S>,
+        config: ClientConfig<S>,
+        server_name: &str,
+    ) -> Result<Self, ConnectError> {
+        let mut network = Network::new(seed);
+        let client = network.add_node(client);
+        let server = network.add_node(server);
+        let client_ch = network.connect(client, server, config, server_name)?;
+        Ok(Self {
+            network,
+            client,
+            server,
+            client_ch,
+            server_ch: None,
+            connected: [false; 2],
+            lost: [None, None],
+        })
+    }
+
+    /// Run until the network is idle, returning whether both sides are connected
+    pub fn handshake(&mut self) -> bool {
+        self.run();
+        self.connected == [true, true]
+    }
+
+    /// Run until no datagrams are in flight and neither connection has work pending
+    pub fn run(&mut self) {
+        self.network.run();
+        if self.server_ch.is_none() {
+            self.server_ch = self.network.node_mut(self.server).accept();
+        }
+        self.drain(Side::Client);
+        self.drain(Side::Server);
+    }
+
+    /// Send `frames` from `side` in a 1-RTT packet of their own, then run
+    ///
+    /// See [`Connection::inject_frames()`].
+    pub fn inject(&mut self, side: Side, frames: Bytes) -> Result<(), FramesTooLarge> {
+        if let Some(conn) = self.connection_mut(side) {
+            conn.inject_frames(frames)?;
+        }
+        self.run();
+        Ok(())
+    }
+
+    /// Put every datagram `side` sent since [`record()`](Self::record) was called back on the
+    /// wire, then run
+    pub fn replay(&mut self, side: Side) {
+        let (from, to) = match side {
+            Side::Client => (self.client, self.server),
+            Side::Server => (self.server, self.client),
+        };
+        let destination = self.network.node(to).addr();
+        let datagrams = self
+            .network
+            .recorded()
+            .iter()
+            .filter(|x| x.from == from && x.destination == destination)
+            .map(|x| x.contents.clone())
+            .collect::<Vec<_>>();
+        for contents in datagrams {
+            self.network.send_raw(from, destination, contents);
+        }
+        self.run();
+    }
+
+    /// Start recording datagrams for a later [`replay()`](Self::replay), discarding any recorded
+    /// previously
+    pub fn record(&mut self) {
+        self.network.record(true);
+    }
+
+    /// The reason `side`'s connection was lost, if it has been
+    pub fn error(&self, side: Side) -> Option<&ConnectionError> {
+        self.lost[side as usize].as_ref()
+    }
+
+    /// `side`'s connection, once it exists
+    pub fn connection(&self, side: Side) -> Option<&Connection<S>> {
+        let (node, ch) = self.handle(side)?;
+        self.network.node(node).connection(ch)
+    }
+
+    /// `side`'s connection, mutably
+    ///
+    /// Events left unprocessed when the harness next runs are discarded.
+    pub fn connection_mut(&mut self, side: Side) -> Option<&mut Connection<S>> {
+        let (node, ch) = self.handle(side)?;
+        self.network.node_mut(node).connection_mut(ch)
+    }
+
+    /// The underlying network
+    pub fn network(&self) -> &Network<S> {
+        &self.network
+    }
+
+    /// The underlying network, mutably
+    pub fn network_mut(&mut self) -> &mut Network<S> {
+        &mut self.network
+    }
+
+    fn handle(&self, side: Side) -> Option<(NodeId, ConnectionHandle)> {
+        match side {
+            Side::Client => Some((self.client, self.client_ch)),
+            Side::Server => Some((self.server, self.server_ch?)),
+        }
+    }
+
+    /// Process `side`'s connection events
+    fn drain(&mut self, side: Side) {
+        let mut connected = false;
+        let mut lost = None;
+        if let Some((node, ch)) = self.handle(side) {
+            while let Some(event) = self.network.node_mut(node).poll(ch) {
+                match event {
+                    Event::Connected => connected = true,
+                    Event::ConnectionLost { reason } => lost = Some(reason),
+                    _ => {}
+                }
+            }
+        }
+        self.connected[side as usize] |= connected;
+        if self.lost[side as usize].is_none() {
+            self.lost[side as usize] = lost;
+        }
+    }
+}
+
+/// Builder for a sequence of raw frames
+///
+/// None of the frames are checked for validity, and are encoded exactly as specified.
+#[derive(Debug, Default, Clone)]
+pub struct Frames(BytesMut);
+
+impl Frames {
+    /// Start an empty sequence
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append arbitrary bytes
+    pub fn raw(&mut self, data: &[u8]) -> &mut Self {
+        self.0.put_slice(data);
+        self
+    }
+
+    /// Append `count` PADDING frames
+    pub fn padding(&mut self, count: usize) -> &mut Self {
+        self.0.resize(self.0.len() + count, 0);
+        self
+    }
+
+    /// Append a PING frame
+    pub fn ping(&mut self) -> &mut Self {
+        self.0.write(frame::Type::PING);
+        self
+    }
+
+    /// Append an ACK frame acknowledging the packets numbered `range`
+    pub fn ack(&mut self, range: std::ops::Range<u64>) -> &mut Self {
+        let mut ranges = RangeSet::new();
+        ranges.insert(range);
+        frame::Ack::encode(0, &ranges, None, &mut self.0);
+        self
+    }
+
+    /// Append a STREAM frame carrying `data` at `offset` in stream `id`
+    pub fn stream(&mut self, id: StreamId, offset: u64, data: &[u8], fin: bool) -> &mut Self {
+        StreamMeta {
+            id,
+            offsets: offset..offset + data.len() as u64,
+            fin,
+        }
+        .encode(true, &mut self.0);
+        self.0.put_slice(data);
+        self
+    }
+
+    /// Append a MAX_DATA frame
+    pub fn max_data(&mut self, value: u64) -> &mut Self {
+        self.0.write(frame::Type::MAX_DATA);
+        self.0.write_var(value);
+        self
+    }
+
+    /// Append a NEW_TOKEN frame
+    pub fn new_token(&mut self, token: &[u8]) -> &mut Self {
+        self.0.write(frame::Type::NEW_TOKEN);
+        self.0.write_var(token.len() as u64);
+        self.0.put_slice(token);
+        self
+    }
+
+    /// Append a HANDSHAKE_DONE frame
+    pub fn handshake_done(&mut self) -> &mut Self {
+        self.0.write(frame::Type::HANDSHAKE_DONE);
+        self
+    }
+
+    /// The encoded frames
+    pub fn build(&self) -> Bytes {
+        self.0.clone().freeze()
+    }
+}
+
+/// A protocol violation a server must detect
+pub struct Check<S>
+where
+    S: crypto::Session,
+{
+    name: &'static str,
+    run: fn(&mut Harness<S>) -> Result<(), Failure>,
+}
+
+impl<S> Check<S>
+where
+    S: crypto::Session,
+{
+    /// Short description of the violation
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Complete the handshake on `harness`, then commit the violation against the server
+    pub fn run(&self, harness: &mut Harness<S>) -> Result<(), Failure> {
+        if !harness.handshake() {
+            return Err(Failure::Handshake);
+        }
+        (self.run)(harness)
+    }
+}
+
+/// Reasons a [`Check`] can fail
+#[derive(Debug, Error, Clone)]
+pub enum Failure {
+    /// The connection could not be established in the first place
+    #[error("handshake failed")]
+    Handshake,
+    /// The server didn't close the connection
+    #[error("violation was not detected")]
+    Undetected,
+    /// The server closed the connection with an unexpected error
+    #[error("expected {expected}, got {actual}")]
+    WrongError {
+        /// The error required by the specification
+        expected: TransportErrorCode,
+        /// How the connection was actually lost
+        actual: ConnectionError,
+    },
+    /// The server closed a connection that should have survived
+    #[error("connection lost: {0}")]
+    Lost(ConnectionError),
+    /// The check's frames couldn't be injected
+    #[error(transparent)]
+    Inject(#[from] FramesTooLarge),
+}
+
+/// Error returned by [`Connection::inject_frames()`] for frames that don't fit in a packet
+#[derive(Debug, Error, Copy, Clone, Eq, PartialEq)]
+#[error("injected frames must fit in a single packet")]
+pub struct FramesTooLarge;
+
+/// The standard suite of checks
+pub fn checks<S>() -> Vec<Check<S>>
+where
+    S: crypto::Session,
+{
+    vec![
+        Check {
+            name: "unknown frame type",
+            run: |h| {
+                let frames = Frames::new().raw(&[0x3f]).build();
+                violation(h, frames, TransportErrorCode::FRAME_ENCODING_ERROR)
+            },
+        },
+        Check {
+            name: "stream data beyond flow control limit",
+            run: |h| {
+                let id = StreamId::new(Side::Client, Dir::Bi, 0);
+                let frames = Frames::new().stream(id, 1 << 61, b"x", false).build();
+                violation(h, frames, TransportErrorCode::FLOW_CONTROL_ERROR)
+            },
+        },
+        Check {
+            name: "stream beyond stream limit",
+            run: |h| {
+                let id = StreamId::new(Side::Client, Dir::Bi, 1 << 40);
+                let frames = Frames::new().stream(id, 0, b"x", false).build();
+                violation(h, frames, TransportErrorCode::STREAM_LIMIT_ERROR)
+            },
+        },
+        Check {
+            name: "stream data on send-only stream",
+            run: |h| {
+                let id = StreamId::new(Side::Server, Dir::Uni, 0);
+                let frames = Frames::new().stream(id, 0, b"x", false).build();
+                violation(h, frames, TransportErrorCode::STREAM_STATE_ERROR)
+            },
+        },
+        Check {
+            name: "acknowledgement of unsent packet",
+            run: |h| {
+                let frames = Frames::new().ack(1 << 30..(1 << 30) + 1).build();
+                violation(h, frames, TransportErrorCode::PROTOCOL_VIOLATION)
+            },
+        },
+        Check {
+            name: "NEW_TOKEN from client",
+            run: |h| {
+                let frames = Frames::new().new_token(b"token").build();
+                violation(h, frames, TransportErrorCode::PROTOCOL_VIOLATION)
+            },
+        },
+        Check {
+            name: "HANDSHAKE_DONE from client",
+            run: |h| {
+                let frames = Frames::new().handshake_done().build();
+                violation(h, frames, TransportErrorCode::PROTOCOL_VIOLATION)
+            },
+        },
+        Check {
+            name: "replayed packets",
+            run: |h| {
+                h.record();
+                let id = StreamId::new(Side::Client, Dir::Uni, 0);
+                h.inject(
+                    Side::Client,
+                    Frames::new().stream(id, 0, b"x", true).build(),
+                )?;
+                let received = stream_frames(h);
+                h.replay(Side::Client);
+                if let Some(reason) = h.error(Side::Server) {
+                    return Err(Failure::Lost(reason.clone()));
+                }
+                // Duplicate packets must be discarded before their frames are processed
+                if stream_frames(h) != received {
+                    return Err(Failure::Undetected);
+                }
+                Ok(())
+            },
+        },
+    ]
+}
+
+/// Run every check in [`checks()`] on a fresh harness from `setup`
+pub fn run_all<S, F>(mut setup: F) -> Vec<(&'static str, Result<(), Failure>)>
+where
+    S: crypto::Session,
+    F: FnMut() -> Harness<S>,
+{
+    checks()
+        .iter()
+        .map(|check| (check.name(), check.run(&mut setup())))
+        .collect()
+}
+
+/// Send `frames` from the client and expect the server to fail with `expected`
+fn violation<S>(
+    harness: &mut Harness<S>,
+    frames: Bytes,
+    expected: TransportErrorCode,
+) -> Result<(), Failure>
+where
+    S: crypto::Session,
+{
+    harness.inject(Side::Client, frames)?;
+    match harness.error(Side::Server) {
+        None => Err(Failure::Undetected),
+        Some(ConnectionError::TransportError(e)) if e.code == expected => Ok(()),
+        Some(actual) => Err(Failure::WrongError {
+            expected,
+            actual: actual.clone(),
+        }),
+    }
+}
+
+fn stream_frames<S>(harness: &Harness<S>) -> u64
+where
+    S: crypto::Session,
+{
+    harness
+        .connection(Side::Server)
+        .map_or(0, |conn| conn.stats().frame_rx.stream)
+}
@@ -1,6 +1,6 @@
 //! Logic for controlling the rate at which data is sent
 
-use std::time::Instant;
+use crate::Instant;
 
 mod new_reno;
 pub use new_reno::{NewReno, NewRenoConfig};
@@ -28,6 +28,14 @@ pub trait Controller: Send {
 
     /// Initial congestion window
     fn initial_window(&self) -> u64;
+
+    /// Start from what was learned about the path by an earlier connection
+    ///
+    /// `window` is the congestion window that connection ended with. Called at most once, before
+    /// any packets are sent. Controllers that don't support this may ignore it.
+    fn resume(&mut self, window: u64) {
+        let _ = window;
+    }
 }
 
 /// Constructs controllers on demand
@@ -1,7 +1,7 @@
-use std::sync::Arc;
-use std::time::Instant;
+use std::{cmp, sync::Arc};
 
 use super::{Controller, ControllerFactory};
+use crate::Instant;
 
 /// A simple, standard congestion controller
 #[derive(Debug, Clone)]
@@ -93,8 +93,18 @@ impl Controller for NewReno {
     fn initial_window(&self) -> u64 {
         self.config.initial_window
     }
+
+    fn resume(&mut self, window: u64) {
+        // Conditions may have changed since, so only half of the previous window is trusted, and
+        // never so much that a stale or tampered record could unleash a large burst
+        let window = cmp::min(window / 2, MAX_RESUMED_WINDOWS * self.config.initial_window);
+        self.window = self.window.max(window);
+    }
 }
 
+/// Most initial windows a connection may start with when resuming an earlier one's window
+const MAX_RESUMED_WINDOWS: u64 = 10;
+
 /// Configuration for the `NewReno` congestion controller
 #[derive(Debug, Clone)]
 pub struct NewRenoConfig {
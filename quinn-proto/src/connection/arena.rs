@@ -0,0 +1,96 @@
+use super::spaces::Retransmits;
+use crate::frame;
+
+/// Maximum number of idle buffers of each kind retained
+///
+/// Covers the packets acknowledged by a typical ACK frame, so that steady-state sending is served
+/// entirely from recycled buffers.
+const MAX_FREE: usize = 64;
+
+/// Per-connection free lists for the bookkeeping attached to sent packets
+///
+/// Every packet sent carries records of its frames, which are released when the packet is
+/// acknowledged or declared lost. Recycling them here instead of returning them to the global
+/// allocator removes an allocation per packet in steady state, and since the free lists belong to
+/// the connection, their memory is released all at once when the connection is dropped.
+#[derive(Debug, Default)]
+pub(crate) struct Arena {
+    stream_frames: Vec<Vec<frame::StreamMeta>>,
+    retransmits: Vec<Retransmits>,
+}
+
+impl Arena {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get an empty list for recording the STREAM frames of a packet
+    pub(crate) fn stream_frames(&mut self) -> Vec<frame::StreamMeta> {
+        self.stream_frames.pop().unwrap_or_default()
+    }
+
+    /// Get an empty queue for recording the retransmittable frames of a packet
+    pub(crate) fn retransmits(&mut self) -> Retransmits {
+        self.retransmits.pop().unwrap_or_default()
+    }
+
+    /// Return a list obtained from `stream_frames` for future use
+    pub(crate) fn put_stream_frames(&mut self, mut frames: Vec<frame::StreamMeta>) {
+        if frames.capacity() == 0 || self.stream_frames.len() >= MAX_FREE {
+            return;
+        }
+        frames.clear();
+        self.stream_frames.push(frames);
+    }
+
+    /// Return a queue obtained from `retransmits` for future use
+    pub(crate) fn put_retransmits(&mut self, mut retransmits: Retransmits) {
+        if self.retransmits.len() >= MAX_FREE {
+            return;
+        }
+        retransmits.clear();
+        self.retransmits.push(retransmits);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StreamId;
+
+    #[test]
+    fn reuse() {
+        let mut arena = Arena::new();
+        let mut frames = arena.stream_frames();
+        frames.push(frame::StreamMeta {
+            id: StreamId(0),
+            offsets: 0..10,
+            fin: false,
+        });
+        let ptr = frames.as_ptr();
+        arena.put_stream_frames(frames);
+        let frames = arena.stream_frames();
+        assert!(frames.is_empty());
+        assert_eq!(frames.as_ptr(), ptr);
+
+        let mut retransmits = arena.retransmits();
+        retransmits.max_data = true;
+        retransmits.retire_cids.push(3);
+        arena.put_retransmits(retransmits);
+        let retransmits = arena.retransmits();
+        assert!(retransmits.is_empty());
+        assert!(retransmits.retire_cids.capacity() > 0);
+    }
+
+    #[test]
+    fn bounded() {
+        let mut arena = Arena::new();
+        for _ in 0..MAX_FREE + 10 {
+            arena.put_stream_frames(Vec::with_capacity(1));
+            arena.put_retransmits(Retransmits::default());
+        }
+        arena.put_stream_frames(Vec::new());
+        assert_eq!(arena.stream_frames.len(), MAX_FREE);
+        assert_eq!(arena.retransmits.len(), MAX_FREE);
+    }
+}
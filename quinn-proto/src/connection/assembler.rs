@@ -1,19 +1,25 @@
-use std::{
-    cmp::Ordering,
-    collections::{binary_heap::PeekMut, BinaryHeap},
-    mem,
-};
+use std::{collections::BTreeMap, mem};
 
 use bytes::{Buf, Bytes, BytesMut};
 
+use super::stats::RecvStreamStats;
 use crate::range_set::RangeSet;
 
 /// Helper to assemble unordered stream frames into an ordered stream
+///
+/// Buffered data is kept as non-overlapping chunks keyed by stream offset, so duplicate and
+/// overlapping frames are trimmed as they are inserted rather than accumulating in memory.
 #[derive(Debug, Default)]
-pub(crate) struct Assembler {
+pub struct Assembler {
     state: State,
-    data: BinaryHeap<Buffer>,
-    defragmented: usize,
+    /// Non-overlapping chunks of unread data, keyed by their starting offset
+    data: BTreeMap<u64, Buffer>,
+    /// Total number of buffered bytes
+    buffered: usize,
+    /// Estimated number of allocated bytes backing buffered data, never less than `buffered`
+    allocated: usize,
+    /// Number of chunks inserted since the last defragmentation
+    inserted: usize,
     /// Number of bytes read by the application. When only ordered reads have been used, this is the
     /// length of the contiguous prefix of the stream which has been consumed by the application,
     /// aka the stream offset.
@@ -25,12 +31,16 @@ pub(crate) struct Assembler {
 }
 
 impl Assembler {
+    /// Construct an empty assembler
     pub fn new() -> Self {
         Self::default()
     }
 
-    // Get the the next ordered chunk
-    pub(crate) fn read(
+    /// Get the the next chunk
+    ///
+    /// Ordered reads return the data immediately following the previously read data, if
+    /// available. Unordered reads return whichever buffered chunk has the lowest offset.
+    pub fn read(
         &mut self,
         max_length: usize,
         ordered: bool,
@@ -43,152 +53,285 @@ impl Assembler {
             // Enter unordered mode
             let mut recvd = RangeSet::new();
             recvd.insert(0..self.bytes_read);
-            for chunk in &self.data {
-                recvd.insert(chunk.offset..chunk.offset + chunk.bytes.len() as u64);
+            for (&offset, chunk) in &self.data {
+                recvd.insert(offset..offset + chunk.bytes.len() as u64);
             }
             self.state = State::Unordered { recvd };
         }
 
-        loop {
-            let mut chunk = match self.data.peek_mut() {
-                Some(chunk) => chunk,
-                None => return Ok(None),
-            };
-
-            if ordered {
-                if chunk.offset > self.bytes_read {
-                    // Next chunk is after current read index
-                    return Ok(None);
-                } else if (chunk.offset + chunk.bytes.len() as u64) <= self.bytes_read {
-                    // Next chunk is useless as the read index is beyond its end
-                    PeekMut::pop(chunk);
-                    self.defragmented = self.defragmented.saturating_sub(1);
-                    continue;
-                }
-
-                // Determine `start` and `len` of the slice of useful data in chunk
-                let start = (self.bytes_read - chunk.offset) as usize;
-                if start > 0 {
-                    chunk.bytes.advance(start);
-                    chunk.offset += start as u64;
-                }
-            }
+        let offset = match self.data.keys().next() {
+            Some(&offset) => offset,
+            None => return Ok(None),
+        };
+        // Ordered inserts never buffer data below `bytes_read`
+        debug_assert!(!ordered || offset >= self.bytes_read);
+        if ordered && offset > self.bytes_read {
+            // Next chunk is after current read index
+            return Ok(None);
+        }
 
-            return Ok(Some(if max_length < chunk.bytes.len() {
-                self.bytes_read += max_length as u64;
-                let offset = chunk.offset;
-                chunk.offset += max_length as u64;
-                Chunk::new(offset, chunk.bytes.split_to(max_length))
-            } else {
-                self.bytes_read += chunk.bytes.len() as u64;
-                self.defragmented = self.defragmented.saturating_sub(1);
-                let chunk = PeekMut::pop(chunk);
-                Chunk::new(chunk.offset, chunk.bytes)
-            }));
+        let mut chunk = self.data.remove(&offset).unwrap();
+        if max_length < chunk.bytes.len() {
+            let bytes = chunk.bytes.split_to(max_length);
+            // The backing allocation stays alive until the remainder is read, so keep charging it
+            self.buffered -= max_length;
+            self.data.insert(offset + max_length as u64, chunk);
+            self.bytes_read += max_length as u64;
+            return Ok(Some(Chunk::new(offset, bytes)));
         }
+
+        self.buffered -= chunk.bytes.len();
+        self.allocated -= chunk.allocation_size;
+        self.bytes_read += chunk.bytes.len() as u64;
+        Ok(Some(Chunk::new(offset, chunk.bytes)))
     }
 
     // Copy the buffered chunk data to new chunks backed by a single buffer to
     // make sure we're not unnecessarily holding on to many larger allocations.
-    // Merge contiguous chunks in the process of doing so. Reset the `defragmented`
-    // counter to the new number of chunks left in the heap so that we can decide
-    // when to defragment the queue again if necessary.
+    // Merge contiguous chunks in the process of doing so.
     fn defragment(&mut self) {
-        let buffered = self.data.iter().map(|c| c.bytes.len()).sum::<usize>();
-        let mut buffer = BytesMut::with_capacity(buffered);
-        let mut offset = self
-            .data
-            .peek()
-            .as_ref()
-            .expect("defragment is only called when data is buffered")
-            .offset;
-
-        let new = BinaryHeap::with_capacity(self.data.len());
-        let old = mem::replace(&mut self.data, new);
-        for chunk in old.into_sorted_vec().into_iter().rev() {
-            let end = offset + (buffer.len() as u64);
-            if let Some(overlap) = end.checked_sub(chunk.offset) {
-                if let Some(bytes) = chunk.bytes.get(overlap as usize..) {
-                    buffer.extend_from_slice(bytes);
-                }
-            } else {
-                let bytes = buffer.split().freeze();
-                self.data.push(Buffer { offset, bytes });
-                offset = chunk.offset;
-                buffer.extend_from_slice(&chunk.bytes);
+        let mut buffer = BytesMut::with_capacity(self.buffered);
+        let old = mem::take(&mut self.data);
+        let mut start = None;
+        for (offset, chunk) in old {
+            match start {
+                Some(start) if start + buffer.len() as u64 == offset => {}
+                Some(start) => self.push_defragmented(start, &mut buffer),
+                None => {}
+            }
+            if buffer.is_empty() {
+                start = Some(offset);
             }
+            buffer.extend_from_slice(&chunk.bytes);
+        }
+        if let Some(start) = start {
+            self.push_defragmented(start, &mut buffer);
         }
+        self.allocated = self.buffered;
+        self.inserted = 0;
+    }
 
+    fn push_defragmented(&mut self, offset: u64, buffer: &mut BytesMut) {
         let bytes = buffer.split().freeze();
-        self.data.push(Buffer { offset, bytes });
-        self.defragmented = self.data.len();
+        let allocation_size = bytes.len();
+        self.data.insert(
+            offset,
+            Buffer {
+                bytes,
+                allocation_size,
+            },
+        );
     }
 
-    pub(crate) fn insert(&mut self, mut offset: u64, mut bytes: Bytes) {
-        self.end = self.end.max(offset + bytes.len() as u64);
+    /// Buffer `bytes` received at `offset`
+    ///
+    /// `allocation_size` is the size of the allocation backing `bytes`, e.g. the length of the
+    /// packet it was received in, and is used to bound memory wasted on small fragments.
+    pub fn insert(&mut self, mut offset: u64, mut bytes: Bytes, allocation_size: usize) {
+        let end = offset + bytes.len() as u64;
+        self.end = self.end.max(end);
+        if self.stopped {
+            return;
+        }
 
         if let State::Unordered { ref mut recvd } = self.state {
-            // Discard duplicate data
-            for duplicate in recvd.replace(offset..offset + bytes.len() as u64) {
+            // Discard duplicate data. Everything buffered has been recorded in `recvd`, so the
+            // remaining pieces never overlap existing chunks.
+            let total = bytes.len();
+            let duplicates = recvd.replace(offset..end).collect::<Vec<_>>();
+            for duplicate in duplicates {
                 if duplicate.start > offset {
-                    self.data.push(Buffer {
-                        offset,
-                        bytes: bytes.split_to((duplicate.start - offset) as usize),
-                    });
+                    let piece = bytes.split_to((duplicate.start - offset) as usize);
+                    let piece_allocation = share(allocation_size, piece.len(), total);
+                    self.push(offset, piece, piece_allocation);
                     offset = duplicate.start;
                 }
                 bytes.advance((duplicate.end - offset) as usize);
                 offset = duplicate.end;
             }
+            let piece_allocation = share(allocation_size, bytes.len(), total);
+            self.push(offset, bytes, piece_allocation);
+        } else {
+            // Discard data which has already been read
+            if end <= self.bytes_read {
+                return;
+            }
+            if offset < self.bytes_read {
+                bytes.advance((self.bytes_read - offset) as usize);
+                offset = self.bytes_read;
+            }
+
+            // A chunk starting before `offset` takes precedence over the new data
+            if let Some((&prev_offset, prev)) = self.data.range(..offset).next_back() {
+                let prev_end = prev_offset + prev.bytes.len() as u64;
+                if prev_end >= end {
+                    return;
+                }
+                if prev_end > offset {
+                    bytes.advance((prev_end - offset) as usize);
+                    offset = prev_end;
+                }
+            }
+
+            // The new data takes precedence over chunks starting within it
+            let overlapped = self
+                .data
+                .range(offset..end)
+                .map(|(&x, _)| x)
+                .collect::<Vec<_>>();
+            for next_offset in overlapped {
+                let mut next = self.data.remove(&next_offset).unwrap();
+                let next_end = next_offset + next.bytes.len() as u64;
+                if next_end <= end {
+                    // Entirely covered
+                    self.buffered -= next.bytes.len();
+                    self.allocated -= next.allocation_size;
+                    continue;
+                }
+                // Partially covered; keep only the tail
+                let covered = (end - next_offset) as usize;
+                next.bytes.advance(covered);
+                self.buffered -= covered;
+                self.data.insert(end, next);
+            }
+
+            self.push(offset, bytes, allocation_size);
         }
-        if bytes.is_empty() || self.stopped {
-            return;
-        }
-        self.data.push(Buffer { offset, bytes });
-        // Why 32: on the one hand, we want to defragment rarely, ideally never
-        // in non-pathological scenarios. However, a pathological or malicious
-        // peer could send us one-byte frames, and since we use reference-counted
-        // buffers in order to prevent copying, this could result in keeping a lot
-        // of memory allocated. In the worst case scenario of 32 1-byte chunks,
-        // each one from a ~1000-byte datagram, using 32 limits us to having a
-        // maximum pathological over-allocation of about 32k bytes.
-        if self.data.len() - self.defragmented > 32 {
+
+        // Why this threshold: on the one hand, we want to defragment rarely, ideally never in
+        // non-pathological scenarios. However, a pathological or malicious peer could send us
+        // one-byte frames, and since we use reference-counted buffers in order to prevent copying,
+        // this could result in keeping a lot of memory allocated. This limits over-allocation in
+        // proportion to the buffered data. Since defragmenting costs time linear in the number of
+        // chunks, we also wait for a proportional number of chunks to arrive since the last pass,
+        // bounding over-allocation by the memory spent on chunk bookkeeping while keeping the
+        // amortized cost of an insert logarithmic.
+        let over_allocation = self.allocated - self.buffered;
+        let threshold = 32768.max(self.buffered * 3 / 2);
+        if over_allocation > threshold && self.inserted * 8 >= self.data.len() {
             self.defragment()
         }
     }
 
+    /// Take `bytes` received at `offset` without buffering them if they continue the data read so
+    /// far, or else insert them as [`insert()`](Self::insert) does
+    ///
+    /// Spares ordered readers that consume data as soon as it arrives, such as the TLS session,
+    /// from buffering each in-order chunk only to immediately read it back.
+    pub(crate) fn read_through(
+        &mut self,
+        offset: u64,
+        mut bytes: Bytes,
+        allocation_size: usize,
+    ) -> Option<Bytes> {
+        let end = offset + bytes.len() as u64;
+        if self.stopped
+            || !self.state.is_ordered()
+            || !self.data.is_empty()
+            || offset > self.bytes_read
+            || end <= self.bytes_read
+        {
+            self.insert(offset, bytes, allocation_size);
+            return None;
+        }
+        bytes.advance((self.bytes_read - offset) as usize);
+        self.bytes_read = end;
+        self.end = self.end.max(end);
+        Some(bytes)
+    }
+
+    fn push(&mut self, offset: u64, bytes: Bytes, allocation_size: usize) {
+        if bytes.is_empty() {
+            return;
+        }
+        // Allocation estimates must account for at least the data itself
+        let allocation_size = allocation_size.max(bytes.len());
+        self.buffered += bytes.len();
+        self.allocated += allocation_size;
+        self.inserted += 1;
+        self.data.insert(
+            offset,
+            Buffer {
+                bytes,
+                allocation_size,
+            },
+        );
+    }
+
     /// Number of bytes consumed by the application
-    pub(crate) fn bytes_read(&self) -> u64 {
+    pub fn bytes_read(&self) -> u64 {
         self.bytes_read
     }
 
     /// Offset after the largest byte received
-    pub(crate) fn end(&self) -> u64 {
+    pub fn end(&self) -> u64 {
         self.end
     }
 
+    /// Offset of the first byte not yet received
+    pub(crate) fn contiguous(&self) -> u64 {
+        match self.state {
+            State::Ordered => {
+                let mut end = self.bytes_read;
+                for (&offset, buffer) in &self.data {
+                    if offset > end {
+                        break;
+                    }
+                    end = end.max(offset + buffer.bytes.len() as u64);
+                }
+                end
+            }
+            State::Unordered { ref recvd } => match recvd.iter().next() {
+                Some(range) if range.start == 0 => range.end,
+                _ => 0,
+            },
+        }
+    }
+
+    /// Summary of the data currently buffered
+    pub(crate) fn stats(&self) -> RecvStreamStats {
+        let received = match self.state {
+            State::Ordered => self.bytes_read + self.buffered as u64,
+            State::Unordered { ref recvd } => recvd.iter().map(|x| x.end - x.start).sum(),
+        };
+        RecvStreamStats {
+            buffered: self.buffered as u64,
+            allocated: self.allocated as u64,
+            chunks: self.data.len() as u64,
+            missing: self.end - received,
+        }
+    }
+
     /// Whether all data prior to `self.end()` has been read
-    pub(crate) fn is_fully_read(&self) -> bool {
+    pub fn is_fully_read(&self) -> bool {
         self.bytes_read == self.end
     }
 
     /// Discard all buffered data
-    pub(crate) fn clear(&mut self) {
+    pub fn clear(&mut self) {
         self.data.clear();
-        self.defragmented = 0;
+        self.buffered = 0;
+        self.allocated = 0;
+        self.inserted = 0;
     }
 
     /// Discard buffered data and do not buffer future data, but continue tracking offsets.
-    pub(crate) fn stop(&mut self) {
+    pub fn stop(&mut self) {
         self.stopped = true;
-        self.data.clear();
+        self.clear();
     }
 
-    pub(crate) fn is_stopped(&self) -> bool {
+    /// Whether future data will be discarded
+    pub fn is_stopped(&self) -> bool {
         self.stopped
     }
 }
 
+/// Portion of an allocation backing `total` bytes charged to `len` of them
+fn share(allocation_size: usize, len: usize, total: usize) -> usize {
+    (allocation_size as u64 * len as u64 / total.max(1) as u64) as usize
+}
+
 /// A chunk of data from the receive stream
 #[derive(Debug, PartialEq)]
 pub struct Chunk {
@@ -204,33 +347,11 @@ impl Chunk {
     }
 }
 
-#[derive(Debug, Eq)]
+#[derive(Debug)]
 struct Buffer {
-    offset: u64,
     bytes: Bytes,
-}
-
-impl Ord for Buffer {
-    // Invert ordering based on offset (max-heap, min offset first),
-    // prioritize longer chunks at the same offset.
-    fn cmp(&self, other: &Buffer) -> Ordering {
-        self.offset
-            .cmp(&other.offset)
-            .reverse()
-            .then(self.bytes.len().cmp(&other.bytes.len()))
-    }
-}
-
-impl PartialOrd for Buffer {
-    fn partial_cmp(&self, other: &Buffer) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-impl PartialEq for Buffer {
-    fn eq(&self, other: &Buffer) -> bool {
-        (self.offset, self.bytes.len()) == (other.offset, other.bytes.len())
-    }
+    /// Estimated size of the allocation backing `bytes`
+    allocation_size: usize,
 }
 
 #[derive(Debug)]
@@ -258,7 +379,9 @@ impl Default for State {
 /// Error indicating that an ordered read was performed on a stream after an unordered read
 #[derive(Debug, Copy, Clone)]
 pub enum AssembleError {
+    /// Attempted an ordered read following an unordered read
     IllegalOrderedRead,
+    /// The stream was stopped
     UnknownStream,
 }
 
@@ -271,13 +394,13 @@ mod test {
     fn assemble_ordered() {
         let mut x = Assembler::new();
         assert_matches!(next(&mut x, 32), None);
-        x.insert(0, Bytes::from_static(b"123"));
+        x.insert(0, Bytes::from_static(b"123"), 3);
         assert_matches!(next(&mut x, 1), Some(ref y) if &y[..] == b"1");
         assert_matches!(next(&mut x, 3), Some(ref y) if &y[..] == b"23");
-        x.insert(3, Bytes::from_static(b"456"));
+        x.insert(3, Bytes::from_static(b"456"), 3);
         assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"456");
-        x.insert(6, Bytes::from_static(b"789"));
-        x.insert(9, Bytes::from_static(b"10"));
+        x.insert(6, Bytes::from_static(b"789"), 3);
+        x.insert(9, Bytes::from_static(b"10"), 2);
         assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"789");
         assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"10");
         assert_matches!(next(&mut x, 32), None);
@@ -286,19 +409,36 @@ mod test {
     #[test]
     fn assemble_unordered() {
         let mut x = Assembler::new();
-        x.insert(3, Bytes::from_static(b"456"));
+        x.insert(3, Bytes::from_static(b"456"), 3);
         assert_matches!(next(&mut x, 32), None);
-        x.insert(0, Bytes::from_static(b"123"));
+        x.insert(0, Bytes::from_static(b"123"), 3);
         assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"123");
         assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"456");
         assert_matches!(next(&mut x, 32), None);
     }
 
+    #[test]
+    fn contiguous() {
+        let mut x = Assembler::new();
+        x.insert(3, Bytes::from_static(b"456"), 3);
+        assert_eq!(x.contiguous(), 0);
+        x.insert(0, Bytes::from_static(b"123"), 3);
+        x.insert(9, Bytes::from_static(b"10"), 2);
+        assert_eq!(x.contiguous(), 6);
+        assert_matches!(next(&mut x, 2), Some(ref y) if &y[..] == b"12");
+        assert_eq!(x.contiguous(), 6);
+        // Unordered reads track received ranges separately
+        assert_matches!(x.read(32, false), Ok(Some(_)));
+        assert_eq!(x.contiguous(), 6);
+        x.insert(6, Bytes::from_static(b"789"), 3);
+        assert_eq!(x.contiguous(), 11);
+    }
+
     #[test]
     fn assemble_duplicate() {
         let mut x = Assembler::new();
-        x.insert(0, Bytes::from_static(b"123"));
-        x.insert(0, Bytes::from_static(b"123"));
+        x.insert(0, Bytes::from_static(b"123"), 3);
+        x.insert(0, Bytes::from_static(b"123"), 3);
         assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"123");
         assert_matches!(next(&mut x, 32), None);
     }
@@ -306,8 +446,8 @@ mod test {
     #[test]
     fn assemble_duplicate_compact() {
         let mut x = Assembler::new();
-        x.insert(0, Bytes::from_static(b"123"));
-        x.insert(0, Bytes::from_static(b"123"));
+        x.insert(0, Bytes::from_static(b"123"), 3);
+        x.insert(0, Bytes::from_static(b"123"), 3);
         x.defragment();
         assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"123");
         assert_matches!(next(&mut x, 32), None);
@@ -316,8 +456,8 @@ mod test {
     #[test]
     fn assemble_contained() {
         let mut x = Assembler::new();
-        x.insert(0, Bytes::from_static(b"12345"));
-        x.insert(1, Bytes::from_static(b"234"));
+        x.insert(0, Bytes::from_static(b"12345"), 5);
+        x.insert(1, Bytes::from_static(b"234"), 3);
         assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"12345");
         assert_matches!(next(&mut x, 32), None);
     }
@@ -325,8 +465,8 @@ mod test {
     #[test]
     fn assemble_contained_compact() {
         let mut x = Assembler::new();
-        x.insert(0, Bytes::from_static(b"12345"));
-        x.insert(1, Bytes::from_static(b"234"));
+        x.insert(0, Bytes::from_static(b"12345"), 5);
+        x.insert(1, Bytes::from_static(b"234"), 3);
         x.defragment();
         assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"12345");
         assert_matches!(next(&mut x, 32), None);
@@ -335,8 +475,8 @@ mod test {
     #[test]
     fn assemble_contains() {
         let mut x = Assembler::new();
-        x.insert(1, Bytes::from_static(b"234"));
-        x.insert(0, Bytes::from_static(b"12345"));
+        x.insert(1, Bytes::from_static(b"234"), 3);
+        x.insert(0, Bytes::from_static(b"12345"), 5);
         assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"12345");
         assert_matches!(next(&mut x, 32), None);
     }
@@ -344,8 +484,8 @@ mod test {
     #[test]
     fn assemble_contains_compact() {
         let mut x = Assembler::new();
-        x.insert(1, Bytes::from_static(b"234"));
-        x.insert(0, Bytes::from_static(b"12345"));
+        x.insert(1, Bytes::from_static(b"234"), 3);
+        x.insert(0, Bytes::from_static(b"12345"), 5);
         x.defragment();
         assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"12345");
         assert_matches!(next(&mut x, 32), None);
@@ -354,8 +494,8 @@ mod test {
     #[test]
     fn assemble_overlapping() {
         let mut x = Assembler::new();
-        x.insert(0, Bytes::from_static(b"123"));
-        x.insert(1, Bytes::from_static(b"234"));
+        x.insert(0, Bytes::from_static(b"123"), 3);
+        x.insert(1, Bytes::from_static(b"234"), 3);
         assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"123");
         assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"4");
         assert_matches!(next(&mut x, 32), None);
@@ -364,8 +504,8 @@ mod test {
     #[test]
     fn assemble_overlapping_compact() {
         let mut x = Assembler::new();
-        x.insert(0, Bytes::from_static(b"123"));
-        x.insert(1, Bytes::from_static(b"234"));
+        x.insert(0, Bytes::from_static(b"123"), 3);
+        x.insert(1, Bytes::from_static(b"234"), 3);
         x.defragment();
         assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"1234");
         assert_matches!(next(&mut x, 32), None);
@@ -374,10 +514,10 @@ mod test {
     #[test]
     fn assemble_complex() {
         let mut x = Assembler::new();
-        x.insert(0, Bytes::from_static(b"1"));
-        x.insert(2, Bytes::from_static(b"3"));
-        x.insert(4, Bytes::from_static(b"5"));
-        x.insert(0, Bytes::from_static(b"123456"));
+        x.insert(0, Bytes::from_static(b"1"), 1);
+        x.insert(2, Bytes::from_static(b"3"), 1);
+        x.insert(4, Bytes::from_static(b"5"), 1);
+        x.insert(0, Bytes::from_static(b"123456"), 6);
         assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"123456");
         assert_matches!(next(&mut x, 32), None);
     }
@@ -385,10 +525,10 @@ mod test {
     #[test]
     fn assemble_complex_compact() {
         let mut x = Assembler::new();
-        x.insert(0, Bytes::from_static(b"1"));
-        x.insert(2, Bytes::from_static(b"3"));
-        x.insert(4, Bytes::from_static(b"5"));
-        x.insert(0, Bytes::from_static(b"123456"));
+        x.insert(0, Bytes::from_static(b"1"), 1);
+        x.insert(2, Bytes::from_static(b"3"), 1);
+        x.insert(4, Bytes::from_static(b"5"), 1);
+        x.insert(0, Bytes::from_static(b"123456"), 6);
         x.defragment();
         assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"123456");
         assert_matches!(next(&mut x, 32), None);
@@ -397,19 +537,19 @@ mod test {
     #[test]
     fn assemble_old() {
         let mut x = Assembler::new();
-        x.insert(0, Bytes::from_static(b"1234"));
+        x.insert(0, Bytes::from_static(b"1234"), 4);
         assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"1234");
-        x.insert(0, Bytes::from_static(b"1234"));
+        x.insert(0, Bytes::from_static(b"1234"), 4);
         assert_matches!(next(&mut x, 32), None);
     }
 
     #[test]
     fn assemble_old_compact() {
         let mut x = Assembler::new();
-        x.insert(0, Bytes::from_static(b"1234"));
+        x.insert(0, Bytes::from_static(b"1234"), 4);
         x.defragment();
         assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"1234");
-        x.insert(0, Bytes::from_static(b"1234"));
+        x.insert(0, Bytes::from_static(b"1234"), 4);
         x.defragment();
         assert_matches!(next(&mut x, 32), None);
     }
@@ -417,10 +557,10 @@ mod test {
     #[test]
     fn compact() {
         let mut x = Assembler::new();
-        x.insert(0, Bytes::from_static(b"abc"));
-        x.insert(3, Bytes::from_static(b"def"));
-        x.insert(9, Bytes::from_static(b"jkl"));
-        x.insert(12, Bytes::from_static(b"mno"));
+        x.insert(0, Bytes::from_static(b"abc"), 3);
+        x.insert(3, Bytes::from_static(b"def"), 3);
+        x.insert(9, Bytes::from_static(b"jkl"), 3);
+        x.insert(12, Bytes::from_static(b"mno"), 3);
         x.defragment();
         assert_eq!(
             next_unordered(&mut x),
@@ -435,7 +575,7 @@ mod test {
     #[test]
     fn defrag_with_missing_prefix() {
         let mut x = Assembler::new();
-        x.insert(3, Bytes::from_static(b"def"));
+        x.insert(3, Bytes::from_static(b"def"), 3);
         x.defragment();
         assert_eq!(
             next_unordered(&mut x),
@@ -446,17 +586,17 @@ mod test {
     #[test]
     fn defrag_read_chunk() {
         let mut x = Assembler::new();
-        x.insert(3, Bytes::from_static(b"def"));
-        x.insert(0, Bytes::from_static(b"abc"));
-        x.insert(7, Bytes::from_static(b"hij"));
-        x.insert(11, Bytes::from_static(b"lmn"));
+        x.insert(3, Bytes::from_static(b"def"), 3);
+        x.insert(0, Bytes::from_static(b"abc"), 3);
+        x.insert(7, Bytes::from_static(b"hij"), 3);
+        x.insert(11, Bytes::from_static(b"lmn"), 3);
         x.defragment();
         assert_matches!(x.read(usize::MAX, true), Ok(Some(ref y)) if &y.bytes[..] == b"abcdef");
-        x.insert(5, Bytes::from_static(b"fghijklmn"));
+        x.insert(5, Bytes::from_static(b"fghijklmn"), 9);
         assert_matches!(x.read(usize::MAX, true), Ok(Some(ref y)) if &y.bytes[..] == b"ghijklmn");
-        x.insert(13, Bytes::from_static(b"nopq"));
+        x.insert(13, Bytes::from_static(b"nopq"), 4);
         assert_matches!(x.read(usize::MAX, true), Ok(Some(ref y)) if &y.bytes[..] == b"opq");
-        x.insert(15, Bytes::from_static(b"pqrs"));
+        x.insert(15, Bytes::from_static(b"pqrs"), 4);
         assert_matches!(x.read(usize::MAX, true), Ok(Some(ref y)) if &y.bytes[..] == b"rs");
         assert_matches!(x.read(usize::MAX, true), Ok(None));
     }
@@ -464,13 +604,13 @@ mod test {
     #[test]
     fn unordered_happy_path() {
         let mut x = Assembler::new();
-        x.insert(0, Bytes::from_static(b"abc"));
+        x.insert(0, Bytes::from_static(b"abc"), 3);
         assert_eq!(
             next_unordered(&mut x),
             Chunk::new(0, Bytes::from_static(b"abc"))
         );
         assert_eq!(x.read(usize::MAX, false).unwrap(), None);
-        x.insert(3, Bytes::from_static(b"def"));
+        x.insert(3, Bytes::from_static(b"def"), 3);
         assert_eq!(
             next_unordered(&mut x),
             Chunk::new(3, Bytes::from_static(b"def"))
@@ -481,15 +621,15 @@ mod test {
     #[test]
     fn unordered_dedup() {
         let mut x = Assembler::new();
-        x.insert(3, Bytes::from_static(b"def"));
+        x.insert(3, Bytes::from_static(b"def"), 3);
         assert_eq!(
             next_unordered(&mut x),
             Chunk::new(3, Bytes::from_static(b"def"))
         );
         assert_eq!(x.read(usize::MAX, false).unwrap(), None);
-        x.insert(0, Bytes::from_static(b"a"));
-        x.insert(0, Bytes::from_static(b"abcdefghi"));
-        x.insert(0, Bytes::from_static(b"abcd"));
+        x.insert(0, Bytes::from_static(b"a"), 1);
+        x.insert(0, Bytes::from_static(b"abcdefghi"), 9);
+        x.insert(0, Bytes::from_static(b"abcd"), 4);
         assert_eq!(
             next_unordered(&mut x),
             Chunk::new(0, Bytes::from_static(b"a"))
@@ -503,30 +643,30 @@ mod test {
             Chunk::new(6, Bytes::from_static(b"ghi"))
         );
         assert_eq!(x.read(usize::MAX, false).unwrap(), None);
-        x.insert(8, Bytes::from_static(b"ijkl"));
+        x.insert(8, Bytes::from_static(b"ijkl"), 4);
         assert_eq!(
             next_unordered(&mut x),
             Chunk::new(9, Bytes::from_static(b"jkl"))
         );
         assert_eq!(x.read(usize::MAX, false).unwrap(), None);
-        x.insert(12, Bytes::from_static(b"mno"));
+        x.insert(12, Bytes::from_static(b"mno"), 3);
         assert_eq!(
             next_unordered(&mut x),
             Chunk::new(12, Bytes::from_static(b"mno"))
         );
         assert_eq!(x.read(usize::MAX, false).unwrap(), None);
-        x.insert(2, Bytes::from_static(b"cde"));
+        x.insert(2, Bytes::from_static(b"cde"), 3);
         assert_eq!(x.read(usize::MAX, false).unwrap(), None);
     }
 
     #[test]
     fn chunks_dedup() {
         let mut x = Assembler::new();
-        x.insert(3, Bytes::from_static(b"def"));
+        x.insert(3, Bytes::from_static(b"def"), 3);
         assert_eq!(x.read(usize::MAX, true).unwrap(), None);
-        x.insert(0, Bytes::from_static(b"a"));
-        x.insert(1, Bytes::from_static(b"bcdefghi"));
-        x.insert(0, Bytes::from_static(b"abcd"));
+        x.insert(0, Bytes::from_static(b"a"), 1);
+        x.insert(1, Bytes::from_static(b"bcdefghi"), 8);
+        x.insert(0, Bytes::from_static(b"abcd"), 4);
         assert_eq!(
             x.read(usize::MAX, true).unwrap(),
             Some(Chunk::new(0, Bytes::from_static(b"abcd")))
@@ -536,22 +676,135 @@ mod test {
             Some(Chunk::new(4, Bytes::from_static(b"efghi")))
         );
         assert_eq!(x.read(usize::MAX, true).unwrap(), None);
-        x.insert(8, Bytes::from_static(b"ijkl"));
+        x.insert(8, Bytes::from_static(b"ijkl"), 4);
         assert_eq!(
             x.read(usize::MAX, true).unwrap(),
             Some(Chunk::new(9, Bytes::from_static(b"jkl")))
         );
         assert_eq!(x.read(usize::MAX, true).unwrap(), None);
-        x.insert(12, Bytes::from_static(b"mno"));
+        x.insert(12, Bytes::from_static(b"mno"), 3);
         assert_eq!(
             x.read(usize::MAX, true).unwrap(),
             Some(Chunk::new(12, Bytes::from_static(b"mno")))
         );
         assert_eq!(x.read(usize::MAX, true).unwrap(), None);
-        x.insert(2, Bytes::from_static(b"cde"));
+        x.insert(2, Bytes::from_static(b"cde"), 3);
         assert_eq!(x.read(usize::MAX, true).unwrap(), None);
     }
 
+    #[test]
+    fn overlap_replaces_contained() {
+        let mut x = Assembler::new();
+        for i in 0..10 {
+            x.insert(i * 2, Bytes::from_static(b"a"), 1);
+        }
+        assert_eq!(x.data.len(), 10);
+        x.insert(0, Bytes::from_static(b"abcdefghijklmnopqrst"), 20);
+        assert_eq!(x.data.len(), 1);
+        assert_eq!(x.buffered, 20);
+        assert_eq!(
+            next_unordered(&mut x),
+            Chunk::new(0, Bytes::from_static(b"abcdefghijklmnopqrst"))
+        );
+        assert_eq!(x.buffered, 0);
+        assert_eq!(x.allocated, 0);
+    }
+
+    #[test]
+    fn overlap_trims_successor() {
+        let mut x = Assembler::new();
+        x.insert(2, Bytes::from_static(b"cdef"), 4);
+        x.insert(0, Bytes::from_static(b"abcd"), 4);
+        assert_eq!(x.buffered, 6);
+        assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"abcd");
+        assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"ef");
+        assert_eq!(x.buffered, 0);
+        assert_eq!(x.allocated, 0);
+    }
+
+    #[test]
+    fn duplicates_not_buffered() {
+        let mut x = Assembler::new();
+        for _ in 0..1000 {
+            x.insert(0, Bytes::from_static(b"abc"), 3);
+            x.insert(1, Bytes::from_static(b"b"), 1);
+        }
+        assert_eq!(x.data.len(), 1);
+        assert_eq!(x.buffered, 3);
+    }
+
+    #[test]
+    fn fragmentation_bounded() {
+        let mut x = Assembler::new();
+        // One-byte frames at every other offset, each pinning a full-sized datagram
+        for i in 0..1000 {
+            x.insert(i * 2 + 1, Bytes::from(vec![0; 1]), 1200);
+            let bookkeeping = (x.data.len() / 8 + 1) * 1200;
+            assert!(x.allocated - x.buffered <= 32768.max(x.buffered * 3 / 2) + bookkeeping);
+        }
+        assert_eq!(x.buffered, 1000);
+        x.insert(0, Bytes::from(vec![0; 2000]), 2000);
+        assert_eq!(x.buffered, 2000);
+        assert_matches!(x.read(usize::MAX, true), Ok(Some(ref y)) if y.bytes.len() == 2000);
+        assert_eq!(x.allocated, 0);
+    }
+
+    #[test]
+    fn partial_read_keeps_allocation() {
+        let mut x = Assembler::new();
+        x.insert(0, Bytes::from_static(b"abcdef"), 1200);
+        assert_matches!(next(&mut x, 2), Some(ref y) if &y[..] == b"ab");
+        assert_eq!(x.buffered, 4);
+        assert_eq!(x.allocated, 1200);
+        assert_matches!(next(&mut x, 32), Some(ref y) if &y[..] == b"cdef");
+        assert_eq!(x.allocated, 0);
+    }
+
+    #[test]
+    fn read_through() {
+        let mut x = Assembler::new();
+        let data = Bytes::from_static(b"abcdefghi");
+        // In order, including data overlapping what was already read
+        assert_eq!(
+            x.read_through(0, data.slice(..3), 1200).unwrap(),
+            &b"abc"[..]
+        );
+        let bytes = x.read_through(2, data.slice(2..6), 1200).unwrap();
+        assert_eq!(bytes, &b"def"[..]);
+        assert_eq!(bytes.as_ptr(), data[3..].as_ptr());
+        assert_eq!(x.stats().chunks, 0);
+        assert_eq!(x.allocated, 0);
+        // Duplicates are dropped, and data after a gap is buffered
+        assert_eq!(x.read_through(0, data.slice(..6), 1200), None);
+        assert_eq!(x.read_through(7, data.slice(7..), 1200), None);
+        assert_eq!(x.stats().chunks, 1);
+        // While data is buffered, even in-order data takes the ordinary path
+        assert_eq!(x.read_through(6, data.slice(6..7), 1200), None);
+        assert_eq!(next(&mut x, 32).unwrap(), &b"g"[..]);
+        assert_eq!(next(&mut x, 32).unwrap(), &b"hi"[..]);
+        assert_eq!(x.bytes_read(), 9);
+    }
+
+    #[test]
+    fn stats() {
+        let mut x = Assembler::new();
+        x.insert(0, Bytes::from_static(b"abc"), 3);
+        x.insert(6, Bytes::from_static(b"ghi"), 1200);
+        let stats = x.stats();
+        assert_eq!(stats.buffered, 6);
+        assert_eq!(stats.allocated, 1203);
+        assert_eq!(stats.chunks, 2);
+        assert_eq!(stats.missing, 3);
+        next(&mut x, 32);
+        assert_eq!(x.stats().missing, 3);
+        assert_eq!(next_unordered(&mut x).offset, 6);
+        x.insert(12, Bytes::from_static(b"m"), 1);
+        let stats = x.stats();
+        assert_eq!(stats.buffered, 1);
+        assert_eq!(stats.chunks, 1);
+        assert_eq!(stats.missing, 6);
+    }
+
     fn next_unordered(x: &mut Assembler) -> Chunk {
         x.read(usize::MAX, false).unwrap().unwrap()
     }
@@ -1,12 +1,12 @@
 //! Maintain the state of local connection IDs
 use std::{
     collections::{HashSet, VecDeque},
-    time::{Duration, Instant},
+    time::Duration,
 };
 
 use tracing::{debug, trace};
 
-use crate::{shared::IssuedCid, TransportError};
+use crate::{shared::IssuedCid, Instant, TransportError};
 
 /// Local connection ID management
 pub struct CidState {
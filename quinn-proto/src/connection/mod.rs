@@ -1,18 +1,21 @@
 use std::{
     cmp,
     collections::{BTreeMap, VecDeque},
-    fmt, io, mem,
+    fmt, io, iter, mem,
     net::{IpAddr, SocketAddr},
     sync::Arc,
-    time::{Duration, Instant},
+    time::Duration,
 };
 
 use bytes::{Bytes, BytesMut};
-use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand::{rngs::StdRng, Rng};
 use thiserror::Error;
 use tracing::{debug, error, trace, trace_span, warn};
 
+#[cfg(feature = "tap")]
+use crate::tap::{self, PacketTap};
 use crate::{
+    buffer_pool::BufferPool,
     cid_generator::ConnectionIdGenerator,
     cid_queue::CidQueue,
     coding::BufMutExt,
@@ -22,33 +25,43 @@ use crate::{
     frame::{Close, Datagram, FrameStruct},
     is_supported_version,
     packet::{Header, LongType, Packet, PacketNumber, PartialDecode, PartialEncode, SpaceId},
+    qlog::{self, Qlog},
     range_set::RangeSet,
+    resumption::Resumption,
     shared::{
         ConnectionEvent, ConnectionEventInner, ConnectionId, EcnCodepoint, EndpointEvent,
         EndpointEventInner, IssuedCid,
     },
     transport_parameters::TransportParameters,
-    Dir, Frame, Side, StreamId, Transmit, TransportError, TransportErrorCode, VarInt,
-    MAX_STREAM_COUNT, MIN_INITIAL_SIZE, RESET_TOKEN_SIZE, TIMER_GRANULARITY,
+    Dir, Frame, Instant, Side, StreamId, Transmit, TransportError, TransportErrorCode, VarInt,
+    MAX_STREAM_COUNT, MAX_UDP_PAYLOAD, MIN_INITIAL_SIZE, RESET_TOKEN_SIZE, TIMER_GRANULARITY,
 };
 
+mod arena;
+use arena::Arena;
+
 mod assembler;
-pub use assembler::Chunk;
+pub use assembler::{Assembler, Chunk};
 
 mod cid_state;
 use cid_state::CidState;
 
+mod mtud;
+use mtud::MtuDiscovery;
+
 mod pacing;
 mod paths;
 use paths::PathData;
+pub use paths::{AddressValidation, PathInfo};
 
 mod send_buffer;
 
 mod spaces;
+pub use spaces::SentPackets;
 use spaces::{PacketSpace, Retransmits, SentPacket};
 
 mod stats;
-pub use stats::ConnectionStats;
+pub use stats::{ConnectionStats, RecvStreamStats, SpaceStats};
 
 mod streams;
 pub use streams::Streams;
@@ -155,15 +168,26 @@ where
     permit_idle_reset: bool,
     /// Negotiated idle timeout
     idle_timeout: Option<Duration>,
+    keep_alive_interval: Option<Duration>,
+    /// Whether the application waits for the peer other than on a stream
+    awaiting_peer: bool,
     timers: TimerTable,
     /// Number of packets received which could not be authenticated
     authentication_failures: u64,
+    /// Where to record what's learned about the server, for clients
+    resumption: Option<Resumption>,
+    /// Number of migrations initiated by the peer
+    migrations: u64,
+    /// How the peer's address was validated during the handshake, which migrations don't change
+    handshake_validation: AddressValidation,
 
     //
     // Queued non-retransmittable 1-RTT data
     //
     path_response: Option<PathResponse>,
     close: bool,
+    /// Application close to send once all outgoing stream data has been acknowledged
+    close_after_flush: Option<frame::ApplicationClose>,
 
     //
     // Loss Detection
@@ -183,6 +207,10 @@ where
     /// Whether the last `poll_transmit` call yielded no data because there was
     /// no outgoing application data.
     app_limited: bool,
+    /// What most recently kept us from sending, and since when
+    ///
+    /// Only tracked once the connection is established.
+    send_limit: Option<(SendLimit, Instant)>,
 
     streams: Streams,
     /// Surplus remote CIDs for future use on new paths
@@ -193,6 +221,21 @@ where
     datagrams: DatagramState,
     /// Connection level statistics
     stats: ConnectionStats,
+    /// Source of buffers for outgoing datagrams, shared with the endpoint
+    buffers: BufferPool,
+    /// Recycled bookkeeping for sent packets
+    arena: Arena,
+    /// Size of the receive buffer backing the datagram being processed
+    ///
+    /// Frame data is stored as slices of that buffer, keeping all of it alive.
+    recv_allocation: usize,
+    /// Trace output, if enabled by the transport configuration
+    qlog: Option<Qlog>,
+    #[cfg(feature = "tap")]
+    packet_tap: Option<Arc<dyn PacketTap>>,
+    /// Raw frames to send verbatim in upcoming 1-RTT packets, one entry per packet
+    #[cfg(feature = "conformance")]
+    injected_frames: VecDeque<Bytes>,
 }
 
 impl<S> Connection<S>
@@ -209,6 +252,12 @@ where
         local_ip: Option<IpAddr>,
         crypto: S,
         cid_gen: &dyn ConnectionIdGenerator,
+        buffers: BufferPool,
+        mut rng: StdRng,
+        qlog: Option<Qlog>,
+        resumption: Option<Resumption>,
+        max_udp_payload_size: u16,
+        address_validation: AddressValidation,
         now: Instant,
     ) -> Self {
         let side = if server_config.is_some() {
@@ -220,15 +269,18 @@ where
             crypto: Some(S::initial_keys(&init_cid, side)),
             ..PacketSpace::new(now)
         };
+        let resumed = resumption
+            .as_ref()
+            .map(|x| x.store.resume(&x.server_name))
+            .unwrap_or_default();
         let state = State::Handshake(state::Handshake {
             rem_cid_set: side.is_server(),
-            token: None,
+            token: resumed.token,
             client_hello: None,
         });
-        let mut rng = StdRng::from_entropy();
-        let path_validated = server_config
-            .as_ref()
-            .map_or(true, |c| c.use_stateless_retry);
+        #[cfg(feature = "tap")]
+        let packet_tap = config.packet_tap.clone();
+        let local_path = config.is_local_path(&remote);
         let mut this = Self {
             server_config,
             crypto,
@@ -237,10 +289,20 @@ where
             local_cid_state: CidState::new(cid_gen.cid_len(), cid_gen.cid_lifetime(), now),
             path: PathData::new(
                 remote,
-                config.initial_rtt,
+                resumed.rtt.unwrap_or(config.initial_rtt),
                 config.congestion_controller_factory.build(now),
                 now,
-                path_validated,
+                address_validation,
+                local_path,
+                // Only the peer's `max_udp_payload_size` limits datagrams on a local path
+                MtuDiscovery::new(
+                    if local_path {
+                        MAX_UDP_PAYLOAD
+                    } else {
+                        max_udp_payload_size
+                    },
+                    config.mtu_discovery_interval_for(local_path),
+                ),
             ),
             local_ip,
             prev_path: None,
@@ -265,15 +327,22 @@ where
             accepted_0rtt: false,
             permit_idle_reset: true,
             idle_timeout: config.max_idle_timeout,
+            keep_alive_interval: config.keep_alive_interval,
+            awaiting_peer: false,
             timers: TimerTable::default(),
             authentication_failures: 0,
+            resumption,
+            migrations: 0,
+            handshake_validation: address_validation,
 
             path_response: None,
             close: false,
+            close_after_flush: None,
 
             pto_count: 0,
 
             app_limited: false,
+            send_limit: None,
             in_flight: InFlight::new(),
             receiving_ecn: false,
             total_authed_packets: 0,
@@ -285,13 +354,25 @@ where
                 config.send_window,
                 config.receive_window,
                 config.stream_receive_window,
+                config.stream_reassembly_window,
             ),
-            datagrams: DatagramState::new(),
+            datagrams: DatagramState::new(&config),
             config,
             rem_cids: CidQueue::new(rem_cid),
             rng,
             stats: ConnectionStats::default(),
+            buffers,
+            arena: Arena::new(),
+            recv_allocation: 0,
+            qlog,
+            #[cfg(feature = "tap")]
+            packet_tap,
+            #[cfg(feature = "conformance")]
+            injected_frames: VecDeque::new(),
         };
+        if let Some(window) = resumed.congestion_window {
+            this.path.congestion.resume(window);
+        }
         if side.is_client() {
             // Kick off the connection
             this.write_crypto();
@@ -309,7 +390,7 @@ where
     /// - a call was made to `handle_timeout`
     #[must_use]
     pub fn poll_timeout(&mut self) -> Option<Instant> {
-        self.timers.next_timeout()
+        self.timers.next_timeout(self.config.timer_slack)
     }
 
     /// Returns application-facing events
@@ -336,6 +417,28 @@ where
         self.endpoint_events.pop_front().map(EndpointEvent)
     }
 
+    /// Returns up to `max` packets to transmit, appended to `transmits`
+    ///
+    /// Equivalent to calling [`poll_transmit`](Self::poll_transmit) until it yields `None` or
+    /// `max` transmits, so that a driver can hand them to the operating system in a single
+    /// `sendmmsg` call or similar. Returns the number of transmits appended.
+    pub fn poll_transmits(
+        &mut self,
+        now: Instant,
+        transmits: &mut Vec<Transmit>,
+        max: usize,
+    ) -> usize {
+        let mut count = 0;
+        while count < max {
+            match self.poll_transmit(now) {
+                Some(x) => transmits.push(x),
+                None => break,
+            }
+            count += 1;
+        }
+        count
+    }
+
     /// Returns packets to transmit
     ///
     /// Connections should be polled for transmit after:
@@ -344,6 +447,8 @@ where
     /// - a call was made to `handle_timeout`
     #[must_use]
     pub fn poll_transmit(&mut self, now: Instant) -> Option<Transmit> {
+        self.close_if_flushed(now);
+
         // Send PATH_CHALLENGE for a previous path if necessary
         if let Some(ref mut prev_path) = self.prev_path {
             if prev_path.challenge_pending {
@@ -357,11 +462,17 @@ where
                     SpaceId::Data,
                     "PATH_CHALLENGE queued without 1-RTT keys"
                 );
-                let mut buf = Vec::with_capacity(self.path.mtu as usize);
+                let mut buf = self.buffers.get(self.path.mtu as usize);
                 let buf_capacity = self.path.mtu as usize;
 
                 let builder =
-                    self.begin_packet(now, SpaceId::Data, false, &mut buf, buf_capacity)?;
+                    match self.begin_packet(now, SpaceId::Data, false, &mut buf, buf_capacity) {
+                        Some(x) => x,
+                        None => {
+                            self.buffers.put(buf);
+                            return None;
+                        }
+                    };
                 trace!("validating previous path with PATH_CHALLENGE {:08x}", token);
                 builder.buffer.write(frame::Type::PATH_CHALLENGE);
                 builder.buffer.write(token);
@@ -381,6 +492,10 @@ where
             return None;
         }
 
+        if let Some(transmit) = self.poll_mtu_probe(now) {
+            return Some(transmit);
+        }
+
         // If we need to send a probe, make sure we have something to send.
         for space in SpaceId::iter() {
             if self.spaces[space].loss_probes != 0 {
@@ -389,35 +504,46 @@ where
         }
 
         // Select the set of spaces that have data to send so we can try to coalesce them
-        let (spaces, close) = match self.state {
+        let mut spaces = [SpaceId::Initial; 3];
+        let (space_count, close) = match self.state {
             State::Drained => {
                 self.app_limited = true;
                 return None;
             }
             State::Draining | State::Closed(_) => {
                 if mem::replace(&mut self.close, false) {
-                    (vec![self.highest_space], true)
+                    spaces[0] = self.highest_space;
+                    (1, true)
                 } else {
                     self.app_limited = true;
                     return None;
                 }
             }
-            _ => (
-                SpaceId::iter()
-                    .filter(|&x| {
-                        (self.spaces[x].crypto.is_some() && self.spaces[x].can_send())
-                            || (x == SpaceId::Data
-                                && ((self.spaces[x].crypto.is_some() && self.can_send_1rtt())
-                                    || (self.zero_rtt_crypto.is_some()
-                                        && self.side.is_client()
-                                        && (self.spaces[x].can_send() || self.can_send_1rtt()))))
-                    })
-                    .collect(),
-                false,
-            ),
+            _ => {
+                let mut count = 0;
+                for x in SpaceId::iter().filter(|&x| {
+                    (self.spaces[x].crypto.is_some() && self.spaces[x].can_send())
+                        || (x == SpaceId::Data
+                            && ((self.spaces[x].crypto.is_some() && self.can_send_1rtt())
+                                || (self.zero_rtt_crypto.is_some()
+                                    && self.side.is_client()
+                                    && (self.spaces[x].can_send() || self.can_send_1rtt()))))
+                }) {
+                    spaces[count] = x;
+                    count += 1;
+                }
+                (count, false)
+            }
         };
+        let spaces = &spaces[..space_count];
+        if spaces.is_empty() {
+            // Nothing to send, so don't bother acquiring a buffer
+            self.app_limited = true;
+            self.record_send_limit(now, false);
+            return None;
+        }
 
-        let mut buf = Vec::with_capacity(self.path.mtu as usize);
+        let mut buf = self.buffers.get(self.path.mtu as usize);
         // Reserving capacity can provide more capacity than we asked for.
         // However we are not allowed to write more than MTU size. Therefore
         // the maximum capacity is tracked separately.
@@ -432,7 +558,7 @@ where
 
         let mut congestion_blocked = false;
 
-        for space_id in spaces {
+        for &space_id in spaces {
             let buf_start = buf.len();
             let mut ack_eliciting =
                 !self.spaces[space_id].pending.is_empty() || self.spaces[space_id].ping_pending;
@@ -474,13 +600,19 @@ where
                 prev.update_unacked = false;
             }
 
-            let mut builder = self.begin_packet(
+            let mut builder = match self.begin_packet(
                 now,
                 space_id,
                 pad_space == Some(space_id),
                 &mut buf,
                 buf_capacity,
-            )?;
+            ) {
+                Some(x) => x,
+                None => {
+                    self.buffers.put(buf);
+                    return None;
+                }
+            };
             coalesce = coalesce && !builder.short_header;
 
             let sent = if close {
@@ -511,11 +643,21 @@ where
                 coalesce = false;
                 None
             } else {
-                Some(self.populate_packet(space_id, &mut builder.buffer, buf_capacity))
+                Some(self.populate_packet(space_id, builder.buffer, buf_capacity))
             };
 
             let exact_number = builder.exact_number;
+            let short_header = builder.short_header;
             let padded = self.finish_packet(builder);
+            if let Some(ref mut qlog) = self.qlog {
+                let ty = match space_id {
+                    SpaceId::Initial => "initial",
+                    SpaceId::Handshake => "handshake",
+                    SpaceId::Data if short_header => "1RTT",
+                    SpaceId::Data => "0RTT",
+                };
+                qlog.packet_sent(now, ty, exact_number, buf.len() - buf_start);
+            }
 
             if let Some(mut sent) = sent {
                 sent.padding = padded;
@@ -551,6 +693,8 @@ where
         self.app_limited = buf.is_empty() && !congestion_blocked;
 
         if buf.is_empty() {
+            self.buffers.put(buf);
+            self.record_send_limit(now, congestion_blocked);
             return None;
         }
 
@@ -573,6 +717,104 @@ where
         })
     }
 
+    /// Send a probe for a larger path MTU, if one is due
+    fn poll_mtu_probe(&mut self, now: Instant) -> Option<Transmit> {
+        // Only probe a validated path once the handshake is confirmed, while there's room in the
+        // congestion window
+        if !self.state.is_established()
+            || self.spaces[SpaceId::Handshake].crypto.is_some()
+            || self.migrating()
+            || self.congestion_blocked()
+        {
+            return None;
+        }
+        let size = self.path.mtud.poll_probe(now)?;
+        let window = self.path.congestion.window();
+        if self.in_flight.bytes + u64::from(size) >= window {
+            return None;
+        }
+        // Paced like any other packet, lest probes add to a burst
+        if let Some(delay) = self
+            .path
+            .pacing
+            .delay(self.path.rtt.get(), self.path.mtu, window, now)
+        {
+            self.timers.set(Timer::Pacing, delay);
+            return None;
+        }
+        let size = size as usize;
+        let mut buf = self.buffers.get(size);
+        let mut builder = match self.begin_packet(now, SpaceId::Data, false, &mut buf, size) {
+            Some(x) => x,
+            None => {
+                self.buffers.put(buf);
+                return None;
+            }
+        };
+        trace!(size, "sending MTU probe");
+        builder.buffer.write(frame::Type::PING);
+        self.stats.frame_tx.ping += 1;
+        // Pad to exactly `size`
+        builder.min_size = builder.frame_space_end();
+        let exact_number = builder.exact_number;
+        self.finish_packet(builder);
+        if let Some(ref mut qlog) = self.qlog {
+            qlog.packet_sent(now, "1RTT", exact_number, buf.len());
+        }
+        self.path.mtud.on_probe_sent(exact_number);
+        self.on_packet_sent(
+            now,
+            SpaceId::Data,
+            exact_number,
+            SentPacket {
+                acks: RangeSet::new(),
+                time_sent: now,
+                size: buf.len() as u16,
+                ack_eliciting: true,
+                retransmits: Retransmits::default(),
+                stream_frames: Vec::new(),
+            },
+        );
+        self.path.total_sent = self.path.total_sent.saturating_add(buf.len() as u64);
+        self.stats.udp_tx.datagrams += 1;
+        self.stats.udp_tx.bytes += buf.len() as u64;
+        // Marked like any other packet, lest the peer's ECN counts seem to show bleaching
+        Some(Transmit {
+            destination: self.path.remote,
+            contents: buf,
+            ecn: if self.path.sending_ecn {
+                Some(EcnCodepoint::ECT0)
+            } else {
+                None
+            },
+            segment_size: None,
+            src_ip: self.local_ip,
+        })
+    }
+
+    /// Account for the time since the last call in the statistics for whatever was limiting us then
+    fn record_send_limit(&mut self, now: Instant, congestion_blocked: bool) {
+        if !self.state.is_established() {
+            return;
+        }
+        let limit = if congestion_blocked {
+            SendLimit::Congestion
+        } else if self.streams.flow_blocked() {
+            SendLimit::FlowControl
+        } else {
+            SendLimit::App
+        };
+        if let Some((prev, since)) = self.send_limit.replace((limit, now)) {
+            let elapsed = now.saturating_duration_since(since);
+            let total = match prev {
+                SendLimit::Congestion => &mut self.stats.path.congestion_limited,
+                SendLimit::FlowControl => &mut self.stats.path.flow_control_limited,
+                SendLimit::App => &mut self.stats.path.app_limited,
+            };
+            *total += elapsed;
+        }
+    }
+
     /// Write a new packet header to `buffer` and determine the packet's properties
     ///
     /// Marks the connection drained and returns `None` if the confidentiality limit would be
@@ -701,6 +943,13 @@ where
             trace!("PADDING * {}", builder.min_size - builder.buffer.len());
             builder.buffer.resize(builder.min_size, 0);
         }
+        #[cfg(feature = "tap")]
+        self.tap(
+            tap::Direction::Sent,
+            builder.space,
+            builder.exact_number,
+            &builder.buffer[builder.partial_encode.start + builder.partial_encode.header_len..],
+        );
 
         let space = &self.spaces[builder.space];
         let (header_crypto, packet_crypto) = if let Some(ref crypto) = space.crypto {
@@ -715,7 +964,7 @@ where
         builder
             .buffer
             .resize(builder.buffer.len() + packet_crypto.tag_len(), 0);
-        debug_assert!(builder.buffer.len() <= self.path.mtu as usize);
+        debug_assert!(builder.buffer.len() <= builder.frame_space_end() + packet_crypto.tag_len());
         let packet_buf = &mut builder.buffer[builder.partial_encode.start..];
         builder.partial_encode.finish(
             packet_buf,
@@ -742,6 +991,7 @@ where
                 ecn,
                 first_decode,
                 remaining,
+                allocation_size,
             } => {
                 // If this packet could initiate a migration and we're a client or a server that
                 // forbids migration, drop the datagram. This could be relaxed to heuristically
@@ -760,6 +1010,7 @@ where
                     .total_recvd
                     .saturating_add(first_decode.len() as u64);
 
+                self.recv_allocation = allocation_size;
                 self.handle_decode(now, remote, ecn, first_decode);
                 if let Some(data) = remaining {
                     self.stats.udp_rx.bytes += data.len() as u64;
@@ -808,8 +1059,17 @@ where
                     self.kill(ConnectionError::TimedOut);
                 }
                 Timer::KeepAlive => {
-                    trace!("sending keep-alive");
-                    self.ping();
+                    if self.config.keep_alive_only_when_active
+                        && !self.awaiting_peer
+                        && !self.streams.has_open()
+                    {
+                        // Check again later, since activity may resume without any packets
+                        trace!("skipping keep-alive for inactive connection");
+                        self.reset_keep_alive(now);
+                    } else {
+                        trace!("sending keep-alive");
+                        self.ping();
+                    }
                 }
                 Timer::LossDetection => {
                     self.on_loss_detection_timeout(now);
@@ -851,7 +1111,8 @@ where
     /// [`StreamEvent::Finished`] event.
     ///
     /// If [`Connection::send_streams`] returns 0, all outstanding stream data has been
-    /// delivered. There may still be data from the peer that has not been received.
+    /// delivered. There may still be data from the peer that has not been received. Alternatively,
+    /// [`Connection::close_after_flush`] waits for delivery before closing.
     ///
     /// [`StreamEvent::Finished`]: crate::StreamEvent::Finished
     pub fn close(&mut self, now: Instant, error_code: VarInt, reason: Bytes) {
@@ -861,6 +1122,38 @@ where
         )
     }
 
+    /// Close a connection once all data written to its streams has been delivered
+    ///
+    /// Unlike [`close()`](Self::close), this holds off on sending the CONNECTION_CLOSE frame until
+    /// everything written to send streams so far, including the end of any finished streams, has
+    /// been acknowledged by the peer. Streams remain usable in the meantime, and data written to
+    /// them is waited for too. When the close finally takes place, an [`Event::ConnectionLost`]
+    /// with [`ConnectionError::LocallyClosed`] is emitted.
+    ///
+    /// A peer that withholds flow control credit or acknowledgements can delay the close until
+    /// the idle timeout, so the application may want to fall back to `close()` after a while.
+    pub fn close_after_flush(&mut self, now: Instant, error_code: VarInt, reason: Bytes) {
+        if self.state.is_closed() {
+            return;
+        }
+        self.close_after_flush = Some(frame::ApplicationClose { error_code, reason });
+        self.close_if_flushed(now);
+    }
+
+    /// Carry out a pending `close_after_flush` if outgoing stream data has been delivered
+    fn close_if_flushed(&mut self, now: Instant) {
+        if self.close_after_flush.is_none() || !self.streams.is_flushed() {
+            return;
+        }
+        let close = self.close_after_flush.take().unwrap();
+        if self.state.is_closed() {
+            return;
+        }
+        trace!("stream data flushed, closing");
+        self.close_inner(now, Close::Application(close));
+        self.events.push_back(ConnectionError::LocallyClosed.into());
+    }
+
     fn close_inner(&mut self, now: Instant, reason: Close) {
         let was_closed = self.state.is_closed();
         if !was_closed {
@@ -959,11 +1252,31 @@ where
     pub fn stats(&self) -> ConnectionStats {
         let mut stats = self.stats;
         stats.path.rtt = self.path.rtt.get();
+        stats.path.min_rtt = self.path.rtt.min;
+        stats.path.rtt_variance = self.path.rtt.var;
         stats.path.cwnd = self.path.congestion.window();
+        stats.path.bytes_in_flight = self.in_flight.bytes;
+        stats.path.current_mtu = self.path.mtu;
 
         stats
     }
 
+    /// Returns reassembly statistics for the given receive stream
+    pub fn recv_stream_stats(&self, id: StreamId) -> Result<RecvStreamStats, UnknownStream> {
+        self.streams.recv_stats(id)
+    }
+
+    /// Whether any data on the given receive stream arrived in 0-RTT packets
+    ///
+    /// A server may read 0-RTT data as soon as the stream is accepted, before the handshake
+    /// completes. Such data is not protected against replay: an attacker may deliver it to the
+    /// server again on another connection, so it should only cause actions that are safe to
+    /// repeat. Once the stream is finished and all its data read, this is no longer known and
+    /// `UnknownStream` is returned.
+    pub fn received_0rtt(&self, id: StreamId) -> Result<bool, UnknownStream> {
+        self.streams.received_0rtt(id)
+    }
+
     /// Stop accepting data on the given receive stream
     ///
     /// Discards unread data and notifies the peer to stop transmitting. Once stopped, further
@@ -1007,13 +1320,13 @@ where
     ///
     /// Returns `Err` iff a `len`-byte datagram cannot currently be sent
     pub fn send_datagram(&mut self, data: Bytes) -> Result<(), SendDatagramError> {
-        if self.config.datagram_receive_buffer_size.is_none() {
+        if self.datagrams.receive_buffer_size.is_none() {
             return Err(SendDatagramError::Disabled);
         }
         let max = self
             .max_datagram_size()
             .ok_or(SendDatagramError::UnsupportedByPeer)?;
-        while self.datagrams.outgoing_total > self.config.datagram_send_buffer_size {
+        while self.datagrams.outgoing_total > self.datagrams.send_buffer_size {
             let prev = self
                 .datagrams
                 .outgoing
@@ -1065,6 +1378,86 @@ where
         self.spaces[self.highest_space].ping_pending = true;
     }
 
+    /// Change the connection-level receive window, overriding
+    /// [`TransportConfig::receive_window()`]
+    ///
+    /// Growth is granted to the peer immediately. Because flow control credit can't be revoked, a
+    /// reduction only takes effect as the application reads data.
+    pub fn set_receive_window(&mut self, value: VarInt) {
+        if self.streams.set_receive_window(value).should_transmit() {
+            self.spaces[SpaceId::Data].pending.max_data = true;
+        }
+    }
+
+    /// Change the per-stream receive window, overriding
+    /// [`TransportConfig::stream_receive_window()`]
+    ///
+    /// Applies to open streams as well as future ones. As with
+    /// [`set_receive_window()`](Self::set_receive_window), growth is granted immediately and a
+    /// reduction takes effect as data is read.
+    pub fn set_stream_receive_window(&mut self, value: VarInt) {
+        self.streams
+            .set_stream_receive_window(value, &mut self.spaces[SpaceId::Data].pending);
+    }
+
+    /// Change the number of streams in `dir` the peer may have open concurrently, overriding
+    /// [`TransportConfig::max_concurrent_bidi_streams()`] or
+    /// [`TransportConfig::max_concurrent_uni_streams()`]
+    ///
+    /// Growth is granted to the peer immediately. A reduction takes effect as open streams are
+    /// closed, since stream credit can't be revoked either.
+    pub fn set_max_concurrent_streams(&mut self, dir: Dir, count: VarInt) {
+        self.streams.set_max_concurrent(dir, count);
+        if self.streams.take_max_streams_dirty(dir) {
+            let pending = &mut self.spaces[SpaceId::Data].pending;
+            match dir {
+                Dir::Uni => pending.max_uni_stream_id = true,
+                Dir::Bi => pending.max_bi_stream_id = true,
+            }
+        }
+    }
+
+    /// Change the keep-alive interval, overriding [`TransportConfig::keep_alive_interval()`]
+    ///
+    /// `None` disables keep-alives.
+    pub fn set_keep_alive_interval(&mut self, now: Instant, interval: Option<Duration>) {
+        self.keep_alive_interval = interval;
+        self.timers.stop(Timer::KeepAlive);
+        self.reset_keep_alive(now);
+    }
+
+    /// Report whether the application is waiting for the peer other than on a stream
+    ///
+    /// For instance, an application waiting to receive a datagram counts as activity under
+    /// [`TransportConfig::keep_alive_only_when_active()`], keeping the connection alive while it
+    /// waits. Open streams count as activity regardless.
+    pub fn set_awaiting_peer(&mut self, awaiting: bool) {
+        self.awaiting_peer = awaiting;
+    }
+
+    /// Change the space available for buffering received datagrams, overriding
+    /// [`TransportConfig::datagram_receive_buffer_size()`]
+    ///
+    /// Whether datagrams are supported at all is settled during the handshake, so this has no
+    /// effect if they were disabled by the transport configuration. Datagrams that no longer fit
+    /// are dropped, oldest first.
+    pub fn set_datagram_receive_buffer_size(&mut self, size: usize) {
+        if self.config.datagram_receive_buffer_size.is_none() {
+            return;
+        }
+        self.datagrams.receive_buffer_size = Some(size);
+        while self.datagrams.recv_buffered > size {
+            debug!("dropping stale datagram");
+            self.recv_datagram();
+        }
+    }
+
+    /// Change the space available for buffering outgoing datagrams, overriding
+    /// [`TransportConfig::datagram_send_buffer_size()`]
+    pub fn set_datagram_send_buffer_size(&mut self, size: usize) {
+        self.datagrams.send_buffer_size = size;
+    }
+
     #[doc(hidden)]
     pub fn initiate_key_update(&mut self) {
         self.update_keys(None, false);
@@ -1129,6 +1522,40 @@ where
         self.side
     }
 
+    /// Observe the decrypted contents of packets sent and received from now on
+    ///
+    /// Replaces any tap set previously or by the transport configuration.
+    #[cfg(feature = "tap")]
+    pub fn set_packet_tap(&mut self, tap: Option<Arc<dyn PacketTap>>) {
+        self.packet_tap = tap;
+    }
+
+    /// Send `frames` verbatim in a 1-RTT packet of their own
+    ///
+    /// The frames bypass all local validation and state tracking, and are never retransmitted.
+    /// This allows otherwise well-behaved connections to commit protocol violations, to verify
+    /// that the peer detects them. `frames` must fit in a minimum-size packet alongside any ACK
+    /// frame sent with it.
+    #[cfg(feature = "conformance")]
+    pub fn inject_frames(
+        &mut self,
+        frames: Bytes,
+    ) -> Result<(), crate::conformance::FramesTooLarge> {
+        if frames.len() > 1000 {
+            return Err(crate::conformance::FramesTooLarge);
+        }
+        self.injected_frames.push_back(frames);
+        Ok(())
+    }
+
+    /// The destination CID of the first Initial packet sent by the client, as seen locally
+    ///
+    /// Conventionally used to correlate traces of a connection across endpoints. If the server
+    /// requested a retry, the server sees the destination CID of the client's second Initial.
+    pub fn initial_dst_cid(&self) -> ConnectionId {
+        self.initial_dst_cid
+    }
+
     /// The latest socket address for this connection's peer
     pub fn remote_address(&self) -> SocketAddr {
         self.path.remote
@@ -1157,6 +1584,20 @@ where
         self.path.rtt.get()
     }
 
+    /// Summary of the path this connection currently uses
+    ///
+    /// Gathers what security-sensitive applications may want to record about a connection: where
+    /// the peer is, whether it was proven to be there and how, and how often it moved.
+    pub fn path_info(&self) -> PathInfo {
+        PathInfo {
+            remote: self.path.remote,
+            mtu: self.path.mtu,
+            address_validation: self.path.validation,
+            handshake_validation: self.handshake_validation,
+            migrations: self.migrations,
+        }
+    }
+
     fn on_packet_sent(
         &mut self,
         now: Instant,
@@ -1172,6 +1613,8 @@ where
 
         self.in_flight.insert(&packet);
         self.spaces[space].sent(packet_number, packet);
+        self.stats.space_mut(space).sent += 1;
+        self.stats.path.sent_packets += 1;
         self.reset_keep_alive(now);
         if size != 0 {
             if ack_eliciting {
@@ -1195,6 +1638,11 @@ where
         if ack.largest >= self.spaces[space].next_packet_number {
             return Err(TransportError::PROTOCOL_VIOLATION("unsent packet acked"));
         }
+        let reported_delay =
+            Duration::from_micros(ack.delay << self.peer_params.ack_delay_exponent.0);
+        let stats = self.stats.space_mut(space);
+        stats.latest_ack_delay = reported_delay;
+        stats.max_ack_delay = stats.max_ack_delay.max(reported_delay);
         let new_largest = {
             let space = &mut self.spaces[space];
             if space
@@ -1215,36 +1663,38 @@ where
         };
 
         // Avoid DoS from unreasonably huge ack ranges by filtering out just the new acks.
-        let newly_acked = ack
-            .iter()
-            .flat_map(|range| {
-                self.spaces[space]
-                    .sent_packets
-                    .range(range)
-                    .map(|(&n, _)| n)
-            })
-            .collect::<Vec<_>>();
+        let newly_acked = spaces::newly_acked(&self.spaces[space].sent_packets, ack.iter());
         if newly_acked.is_empty() {
             return Ok(());
         }
 
+        // Every ACK we send covers all pending acknowledgements, so the most recently sent ACK
+        // among the newly acknowledged packets accounts for those carried by the others.
+        // Subtracting only its ranges keeps this independent of the number of packets acked.
+        let mut latest_acks = (0, RangeSet::new());
         let mut ack_eliciting_acked = false;
         for &packet in &newly_acked {
-            if let Some(info) = self.spaces[space].sent_packets.remove(&packet) {
-                self.spaces[space].pending_acks.subtract(&info.acks);
+            if let Some(mut info) = self.spaces[space].sent_packets.remove(&packet) {
+                if !info.acks.is_empty() && (latest_acks.1.is_empty() || packet > latest_acks.0) {
+                    latest_acks = (packet, mem::replace(&mut info.acks, RangeSet::new()));
+                }
                 ack_eliciting_acked |= info.ack_eliciting;
+                self.stats.space_mut(space).acked += 1;
+                if space == SpaceId::Data {
+                    if let Some(mtu) = self.path.mtud.on_acked(now, packet) {
+                        self.path.mtu = mtu;
+                    }
+                }
                 self.on_packet_acked(now, space, info);
             }
         }
+        self.spaces[space].pending_acks.subtract(&latest_acks.1);
 
         if new_largest && ack_eliciting_acked {
             let ack_delay = if space != SpaceId::Data {
                 Duration::from_micros(0)
             } else {
-                cmp::min(
-                    self.max_ack_delay(),
-                    Duration::from_micros(ack.delay << self.peer_params.ack_delay_exponent.0),
-                )
+                cmp::min(self.max_ack_delay(), reported_delay)
             };
             let rtt = instant_saturating_sub(now, self.spaces[space].largest_acked_packet_sent);
             self.path.rtt.update(ack_delay, rtt);
@@ -1276,9 +1726,41 @@ where
         }
 
         self.set_loss_detection_timer(now);
+        self.qlog_metrics(now);
         Ok(())
     }
 
+    #[cfg(feature = "tap")]
+    fn tap(&self, direction: tap::Direction, space: SpaceId, number: u64, payload: &[u8]) {
+        if let Some(ref tap) = self.packet_tap {
+            tap.packet(&tap::TappedPacket {
+                direction,
+                initial_dst_cid: self.initial_dst_cid,
+                space,
+                number,
+                payload,
+            });
+        }
+    }
+
+    /// Report any changes to recovery state to the qlog trace
+    fn qlog_metrics(&mut self, now: Instant) {
+        if let Some(ref mut qlog) = self.qlog {
+            let rtt = &self.path.rtt;
+            qlog.metrics_updated(
+                now,
+                qlog::Metrics {
+                    min_rtt: rtt.min,
+                    smoothed_rtt: rtt.get(),
+                    latest_rtt: rtt.latest,
+                    rtt_variance: rtt.var,
+                    congestion_window: self.path.congestion.window(),
+                    bytes_in_flight: self.in_flight.bytes,
+                },
+            );
+        }
+    }
+
     /// Process a new ECN block from an in-order ACK
     fn process_ecn(
         &mut self,
@@ -1288,6 +1770,7 @@ where
         ecn: frame::EcnCounts,
         largest_sent_time: Instant,
     ) {
+        let prev_ce = self.spaces[space].ecn_feedback.ce;
         match self.spaces[space].detect_ecn(newly_acked, ecn) {
             Err(e) => {
                 debug!("halting ECN due to verification failure: {}", e);
@@ -1298,6 +1781,8 @@ where
             }
             Ok(false) => {}
             Ok(true) => {
+                self.stats.path.ce_reported += ecn.ce - prev_ce;
+                self.stats.path.ecn_congestion_events += 1;
                 self.path
                     .congestion
                     .on_congestion_event(now, largest_sent_time, false);
@@ -1307,7 +1792,7 @@ where
 
     // Not timing-aware, so it's safe to call this for inferred acks, such as arise from
     // high-latency handshakes
-    fn on_packet_acked(&mut self, now: Instant, space: SpaceId, info: SentPacket) {
+    fn on_packet_acked(&mut self, now: Instant, space: SpaceId, mut info: SentPacket) {
         self.remove_in_flight(space, &info);
         if info.ack_eliciting {
             // Congestion control
@@ -1323,13 +1808,15 @@ where
         }
 
         // Update state for confirmed delivery of frames
-        for (id, _) in info.retransmits.reset_stream {
+        for (id, _) in info.retransmits.reset_stream.drain(..) {
             self.streams.reset_acked(id);
         }
 
-        for frame in info.stream_frames {
+        for frame in info.stream_frames.drain(..) {
             self.streams.received_ack_of(frame);
         }
+        self.arena.put_retransmits(info.retransmits);
+        self.arena.put_stream_frames(info.stream_frames);
     }
 
     fn set_key_discard_timer(&mut self, now: Instant) {
@@ -1374,6 +1861,7 @@ where
 
         self.spaces[space].loss_probes = self.spaces[space].loss_probes.saturating_add(count);
         self.pto_count = self.pto_count.saturating_add(1);
+        self.stats.path.ptos += 1;
         self.set_loss_detection_timer(now);
     }
 
@@ -1411,7 +1899,6 @@ where
     }
 
     fn detect_lost_packets(&mut self, now: Instant, pn_space: SpaceId) {
-        let mut lost_packets = Vec::<u64>::new();
         let rtt = self.path.rtt.conservative();
         let loss_delay = cmp::max(rtt.mul_f32(self.config.time_threshold), TIMER_GRANULARITY);
 
@@ -1421,37 +1908,40 @@ where
         let packet_threshold = self.config.packet_threshold as u64;
 
         let space = &mut self.spaces[pn_space];
-        space.loss_time = None;
-        for (&packet, info) in space.sent_packets.range(0..largest_acked_packet) {
-            if info.time_sent <= lost_send_time || largest_acked_packet >= packet + packet_threshold
-            {
-                lost_packets.push(packet);
-            } else {
-                let next_loss_time = info.time_sent + loss_delay;
-                space.loss_time = Some(
-                    space
-                        .loss_time
-                        .map_or(next_loss_time, |x| cmp::min(x, next_loss_time)),
-                );
-            }
-        }
+        let (lost_packets, earliest_unlost) = spaces::detect_lost(
+            &space.sent_packets,
+            largest_acked_packet,
+            packet_threshold,
+            lost_send_time,
+        );
+        space.loss_time = earliest_unlost.map(|x| x + loss_delay);
 
         // OnPacketsLost
         if let Some(largest_lost) = lost_packets.last().cloned() {
             let old_bytes_in_flight = self.in_flight.bytes;
             let largest_lost_sent = self.spaces[pn_space].sent_packets[&largest_lost].time_sent;
             self.lost_packets += lost_packets.len() as u64;
+            self.stats.space_mut(pn_space).lost += lost_packets.len() as u64;
+            self.stats.path.lost_packets += lost_packets.len() as u64;
             trace!("packets lost: {:?}", lost_packets);
+            let mut lost_probe_bytes = 0;
             for packet in &lost_packets {
-                let info = self.spaces[pn_space].sent_packets.remove(&packet).unwrap(); // safe: lost_packets is populated just above
+                let mut info = self.spaces[pn_space].sent_packets.remove(&packet).unwrap(); // safe: lost_packets is populated just above
                 self.remove_in_flight(pn_space, &info);
-                for frame in info.stream_frames {
+                if pn_space == SpaceId::Data && self.path.mtud.on_lost(now, *packet) {
+                    lost_probe_bytes += u64::from(info.size);
+                }
+                self.stats.path.lost_bytes += u64::from(info.size);
+                for frame in info.stream_frames.drain(..) {
+                    self.stats.path.retransmitted_stream_bytes +=
+                        frame.offsets.end - frame.offsets.start;
                     self.streams.retransmit(frame);
                 }
+                self.arena.put_stream_frames(info.stream_frames);
                 self.spaces[pn_space].pending |= info.retransmits;
             }
-            // Don't apply congestion penalty for lost ack-only packets
-            let lost_ack_eliciting = old_bytes_in_flight != self.in_flight.bytes;
+            // Don't apply congestion penalty for lost ack-only packets or MTU probes
+            let lost_ack_eliciting = old_bytes_in_flight - self.in_flight.bytes > lost_probe_bytes;
 
             // InPersistentCongestion: Determine if all packets in the time period before the newest
             // lost packet, including the edges, are marked lost
@@ -1460,6 +1950,13 @@ where
                 < largest_lost_sent - congestion_period;
 
             if lost_ack_eliciting {
+                self.stats.path.congestion_events += 1;
+                if in_persistent_congestion {
+                    self.stats.path.black_holes += 1;
+                    // Perhaps the path stopped carrying packets as large as we've been sending
+                    self.path.mtud.on_black_hole(now);
+                    self.path.mtu = self.path.mtud.current_mtu();
+                }
                 self.path.congestion.on_congestion_event(
                     now,
                     largest_lost_sent,
@@ -1577,6 +2074,9 @@ where
         self.receiving_ecn |= ecn.is_some();
         if let Some(x) = ecn {
             self.spaces[space_id].ecn_counters += x;
+            if x == EcnCodepoint::CE {
+                self.stats.path.ce_received += 1;
+            }
         }
 
         let packet = match packet {
@@ -1621,7 +2121,7 @@ where
     }
 
     fn reset_keep_alive(&mut self, now: Instant) {
-        let interval = match self.config.keep_alive_interval {
+        let interval = match self.keep_alive_interval {
             Some(x) if self.state.is_established() => x,
             _ => return,
         };
@@ -1665,12 +2165,14 @@ where
         packet_number: u64,
         packet: Packet,
         remaining: Option<BytesMut>,
+        allocation_size: usize,
     ) -> Result<(), ConnectionError> {
         let span = trace_span!("first recv");
         let _guard = span.enter();
         debug_assert!(self.side.is_server());
         let len = packet.header_data.len() + packet.payload.len();
         self.path.total_recvd = len as u64;
+        self.recv_allocation = allocation_size;
 
         self.on_packet_authenticated(
             now,
@@ -1724,7 +2226,8 @@ where
     fn read_crypto(
         &mut self,
         space: SpaceId,
-        crypto: &frame::Crypto,
+        crypto: frame::Crypto,
+        allocation_size: usize,
     ) -> Result<(), TransportError> {
         let expected = if !self.state.is_handshake() {
             SpaceId::Data
@@ -1757,12 +2260,17 @@ where
             return Err(TransportError::CRYPTO_BUFFER_EXCEEDED(""));
         }
 
-        space
+        // Data arriving in order, as most does, goes straight to the session
+        let direct = space
             .crypto_stream
-            .insert(crypto.offset, crypto.data.clone());
-        while let Some(chunk) = space.crypto_stream.read(usize::MAX, true).unwrap() {
-            trace!("consumed {} CRYPTO bytes", chunk.bytes.len());
-            if self.crypto.read_handshake(&chunk.bytes)? {
+            .read_through(crypto.offset, crypto.data, allocation_size);
+        let buffered = iter::from_fn(|| {
+            let chunk = space.crypto_stream.read(usize::MAX, true).unwrap()?;
+            Some(chunk.bytes)
+        });
+        for bytes in direct.into_iter().chain(buffered) {
+            trace!("consumed {} CRYPTO bytes", bytes.len());
+            if self.crypto.read_handshake(&bytes)? {
                 self.events.push_back(Event::HandshakeDataReady);
             }
         }
@@ -1976,6 +2484,23 @@ where
                     trace!("dropping short packet during handshake");
                     return;
                 } else {
+                    if let Some(ref mut qlog) = self.qlog {
+                        qlog.packet_received(
+                            now,
+                            qlog::packet_type(&packet.header),
+                            number,
+                            packet.header_data.len() + packet.payload.len(),
+                        );
+                    }
+                    #[cfg(feature = "tap")]
+                    if let Some(number) = number {
+                        self.tap(
+                            tap::Direction::Received,
+                            packet.header.space(),
+                            number,
+                            &packet.payload,
+                        );
+                    }
                     if !self.state.is_closed() {
                         let spin = match packet.header {
                             Header::Short { spin, .. } => spin,
@@ -2135,7 +2660,10 @@ where
                             );
                             return Ok(());
                         }
-                        self.path.validated = true;
+                        if self.path.validation == AddressValidation::Pending {
+                            self.path.validation = AddressValidation::Implicit;
+                            self.handshake_validation = AddressValidation::Implicit;
+                        }
 
                         let state = state.clone();
                         self.process_early_payload(now, packet)?;
@@ -2178,6 +2706,9 @@ where
                                     ));
                             }
                             self.validate_peer_params(&params)?;
+                            if let Some(ref mut qlog) = self.qlog {
+                                qlog.parameters_set(now, false, &params);
+                            }
                             self.set_peer_params(params);
                             self.issue_cids(now);
                         } else {
@@ -2187,6 +2718,8 @@ where
                         }
 
                         self.events.push_back(Event::Connected);
+                        self.endpoint_events
+                            .push_back(EndpointEventInner::Established);
                         self.state = State::Established;
                         trace!("established");
                         Ok(())
@@ -2225,6 +2758,9 @@ where
                                 }
                             })?;
                             self.validate_peer_params(&params)?;
+                            if let Some(ref mut qlog) = self.qlog {
+                                qlog.parameters_set(now, false, &params);
+                            }
                             self.set_peer_params(params);
                             self.issue_cids(now);
                             self.init_0rtt();
@@ -2240,6 +2776,7 @@ where
                             remote,
                             number.unwrap(),
                             packet.payload.freeze(),
+                            true,
                         )?;
                         Ok(())
                     }
@@ -2260,9 +2797,13 @@ where
             }
             State::Established => {
                 match packet.header.space() {
-                    SpaceId::Data => {
-                        self.process_payload(now, remote, number.unwrap(), packet.payload.freeze())?
-                    }
+                    SpaceId::Data => self.process_payload(
+                        now,
+                        remote,
+                        number.unwrap(),
+                        packet.payload.freeze(),
+                        packet.header.is_0rtt(),
+                    )?,
                     _ => self.process_early_payload(now, packet)?,
                 }
                 Ok(())
@@ -2294,6 +2835,7 @@ where
         packet: Packet,
     ) -> Result<(), TransportError> {
         debug_assert_ne!(packet.header.space(), SpaceId::Data);
+        let allocation_size = self.recv_allocation.max(packet.payload.len());
         for frame in frame::Iter::new(packet.payload.freeze()) {
             let span = match frame {
                 Frame::Padding => continue,
@@ -2314,7 +2856,7 @@ where
             match frame {
                 Frame::Padding | Frame::Ping => {}
                 Frame::Crypto(frame) => {
-                    self.read_crypto(packet.header.space(), &frame)?;
+                    self.read_crypto(packet.header.space(), frame, allocation_size)?;
                 }
                 Frame::Ack(ack) => {
                     self.on_ack_received(now, packet.header.space(), ack)?;
@@ -2326,7 +2868,7 @@ where
                 }
                 Frame::Invalid { ty, reason } => {
                     let mut err = TransportError::FRAME_ENCODING_ERROR(reason);
-                    err.frame = Some(ty);
+                    err.frame = ty;
                     return Err(err);
                 }
                 _ => {
@@ -2348,10 +2890,11 @@ where
         remote: SocketAddr,
         number: u64,
         payload: Bytes,
+        is_0rtt: bool,
     ) -> Result<(), TransportError> {
-        let is_0rtt = self.spaces[SpaceId::Data].crypto.is_none();
         let mut is_probing_packet = true;
         let mut close = None;
+        let allocation_size = self.recv_allocation.max(payload.len());
         for frame in frame::Iter::new(payload) {
             let span = match frame {
                 Frame::Padding => continue,
@@ -2392,14 +2935,23 @@ where
             match frame {
                 Frame::Invalid { ty, reason } => {
                     let mut err = TransportError::FRAME_ENCODING_ERROR(reason);
-                    err.frame = Some(ty);
+                    err.frame = ty;
                     return Err(err);
                 }
                 Frame::Crypto(frame) => {
-                    self.read_crypto(SpaceId::Data, &frame)?;
+                    self.read_crypto(SpaceId::Data, frame, allocation_size)?;
                 }
                 Frame::Stream(frame) => {
-                    if self.streams.received(frame)?.should_transmit() {
+                    if self
+                        .streams
+                        .received(
+                            frame,
+                            allocation_size,
+                            is_0rtt,
+                            &mut self.spaces[SpaceId::Data].pending,
+                        )?
+                        .should_transmit()
+                    {
                         self.spaces[SpaceId::Data].pending.max_data = true;
                     }
                 }
@@ -2432,7 +2984,7 @@ where
                         trace!("new path validated");
                         self.timers.stop(Timer::PathValidation);
                         self.path.challenge = None;
-                        self.path.validated = true;
+                        self.path.validation = AddressValidation::Implicit;
                         if let Some(ref mut prev_path) = self.prev_path {
                             prev_path.challenge = None;
                             prev_path.challenge_pending = false;
@@ -2577,10 +3129,13 @@ where
                         return Err(TransportError::FRAME_ENCODING_ERROR("empty token"));
                     }
                     trace!("got new token");
-                    // TODO: Cache, or perhaps forward to user?
+                    if let Some(ref resumption) = self.resumption {
+                        resumption.store.set_token(&resumption.server_name, token);
+                    }
                 }
                 Frame::Datagram(datagram) => {
-                    let window = match self.config.datagram_receive_buffer_size {
+                    // Validated against the limits advertised during the handshake
+                    let advertised = match self.config.datagram_receive_buffer_size {
                         None => {
                             return Err(TransportError::PROTOCOL_VIOLATION(
                                 "unexpected DATAGRAM frame",
@@ -2588,9 +3143,14 @@ where
                         }
                         Some(x) => x,
                     };
-                    if datagram.data.len() > window {
+                    if datagram.data.len() > advertised {
                         return Err(TransportError::PROTOCOL_VIOLATION("oversized datagram"));
                     }
+                    let window = self.datagrams.receive_buffer_size.unwrap_or(0);
+                    if datagram.data.len() > window {
+                        debug!("dropping datagram larger than the receive buffer");
+                        continue;
+                    }
                     if self.datagrams.recv_buffered == 0 {
                         self.events.push_back(Event::DatagramReceived);
                     }
@@ -2658,22 +3218,32 @@ where
 
     fn migrate(&mut self, now: Instant, remote: SocketAddr) {
         trace!(%remote, "migration initiated");
+        self.migrations += 1;
         // Reset rtt/congestion state for new path unless it looks like a NAT rebinding.
         // Note that the congestion window will not grow until validation terminates. Helps mitigate
         // amplification attacks performed by spoofing source addresses.
         let mut new_path = if remote.is_ipv4() && remote.ip() == self.path.remote.ip() {
             PathData::from_previous(remote, &self.path, now)
         } else {
+            let local = self.config.is_local_path(&remote);
             PathData::new(
                 remote,
                 self.config.initial_rtt,
                 self.config.congestion_controller_factory.build(now),
                 now,
-                false,
+                AddressValidation::Pending,
+                local,
+                self.path
+                    .mtud
+                    .for_new_path(self.config.mtu_discovery_interval_for(local)),
             )
         };
+        if new_path.local {
+            new_path.mtu = new_path.mtud.assume_max();
+        }
         new_path.challenge = Some(self.rng.gen());
         new_path.challenge_pending = true;
+        self.events.push_back(Event::Migrated { remote });
 
         let mut prev = mem::replace(&mut self.path, new_path);
         // Don't clobber the original path if the previous one hasn't been validated yet
@@ -2727,7 +3297,11 @@ where
         buf: &mut Vec<u8>,
         buf_capacity: usize,
     ) -> SentFrames {
-        let mut sent = SentFrames::default();
+        let mut sent = SentFrames {
+            retransmits: self.arena.retransmits(),
+            stream_frames: self.arena.stream_frames(),
+            ..SentFrames::default()
+        };
         let space = &mut self.spaces[space_id];
         let zero_rtt_crypto = self.zero_rtt_crypto.as_ref();
         let tag_len = space
@@ -2765,6 +3339,15 @@ where
             self.stats.frame_tx.ping += 1;
         }
 
+        // Injected frames
+        #[cfg(feature = "conformance")]
+        if space_id == SpaceId::Data && !is_0rtt {
+            if let Some(frames) = self.injected_frames.pop_front() {
+                trace!(len = frames.len(), "injected frames");
+                buf.extend_from_slice(&frames);
+            }
+        }
+
         // ACK
         // 0-RTT packets must never carry acks (which would have to be of handshake packets)
         if !space.pending_acks.is_empty() {
@@ -2896,7 +3479,8 @@ where
 
         // STREAM
         if space_id == SpaceId::Data {
-            sent.stream_frames = self.streams.write_stream_frames(buf, max_size);
+            self.streams
+                .write_stream_frames(buf, max_size, &mut sent.stream_frames);
             self.stats.frame_tx.stream += sent.stream_frames.len() as u64;
         }
 
@@ -2908,6 +3492,11 @@ where
         for &timer in &Timer::VALUES {
             self.timers.stop(timer);
         }
+        if let (Some(resumption), Some(rtt)) = (self.resumption.as_ref(), self.path.rtt.smoothed) {
+            resumption
+                .store
+                .set_path(&resumption.server_name, rtt, self.path.congestion.window());
+        }
     }
 
     fn set_close_timer(&mut self, now: Instant) {
@@ -2931,6 +3520,10 @@ where
 
     fn set_peer_params(&mut self, params: TransportParameters) {
         self.streams.set_params(&params);
+        self.path.mtud.set_peer_max(params.max_udp_payload_size.0);
+        if self.path.local {
+            self.path.mtu = self.path.mtud.assume_max();
+        }
         self.idle_timeout = match (self.config.max_idle_timeout, params.max_idle_timeout.0) {
             (None, 0) => None,
             (None, x) => Some(Duration::from_millis(x)),
@@ -3051,6 +3644,9 @@ where
             update_unacked: remote,
         });
         self.key_phase = !self.key_phase;
+        self.events.push_back(Event::KeysUpdated {
+            initiated_by_peer: remote,
+        });
     }
 
     /// The number of bytes of packets containing retransmittable frames that have not been
@@ -3070,7 +3666,7 @@ where
     }
 
     /// Whether no timers but keepalive, idle and pushnewcid are running
-    #[cfg(test)]
+    #[cfg(any(test, feature = "simulation"))]
     pub(crate) fn is_idle(&self) -> bool {
         Timer::VALUES
             .iter()
@@ -3120,6 +3716,10 @@ where
     ///
     /// See also `self.space(SpaceId::Data).can_send()`
     fn can_send_1rtt(&self) -> bool {
+        #[cfg(feature = "conformance")]
+        if !self.injected_frames.is_empty() {
+            return true;
+        }
         self.streams.can_send()
             || self.path.challenge_pending
             || self
@@ -3136,6 +3736,7 @@ where
         debug!("0-RTT rejected");
         self.accepted_0rtt = false;
         self.streams.zero_rtt_rejected();
+        self.events.push_back(Event::ZeroRttRejected);
         // Discard already-queued frames
         self.spaces[SpaceId::Data].pending = Retransmits::default();
         // Discard 0-RTT packets
@@ -3185,10 +3786,10 @@ pub enum ConnectionError {
     #[error("{0}")]
     TransportError(#[from] TransportError),
     /// The peer's QUIC stack aborted the connection automatically
-    #[error("aborted by peer: {}", 0)]
+    #[error("aborted by peer: {0}")]
     ConnectionClosed(frame::ConnectionClose),
     /// The peer closed the connection
-    #[error("closed by peer: {}", 0)]
+    #[error("closed by peer: {0}")]
     ApplicationClosed(frame::ApplicationClose),
     /// The peer is unable to continue processing this connection, usually due to having restarted
     #[error("reset by peer")]
@@ -3205,6 +3806,93 @@ pub enum ConnectionError {
     LocallyClosed,
 }
 
+impl ConnectionError {
+    /// The variant of this error, as a value without fields
+    pub fn kind(&self) -> ConnectionErrorKind {
+        use self::ConnectionError::*;
+        match *self {
+            VersionMismatch => ConnectionErrorKind::VersionMismatch,
+            TransportError(_) => ConnectionErrorKind::TransportError,
+            ConnectionClosed(_) => ConnectionErrorKind::ConnectionClosed,
+            ApplicationClosed(_) => ConnectionErrorKind::ApplicationClosed,
+            Reset => ConnectionErrorKind::Reset,
+            TimedOut => ConnectionErrorKind::TimedOut,
+            LocallyClosed => ConnectionErrorKind::LocallyClosed,
+        }
+    }
+
+    /// The transport error code, whether the violation was detected locally or by the peer
+    pub fn transport_error_code(&self) -> Option<TransportErrorCode> {
+        match *self {
+            ConnectionError::TransportError(ref e) => Some(e.code),
+            ConnectionError::ConnectionClosed(ref x) => Some(x.error_code),
+            _ => None,
+        }
+    }
+
+    /// The code supplied by the peer's application when it closed the connection
+    pub fn application_error_code(&self) -> Option<VarInt> {
+        match *self {
+            ConnectionError::ApplicationClosed(ref x) => Some(x.error_code),
+            _ => None,
+        }
+    }
+
+    /// The type of the frame that triggered a transport error, if known
+    pub fn frame_type(&self) -> Option<u64> {
+        match *self {
+            ConnectionError::TransportError(ref e) => e.frame.map(u64::from),
+            ConnectionError::ConnectionClosed(ref x) => x.frame_type.map(u64::from),
+            _ => None,
+        }
+    }
+
+    /// The human-readable explanation accompanying the error, if any
+    ///
+    /// Reasons supplied by the peer are not guaranteed to be valid UTF-8.
+    pub fn reason(&self) -> Option<&[u8]> {
+        match *self {
+            ConnectionError::TransportError(ref e) => Some(e.reason.as_bytes()),
+            ConnectionError::ConnectionClosed(ref x) => Some(&x.reason),
+            ConnectionError::ApplicationClosed(ref x) => Some(&x.reason),
+            _ => None,
+        }
+    }
+
+    /// Whether the connection was ended by the peer, rather than locally
+    pub fn is_remote(&self) -> bool {
+        use self::ConnectionError::*;
+        match *self {
+            ConnectionClosed(_) | ApplicationClosed(_) | Reset => true,
+            VersionMismatch | TransportError(_) | TimedOut | LocallyClosed => false,
+        }
+    }
+}
+
+/// The variants of [`ConnectionError`], without their fields
+///
+/// Each kind has a fixed numeric value, which may be used in logs and metrics. New kinds may be
+/// added in the future.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+#[repr(u8)]
+pub enum ConnectionErrorKind {
+    /// See [`ConnectionError::VersionMismatch`]
+    VersionMismatch = 1,
+    /// See [`ConnectionError::TransportError`]
+    TransportError = 2,
+    /// See [`ConnectionError::ConnectionClosed`]
+    ConnectionClosed = 3,
+    /// See [`ConnectionError::ApplicationClosed`]
+    ApplicationClosed = 4,
+    /// See [`ConnectionError::Reset`]
+    Reset = 5,
+    /// See [`ConnectionError::TimedOut`]
+    TimedOut = 6,
+    /// See [`ConnectionError::LocallyClosed`]
+    LocallyClosed = 7,
+}
+
 impl From<Close> for ConnectionError {
     fn from(x: Close) -> Self {
         match x {
@@ -3342,7 +4030,10 @@ impl InFlight {
 }
 
 /// Events of interest to the application
+///
+/// New kinds of event may be added in the future.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Event {
     /// The connection's handshake data is ready
     HandshakeDataReady,
@@ -3359,6 +4050,23 @@ pub enum Event {
     Stream(StreamEvent),
     /// One or more application datagrams have been received
     DatagramReceived,
+    /// The peer began sending from a new address
+    ///
+    /// The new path may yet fail validation, in which case the previous one is restored.
+    Migrated {
+        /// The peer's new address
+        remote: SocketAddr,
+    },
+    /// The 1-RTT packet protection keys were replaced with the next generation
+    KeysUpdated {
+        /// Whether the update was initiated by the peer rather than by us
+        initiated_by_peer: bool,
+    },
+    /// The server didn't accept our 0-RTT data
+    ///
+    /// Every stream opened so far has been discarded, along with the data written to it. Streams
+    /// opened from now on are numbered from zero again.
+    ZeroRttRejected,
 }
 
 impl From<ConnectionError> for Event {
@@ -3367,6 +4075,14 @@ impl From<ConnectionError> for Event {
     }
 }
 
+/// Reasons a connection may be unable to send
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum SendLimit {
+    Congestion,
+    FlowControl,
+    App,
+}
+
 struct PathResponse {
     /// The packet number the corresponding PATH_CHALLENGE was received in
     packet: u64,
@@ -3410,15 +4126,21 @@ struct DatagramState {
     incoming: VecDeque<Datagram>,
     outgoing: VecDeque<Datagram>,
     outgoing_total: usize,
+    /// Current limit on `recv_buffered`, or `None` if datagrams are disabled
+    receive_buffer_size: Option<usize>,
+    /// Current limit on `outgoing_total`
+    send_buffer_size: usize,
 }
 
 impl DatagramState {
-    fn new() -> Self {
+    fn new(config: &TransportConfig) -> Self {
         Self {
             recv_buffered: 0,
             incoming: VecDeque::new(),
             outgoing: VecDeque::new(),
             outgoing_total: 0,
+            receive_buffer_size: config.datagram_receive_buffer_size,
+            send_buffer_size: config.datagram_send_buffer_size,
         }
     }
 }
@@ -3443,10 +4165,18 @@ struct PacketBuilder<'a> {
     exact_number: u64,
     short_header: bool,
     min_size: usize,
+    /// Maximum size of the packet's frames
     max_size: usize,
     span: tracing::Span,
 }
 
+impl PacketBuilder<'_> {
+    /// Length `buffer` may reach while writing frames, leaving room for the AEAD tag
+    fn frame_space_end(&self) -> usize {
+        self.partial_encode.start + self.partial_encode.header_len + self.max_size
+    }
+}
+
 /// Perform key updates this many packets before the AEAD confidentiality limit.
 ///
 /// Chosen arbitrarily, intended to be large enough to prevent spurious connection loss.
@@ -0,0 +1,238 @@
+//! Path MTU discovery, loosely following DPLPMTUD (RFC 8899)
+//!
+//! Packets never exceed a size the path has proven to carry. Larger sizes are tried by sending a
+//! probe: a packet holding only a PING, padded to the candidate size. An acknowledged probe raises
+//! the MTU to its size, and a size whose probe is lost `MAX_PROBES` times in a row is deemed too
+//! large. Probes aren't counted as congestion when lost, since their loss says nothing about the
+//! network being overloaded.
+
+use std::time::Duration;
+
+use tracing::trace;
+
+use crate::{Instant, MIN_MTU};
+
+/// Search state for the largest datagram a path can carry
+#[derive(Debug, Clone)]
+pub struct MtuDiscovery {
+    /// Largest size the path has been shown to carry
+    current: u16,
+    /// Largest size we and the peer are willing to use
+    max: u16,
+    /// How long to wait before searching again after a search ends, or `None` to never search
+    interval: Option<Duration>,
+    phase: Phase,
+}
+
+#[derive(Debug, Clone)]
+enum Phase {
+    /// Not searching until the given time, if any
+    Idle(Option<Instant>),
+    Searching(Search),
+}
+
+#[derive(Debug, Clone)]
+struct Search {
+    /// Size to probe next
+    candidate: u16,
+    /// Smallest size known not to get through
+    upper: u16,
+    /// Packet number of the probe in flight, if any
+    in_flight: Option<u64>,
+    /// Number of probes of `candidate` lost in a row
+    lost: u8,
+}
+
+impl MtuDiscovery {
+    /// Discover the MTU up to `max`, searching every `interval`
+    pub fn new(max: u16, interval: Option<Duration>) -> Self {
+        Self {
+            current: MIN_MTU,
+            max: max.max(MIN_MTU),
+            interval,
+            phase: Phase::Idle(None),
+        }
+    }
+
+    /// State for a new path to the same peer, which must be searched afresh every `interval`
+    pub fn for_new_path(&self, interval: Option<Duration>) -> Self {
+        Self::new(self.max, interval)
+    }
+
+    /// Largest size the path has been shown to carry
+    pub fn current_mtu(&self) -> u16 {
+        self.current
+    }
+
+    /// Limit the search to the peer's `max_udp_payload_size`
+    pub fn set_peer_max(&mut self, max: u64) {
+        if max < u64::from(self.max) {
+            self.max = (max as u16).max(MIN_MTU);
+        }
+    }
+
+    /// Take the path to carry the largest size allowed, without searching for it
+    ///
+    /// For local paths, whose MTU is large and unlikely to shrink. Should a black hole be
+    /// suspected nonetheless, the path falls back on the minimum MTU and is searched as usual.
+    pub fn assume_max(&mut self) -> u16 {
+        self.current = self.max;
+        self.phase = Phase::Idle(None);
+        self.current
+    }
+
+    /// Size of the probe to send now, if any
+    ///
+    /// Must only be called once the handshake is confirmed.
+    pub fn poll_probe(&mut self, now: Instant) -> Option<u16> {
+        self.interval?;
+        if let Phase::Idle(next) = self.phase {
+            if matches!(next, Some(x) if x > now) || self.current >= self.max {
+                return None;
+            }
+            // Try the largest size first, which is the common case on a well-configured link
+            self.phase = Phase::Searching(Search {
+                candidate: self.max,
+                upper: self.max + 1,
+                in_flight: None,
+                lost: 0,
+            });
+        }
+        match self.phase {
+            Phase::Searching(ref search) if search.in_flight.is_none() => Some(search.candidate),
+            _ => None,
+        }
+    }
+
+    /// A probe was sent with packet number `pn`
+    pub fn on_probe_sent(&mut self, pn: u64) {
+        if let Phase::Searching(ref mut search) = self.phase {
+            search.in_flight = Some(pn);
+        }
+    }
+
+    /// Packet `pn` was acknowledged, returning the new MTU if it was a probe
+    pub fn on_acked(&mut self, now: Instant, pn: u64) -> Option<u16> {
+        let search = match self.phase {
+            Phase::Searching(ref mut x) if x.in_flight == Some(pn) => x,
+            _ => return None,
+        };
+        trace!(size = search.candidate, "MTU probe acknowledged");
+        self.current = search.candidate;
+        search.in_flight = None;
+        search.lost = 0;
+        self.next_candidate(now);
+        Some(self.current)
+    }
+
+    /// Packet `pn` was declared lost, returning whether it was a probe
+    pub fn on_lost(&mut self, now: Instant, pn: u64) -> bool {
+        let search = match self.phase {
+            Phase::Searching(ref mut x) if x.in_flight == Some(pn) => x,
+            _ => return false,
+        };
+        trace!(size = search.candidate, "MTU probe lost");
+        search.in_flight = None;
+        search.lost += 1;
+        if search.lost >= MAX_PROBES {
+            search.upper = search.candidate;
+            search.lost = 0;
+            self.next_candidate(now);
+        }
+        true
+    }
+
+    /// Packets stopped getting through, so fall back on the minimum MTU and search again later
+    pub fn on_black_hole(&mut self, now: Instant) {
+        if self.current == MIN_MTU {
+            return;
+        }
+        trace!(mtu = self.current, "suspected MTU black hole");
+        self.current = MIN_MTU;
+        self.phase = Phase::Idle(self.interval.map(|x| now + x));
+    }
+
+    /// Bisect the remaining range, or end the search if it's narrow enough
+    fn next_candidate(&mut self, now: Instant) {
+        let search = match self.phase {
+            Phase::Searching(ref mut x) => x,
+            Phase::Idle(_) => return,
+        };
+        if search.upper - self.current <= MIN_STEP {
+            trace!(mtu = self.current, "MTU search complete");
+            self.phase = Phase::Idle(self.interval.map(|x| now + x));
+            return;
+        }
+        search.candidate = self.current + (search.upper - self.current) / 2;
+    }
+}
+
+/// Number of lost probes after which a size is deemed too large
+const MAX_PROBES: u8 = 3;
+
+/// Search precision, below which a larger MTU isn't worth the probes
+const MIN_STEP: u16 = 20;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn probe_and(mtud: &mut MtuDiscovery, now: Instant, pn: u64, acked: bool) -> u16 {
+        let size = mtud.poll_probe(now).unwrap();
+        mtud.on_probe_sent(pn);
+        assert_eq!(mtud.poll_probe(now), None, "probe already in flight");
+        if acked {
+            mtud.on_acked(now, pn);
+        } else {
+            for _ in 0..MAX_PROBES - 1 {
+                assert!(mtud.on_lost(now, pn));
+                assert_eq!(mtud.poll_probe(now), Some(size));
+                mtud.on_probe_sent(pn);
+            }
+            assert!(mtud.on_lost(now, pn));
+        }
+        size
+    }
+
+    #[test]
+    fn disabled() {
+        let mut mtud = MtuDiscovery::new(9000, None);
+        assert_eq!(mtud.poll_probe(Instant::now()), None);
+        assert_eq!(mtud.current_mtu(), MIN_MTU);
+    }
+
+    #[test]
+    fn max_reachable() {
+        let now = Instant::now();
+        let mut mtud = MtuDiscovery::new(9000, Some(Duration::from_secs(600)));
+        mtud.set_peer_max(8000);
+        assert_eq!(probe_and(&mut mtud, now, 0, true), 8000);
+        assert_eq!(mtud.current_mtu(), 8000);
+        assert!(!mtud.on_lost(now, 1));
+        assert_eq!(mtud.poll_probe(now), None);
+    }
+
+    #[test]
+    fn bisect() {
+        let now = Instant::now();
+        let mut mtud = MtuDiscovery::new(9000, Some(Duration::from_secs(600)));
+        // The path carries up to 1500 bytes
+        let mut pn = 0;
+        while let Some(size) = mtud.poll_probe(now) {
+            assert!(size > mtud.current_mtu());
+            probe_and(&mut mtud, now, pn, size <= 1500);
+            pn += 1;
+        }
+        assert!(mtud.current_mtu() <= 1500);
+        assert!(mtud.current_mtu() > 1500 - MIN_STEP);
+
+        mtud.on_black_hole(now);
+        assert_eq!(mtud.current_mtu(), MIN_MTU);
+        assert_eq!(mtud.poll_probe(now), None);
+        assert_eq!(
+            mtud.poll_probe(now + Duration::from_secs(600)),
+            Some(9000),
+            "search resumes after the interval"
+        );
+    }
+}
@@ -1,9 +1,11 @@
 //! Pacing of packet transmissions.
 
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
 use tracing::warn;
 
+use crate::Instant;
+
 /// A simple token-bucket pacer
 ///
 /// The pacer's capacity is derived on a fraction of the congestion window
@@ -17,6 +19,8 @@ pub struct Pacer {
     last_window: u64,
     tokens: u64,
     prev: Instant,
+    /// Congestion windows to refill per RTT, in quarters
+    rate: u64,
 }
 
 impl Pacer {
@@ -28,9 +32,18 @@ impl Pacer {
             last_window: window,
             tokens: capacity,
             prev: now,
+            rate: RATE,
         }
     }
 
+    /// Refill at `LOCAL_RATE` rather than `RATE`, as suits a local path
+    ///
+    /// A local path has no router queues for pacing to spare, but the receiver's socket buffer
+    /// can still overflow from an unbounded burst.
+    pub fn relax(&mut self) {
+        self.rate = LOCAL_RATE;
+    }
+
     /// Record that a packet has been transmitted.
     pub fn on_transmit(&mut self, packet_length: u16) {
         self.tokens = self.tokens.saturating_sub(packet_length.into())
@@ -41,8 +54,8 @@ impl Pacer {
     /// If we can send a packet right away, this returns `None`. Otherwise, returns `Some(d)`,
     /// where `d` is the time before this function should be called again.
     ///
-    /// The 5/4 ratio used here comes from the suggestion that N = 1.25 in the draft IETF RFC for
-    /// QUIC.
+    /// The default 5/4 ratio used here comes from the suggestion that N = 1.25 in the draft IETF
+    /// RFC for QUIC.
     pub fn delay(
         &mut self,
         smoothed_rtt: Duration,
@@ -85,7 +98,7 @@ impl Pacer {
         }
 
         let elapsed_rtts = time_elapsed.as_secs_f64() / smoothed_rtt.as_secs_f64();
-        let new_tokens = window as f64 * 1.25 * elapsed_rtts;
+        let new_tokens = window as f64 * (self.rate as f64 / 4.0) * elapsed_rtts;
         self.tokens = self
             .tokens
             .saturating_add(new_tokens as _)
@@ -105,7 +118,7 @@ impl Pacer {
 
         // divisions come before multiplications to prevent overflow
         // this is the time at which the pacing window becomes empty
-        Some(self.prev + (unscaled_delay / 5) * 4)
+        Some(self.prev + (unscaled_delay / self.rate as u32) * 4)
     }
 }
 
@@ -142,6 +155,12 @@ fn optimal_capacity(smoothed_rtt: Duration, window: u64, mtu: u16) -> u64 {
 /// more applicable.
 const BURST_INTERVAL_NANOS: u128 = 2_000_000; // 2ms
 
+/// Congestion windows refilled per RTT, in quarters, for N = 1.25
+const RATE: u64 = 5;
+
+/// Congestion windows refilled per RTT, in quarters, on a local path
+const LOCAL_RATE: u64 = 16;
+
 /// Allows some usage of GSO, and doesn't slow down the handshake.
 const MIN_BURST_SIZE: u64 = 10;
 
@@ -276,4 +295,25 @@ mod tests {
         );
         assert_eq!(pacer.tokens, pacer.capacity);
     }
+
+    #[test]
+    fn relaxed_pause() {
+        let window = 2_000_000u64;
+        let mtu = 1000;
+        let rtt = Duration::from_millis(50);
+        let now = Instant::now();
+
+        let mut pacer = Pacer::new(rtt, window, mtu, now);
+        pacer.relax();
+        for _ in 0..pacer.capacity / mtu as u64 {
+            pacer.on_transmit(mtu);
+        }
+        assert_eq!(
+            pacer
+                .delay(rtt, mtu, window, now)
+                .expect("Send must be delayed")
+                .duration_since(now),
+            Duration::from_nanos((BURST_INTERVAL_NANOS * 4 / 16) as u64)
+        );
+    }
 }
@@ -1,7 +1,51 @@
-use std::{cmp, net::SocketAddr, time::Duration, time::Instant};
+use std::{cmp, net::SocketAddr, time::Duration};
 
-use super::pacing::Pacer;
-use crate::{congestion, MIN_MTU, TIMER_GRANULARITY};
+use super::{mtud::MtuDiscovery, pacing::Pacer};
+use crate::{congestion, Instant, TIMER_GRANULARITY};
+
+/// Summary of the path a connection currently uses
+#[derive(Debug, Copy, Clone)]
+#[non_exhaustive]
+pub struct PathInfo {
+    /// The peer's address
+    pub remote: SocketAddr,
+    /// Largest datagram currently sent on the path, as established by MTU discovery
+    pub mtu: u16,
+    /// How the peer's current address was validated
+    ///
+    /// Starts over as [`Pending`](AddressValidation::Pending) whenever the peer migrates.
+    pub address_validation: AddressValidation,
+    /// How the peer's address was validated during the handshake
+    ///
+    /// Unaffected by migrations, so that a connection that was validated with a Retry token
+    /// continues to be reported as such.
+    pub handshake_validation: AddressValidation,
+    /// Number of times the peer moved to a new address
+    ///
+    /// Counts every migration initiated by the peer, including ones to addresses that failed
+    /// validation or that turned out to be spurious.
+    pub migrations: u64,
+}
+
+/// How a connection became certain that its peer receives packets at the peer's address
+///
+/// Until it is, a server sends at most three times as much as it received, so that a client
+/// spoofing its address can't use the server to flood someone else. Servers don't issue NEW_TOKEN
+/// frames, so a client can only skip validation during the handshake with a Retry token.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AddressValidation {
+    /// The peer has yet to prove that it receives packets at its address
+    Pending,
+    /// The client returned a token sent to its address in a Retry packet
+    Retry,
+    /// The peer answered packets sent to its address
+    ///
+    /// A server concludes this from the client's Handshake packets, or after a migration, from
+    /// the response to its path challenge. A client, having chosen the server's address itself,
+    /// regards it as validated from the start.
+    Implicit,
+}
 
 /// Description of a particular network path
 pub struct PathData {
@@ -15,16 +59,20 @@ pub struct PathData {
     pub pacing: Pacer,
     pub challenge: Option<u64>,
     pub challenge_pending: bool,
-    /// Whether we're certain the peer can both send and receive on this address
+    /// How we became certain that the peer can both send and receive on this address, if we have
     ///
-    /// Initially equal to `use_stateless_retry` for servers, and becomes false again on every
-    /// migration. Always true for clients.
-    pub validated: bool,
+    /// Pending for servers until the client's address is validated, which starts over on every
+    /// migration. Never pending for clients.
+    pub validation: AddressValidation,
     /// Total size of all UDP datagrams sent on this path
     pub total_sent: u64,
     /// Total size of all UDP datagrams received on this path
     pub total_recvd: u64,
+    /// Largest datagram to send, as established by `mtud`
     pub mtu: u16,
+    pub mtud: MtuDiscovery,
+    /// Whether the path is local, e.g. loopback, so that packets are paced less strictly
+    pub local: bool,
 }
 
 impl PathData {
@@ -33,58 +81,74 @@ impl PathData {
         initial_rtt: Duration,
         congestion: Box<dyn congestion::Controller>,
         now: Instant,
-        validated: bool,
+        validation: AddressValidation,
+        local: bool,
+        mtud: MtuDiscovery,
     ) -> Self {
+        let mtu = mtud.current_mtu();
+        let mut pacing = Pacer::new(initial_rtt, congestion.initial_window(), mtu, now);
+        if local {
+            pacing.relax();
+        }
         PathData {
             remote,
             rtt: RttEstimator::new(initial_rtt),
             sending_ecn: true,
-            pacing: Pacer::new(initial_rtt, congestion.initial_window(), MIN_MTU, now),
+            pacing,
             congestion,
             challenge: None,
             challenge_pending: false,
-            validated,
+            validation,
             total_sent: 0,
             total_recvd: 0,
-            mtu: MIN_MTU,
+            mtu,
+            mtud,
+            local,
         }
     }
 
     pub fn from_previous(remote: SocketAddr, prev: &PathData, now: Instant) -> Self {
         let congestion = prev.congestion.clone_box();
         let smoothed_rtt = prev.rtt.get();
+        let mut pacing = Pacer::new(smoothed_rtt, congestion.window(), prev.mtu, now);
+        if prev.local {
+            pacing.relax();
+        }
         PathData {
             remote,
             rtt: prev.rtt,
-            pacing: Pacer::new(smoothed_rtt, congestion.window(), prev.mtu, now),
+            pacing,
             sending_ecn: true,
             congestion,
             challenge: None,
             challenge_pending: false,
-            validated: false,
+            validation: AddressValidation::Pending,
             total_sent: 0,
             total_recvd: 0,
             mtu: prev.mtu,
+            mtud: prev.mtud.clone(),
+            local: prev.local,
         }
     }
 
     /// Indicates whether we're a server that hasn't validated the peer's address and hasn't
     /// received enough data from the peer to permit sending `bytes_to_send` additional bytes
     pub fn anti_amplification_blocked(&self, bytes_to_send: u64) -> bool {
-        !self.validated && self.total_recvd * 3 < self.total_sent + bytes_to_send
+        self.validation == AddressValidation::Pending
+            && self.total_recvd * 3 < self.total_sent + bytes_to_send
     }
 }
 
 #[derive(Copy, Clone)]
 pub struct RttEstimator {
     /// The most recent RTT measurement made when receiving an ack for a previously unacked packet
-    latest: Duration,
+    pub(super) latest: Duration,
     /// The smoothed RTT of the connection, computed as described in RFC6298
-    smoothed: Option<Duration>,
+    pub(super) smoothed: Option<Duration>,
     /// The RTT variance, computed as described in RFC6298
-    var: Duration,
+    pub(super) var: Duration,
     /// The minimum RTT seen in the connection, ignoring ack delay.
-    min: Duration,
+    pub(super) min: Duration,
 }
 
 impl RttEstimator {
@@ -1,15 +1,22 @@
-use std::{collections::VecDeque, ops::Range};
+use std::{collections::BTreeMap, ops::Range};
 
-use bytes::{Buf, Bytes};
+use bytes::Bytes;
 
 use crate::range_set::RangeSet;
 
 /// Buffer of outgoing retransmittable stream data
+///
+/// Data is stored as a rope of the segments written by the application, indexed by stream offset.
+/// Transmissions and retransmissions read from the segments by range, and each segment is released
+/// as soon as all of its data is acknowledged, regardless of what precedes it.
 #[derive(Default, Debug)]
 pub struct SendBuffer {
-    /// Data queued by the application but not yet acknowledged. May or may not have been sent.
-    unacked_segments: VecDeque<Bytes>,
-    /// Total size of `unacked_segments`
+    /// Data queued by the application but not yet acknowledged, keyed by stream offset. May or may
+    /// not have been sent.
+    ///
+    /// The first segment may begin before the unacknowledged prefix of the stream.
+    segments: BTreeMap<u64, Bytes>,
+    /// Number of bytes past the longest acknowledged prefix of the stream
     unacked_len: usize,
     /// The first offset that hasn't been written by the application, i.e. the offset past the end of `unacked`
     offset: u64,
@@ -19,7 +26,6 @@ pub struct SendBuffer {
     unsent: u64,
     /// Acknowledged ranges which couldn't be discarded yet as they don't include the earliest
     /// offset in `unacked`
-    // TODO: Recover storage from these by compacting (#700)
     acks: RangeSet,
     /// Previously transmitted ranges deemed lost
     retransmits: RangeSet,
@@ -34,7 +40,7 @@ impl SendBuffer {
     /// Append application data to the end of the stream
     pub fn write(&mut self, data: &[u8]) {
         let buf = Bytes::from(data.to_owned());
-        self.unacked_segments.push_back(buf);
+        self.segments.insert(self.offset, buf);
         self.unacked_len += data.len();
         self.offset += data.len() as u64;
     }
@@ -45,30 +51,33 @@ impl SendBuffer {
         let base_offset = self.offset - self.unacked_len as u64;
         range.start = base_offset.max(range.start);
         range.end = base_offset.max(range.end);
+        if range.start == range.end {
+            return;
+        }
 
-        self.acks.insert(range);
+        self.acks.insert(range.clone());
+        self.retransmits.remove(range.clone());
+
+        // Release segments which are now entirely acknowledged
+        let acked = self
+            .acks
+            .intersecting(range)
+            .next()
+            .expect("range was just inserted");
+        while let Some((&start, segment)) = self.segments.range(acked.start..acked.end).next() {
+            if start + segment.len() as u64 > acked.end {
+                break;
+            }
+            self.segments.remove(&start);
+        }
 
-        while self.acks.min() == Some(self.offset - self.unacked_len as u64) {
+        if self.acks.min() == Some(base_offset) {
             let prefix = self.acks.pop_min().unwrap();
-            let mut to_advance = (prefix.end - prefix.start) as usize;
-
-            self.unacked_len -= to_advance;
-            while to_advance > 0 {
-                let front = self
-                    .unacked_segments
-                    .front_mut()
-                    .expect("Expected buffered data");
-
-                if front.len() <= to_advance {
-                    to_advance -= front.len();
-                    self.unacked_segments.pop_front();
-
-                    if self.unacked_segments.len() * 4 < self.unacked_segments.capacity() {
-                        self.unacked_segments.shrink_to_fit();
-                    }
-                } else {
-                    front.advance(to_advance);
-                    to_advance = 0;
+            self.unacked_len -= (prefix.end - prefix.start) as usize;
+            // A segment straddling the start of the acknowledged range may also be covered now
+            if let Some((&start, segment)) = self.segments.iter().next() {
+                if start + segment.len() as u64 <= prefix.end {
+                    self.segments.remove(&start);
                 }
             }
         }
@@ -101,28 +110,37 @@ impl SendBuffer {
     /// should call the function again with an incremented start offset to
     /// retrieve more data.
     pub fn get(&self, offsets: Range<u64>) -> &[u8] {
-        let base_offset = self.offset - self.unacked_len as u64;
-
-        let mut segment_offset = base_offset;
-        for segment in self.unacked_segments.iter() {
-            if offsets.start >= segment_offset
-                && offsets.start < segment_offset + segment.len() as u64
-            {
-                let start = (offsets.start - segment_offset) as usize;
-                let end = (offsets.end - segment_offset) as usize;
-
-                return &segment[start..end.min(segment.len())];
-            }
-            segment_offset += segment.len() as u64;
+        let (&segment_offset, segment) = match self.segments.range(..=offsets.start).next_back() {
+            Some(x) => x,
+            None => return &[],
+        };
+        let start = (offsets.start - segment_offset) as usize;
+        if start >= segment.len() {
+            return &[];
         }
-
-        &[]
+        let end = (offsets.end - segment_offset) as usize;
+        &segment[start..end.min(segment.len())]
     }
 
     /// Queue a range of sent but unacknowledged data to be retransmitted
-    pub fn retransmit(&mut self, range: Range<u64>) {
+    ///
+    /// Portions of the range which have been acknowledged in the meantime, e.g. because they were
+    /// also carried by a later packet, are ignored.
+    pub fn retransmit(&mut self, mut range: Range<u64>) {
         debug_assert!(range.end <= self.unsent, "unsent data can't be lost");
-        self.retransmits.insert(range);
+        let base_offset = self.offset - self.unacked_len as u64;
+        range.start = base_offset.max(range.start);
+        range.end = base_offset.max(range.end);
+        let mut start = range.start;
+        for acked in self.acks.intersecting(range.clone()) {
+            if acked.start > start {
+                self.retransmits.insert(start..acked.start);
+            }
+            start = acked.end;
+        }
+        if start < range.end {
+            self.retransmits.insert(start..range.end);
+        }
     }
 
     pub fn retransmit_all_for_0rtt(&mut self) {
@@ -206,8 +224,9 @@ mod tests {
         assert_eq!(aggregate_unacked(&buf), &MSG[3..]);
         buf.ack(3..5);
         assert_eq!(aggregate_unacked(&buf), &MSG[5..]);
+        // Fully acknowledged segments are released even while earlier data is outstanding
         buf.ack(7..9);
-        assert_eq!(aggregate_unacked(&buf), &MSG[5..]);
+        assert_eq!(aggregate_unacked(&buf), [&MSG[5..7], &MSG[9..]].concat());
         buf.ack(4..7);
         assert_eq!(aggregate_unacked(&buf), &MSG[9..]);
         buf.ack(0..MSG_LEN);
@@ -253,10 +272,49 @@ mod tests {
         assert!(buf.acks.is_empty());
     }
 
+    #[test]
+    fn retransmit_skips_acked() {
+        let mut buf = SendBuffer::new();
+        const MSG: &[u8] = b"Hello, world!";
+        buf.write(MSG);
+        assert_eq!(buf.poll_transmit(MSG.len()), 0..MSG.len() as u64);
+        // Part of a lost range may have been delivered by another packet
+        buf.ack(3..5);
+        buf.ack(9..11);
+        buf.retransmit(0..MSG.len() as u64);
+        assert_eq!(buf.poll_transmit(42), 0..3);
+        assert_eq!(buf.poll_transmit(42), 5..9);
+        assert_eq!(buf.get(5..9), &MSG[5..9]);
+        // Acknowledging data queued for retransmission cancels the retransmission
+        buf.ack(11..13);
+        assert_eq!(buf.poll_transmit(42), 13..13);
+        assert!(!buf.has_unsent_data());
+    }
+
+    #[test]
+    fn segments_released_out_of_order() {
+        let mut buf = SendBuffer::new();
+        for _ in 0..4 {
+            buf.write(&[0; 100]);
+        }
+        assert_eq!(buf.poll_transmit(400), 0..400);
+        buf.ack(100..300);
+        assert_eq!(buf.segments.len(), 2);
+        assert_eq!(buf.unacked(), 200);
+        buf.ack(0..100);
+        assert_eq!(buf.segments.len(), 1);
+        assert_eq!(buf.get(300..400), &[0; 100][..]);
+        buf.ack(300..400);
+        assert!(buf.segments.is_empty());
+        assert!(buf.is_fully_acked());
+    }
+
     fn aggregate_unacked(buf: &SendBuffer) -> Vec<u8> {
+        let base_offset = buf.offset - buf.unacked_len as u64;
         let mut result = Vec::new();
-        for segment in buf.unacked_segments.iter() {
-            result.extend_from_slice(&segment[..]);
+        for (&start, segment) in buf.segments.iter() {
+            let skip = base_offset.saturating_sub(start) as usize;
+            result.extend_from_slice(&segment[skip.min(segment.len())..]);
         }
         result
     }
@@ -2,15 +2,14 @@ use std::{
     cmp,
     collections::{BTreeMap, HashSet, VecDeque},
     mem,
-    ops::{Index, IndexMut},
-    time::Instant,
+    ops::{Index, IndexMut, RangeInclusive},
 };
 
 use super::assembler::Assembler;
 use super::streams::ShouldTransmit;
 use crate::{
     crypto, crypto::Keys, frame, packet::SpaceId, range_set::RangeSet, shared::IssuedCid, Dir,
-    StreamId, VarInt,
+    Instant, StreamId, VarInt,
 };
 
 pub(crate) struct PacketSpace<S>
@@ -189,6 +188,115 @@ pub(crate) struct SentPacket {
     pub(crate) stream_frames: Vec<frame::StreamMeta>,
 }
 
+/// Find the outstanding packets acknowledged by `ranges`, which must be in descending order as
+/// decoded from an ACK frame
+///
+/// Peers repeat old ranges in every ACK until they see them acknowledged, so under reordering a
+/// frame may carry many ranges that no longer refer to outstanding packets. Iteration stops at the
+/// first range below the oldest outstanding packet, so the cost is independent of those.
+pub(crate) fn newly_acked(
+    sent_packets: &BTreeMap<u64, SentPacket>,
+    ranges: impl Iterator<Item = RangeInclusive<u64>>,
+) -> Vec<u64> {
+    let oldest = match sent_packets.keys().next() {
+        Some(&x) => x,
+        None => return Vec::new(),
+    };
+    ranges
+        .take_while(|range| *range.end() >= oldest)
+        .flat_map(|range| sent_packets.range(range).map(|(&n, _)| n))
+        .collect()
+}
+
+/// Find the outstanding packets below `largest_acked` deemed lost
+///
+/// A packet is lost if it was sent no later than `lost_send_time` or at least `packet_threshold`
+/// packets before `largest_acked`. Packets are sent in packet number order, so the scan stops at
+/// the first packet that isn't lost yet, whose send time is returned alongside the lost packets.
+/// This keeps repeated calls cheap while many packets are awaiting reordered acknowledgements.
+pub(crate) fn detect_lost(
+    sent_packets: &BTreeMap<u64, SentPacket>,
+    largest_acked: u64,
+    packet_threshold: u64,
+    lost_send_time: Instant,
+) -> (Vec<u64>, Option<Instant>) {
+    let mut lost = Vec::new();
+    for (&packet, info) in sent_packets.range(0..largest_acked) {
+        if info.time_sent <= lost_send_time || largest_acked >= packet + packet_threshold {
+            lost.push(packet);
+        } else {
+            return (lost, Some(info.time_sent));
+        }
+    }
+    (lost, None)
+}
+
+/// Sent packet tracking driven directly, for benchmarks
+#[doc(hidden)]
+#[derive(Debug, Default)]
+pub struct SentPackets {
+    packets: BTreeMap<u64, SentPacket>,
+}
+
+impl SentPackets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a packet as sent at `time`
+    pub fn send(&mut self, number: u64, time: Instant) {
+        self.packets.insert(
+            number,
+            SentPacket {
+                time_sent: time,
+                size: 1200,
+                ack_eliciting: true,
+                acks: RangeSet::new(),
+                retransmits: Retransmits::default(),
+                stream_frames: Vec::new(),
+            },
+        );
+    }
+
+    /// Process the ranges of an ACK frame, in descending order, returning the number of packets
+    /// newly acknowledged
+    pub fn ack(&mut self, ranges: &[RangeInclusive<u64>]) -> usize {
+        let acked = newly_acked(&self.packets, ranges.iter().cloned());
+        for packet in &acked {
+            self.packets.remove(packet);
+        }
+        acked.len()
+    }
+
+    /// Remove packets deemed lost, returning how many there were
+    pub fn detect_lost(
+        &mut self,
+        largest_acked: u64,
+        packet_threshold: u64,
+        lost_send_time: Instant,
+    ) -> usize {
+        let (lost, _) = detect_lost(
+            &self.packets,
+            largest_acked,
+            packet_threshold,
+            lost_send_time,
+        );
+        for packet in &lost {
+            self.packets.remove(packet);
+        }
+        lost.len()
+    }
+
+    /// Number of outstanding packets
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+}
+
 /// Retransmittable data queue
 #[derive(Debug, Clone)]
 pub struct Retransmits {
@@ -226,6 +334,20 @@ impl Retransmits {
         }
     }
 
+    /// Remove all queued frames, retaining allocated capacity
+    pub(crate) fn clear(&mut self) {
+        self.max_data = false;
+        self.max_uni_stream_id = false;
+        self.max_bi_stream_id = false;
+        self.reset_stream.clear();
+        self.stop_sending.clear();
+        self.max_stream_data.clear();
+        self.crypto.clear();
+        self.new_cids.clear();
+        self.retire_cids.clear();
+        self.handshake_done = false;
+    }
+
     pub fn is_empty(&self) -> bool {
         !self.max_data
             && !self.max_uni_stream_id
@@ -359,6 +481,7 @@ impl Dedup {
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::time::Duration;
 
     #[test]
     fn sanity() {
@@ -415,4 +538,36 @@ mod test {
         assert_eq!(dedup.next, 2 * WINDOW_SIZE + 1);
         assert_eq!(dedup.window, 1 << (WINDOW_SIZE - 2));
     }
+
+    #[test]
+    fn stale_ack_ranges() {
+        let now = Instant::now();
+        let mut sent = SentPackets::new();
+        for n in 100..110 {
+            sent.send(n, now);
+        }
+        let ranges = (0..50).rev().map(|i| i * 2..=i * 2).collect::<Vec<_>>();
+        assert_eq!(newly_acked(&sent.packets, ranges.iter().cloned()), []);
+        assert_eq!(sent.ack(&[108..=109, 104..=105, 1..=2]), 4);
+        assert_eq!(sent.ack(&[108..=109, 104..=105, 1..=2]), 0);
+        assert_eq!(sent.len(), 6);
+    }
+
+    #[test]
+    fn loss_scan_stops_early() {
+        let start = Instant::now();
+        let mut sent = SentPackets::new();
+        for n in 0..10 {
+            sent.send(n, start + Duration::from_millis(n));
+        }
+        let (lost, next) = detect_lost(&sent.packets, 9, 100, start + Duration::from_millis(3));
+        assert_eq!(lost, [0, 1, 2, 3]);
+        assert_eq!(next, Some(start + Duration::from_millis(4)));
+        let (lost, next) = detect_lost(&sent.packets, 9, 3, start);
+        assert_eq!(lost, [0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(next, Some(start + Duration::from_millis(7)));
+        let (lost, next) = detect_lost(&sent.packets, 5, 100, start + Duration::from_secs(1));
+        assert_eq!(lost, [0, 1, 2, 3, 4]);
+        assert_eq!(next, None);
+    }
 }
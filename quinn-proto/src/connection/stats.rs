@@ -1,6 +1,6 @@
 //! Connection statistics
 
-use crate::{frame::Frame, Dir};
+use crate::{frame::Frame, packet::SpaceId, Dir};
 use std::time::Duration;
 
 /// Statistics about UDP datagrams transmitted or received on a connection
@@ -117,8 +117,86 @@ impl std::fmt::Debug for FrameStats {
 pub struct PathStats {
     /// Current best estimate of this connection's latency (round-trip-time)
     pub rtt: Duration,
+    /// Minimum round-trip time observed, excluding any acknowledgement delay
+    pub min_rtt: Duration,
+    /// Variation in round-trip time samples, as used to compute the probe timeout
+    pub rtt_variance: Duration,
     /// Current congestion window of the connection
     pub cwnd: u64,
+    /// Number of bytes sent in packets that are neither acknowledged nor declared lost
+    pub bytes_in_flight: u64,
+    /// Largest datagram currently sent on the path, as established by MTU discovery
+    pub current_mtu: u16,
+    /// Number of packets sent in all packet number spaces
+    pub sent_packets: u64,
+    /// Number of packets declared lost in all packet number spaces
+    pub lost_packets: u64,
+    /// Number of bytes in packets declared lost
+    ///
+    /// Excludes packets containing only acknowledgements, which aren't counted as in flight.
+    pub lost_bytes: u64,
+    /// Number of bytes of stream data queued for retransmission after the packets carrying them
+    /// were lost
+    pub retransmitted_stream_bytes: u64,
+    /// Number of times loss caused the congestion window to be reduced
+    pub congestion_events: u64,
+    /// Number of times persistent congestion was detected, indicating that the path stopped
+    /// delivering packets altogether for a period
+    pub black_holes: u64,
+    /// Number of times the probe timeout fired
+    pub ptos: u64,
+    /// Number of packets received with the ECN Congestion Experienced (CE) codepoint
+    pub ce_received: u64,
+    /// Number of packets the peer reported receiving with the CE codepoint
+    pub ce_reported: u64,
+    /// Number of times CE reports from the peer caused the congestion window to be reduced
+    pub ecn_congestion_events: u64,
+    /// Time spent with data to send, but blocked by the congestion controller or pacing
+    pub congestion_limited: Duration,
+    /// Time spent unable to accept more stream data from the application due to connection-level
+    /// flow control or the send window
+    pub flow_control_limited: Duration,
+    /// Time spent with nothing to send because the application supplied no data
+    pub app_limited: Duration,
+}
+
+/// Statistics about a single packet number space
+///
+/// Loss in the Initial and Handshake spaces delays connection establishment, but is otherwise
+/// hidden by the connection-level statistics.
+#[derive(Debug, Default, Copy, Clone)]
+#[non_exhaustive]
+pub struct SpaceStats {
+    /// Packets sent
+    pub sent: u64,
+    /// Packets acknowledged by the peer
+    pub acked: u64,
+    /// Packets declared lost
+    pub lost: u64,
+    /// Acknowledgement delay reported in the most recent ACK frame received
+    pub latest_ack_delay: Duration,
+    /// Largest acknowledgement delay reported by the peer
+    pub max_ack_delay: Duration,
+}
+
+/// Statistics about data buffered for reassembly on a receive stream
+///
+/// A large `allocated` relative to `buffered`, or a large number of `chunks`, indicates that the
+/// peer is sending pathologically fragmented or out-of-order data.
+#[derive(Debug, Default, Copy, Clone)]
+#[non_exhaustive]
+pub struct RecvStreamStats {
+    /// Bytes received but not yet read by the application
+    pub buffered: u64,
+    /// Estimated memory retained by buffered data
+    ///
+    /// Never less than `buffered`. Exceeds it when small fragments keep their enclosing packets
+    /// alive.
+    pub allocated: u64,
+    /// Number of disjoint chunks buffered
+    pub chunks: u64,
+    /// Bytes below the highest offset received which have neither been received nor read
+    pub missing: u64,
 }
 
 /// Connection statistics
@@ -135,4 +213,20 @@ pub struct ConnectionStats {
     pub frame_rx: FrameStats,
     /// Statistics related to the current transmission path
     pub path: PathStats,
+    /// Statistics about the Initial packet number space
+    pub initial: SpaceStats,
+    /// Statistics about the Handshake packet number space
+    pub handshake: SpaceStats,
+    /// Statistics about the application data packet number space, used by 0-RTT and 1-RTT packets
+    pub data: SpaceStats,
+}
+
+impl ConnectionStats {
+    pub(crate) fn space_mut(&mut self, space: SpaceId) -> &mut SpaceStats {
+        match space {
+            SpaceId::Initial => &mut self.initial,
+            SpaceId::Handshake => &mut self.handshake,
+            SpaceId::Data => &mut self.data,
+        }
+    }
 }
@@ -12,7 +12,7 @@ use super::assembler::Chunk;
 use super::spaces::Retransmits;
 use crate::{
     coding::BufMutExt,
-    connection::stats::FrameStats,
+    connection::stats::{FrameStats, RecvStreamStats},
     frame::{self, FrameStruct},
     transport_parameters::TransportParameters,
     Dir, Side, StreamId, TransportError, VarInt, MAX_STREAM_COUNT,
@@ -76,6 +76,19 @@ pub struct Streams {
     send_window: u64,
     /// Configured upper bound for how much unacked data the peer can send us per stream
     stream_receive_window: u64,
+    /// The per-stream receive window advertised in our transport parameters, which the peer
+    /// assumes for every stream until told otherwise
+    initial_stream_receive_window: u64,
+    /// Connection-level credit to withhold from the peer as data is read, after the receive window
+    /// was reduced
+    withheld_data: u64,
+    /// Configured limit on concurrent remotely-initiated streams
+    max_concurrent_remote: [u64; 2],
+    /// Number of freed remotely-initiated streams not to replace, after `max_concurrent_remote`
+    /// was reduced
+    withheld_streams: [u64; 2],
+    /// Maximum distance ahead of a stream's read offset at which data is accepted
+    reassembly_window: Option<u64>,
     /// Whether the corresponding `max_remote` has increased
     max_streams_dirty: [bool; 2],
 
@@ -93,6 +106,7 @@ impl Streams {
         send_window: u64,
         receive_window: VarInt,
         stream_receive_window: VarInt,
+        reassembly_window: Option<u64>,
     ) -> Self {
         let mut this = Self {
             side,
@@ -117,6 +131,11 @@ impl Streams {
             unacked_data: 0,
             send_window,
             stream_receive_window: stream_receive_window.into(),
+            initial_stream_receive_window: stream_receive_window.into(),
+            withheld_data: 0,
+            max_concurrent_remote: [max_remote_bi.into(), max_remote_uni.into()],
+            withheld_streams: [0, 0],
+            reassembly_window,
             max_streams_dirty: [false, false],
             initial_max_stream_data_uni: 0u32.into(),
             initial_max_stream_data_bidi_local: 0u32.into(),
@@ -158,6 +177,63 @@ impl Streams {
         }
     }
 
+    /// Change the connection-level receive window
+    ///
+    /// Growth is granted to the peer immediately, whereas a reduction takes effect as the
+    /// application reads data, since flow control credit can't be revoked.
+    pub fn set_receive_window(&mut self, receive_window: VarInt) -> ShouldTransmit {
+        let new = receive_window.into_inner();
+        let old = mem::replace(&mut self.receive_window, new);
+        if new < old {
+            self.withheld_data += old - new;
+            return ShouldTransmit(false);
+        }
+        let restored = (new - old).min(self.withheld_data);
+        self.withheld_data -= restored;
+        let growth = new - old - restored;
+        self.local_max_data = self.local_max_data.saturating_add(growth);
+        ShouldTransmit(growth > 0)
+    }
+
+    /// Change the per-stream receive window, queueing `MAX_STREAM_DATA` frames for streams whose
+    /// window grew significantly
+    pub fn set_stream_receive_window(&mut self, window: VarInt, pending: &mut Retransmits) {
+        self.stream_receive_window = window.into_inner();
+        for (&id, rs) in self.recv.iter_mut() {
+            // Streams the peer hasn't opened yet get their credit as they're read
+            if id.initiator() != self.side && id.index() >= self.next_remote[id.dir() as usize] {
+                continue;
+            }
+            if rs
+                .max_stream_data(self.stream_receive_window)
+                .1
+                .should_transmit()
+            {
+                pending.max_stream_data.insert(id);
+            }
+        }
+    }
+
+    /// Change the limit on concurrent remotely-initiated streams
+    ///
+    /// As with [`set_receive_window()`](Self::set_receive_window), growth is granted immediately
+    /// and a reduction takes effect as streams are freed. Check
+    /// [`take_max_streams_dirty()`](Self::take_max_streams_dirty) afterwards.
+    pub fn set_max_concurrent(&mut self, dir: Dir, count: VarInt) {
+        let new = count.into_inner();
+        let old = mem::replace(&mut self.max_concurrent_remote[dir as usize], new);
+        let withheld = &mut self.withheld_streams[dir as usize];
+        if new < old {
+            *withheld += old - new;
+            return;
+        }
+        let restored = (new - old).min(*withheld);
+        *withheld -= restored;
+        for _ in 0..new - old - restored {
+            self.alloc_remote_stream(dir);
+        }
+    }
+
     pub fn send_streams(&self) -> usize {
         self.send_streams
     }
@@ -279,8 +355,16 @@ impl Streams {
 
     /// Process incoming stream frame
     ///
-    /// If successful, returns whether a `MAX_DATA` frame needs to be transmitted
-    pub fn received(&mut self, frame: frame::Stream) -> Result<ShouldTransmit, TransportError> {
+    /// `allocation_size` is the size of the receive buffer the frame's data is a slice of. A
+    /// `STOP_SENDING` frame is queued in `pending` if the data lies beyond the reassembly window. If
+    /// successful, returns whether a `MAX_DATA` frame needs to be transmitted
+    pub fn received(
+        &mut self,
+        frame: frame::Stream,
+        allocation_size: usize,
+        zero_rtt: bool,
+        pending: &mut Retransmits,
+    ) -> Result<ShouldTransmit, TransportError> {
         trace!(id = %frame.id, offset = frame.offset, len = frame.data.len(), fin = frame.fin, "got stream");
         let stream = frame.id;
         self.validate_receive_id(stream).map_err(|e| {
@@ -301,7 +385,26 @@ impl Streams {
             return Ok(ShouldTransmit(false));
         }
 
-        let new_bytes = rs.ingest(frame, self.data_recvd, self.local_max_data)?;
+        rs.zero_rtt |= zero_rtt;
+        let end = frame.offset + frame.data.len() as u64;
+        let mut max_data = ShouldTransmit(false);
+        if matches!(self.reassembly_window, Some(window) if rs.beyond_window(end, window)) {
+            debug!(id = %stream, end, "stream data beyond reassembly window");
+            // Rather than buffer the data, give up on the stream as though the application had
+            // stopped it, which it learns of from its next read
+            let result = self.stop(stream).expect("stream is known");
+            if result.stop_sending.should_transmit() {
+                pending.stop_sending.push(frame::StopSending {
+                    id: stream,
+                    error_code: 0u32.into(),
+                });
+            }
+            max_data = result.max_data;
+            self.on_stream_frame(true, stream);
+        }
+
+        let rs = self.recv.get_mut(&stream).unwrap();
+        let new_bytes = rs.ingest(frame, allocation_size, self.data_recvd, self.local_max_data)?;
         self.data_recvd = self.data_recvd.saturating_add(new_bytes);
 
         if !rs.assembler.is_stopped() {
@@ -316,7 +419,8 @@ impl Streams {
         }
 
         // We don't buffer data on stopped streams, so issue flow control credit immediately
-        Ok(self.add_read_credits(new_bytes))
+        let credited = self.add_read_credits(new_bytes);
+        Ok(ShouldTransmit(max_data.0 || credited.0))
     }
 
     /// Process incoming RESET_STREAM frame
@@ -459,6 +563,20 @@ impl Streams {
         })
     }
 
+    pub fn recv_stats(&self, id: StreamId) -> Result<RecvStreamStats, UnknownStream> {
+        match self.recv.get(&id) {
+            Some(s) => Ok(s.assembler.stats()),
+            None => Err(UnknownStream { _private: () }),
+        }
+    }
+
+    pub fn received_0rtt(&self, id: StreamId) -> Result<bool, UnknownStream> {
+        match self.recv.get(&id) {
+            Some(s) => Ok(s.zero_rtt),
+            None => Err(UnknownStream { _private: () }),
+        }
+    }
+
     pub fn stop_reason(&self, id: StreamId) -> Result<Option<VarInt>, UnknownStream> {
         match self.send.get(&id) {
             Some(s) => Ok(s.stop_reason),
@@ -466,6 +584,27 @@ impl Streams {
         }
     }
 
+    /// Whether any stream has been opened, by either peer, and not yet closed
+    pub fn has_open(&self) -> bool {
+        self.send.keys().chain(self.recv.keys()).any(|id| {
+            let next = if id.initiator() == self.side {
+                self.next
+            } else {
+                self.next_remote
+            };
+            id.index() < next[id.dir() as usize]
+        })
+    }
+
+    /// Whether everything written to send streams, including any FIN bits, has been acknowledged
+    pub fn is_flushed(&self) -> bool {
+        self.unacked_data == 0
+            && !self
+                .send
+                .values()
+                .any(|x| matches!(x.state, SendState::DataSent { .. }))
+    }
+
     pub fn can_send(&self) -> bool {
         !self.pending.is_empty()
     }
@@ -588,12 +727,13 @@ impl Streams {
         }
     }
 
+    /// Write STREAM frames into `buf`, recording each in `stream_frames`
     pub fn write_stream_frames(
         &mut self,
         buf: &mut Vec<u8>,
         max_buf_size: usize,
-    ) -> Vec<frame::StreamMeta> {
-        let mut stream_frames = Vec::new();
+        stream_frames: &mut Vec<frame::StreamMeta>,
+    ) {
         while buf.len() + frame::Stream::SIZE_BOUND < max_buf_size {
             let max_data_len = match max_buf_size.checked_sub(buf.len() + frame::Stream::SIZE_BOUND)
             {
@@ -646,8 +786,6 @@ impl Streams {
             }
             stream_frames.push(meta);
         }
-
-        stream_frames
     }
 
     /// Notify the application that new streams were opened or a stream became readable.
@@ -858,14 +996,14 @@ impl Streams {
         if bi || remote {
             assert!(self
                 .recv
-                .insert(id, Recv::new(self.stream_receive_window))
+                .insert(id, Recv::new(self.initial_stream_receive_window))
                 .is_none());
         }
     }
 
     /// Whether application stream writes are currently blocked on connection-level flow control or
     /// the send window
-    fn flow_blocked(&self) -> bool {
+    pub(crate) fn flow_blocked(&self) -> bool {
         self.data_sent >= self.max_data || self.unacked_data >= self.send_window
     }
 
@@ -878,7 +1016,9 @@ impl Streams {
     /// suppress sending further updates until the window increases significantly
     /// again.
     fn add_read_credits(&mut self, credits: u64) -> ShouldTransmit {
-        self.local_max_data = self.local_max_data.saturating_add(credits);
+        let withheld = credits.min(self.withheld_data);
+        self.withheld_data -= withheld;
+        self.local_max_data = self.local_max_data.saturating_add(credits - withheld);
 
         if self.local_max_data > VarInt::MAX.into_inner() {
             return ShouldTransmit(false);
@@ -913,7 +1053,12 @@ impl Streams {
                     StreamHalf::Recv => !self.send.contains_key(&id),
                 };
             if fully_free {
-                self.alloc_remote_stream(id.dir());
+                let withheld = &mut self.withheld_streams[id.dir() as usize];
+                if *withheld > 0 {
+                    *withheld -= 1;
+                } else {
+                    self.alloc_remote_stream(id.dir());
+                }
             }
         }
         if half == StreamHalf::Send {
@@ -1002,6 +1147,7 @@ mod tests {
             1024 * 1024,
             (1024 * 1024u32).into(),
             (1024 * 1024u32).into(),
+            None,
         )
     }
 
@@ -1012,12 +1158,17 @@ mod tests {
         let initial_max = client.local_max_data;
         assert_eq!(
             client
-                .received(frame::Stream {
-                    id,
-                    offset: 0,
-                    fin: false,
-                    data: Bytes::from_static(&[0; 2048]),
-                })
+                .received(
+                    frame::Stream {
+                        id,
+                        offset: 0,
+                        fin: false,
+                        data: Bytes::from_static(&[0; 2048]),
+                    },
+                    2048,
+                    false,
+                    &mut Retransmits::default(),
+                )
                 .unwrap(),
             ShouldTransmit(false)
         );
@@ -1039,6 +1190,38 @@ mod tests {
         assert_eq!(client.local_max_data - initial_max, 4096);
     }
 
+    #[test]
+    fn reassembly_window() {
+        let mut client = make(Side::Client);
+        client.reassembly_window = Some(4096);
+        let id = StreamId::new(Side::Server, Dir::Uni, 0);
+        let mut pending = Retransmits::default();
+        let mut receive = |client: &mut Streams, offset| {
+            let frame = frame::Stream {
+                id,
+                offset,
+                fin: false,
+                data: Bytes::from_static(&[0; 1024]),
+            };
+            client.received(frame, 1024, false, &mut pending).unwrap()
+        };
+        assert_eq!(receive(&mut client, 3072), ShouldTransmit(false));
+        assert_eq!(receive(&mut client, 0), ShouldTransmit(false));
+        // Unread data received without gaps doesn't count toward the window
+        assert_eq!(receive(&mut client, 4096), ShouldTransmit(false));
+        assert_eq!(receive(&mut client, 1024), ShouldTransmit(false));
+
+        // Data too far past the gap at 2048 gives up on the stream, not the connection
+        assert_eq!(receive(&mut client, 6144), ShouldTransmit(false));
+        assert_eq!(
+            client.read(id, usize::MAX, true),
+            Err(ReadError::UnknownStream)
+        );
+        assert_eq!(pending.stop_sending.len(), 1);
+        assert_eq!(pending.stop_sending[0].id, id);
+        assert_eq!(client.data_recvd, 7168);
+    }
+
     #[test]
     fn reset_after_empty_frame_flow_control() {
         let mut client = make(Side::Client);
@@ -1046,12 +1229,17 @@ mod tests {
         let initial_max = client.local_max_data;
         assert_eq!(
             client
-                .received(frame::Stream {
-                    id,
-                    offset: 4096,
-                    fin: false,
-                    data: Bytes::from_static(&[0; 0]),
-                })
+                .received(
+                    frame::Stream {
+                        id,
+                        offset: 4096,
+                        fin: false,
+                        data: Bytes::from_static(&[0; 0]),
+                    },
+                    2048,
+                    false,
+                    &mut Retransmits::default(),
+                )
                 .unwrap(),
             ShouldTransmit(false)
         );
@@ -1106,12 +1294,17 @@ mod tests {
         let initial_max = client.local_max_data;
         assert_eq!(
             client
-                .received(frame::Stream {
-                    id,
-                    offset: 0,
-                    fin: false,
-                    data: Bytes::from_static(&[0; 32]),
-                })
+                .received(
+                    frame::Stream {
+                        id,
+                        offset: 0,
+                        fin: false,
+                        data: Bytes::from_static(&[0; 32]),
+                    },
+                    2048,
+                    false,
+                    &mut Retransmits::default(),
+                )
                 .unwrap(),
             ShouldTransmit(false)
         );
@@ -1132,12 +1325,17 @@ mod tests {
         assert_eq!(client.local_max_data - initial_max, 32);
         assert_eq!(
             client
-                .received(frame::Stream {
-                    id,
-                    offset: 32,
-                    fin: true,
-                    data: Bytes::from_static(&[0; 16]),
-                })
+                .received(
+                    frame::Stream {
+                        id,
+                        offset: 32,
+                        fin: true,
+                        data: Bytes::from_static(&[0; 16]),
+                    },
+                    2048,
+                    false,
+                    &mut Retransmits::default(),
+                )
                 .unwrap(),
             ShouldTransmit(false)
         );
@@ -1152,12 +1350,17 @@ mod tests {
         // Server opens stream
         assert_eq!(
             client
-                .received(frame::Stream {
-                    id,
-                    offset: 0,
-                    fin: false,
-                    data: Bytes::from_static(&[0; 32]),
-                })
+                .received(
+                    frame::Stream {
+                        id,
+                        offset: 0,
+                        fin: false,
+                        data: Bytes::from_static(&[0; 32]),
+                    },
+                    2048,
+                    false,
+                    &mut Retransmits::default(),
+                )
                 .unwrap(),
             ShouldTransmit(false)
         );
@@ -11,6 +11,8 @@ pub(super) struct Recv {
     state: RecvState,
     pub(super) assembler: Assembler,
     sent_max_stream_data: u64,
+    /// Whether any data was received in 0-RTT packets
+    pub(super) zero_rtt: bool,
 }
 
 impl Recv {
@@ -19,12 +21,14 @@ impl Recv {
             state: RecvState::default(),
             assembler: Assembler::new(),
             sent_max_stream_data: initial_max_data,
+            zero_rtt: false,
         }
     }
 
     pub(super) fn ingest(
         &mut self,
         frame: frame::Stream,
+        allocation_size: usize,
         received: u64,
         max_data: u64,
     ) -> Result<u64, TransportError> {
@@ -54,11 +58,22 @@ impl Recv {
             }
         }
 
-        self.assembler.insert(frame.offset, frame.data);
+        self.assembler
+            .insert(frame.offset, frame.data, allocation_size);
 
         Ok(new_bytes)
     }
 
+    /// Whether data up to `end` lies more than `window` past the first gap in the stream
+    ///
+    /// Stopped streams don't buffer data, so are never considered to.
+    pub(super) fn beyond_window(&self, end: u64, window: u64) -> bool {
+        // The first gap can't precede what's been read, so most frames needn't look for it
+        !self.assembler.is_stopped()
+            && end > self.assembler.bytes_read().saturating_add(window)
+            && end > self.assembler.contiguous().saturating_add(window)
+    }
+
     pub(super) fn read(&mut self, max_length: usize, ordered: bool) -> StreamReadResult<Chunk> {
         match self.assembler.read(max_length, ordered)? {
             Some(chunk) => Ok(Some(chunk)),
@@ -118,7 +133,9 @@ impl Recv {
     /// `false` the new window should only be transmitted if a previous transmission
     /// had failed.
     pub(super) fn max_stream_data(&mut self, stream_receive_window: u64) -> (u64, ShouldTransmit) {
-        let max_stream_data = self.assembler.bytes_read() + stream_receive_window;
+        // Never less than already announced, which a reduced window could otherwise lead to
+        let max_stream_data =
+            (self.assembler.bytes_read() + stream_receive_window).max(self.sent_max_stream_data);
 
         // Only announce a window update if it's significant enough
         // to make it worthwhile sending a MAX_STREAM_DATA frame.
@@ -1,4 +1,6 @@
-use std::time::Instant;
+use std::time::Duration;
+
+use crate::Instant;
 
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
 pub(crate) enum Timer {
@@ -31,6 +33,18 @@ impl Timer {
         Timer::Pacing,
         Timer::PushNewCid,
     ];
+
+    /// Whether the timer may fire somewhat late without affecting protocol behavior
+    ///
+    /// Loss detection and pacing drive the send path and must fire on time, and a late keep-alive
+    /// could let the peer's idle timer expire first; the others guard timescales far longer than
+    /// any reasonable coalescing slack.
+    fn is_lenient(self) -> bool {
+        !matches!(
+            self,
+            Timer::LossDetection | Timer::Pacing | Timer::KeepAlive
+        )
+    }
 }
 
 /// A table of data associated with each distinct kind of `Timer`
@@ -52,11 +66,88 @@ impl TimerTable {
         self.data[timer as usize] = None;
     }
 
-    pub fn next_timeout(&self) -> Option<Instant> {
-        self.data.iter().filter_map(|&x| x).min()
+    /// Compute when the connection next needs to handle a timeout
+    ///
+    /// Lenient timers expiring within `slack` of the earliest of them are coalesced into a single
+    /// deadline, which is never more than `slack` late for any timer and never late for a timer
+    /// which isn't lenient.
+    pub fn next_timeout(&self, slack: Duration) -> Option<Instant> {
+        let times = |lenient: bool| {
+            Timer::VALUES
+                .iter()
+                .filter(move |timer| timer.is_lenient() == lenient)
+                .filter_map(move |&timer| self.data[timer as usize])
+        };
+        let strict = times(false).min();
+        let lenient = times(true).min().map(|earliest| {
+            let limit = earliest + slack;
+            times(true)
+                .filter(|&time| time <= limit)
+                .max()
+                .unwrap_or(earliest)
+        });
+        match (strict, lenient) {
+            (Some(x), Some(y)) => Some(x.min(y)),
+            (x, y) => x.or(y),
+        }
     }
 
     pub fn is_expired(&self, timer: Timer, after: Instant) -> bool {
         self.data[timer as usize].map_or(false, |x| x <= after)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coalesce_lenient() {
+        let now = Instant::now();
+        let mut timers = TimerTable::default();
+        assert_eq!(timers.next_timeout(Duration::from_millis(10)), None);
+        timers.set(Timer::Idle, now + Duration::from_millis(100));
+        timers.set(Timer::PushNewCid, now + Duration::from_millis(105));
+        timers.set(Timer::KeyDiscard, now + Duration::from_millis(200));
+        assert_eq!(
+            timers.next_timeout(Duration::from_millis(0)),
+            Some(now + Duration::from_millis(100))
+        );
+        assert_eq!(
+            timers.next_timeout(Duration::from_millis(10)),
+            Some(now + Duration::from_millis(105))
+        );
+    }
+
+    #[test]
+    fn strict_not_delayed() {
+        let now = Instant::now();
+        let mut timers = TimerTable::default();
+        timers.set(Timer::Idle, now + Duration::from_millis(100));
+        timers.set(Timer::PushNewCid, now + Duration::from_millis(105));
+        timers.set(Timer::LossDetection, now + Duration::from_millis(102));
+        assert_eq!(
+            timers.next_timeout(Duration::from_millis(10)),
+            Some(now + Duration::from_millis(102))
+        );
+        // Strict timers aren't delayed to meet lenient ones either
+        timers.set(Timer::Pacing, now + Duration::from_millis(101));
+        timers.stop(Timer::Idle);
+        assert_eq!(
+            timers.next_timeout(Duration::from_millis(10)),
+            Some(now + Duration::from_millis(101))
+        );
+    }
+
+    #[test]
+    fn keep_alive_not_delayed() {
+        let now = Instant::now();
+        let mut timers = TimerTable::default();
+        timers.set(Timer::KeepAlive, now + Duration::from_millis(100));
+        timers.set(Timer::Idle, now + Duration::from_millis(105));
+        assert_eq!(
+            timers.next_timeout(Duration::from_millis(10)),
+            Some(now + Duration::from_millis(100))
+        );
+    }
+}
@@ -58,6 +58,14 @@ pub trait Session: Send + Sized {
     /// Get the peer's identity, if available
     fn peer_identity(&self) -> Option<Self::Identity>;
 
+    /// The application protocol negotiated with the peer, if any
+    ///
+    /// Returns `None` until the connection emits `HandshakeDataReady`, or always if the protocol
+    /// can't negotiate one, which the default assumes.
+    fn application_protocol(&self) -> Option<Vec<u8>> {
+        None
+    }
+
     /// Get the 0-RTT keys if available (clients only)
     ///
     /// On the client side, this method can be used to see if 0-RTT key material is available
@@ -165,6 +173,21 @@ where
 
     /// Start a server session with this configuration
     fn start_session(&self, params: &TransportParameters) -> S;
+
+    /// This configuration, changed to negotiate only `protocol` as the application protocol
+    ///
+    /// Connections whose protocol
+    /// [`ServerConfig::alpn_selector()`](crate::generic::ServerConfig::alpn_selector) chose start
+    /// their sessions from the result, which endpoints keep for later connections choosing the
+    /// same protocol. The default implementation returns `None`, leaving the choice to this
+    /// configuration's own preferences.
+    fn with_protocol(&self, protocol: &[u8]) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        let _ = protocol;
+        None
+    }
 }
 
 /// Keys used to protect packet payloads
@@ -26,6 +26,8 @@ use crate::{
 pub struct TlsSession {
     using_alpn: bool,
     got_handshake_data: bool,
+    /// The server name requested by the client, if this is a client session
+    server_name: Option<String>,
     inner: SessionKind,
 }
 
@@ -81,9 +83,14 @@ impl crypto::Session for TlsSession {
         Some(HandshakeData {
             protocol: self.get_alpn_protocol().map(|x| x.into()),
             server_name: match self.inner {
-                SessionKind::Client(_) => None,
+                SessionKind::Client(_) => self.server_name.clone(),
                 SessionKind::Server(ref session) => session.get_sni_hostname().map(|x| x.into()),
             },
+            cipher_suite: self
+                .get_negotiated_ciphersuite()
+                .map(|x| CipherSuite::from_code(x.suite.get_u16())),
+            tls_version: self.get_protocol_version().map(|x| x.get_u16()),
+            peer_certificates: self.get_peer_certificates().map(|v| v.into()),
         })
     }
 
@@ -91,6 +98,13 @@ impl crypto::Session for TlsSession {
         self.get_peer_certificates().map(|v| v.into())
     }
 
+    fn application_protocol(&self) -> Option<Vec<u8>> {
+        if !self.got_handshake_data {
+            return None;
+        }
+        self.get_alpn_protocol().map(|x| x.into())
+    }
+
     fn early_crypto(&self) -> Option<(Self::HeaderKey, Self::PacketKey)> {
         let keys = self.get_0rtt_keys()?;
         Some((keys.header, keys.packet))
@@ -263,6 +277,10 @@ const RETRY_INTEGRITY_NONCE: [u8; 12] = [
 ];
 
 /// Authentication data for (rustls) TLS session
+///
+/// Fields other than `protocol` and `server_name` may not be known yet when the connection first
+/// reports its handshake data. All are set, where applicable, once the handshake completes.
+#[derive(Debug, Clone)]
 pub struct HandshakeData {
     /// The negotiated application protocol, if ALPN is in use
     ///
@@ -270,8 +288,51 @@ pub struct HandshakeData {
     pub protocol: Option<Vec<u8>>,
     /// The server name specified by the client, if any
     ///
-    /// Always `None` for outgoing connections
+    /// For outgoing connections, this is the name passed when connecting.
     pub server_name: Option<String>,
+    /// The cipher suite protecting the connection
+    pub cipher_suite: Option<CipherSuite>,
+    /// The TLS version in use, as its wire encoding, e.g. `0x0304` for TLS 1.3
+    pub tls_version: Option<u16>,
+    /// The certificate chain presented by the peer, if any
+    ///
+    /// Servers only obtain one from clients they request client authentication from.
+    pub peer_certificates: Option<CertificateChain>,
+}
+
+/// A TLS 1.3 cipher suite
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum CipherSuite {
+    /// `TLS_AES_128_GCM_SHA256`
+    Aes128GcmSha256,
+    /// `TLS_AES_256_GCM_SHA384`
+    Aes256GcmSha384,
+    /// `TLS_CHACHA20_POLY1305_SHA256`
+    Chacha20Poly1305Sha256,
+    /// Any other suite, identified by its IANA code point
+    Other(u16),
+}
+
+impl CipherSuite {
+    fn from_code(code: u16) -> Self {
+        match code {
+            0x1301 => CipherSuite::Aes128GcmSha256,
+            0x1302 => CipherSuite::Aes256GcmSha384,
+            0x1303 => CipherSuite::Chacha20Poly1305Sha256,
+            x => CipherSuite::Other(x),
+        }
+    }
+
+    /// The suite's IANA code point
+    pub fn code(self) -> u16 {
+        match self {
+            CipherSuite::Aes128GcmSha256 => 0x1301,
+            CipherSuite::Aes256GcmSha384 => 0x1302,
+            CipherSuite::Chacha20Poly1305Sha256 => 0x1303,
+            CipherSuite::Other(x) => x,
+        }
+    }
 }
 
 impl crypto::ClientConfig<TlsSession> for Arc<rustls::ClientConfig> {
@@ -309,6 +370,7 @@ impl crypto::ClientConfig<TlsSession> for Arc<rustls::ClientConfig> {
         Ok(TlsSession {
             using_alpn: !self.alpn_protocols.is_empty(),
             got_handshake_data: false,
+            server_name: Some(server_name.into()),
             inner: SessionKind::Client(rustls::ClientSession::new_quic(
                 self,
                 pki_server_name,
@@ -330,9 +392,16 @@ impl crypto::ServerConfig<TlsSession> for Arc<rustls::ServerConfig> {
         TlsSession {
             using_alpn: !self.alpn_protocols.is_empty(),
             got_handshake_data: false,
+            server_name: None,
             inner: SessionKind::Server(rustls::ServerSession::new_quic(self, to_vec(params))),
         }
     }
+
+    fn with_protocol(&self, protocol: &[u8]) -> Option<Self> {
+        let mut config = (**self).clone();
+        config.alpn_protocols = vec![protocol.to_vec()];
+        Some(Arc::new(config))
+    }
 }
 
 fn to_vec(params: &TransportParameters) -> Vec<u8> {
@@ -0,0 +1,251 @@
+//! Event-emitting driver for an endpoint and all of its connections
+//!
+//! Using [`Endpoint`] and [`Connection`] directly requires the host to route datagrams between
+//! them, shuttle [`EndpointEvent`]s and [`ConnectionEvent`]s back and forth, track one timer per
+//! connection, and remember to poll every connection it touched. That is natural for an async
+//! runtime but awkward for hosts such as game loops or foreign-language bindings, which would
+//! rather react to a single stream of values.
+//!
+//! A [`Driver`] takes care of that bookkeeping. The host feeds it inputs through
+//! [`handle()`](Driver::handle), [`handle_timeout()`](Driver::handle_timeout) and
+//! [`connection_mut()`](Driver::connection_mut), then calls [`poll()`](Driver::poll) until it
+//! returns `None`. Every resulting state transition is yielded as an [`Output`]: datagrams to
+//! send, changes to the single timer the host needs to run, newly accepted and drained
+//! connections, and each connection's [`Event`]s, such as streams being opened or becoming
+//! readable and the peer migrating to a new address.
+//!
+//! ```ignore
+//! let mut driver = Driver::new(Endpoint::new(Default::default(), Some(server_config)));
+//! loop {
+//!     while let Some(output) = driver.poll(now) {
+//!         match output {
+//!             Output::Transmit(transmit) => socket.send_to(&transmit.contents, transmit.destination)?,
+//!             Output::Timeout(deadline) => timer = deadline,
+//!             Output::Event { connection, event: Event::Stream(StreamEvent::Readable { id }) } => {
+//!                 driver.connection_mut(connection).unwrap().read(id, 4096, true)?;
+//!             }
+//!             _ => {}
+//!         }
+//!     }
+//!     // Wait for a datagram or for `timer` to expire, then pass it to `handle` or `handle_timeout`
+//! }
+//! ```
+//!
+//! [`Endpoint`]: crate::generic::Endpoint
+//! [`Connection`]: crate::generic::Connection
+//! [`EndpointEvent`]: crate::EndpointEvent
+//! [`ConnectionEvent`]: crate::ConnectionEvent
+
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+};
+
+use bytes::BytesMut;
+
+use crate::{
+    config::ClientConfig, connection::Connection, crypto, endpoint::Endpoint, ConnectError,
+    ConnectionHandle, DatagramEvent, EcnCodepoint, Event, Instant, Transmit,
+};
+
+/// An endpoint and its connections, reporting everything of interest as [`Output`]s
+pub struct Driver<S>
+where
+    S: crypto::Session,
+{
+    endpoint: Endpoint<S>,
+    connections: HashMap<ConnectionHandle, Connection<S>>,
+    timeouts: HashMap<ConnectionHandle, Instant>,
+    /// Connections whose state may have changed since they were last polled
+    dirty: BTreeSet<ConnectionHandle>,
+    /// Outputs ready to be returned by `poll`
+    pending: VecDeque<Output>,
+    /// The deadline most recently reported through `Output::Timeout`
+    reported_timeout: Option<Instant>,
+}
+
+impl<S> Driver<S>
+where
+    S: crypto::Session,
+{
+    /// Take over `endpoint`, which should not have any connections yet
+    pub fn new(endpoint: Endpoint<S>) -> Self {
+        Self {
+            endpoint,
+            connections: HashMap::new(),
+            timeouts: HashMap::new(),
+            dirty: BTreeSet::new(),
+            pending: VecDeque::new(),
+            reported_timeout: None,
+        }
+    }
+
+    /// Initiate a connection to `remote`
+    ///
+    /// The connection's handshake packets are yielded by the next call to `poll`.
+    pub fn connect(
+        &mut self,
+        now: Instant,
+        config: ClientConfig<S>,
+        remote: SocketAddr,
+        server_name: &str,
+    ) -> Result<ConnectionHandle, ConnectError> {
+        let (ch, conn) = self.endpoint.connect_at(now, config, remote, server_name)?;
+        self.connections.insert(ch, conn);
+        self.dirty.insert(ch);
+        Ok(ch)
+    }
+
+    /// Process an incoming UDP datagram
+    ///
+    /// See [`Endpoint::handle()`](crate::generic::Endpoint::handle). Incoming connection attempts
+    /// are accepted unless the endpoint's configuration says otherwise.
+    pub fn handle(
+        &mut self,
+        now: Instant,
+        remote: SocketAddr,
+        local_ip: Option<IpAddr>,
+        ecn: Option<EcnCodepoint>,
+        data: BytesMut,
+    ) {
+        match self.endpoint.handle(now, remote, local_ip, ecn, data) {
+            Some(DatagramEvent::NewConnection(incoming)) => {
+                if let Ok((ch, conn)) = self.endpoint.accept(incoming, now) {
+                    self.connections.insert(ch, conn);
+                    self.dirty.insert(ch);
+                    self.pending.push_back(Output::NewConnection(ch));
+                }
+            }
+            Some(DatagramEvent::ConnectionEvent(ch, event)) => {
+                if let Some(conn) = self.connections.get_mut(&ch) {
+                    conn.handle_event(event);
+                    self.dirty.insert(ch);
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// Process expired timers
+    ///
+    /// Should be called once the deadline last reported through [`Output::Timeout`] has passed.
+    /// Calling it early is harmless.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        for (&ch, conn) in self.connections.iter_mut() {
+            if matches!(self.timeouts.get(&ch), Some(&x) if x <= now) {
+                self.timeouts.remove(&ch);
+                conn.handle_timeout(now);
+                self.dirty.insert(ch);
+            }
+        }
+    }
+
+    /// Return the next output, or `None` once every input so far has been fully processed
+    ///
+    /// Should be called repeatedly after each input, until it returns `None`.
+    pub fn poll(&mut self, now: Instant) -> Option<Output> {
+        if self.pending.is_empty() {
+            self.process(now);
+        }
+        self.pending.pop_front()
+    }
+
+    /// The endpoint driven by this driver
+    pub fn endpoint(&self) -> &Endpoint<S> {
+        &self.endpoint
+    }
+
+    /// The endpoint driven by this driver, mutably
+    pub fn endpoint_mut(&mut self) -> &mut Endpoint<S> {
+        &mut self.endpoint
+    }
+
+    /// Look up a connection that has not yet been drained
+    pub fn connection(&self, ch: ConnectionHandle) -> Option<&Connection<S>> {
+        self.connections.get(&ch)
+    }
+
+    /// Look up a connection mutably, for instance to open streams or write data
+    ///
+    /// The effects of any changes, such as packets to send, are yielded by the next call to `poll`.
+    pub fn connection_mut(&mut self, ch: ConnectionHandle) -> Option<&mut Connection<S>> {
+        let conn = self.connections.get_mut(&ch)?;
+        self.dirty.insert(ch);
+        Some(conn)
+    }
+
+    /// Handles of all connections that have not yet been drained
+    pub fn connections(&self) -> impl Iterator<Item = ConnectionHandle> + '_ {
+        self.connections.keys().cloned()
+    }
+
+    /// Poll the endpoint and every dirty connection, queueing the resulting outputs
+    fn process(&mut self, now: Instant) {
+        while let Some(transmit) = self.endpoint.poll_transmit() {
+            self.pending.push_back(Output::Transmit(transmit));
+        }
+
+        while let Some(ch) = self.dirty.iter().next().cloned() {
+            self.dirty.remove(&ch);
+            let conn = match self.connections.get_mut(&ch) {
+                Some(x) => x,
+                None => continue,
+            };
+            let mut drained = false;
+            while let Some(event) = conn.poll_endpoint_events() {
+                drained |= event.is_drained();
+                if let Some(event) = self.endpoint.handle_event(ch, event) {
+                    conn.handle_event(event);
+                }
+            }
+            while let Some(event) = conn.poll() {
+                self.pending.push_back(Output::Event {
+                    connection: ch,
+                    event,
+                });
+            }
+            while let Some(transmit) = conn.poll_transmit(now) {
+                self.pending.push_back(Output::Transmit(transmit));
+            }
+            match conn.poll_timeout() {
+                Some(x) => self.timeouts.insert(ch, x),
+                None => self.timeouts.remove(&ch),
+            };
+            if drained {
+                self.connections.remove(&ch);
+                self.timeouts.remove(&ch);
+                self.pending.push_back(Output::Drained(ch));
+            }
+        }
+
+        let timeout = self.timeouts.values().min().cloned();
+        if timeout != self.reported_timeout {
+            self.reported_timeout = timeout;
+            self.pending.push_back(Output::Timeout(timeout));
+        }
+    }
+}
+
+/// Something a [`Driver`]'s host must act on or may want to observe
+#[derive(Debug)]
+pub enum Output {
+    /// A datagram to send
+    Transmit(Transmit),
+    /// The deadline at which `handle_timeout` should next be called, replacing any earlier one
+    ///
+    /// `None` means no timer needs to run.
+    Timeout(Option<Instant>),
+    /// A peer initiated a new connection
+    ///
+    /// Its events follow as `Output::Event`s.
+    NewConnection(ConnectionHandle),
+    /// Something happened on a connection
+    Event {
+        /// The connection the event relates to
+        connection: ConnectionHandle,
+        /// What happened
+        event: Event,
+    },
+    /// A connection has been drained and released, so its handle is no longer valid
+    Drained(ConnectionHandle),
+}
@@ -5,7 +5,7 @@ use std::{
     net::{IpAddr, SocketAddr},
     ops::{Index, IndexMut},
     sync::Arc,
-    time::{Duration, Instant, SystemTime},
+    time::Duration,
 };
 
 use bytes::{BufMut, Bytes, BytesMut};
@@ -15,23 +15,29 @@ use thiserror::Error;
 use tracing::{debug, trace, warn};
 
 use crate::{
-    cid_generator::{ConnectionIdGenerator, RandomConnectionIdGenerator},
+    buffer_pool::BufferPool,
+    cid_generator::{ConnectionIdGenerator, HandleCodec},
+    client_hello::ClientHello,
     coding::BufMutExt,
-    config::{ClientConfig, ConfigError, EndpointConfig, ServerConfig},
-    connection::{Connection, ConnectionError},
+    config::{ClientConfig, ConfigError, EndpointConfig, ServerConfig, TransportConfig},
+    connection::{AddressValidation, Connection, ConnectionError},
     crypto::{
         self, ClientConfig as ClientCryptoConfig, Keys, PacketKey,
         ServerConfig as ServerCryptoConfig,
     },
     frame,
     packet::{Header, Packet, PacketDecodeError, PacketNumber, PartialDecode},
+    qlog::Qlog,
+    random,
+    resumption::Resumption,
     shared::{
         ConnectionEvent, ConnectionEventInner, ConnectionId, EcnCodepoint, EndpointEvent,
         EndpointEventInner, IssuedCid,
     },
+    time,
     transport_parameters::TransportParameters,
-    ResetToken, RetryToken, Side, Transmit, TransportError, MAX_CID_SIZE, MIN_INITIAL_SIZE,
-    MIN_MTU, RESET_TOKEN_SIZE, VERSION,
+    Instant, ResetToken, RetryToken, Side, Transmit, TransportError, TransportErrorCode,
+    MAX_CID_SIZE, MAX_UDP_PAYLOAD, MIN_INITIAL_SIZE, MIN_MTU, RESET_TOKEN_SIZE, VERSION,
 };
 
 /// The main entry point to the library
@@ -56,12 +62,29 @@ where
     connection_reset_tokens: ResetTokenTable,
     connections: Slab<ConnectionMeta>,
     local_cid_generator: Box<dyn ConnectionIdGenerator>,
+    /// Embeds connection handles in local CIDs, if the CID generator allows it
+    ///
+    /// When set, packets for established connections are routed by decoding their CID rather than
+    /// looking it up in `connection_ids`.
+    handle_codec: Option<HandleCodec>,
     config: Arc<EndpointConfig<S>>,
     server_config: Option<Arc<ServerConfig<S>>>,
+    /// Crypto configurations derived from `server_config` for protocols chosen by its ALPN selector
+    ///
+    /// Holds at most `MAX_PROTOCOL_CONFIGS`, since the choices depend on what clients offer.
+    protocol_configs: HashMap<Vec<u8>, S::ServerConfig>,
     /// Whether incoming connections should be unconditionally rejected by a server
     ///
     /// Equivalent to a `ServerConfig.accept_buffer` of `0`, but can be changed after the endpoint is constructed.
     reject_new_connections: bool,
+    /// Buffers for outgoing datagrams, shared with all connections
+    buffers: BufferPool,
+    stats: EndpointStats,
+    /// Packets received for connection attempts that have yet to be accepted or turned away
+    incoming_buffers: Slab<IncomingBuffer>,
+    /// Identifies the entry in `incoming_buffers` for each pending connection attempt's initial
+    /// destination CID
+    incoming_ids: HashMap<ConnectionId, usize>,
 }
 
 impl<S> Endpoint<S>
@@ -75,18 +98,31 @@ where
         config: Arc<EndpointConfig<S>>,
         server_config: Option<Arc<ServerConfig<S>>>,
     ) -> Self {
+        let mut rng = random::rng_from(&*config.random_source);
+        let local_cid_generator = (config.connection_id_generator_factory.as_ref())();
+        let handle_codec = if local_cid_generator.allow_handle_embedding() {
+            Some(HandleCodec::new(&mut rng))
+        } else {
+            None
+        };
         Self {
-            rng: StdRng::from_entropy(),
+            rng,
             transmits: VecDeque::new(),
             connection_ids_initial: HashMap::new(),
             connection_ids: HashMap::new(),
             connection_remotes: HashMap::new(),
             connection_reset_tokens: ResetTokenTable::default(),
             connections: Slab::new(),
-            local_cid_generator: (config.connection_id_generator_factory.as_ref())(),
+            local_cid_generator,
+            handle_codec,
             reject_new_connections: false,
             config,
             server_config,
+            protocol_configs: HashMap::new(),
+            buffers: BufferPool::new(),
+            stats: EndpointStats::default(),
+            incoming_buffers: Slab::new(),
+            incoming_ids: HashMap::new(),
         }
     }
 
@@ -100,6 +136,14 @@ where
         self.transmits.pop_front()
     }
 
+    /// Return the buffer of a sent `Transmit` for reuse
+    ///
+    /// Applies to transmits produced by this endpoint and by any of its connections. Recycling is
+    /// optional, but avoids allocating a fresh buffer for most outgoing datagrams.
+    pub fn recycle(&self, contents: Vec<u8>) {
+        self.buffers.put(contents);
+    }
+
     /// Process `EndpointEvent`s emitted from related `Connection`s
     ///
     /// In turn, processing this event may return a `ConnectionEvent` for the same `Connection`.
@@ -130,8 +174,18 @@ where
                     }
                 }
             }
+            Established => {
+                let conn = &mut self.connections[ch];
+                if conn.handshaking {
+                    conn.handshaking = false;
+                    self.stats.handshaking -= 1;
+                }
+            }
             Drained => {
                 let conn = self.connections.remove(ch.0);
+                if conn.handshaking {
+                    self.stats.handshaking -= 1;
+                }
                 if conn.init_cid.len() > 0 {
                     self.connection_ids_initial.remove(&conn.init_cid);
                 }
@@ -148,6 +202,10 @@ where
     }
 
     /// Process an incoming UDP datagram
+    ///
+    /// `data` may be a view into a larger receive buffer. Its capacity is taken as the size of the
+    /// allocation it keeps alive, and stream data buffered from it is charged accordingly, so that
+    /// receive buffers can be shared with the connection without copying.
     pub fn handle(
         &mut self,
         now: Instant,
@@ -155,8 +213,9 @@ where
         local_ip: Option<IpAddr>,
         ecn: Option<EcnCodepoint>,
         data: BytesMut,
-    ) -> Option<(ConnectionHandle, DatagramEvent<S>)> {
+    ) -> Option<DatagramEvent<S>> {
         let datagram_len = data.len();
+        let allocation_size = data.capacity();
         let (first_decode, remaining) =
             match PartialDecode::new(data, self.local_cid_generator.cid_len()) {
                 Ok(x) => x,
@@ -167,11 +226,18 @@ where
                 }) => {
                     if !self.is_server() {
                         debug!("dropping packet with unsupported version");
+                        self.stats.dropped.unsupported_version += 1;
+                        return None;
+                    }
+                    if datagram_len < MIN_INITIAL_SIZE {
+                        debug!("ignoring short packet with unsupported version");
+                        self.stats.dropped.anti_amplification += 1;
                         return None;
                     }
                     trace!("sending version negotiation");
+                    self.stats.version_negotiations_sent += 1;
                     // Negotiate versions
-                    let mut buf = Vec::<u8>::new();
+                    let mut buf = self.buffers.get(MIN_MTU as usize);
                     Header::VersionNegotiate {
                         random: self.rng.gen::<u8>() | 0x40,
                         src_cid: dst_cid,
@@ -196,6 +262,7 @@ where
                 }
                 Err(e) => {
                     trace!("malformed header: {}", e);
+                    self.stats.dropped.malformed_header += 1;
                     return None;
                 }
             };
@@ -206,21 +273,23 @@ where
 
         let dst_cid = first_decode.dst_cid();
         let known_ch = {
-            let ch = if self.local_cid_generator.cid_len() > 0 {
-                self.connection_ids.get(&dst_cid)
+            let ch = if self.handle_codec.is_some() {
+                self.decode_handle(&dst_cid)
+            } else if self.local_cid_generator.cid_len() > 0 {
+                self.connection_ids.get(&dst_cid).cloned()
             } else {
                 None
             };
             ch.or_else(|| {
                 if first_decode.is_initial() || first_decode.is_0rtt() {
-                    self.connection_ids_initial.get(&dst_cid)
+                    self.connection_ids_initial.get(&dst_cid).cloned()
                 } else {
                     None
                 }
             })
             .or_else(|| {
                 if self.local_cid_generator.cid_len() == 0 {
-                    self.connection_remotes.get(&remote)
+                    self.connection_remotes.get(&remote).cloned()
                 } else {
                     None
                 }
@@ -232,28 +301,57 @@ where
                 }
                 self.connection_reset_tokens
                     .get(remote, &data[data.len() - RESET_TOKEN_SIZE..])
+                    .cloned()
             })
-            .cloned()
         };
         if let Some(ch) = known_ch {
-            return Some((
+            return Some(DatagramEvent::ConnectionEvent(
                 ch,
-                DatagramEvent::ConnectionEvent(ConnectionEvent(ConnectionEventInner::Datagram {
+                ConnectionEvent(ConnectionEventInner::Datagram {
                     now,
                     remote,
                     ecn,
                     first_decode,
                     remaining,
-                })),
+                    allocation_size,
+                }),
             ));
         }
 
+        if first_decode.is_initial() || first_decode.is_0rtt() {
+            if let Some(&index) = self.incoming_ids.get(&dst_cid) {
+                let buffer = &mut self.incoming_buffers[index];
+                if buffer.bytes + datagram_len > MAX_INCOMING_BUFFER {
+                    debug!("dropping packet for pending connection attempt with full buffer");
+                    self.stats.dropped.unknown_connection += 1;
+                    return None;
+                }
+                trace!(
+                    "buffering packet for pending connection attempt {}",
+                    dst_cid
+                );
+                buffer.bytes += datagram_len;
+                buffer
+                    .datagrams
+                    .push(ConnectionEvent(ConnectionEventInner::Datagram {
+                        now,
+                        remote,
+                        ecn,
+                        first_decode,
+                        remaining,
+                        allocation_size,
+                    }));
+                return None;
+            }
+        }
+
         //
         // Potentially create a new connection
         //
 
         if !self.is_server() {
             debug!("packet for unrecognized connection {}", dst_cid);
+            self.stats.dropped.unknown_connection += 1;
             self.stateless_reset(datagram_len, remote, local_ip, &dst_cid);
             return None;
         }
@@ -264,20 +362,32 @@ where
                     "ignoring non-initial packet for unknown connection {}",
                     dst_cid
                 );
+                self.stats.dropped.unknown_connection += 1;
                 return None;
             }
             if datagram_len < MIN_INITIAL_SIZE {
                 debug!("ignoring short initial for connection {}", dst_cid);
+                self.stats.dropped.invalid_initial += 1;
                 return None;
             }
 
             let crypto = S::initial_keys(&dst_cid, Side::Server);
             return match first_decode.finish(Some(&crypto.header.remote)) {
                 Ok(packet) => self
-                    .handle_first_packet(now, remote, local_ip, ecn, packet, remaining, &crypto)
-                    .map(|(ch, conn)| (ch, DatagramEvent::NewConnection(conn))),
+                    .handle_first_packet(
+                        now,
+                        remote,
+                        local_ip,
+                        ecn,
+                        packet,
+                        remaining,
+                        allocation_size,
+                        crypto,
+                    )
+                    .map(DatagramEvent::NewConnection),
                 Err(e) => {
                     trace!("unable to decode initial packet: {}", e);
+                    self.stats.dropped.invalid_initial += 1;
                     None
                 }
             };
@@ -288,14 +398,17 @@ where
         // connection. Send a stateless reset.
         //
 
+        self.stats.dropped.unknown_connection += 1;
         if !dst_cid.is_empty() {
             self.stateless_reset(datagram_len, remote, local_ip, &dst_cid);
         } else {
             trace!("dropping unrecognized short packet without ID");
+            self.stats.stateless_resets_suppressed += 1;
         }
         None
     }
 
+    /// Answer a packet for an unknown connection with a stateless reset, if that's safe
     fn stateless_reset(
         &mut self,
         inciting_dgram_len: usize,
@@ -312,12 +425,13 @@ where
             Some(headroom) if headroom > MIN_PADDING_LEN => headroom - 1,
             _ => {
                 debug!("ignoring unexpected {} byte packet: not larger than minimum stateless reset size", inciting_dgram_len);
+                self.stats.stateless_resets_suppressed += 1;
                 return;
             }
         };
 
         debug!("sending stateless reset for {} to {}", dst_cid, remote);
-        let mut buf = Vec::<u8>::new();
+        self.stats.stateless_resets_sent += 1;
         // Resets with at least this much padding can't possibly be distinguished from real packets
         const IDEAL_MIN_PADDING_LEN: usize = MIN_PADDING_LEN + MAX_CID_SIZE;
         let padding_len = if max_padding_len <= IDEAL_MIN_PADDING_LEN {
@@ -325,7 +439,7 @@ where
         } else {
             self.rng.gen_range(IDEAL_MIN_PADDING_LEN..max_padding_len)
         };
-        buf.reserve_exact(padding_len + RESET_TOKEN_SIZE);
+        let mut buf = self.buffers.get(padding_len + RESET_TOKEN_SIZE);
         buf.resize(padding_len, 0);
         self.rng.fill_bytes(&mut buf[0..padding_len]);
         buf[0] = 0b0100_0000 | buf[0] >> 2;
@@ -343,11 +457,28 @@ where
     }
 
     /// Initiate a connection
+    ///
+    /// The connection is timestamped by the [`Clock`](crate::Clock) from this endpoint's
+    /// configuration.
     pub fn connect(
         &mut self,
         config: ClientConfig<S>,
         remote: SocketAddr,
         server_name: &str,
+    ) -> Result<(ConnectionHandle, Connection<S>), ConnectError> {
+        let now = self.config.clock.now();
+        self.connect_at(now, config, remote, server_name)
+    }
+
+    /// Initiate a connection at `now`
+    ///
+    /// Like [`connect()`](Self::connect), for callers keeping time themselves.
+    pub fn connect_at(
+        &mut self,
+        now: Instant,
+        config: ClientConfig<S>,
+        remote: SocketAddr,
+        server_name: &str,
     ) -> Result<(ConnectionHandle, Connection<S>), ConnectError> {
         if self.is_full() {
             return Err(ConnectError::TooManyConnections);
@@ -355,7 +486,9 @@ where
         if remote.port() == 0 {
             return Err(ConnectError::InvalidRemoteAddress(remote));
         }
-        let remote_id = RandomConnectionIdGenerator::new(MAX_CID_SIZE).generate_cid();
+        let mut remote_id = [0; MAX_CID_SIZE];
+        self.rng.fill_bytes(&mut remote_id);
+        let remote_id = ConnectionId::new(&remote_id);
         trace!(initial_dcid = %remote_id);
         let (ch, conn) = self.add_connection(
             remote_id,
@@ -366,7 +499,7 @@ where
                 config,
                 server_name: server_name.into(),
             },
-            Instant::now(),
+            now,
         )?;
         Ok((ch, conn))
     }
@@ -379,7 +512,7 @@ where
     ) -> ConnectionEvent {
         let mut ids = vec![];
         for _ in 0..num {
-            let id = self.new_cid();
+            let id = self.new_cid(ch);
             self.connection_ids.insert(id, ch);
             let meta = &mut self.connections[ch];
             meta.cids_issued += 1;
@@ -394,9 +527,25 @@ where
         ConnectionEvent(ConnectionEventInner::NewIdentifiers(ids, now))
     }
 
-    fn new_cid(&mut self) -> ConnectionId {
+    /// Identify the connection a local CID was issued to using `handle_codec`
+    fn decode_handle(&self, cid: &ConnectionId) -> Option<ConnectionHandle> {
+        let index = self.handle_codec.as_ref()?.decode(cid)?;
+        let meta = self.connections.get(index)?;
+        // Guard against forged or retired CIDs
+        if meta.loc_cids.values().any(|x| x == cid) {
+            Some(ConnectionHandle(index))
+        } else {
+            None
+        }
+    }
+
+    /// Generate a fresh local CID for the connection `ch`
+    fn new_cid(&mut self, ch: ConnectionHandle) -> ConnectionId {
         loop {
-            let cid = self.local_cid_generator.generate_cid();
+            let mut cid = self.local_cid_generator.generate_cid();
+            if let Some(ref codec) = self.handle_codec {
+                codec.encode(&mut cid, ch.0);
+            }
             if !self.connection_ids.contains_key(&cid) {
                 break cid;
             }
@@ -413,8 +562,17 @@ where
         opts: ConnectionOpts<S>,
         now: Instant,
     ) -> Result<(ConnectionHandle, Connection<S>), ConnectError> {
-        let loc_cid = self.new_cid();
-        let (server_config, tls, transport_config) = match opts {
+        let ch = ConnectionHandle(self.connections.vacant_key());
+        let loc_cid = self.new_cid(ch);
+        let address_validation = match opts {
+            ConnectionOpts::Client { .. } => AddressValidation::Implicit,
+            ConnectionOpts::Server {
+                retry_src_cid: Some(_),
+                ..
+            } => AddressValidation::Retry,
+            ConnectionOpts::Server { .. } => AddressValidation::Pending,
+        };
+        let (server_config, tls, transport_config, params, odcid, resumption) = match opts {
             ConnectionOpts::Client {
                 config,
                 server_name,
@@ -426,19 +584,29 @@ where
                     loc_cid,
                     None,
                 );
+                let resumption = config.resumption.map(|store| Resumption {
+                    store,
+                    server_name: server_name.clone(),
+                });
                 (
                     None,
                     config.crypto.start_session(&server_name, &params)?,
                     config.transport,
+                    params,
+                    init_cid,
+                    resumption,
                 )
             }
             ConnectionOpts::Server {
                 orig_dst_cid,
                 retry_src_cid,
+                transport,
+                protocol,
             } => {
                 let config = self.server_config.as_ref().unwrap();
+                let transport = transport.unwrap_or_else(|| config.transport.clone());
                 let params = TransportParameters::new(
-                    &config.transport,
+                    &transport,
                     &self.config,
                     self.local_cid_generator.as_ref(),
                     loc_cid,
@@ -450,14 +618,49 @@ where
                     retry_src_cid,
                     ..params
                 };
+                let crypto = match protocol {
+                    Some(protocol) => {
+                        if !self.protocol_configs.contains_key(&protocol) {
+                            if self.protocol_configs.len() >= MAX_PROTOCOL_CONFIGS {
+                                self.protocol_configs.clear();
+                            }
+                            if let Some(x) = config.crypto.with_protocol(&protocol) {
+                                self.protocol_configs.insert(protocol.clone(), x);
+                            }
+                        }
+                        self.protocol_configs
+                            .get(&protocol)
+                            .unwrap_or(&config.crypto)
+                    }
+                    None => &config.crypto,
+                };
+                let tls = crypto.start_session(&server_params);
                 (
                     Some(config.clone()),
-                    config.crypto.start_session(&server_params),
-                    config.transport.clone(),
+                    tls,
+                    transport,
+                    server_params,
+                    orig_dst_cid,
+                    None,
                 )
             }
         };
 
+        let side = if server_config.is_some() {
+            Side::Server
+        } else {
+            Side::Client
+        };
+        let qlog = transport_config
+            .qlog_factory
+            .as_ref()
+            .and_then(|factory| factory.create(side, &odcid))
+            .map(|writer| {
+                let mut qlog = Qlog::new(writer, side, &odcid, now);
+                qlog.parameters_set(now, true, &params);
+                qlog
+            });
+
         let conn = Connection::new(
             server_config,
             transport_config,
@@ -468,6 +671,15 @@ where
             local_ip,
             tls,
             self.local_cid_generator.as_ref(),
+            self.buffers.clone(),
+            StdRng::from_seed(self.rng.gen()),
+            qlog,
+            resumption,
+            self.config
+                .max_udp_payload_size
+                .0
+                .min(MAX_UDP_PAYLOAD.into()) as u16,
+            address_validation,
             now,
         );
         let id = self.connections.insert(ConnectionMeta {
@@ -476,8 +688,10 @@ where
             loc_cids: iter::once((0, loc_cid)).collect(),
             initial_remote: remote,
             reset_token: None,
+            handshaking: true,
         });
-        let ch = ConnectionHandle(id);
+        self.stats.handshaking += 1;
+        debug_assert_eq!(id, ch.0);
 
         if self.local_cid_generator.cid_len() > 0 {
             self.connection_ids.insert(loc_cid, ch);
@@ -495,8 +709,9 @@ where
         ecn: Option<EcnCodepoint>,
         mut packet: Packet,
         rest: Option<BytesMut>,
-        crypto: &Keys<S>,
-    ) -> Option<(ConnectionHandle, Connection<S>)> {
+        allocation_size: usize,
+        crypto: Keys<S>,
+    ) -> Option<Incoming<S>> {
         let (src_cid, dst_cid, token, packet_number) = match packet.header {
             Header::Initial {
                 src_cid,
@@ -507,176 +722,402 @@ where
             _ => panic!("non-initial packet in handle_first_packet()"),
         };
         let packet_number = packet_number.expand(0);
+        let datagram_len =
+            packet.header_data.len() + packet.payload.len() + rest.as_ref().map_or(0, |x| x.len());
 
         if crypto
             .packet
             .remote
-            .decrypt(
-                packet_number as u64,
-                &packet.header_data,
-                &mut packet.payload,
-            )
+            .decrypt(packet_number, &packet.header_data, &mut packet.payload)
             .is_err()
         {
             debug!(packet_number, "failed to authenticate initial packet");
+            self.stats.dropped.invalid_initial += 1;
             return None;
         };
 
         if !packet.reserved_bits_valid() {
             debug!("dropping connection attempt with invalid reserved bits");
+            self.stats.dropped.invalid_initial += 1;
             return None;
         }
 
-        // Local CID used for stateless packets
-        let temp_loc_cid = self.new_cid();
+        self.expire_incoming(now);
         let server_config = self.server_config.as_ref().unwrap();
 
-        if self.connections.len() >= server_config.concurrent_connections as usize
+        // Attempts awaiting a decision hold buffered packets too, so they count against the limit
+        if self.connections.len() + self.incoming_buffers.len()
+            >= server_config.concurrent_connections as usize
             || self.reject_new_connections
             || self.is_full()
         {
             debug!("refusing connection");
+            self.stats.refused_connections += 1;
             self.initial_close(
                 remote,
                 local_ip,
-                crypto,
+                &crypto,
                 &src_cid,
-                &temp_loc_cid,
                 TransportError::CONNECTION_REFUSED(""),
             );
             return None;
         }
 
         if dst_cid.len() < 8
-            && (!server_config.use_stateless_retry
+            && ((!server_config.use_stateless_retry && token.is_empty())
                 || dst_cid.len() != self.local_cid_generator.cid_len())
         {
             debug!(
                 "rejecting connection due to invalid DCID length {}",
                 dst_cid.len()
             );
+            self.stats.refused_connections += 1;
             self.initial_close(
                 remote,
                 local_ip,
-                crypto,
+                &crypto,
                 &src_cid,
-                &temp_loc_cid,
                 TransportError::PROTOCOL_VIOLATION("invalid destination CID length"),
             );
             return None;
         }
 
-        let (retry_src_cid, orig_dst_cid) = if server_config.use_stateless_retry {
-            if token.is_empty() {
-                // First Initial
-                let mut random_bytes = vec![0u8; RetryToken::RANDOM_BYTES_LEN];
-                self.rng.fill_bytes(&mut random_bytes);
-
-                let token = RetryToken {
-                    orig_dst_cid: dst_cid,
-                    issued: SystemTime::now(),
-                    random_bytes: &random_bytes,
-                }
-                .encode(&*server_config.token_key, &remote, &temp_loc_cid);
-
-                let header = Header::Retry {
-                    src_cid: temp_loc_cid,
-                    dst_cid: src_cid,
-                };
-
-                let mut buf = Vec::new();
-                let encode = header.encode(&mut buf);
-                buf.put_slice(&token);
-                buf.extend_from_slice(&S::retry_tag(&dst_cid, &buf));
-                encode.finish::<S::PacketKey, S::HeaderKey>(&mut buf, &crypto.header.local, None);
-
-                self.transmits.push_back(Transmit {
-                    destination: remote,
-                    ecn: None,
-                    contents: buf,
-                    segment_size: None,
-                    src_ip: local_ip,
-                });
+        let retry = if token.is_empty() {
+            if server_config.use_stateless_retry {
+                self.send_retry(now, remote, local_ip, &crypto, &src_cid, &dst_cid);
                 return None;
             }
-
+            None
+        } else {
             match RetryToken::from_bytes(&*server_config.token_key, &remote, &dst_cid, &token) {
                 Ok(token)
-                    if token.issued
-                        + Duration::from_micros(
-                            self.server_config.as_ref().unwrap().retry_token_lifetime,
-                        )
-                        > SystemTime::now() =>
+                    if token.issued + Duration::from_micros(server_config.retry_token_lifetime)
+                        > time::system_time(now) =>
                 {
-                    (Some(dst_cid), token.orig_dst_cid)
+                    Some((dst_cid, token.orig_dst_cid))
                 }
                 _ => {
                     debug!("rejecting invalid stateless retry token");
+                    self.stats.refused_connections += 1;
                     self.initial_close(
                         remote,
                         local_ip,
-                        crypto,
+                        &crypto,
                         &src_cid,
-                        &temp_loc_cid,
                         TransportError::INVALID_TOKEN(""),
                     );
                     return None;
                 }
             }
-        } else {
-            (None, dst_cid)
         };
 
+        let client_hello = ClientHello::peek(Bytes::copy_from_slice(&packet.payload));
+        let buffer = self.incoming_buffers.insert(IncomingBuffer {
+            dst_cid,
+            received_at: now,
+            datagrams: Vec::new(),
+            bytes: datagram_len,
+        });
+        self.incoming_ids.insert(dst_cid, buffer);
+        trace!(icid = %dst_cid, "connection attempt incoming");
+        Some(Incoming {
+            received_at: now,
+            remote,
+            local_ip,
+            ecn,
+            packet,
+            packet_number,
+            rest,
+            allocation_size,
+            crypto,
+            src_cid,
+            dst_cid,
+            retry,
+            client_hello,
+            buffer,
+        })
+    }
+
+    /// Attempt to accept an incoming connection
+    ///
+    /// Any further packets the peer sent while the attempt was pending are passed to the new
+    /// connection. Fails if the endpoint can't take on another connection, or if the peer's first
+    /// packet is found to be invalid, in either case letting the peer know.
+    pub fn accept(
+        &mut self,
+        incoming: Incoming<S>,
+        now: Instant,
+    ) -> Result<(ConnectionHandle, Connection<S>), ConnectionError> {
+        self.accept_inner(incoming, now, None)
+    }
+
+    /// Attempt to accept an incoming connection, using `transport` in place of the server's
+    /// [`ServerConfig::transport`](crate::generic::ServerConfig::transport)
+    ///
+    /// Lets a server choose flow control windows, stream limits, timeouts and so on for a
+    /// particular peer, for instance based on its [`remote_address()`](Incoming::remote_address)
+    /// or [`server_name()`](Incoming::server_name). Otherwise behaves like
+    /// [`accept()`](Self::accept).
+    pub fn accept_with(
+        &mut self,
+        incoming: Incoming<S>,
+        now: Instant,
+        transport: Arc<TransportConfig>,
+    ) -> Result<(ConnectionHandle, Connection<S>), ConnectionError> {
+        self.accept_inner(incoming, now, Some(transport))
+    }
+
+    fn accept_inner(
+        &mut self,
+        incoming: Incoming<S>,
+        now: Instant,
+        transport: Option<Arc<TransportConfig>>,
+    ) -> Result<(ConnectionHandle, Connection<S>), ConnectionError> {
+        let buffer = self.take_incoming_buffer(&incoming);
+        let server_config = self.server_config.as_ref().unwrap();
+        if self.connections.len() + self.incoming_buffers.len()
+            >= server_config.concurrent_connections as usize
+            || self.reject_new_connections
+            || self.is_full()
+        {
+            debug!("refusing connection");
+            self.stats.refused_connections += 1;
+            let error = TransportError::CONNECTION_REFUSED("");
+            self.initial_close(
+                incoming.remote,
+                incoming.local_ip,
+                &incoming.crypto,
+                &incoming.src_cid,
+                error.clone(),
+            );
+            return Err(error.into());
+        }
+
+        let protocol = match (
+            &server_config.alpn_selector,
+            &incoming.client_hello.alpn_protocols,
+        ) {
+            (Some(selector), Some(offered)) => {
+                match selector(incoming.client_hello.server_name.as_deref(), offered) {
+                    Some(x) if offered.contains(&x) => Some(x),
+                    chosen => {
+                        if chosen.is_some() {
+                            warn!("ALPN selector chose a protocol the client didn't offer");
+                        }
+                        debug!("refusing connection offering no acceptable protocol");
+                        self.stats.refused_connections += 1;
+                        let error = TransportError {
+                            code: TransportErrorCode::crypto(NO_APPLICATION_PROTOCOL),
+                            frame: None,
+                            reason: "no acceptable application protocol".into(),
+                        };
+                        self.initial_close(
+                            incoming.remote,
+                            incoming.local_ip,
+                            &incoming.crypto,
+                            &incoming.src_cid,
+                            error.clone(),
+                        );
+                        return Err(error.into());
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        let (retry_src_cid, orig_dst_cid) = match incoming.retry {
+            Some((retry_src_cid, orig_dst_cid)) => (Some(retry_src_cid), orig_dst_cid),
+            None => (None, incoming.dst_cid),
+        };
         let (ch, mut conn) = self
             .add_connection(
-                dst_cid,
-                src_cid,
-                remote,
-                local_ip,
+                incoming.dst_cid,
+                incoming.src_cid,
+                incoming.remote,
+                incoming.local_ip,
                 ConnectionOpts::Server {
                     retry_src_cid,
                     orig_dst_cid,
+                    transport,
+                    protocol,
                 },
                 now,
             )
             .unwrap();
-        if dst_cid.len() != 0 {
-            self.connection_ids_initial.insert(dst_cid, ch);
+        if incoming.dst_cid.len() != 0 {
+            self.connection_ids_initial.insert(incoming.dst_cid, ch);
         }
-        match conn.handle_first_packet(now, remote, ecn, packet_number as u64, packet, rest) {
+        match conn.handle_first_packet(
+            incoming.received_at,
+            incoming.remote,
+            incoming.ecn,
+            incoming.packet_number,
+            incoming.packet,
+            incoming.rest,
+            incoming.allocation_size,
+        ) {
             Ok(()) => {
-                trace!(id = ch.0, icid = %dst_cid, "connection incoming");
-                Some((ch, conn))
+                trace!(id = ch.0, icid = %incoming.dst_cid, "connection incoming");
+                self.stats.accepted_connections += 1;
+                for event in buffer.into_iter().flat_map(|x| x.datagrams) {
+                    conn.handle_event(event);
+                }
+                Ok((ch, conn))
             }
             Err(e) => {
                 debug!("handshake failed: {}", e);
+                self.stats.refused_connections += 1;
                 self.handle_event(ch, EndpointEvent(EndpointEventInner::Drained));
-                if let ConnectionError::TransportError(e) = e {
-                    self.initial_close(remote, local_ip, crypto, &src_cid, &temp_loc_cid, e);
+                if let ConnectionError::TransportError(ref e) = e {
+                    self.initial_close(
+                        incoming.remote,
+                        incoming.local_ip,
+                        &incoming.crypto,
+                        &incoming.src_cid,
+                        e.clone(),
+                    );
                 }
-                None
+                Err(e)
             }
         }
     }
 
+    /// Turn away an incoming connection, letting the peer know with a `CONNECTION_REFUSED` error
+    pub fn refuse(&mut self, incoming: Incoming<S>) {
+        self.take_incoming_buffer(&incoming);
+        self.stats.refused_connections += 1;
+        self.initial_close(
+            incoming.remote,
+            incoming.local_ip,
+            &incoming.crypto,
+            &incoming.src_cid,
+            TransportError::CONNECTION_REFUSED(""),
+        );
+    }
+
+    /// Ask the peer to prove ownership of its address before the connection is accepted
+    ///
+    /// The peer is sent a Retry packet, to which it should respond with a new connection attempt
+    /// whose [`Incoming::remote_address_validated()`] is `true`. Fails, handing back `incoming`,
+    /// if that is already the case, as a connection attempt may only be retried once.
+    pub fn retry(&mut self, incoming: Incoming<S>) -> Result<(), RetryError<S>> {
+        if incoming.retry.is_some() {
+            return Err(RetryError(Box::new(incoming)));
+        }
+        self.take_incoming_buffer(&incoming);
+        self.send_retry(
+            incoming.received_at,
+            incoming.remote,
+            incoming.local_ip,
+            &incoming.crypto,
+            &incoming.src_cid,
+            &incoming.dst_cid,
+        );
+        Ok(())
+    }
+
+    /// Silently discard an incoming connection attempt
+    ///
+    /// The peer is not told, so it will keep trying until it times out.
+    pub fn ignore(&mut self, incoming: Incoming<S>) {
+        self.take_incoming_buffer(&incoming);
+        self.stats.refused_connections += 1;
+    }
+
+    /// Remove the buffer of a connection attempt a decision was made on, unless it has expired
+    fn take_incoming_buffer(&mut self, incoming: &Incoming<S>) -> Option<IncomingBuffer> {
+        // The slot may have been reused by a later attempt after this one's expired
+        match self.incoming_buffers.get(incoming.buffer) {
+            Some(x) if x.dst_cid == incoming.dst_cid && x.received_at == incoming.received_at => {}
+            _ => return None,
+        }
+        let buffer = self.incoming_buffers.remove(incoming.buffer);
+        self.incoming_ids.remove(&buffer.dst_cid);
+        Some(buffer)
+    }
+
+    /// Discard buffers of connection attempts whose peer has likely given up waiting on a decision
+    ///
+    /// Frees their slots in `ServerConfig::concurrent_connections` even if the application never
+    /// decides on them.
+    fn expire_incoming(&mut self, now: Instant) {
+        let timeout = self
+            .server_config
+            .as_ref()
+            .and_then(|x| x.transport.max_idle_timeout)
+            .unwrap_or(INCOMING_TIMEOUT);
+        let ids = &mut self.incoming_ids;
+        self.incoming_buffers.retain(|_, buffer| {
+            if now.saturating_duration_since(buffer.received_at) < timeout {
+                return true;
+            }
+            debug!(icid = %buffer.dst_cid, "connection attempt expired without a decision");
+            ids.remove(&buffer.dst_cid);
+            false
+        });
+    }
+
+    fn send_retry(
+        &mut self,
+        now: Instant,
+        remote: SocketAddr,
+        local_ip: Option<IpAddr>,
+        crypto: &Keys<S>,
+        src_cid: &ConnectionId,
+        dst_cid: &ConnectionId,
+    ) {
+        let temp_loc_cid = self.new_cid(ConnectionHandle(self.connections.vacant_key()));
+        let server_config = self.server_config.as_ref().unwrap();
+        let mut random_bytes = [0u8; RetryToken::RANDOM_BYTES_LEN];
+        self.rng.fill_bytes(&mut random_bytes);
+
+        let token = RetryToken {
+            orig_dst_cid: *dst_cid,
+            issued: time::system_time(now),
+            random_bytes: &random_bytes,
+        }
+        .encode(&*server_config.token_key, &remote, &temp_loc_cid);
+
+        let header = Header::Retry {
+            src_cid: temp_loc_cid,
+            dst_cid: *src_cid,
+        };
+
+        let mut buf = self.buffers.get(MIN_MTU as usize);
+        let encode = header.encode(&mut buf);
+        buf.put_slice(&token);
+        buf.extend_from_slice(&S::retry_tag(dst_cid, &buf));
+        encode.finish::<S::PacketKey, S::HeaderKey>(&mut buf, &crypto.header.local, None);
+
+        self.transmits.push_back(Transmit {
+            destination: remote,
+            ecn: None,
+            contents: buf,
+            segment_size: None,
+            src_ip: local_ip,
+        });
+        self.stats.retries_sent += 1;
+    }
+
     fn initial_close(
         &mut self,
         destination: SocketAddr,
         local_ip: Option<IpAddr>,
         crypto: &Keys<S>,
         remote_id: &ConnectionId,
-        local_id: &ConnectionId,
         reason: TransportError,
     ) {
+        // Local CID used for stateless packets
+        let local_id = self.new_cid(ConnectionHandle(self.connections.vacant_key()));
         let number = PacketNumber::U8(0);
         let header = Header::Initial {
             dst_cid: *remote_id,
-            src_cid: *local_id,
+            src_cid: local_id,
             number,
             token: Bytes::new(),
         };
 
-        let mut buf = Vec::<u8>::new();
+        let mut buf = self.buffers.get(MIN_MTU as usize);
         let partial_encode = header.encode(&mut buf);
         let max_len = MIN_MTU as usize - partial_encode.header_len - crypto.packet.local.tag_len();
         frame::Close::from(reason).encode(&mut buf, max_len);
@@ -705,6 +1146,14 @@ where
         &self.config
     }
 
+    /// Statistics aggregated over the lifetime of this endpoint
+    pub fn stats(&self) -> EndpointStats {
+        EndpointStats {
+            connections: self.connections.len() as u64,
+            ..self.stats
+        }
+    }
+
     #[cfg(test)]
     pub(crate) fn known_connections(&self) -> usize {
         let x = self.connections.len();
@@ -750,10 +1199,59 @@ where
             .field("config", &self.config)
             .field("server_config", &self.server_config)
             .field("reject_new_connections", &self.reject_new_connections)
+            .field("stats", &self.stats)
             .finish()
     }
 }
 
+/// Endpoint-wide statistics
+///
+/// Counts other than `connections` and `handshaking` cover the lifetime of the endpoint.
+#[derive(Debug, Default, Copy, Clone)]
+#[non_exhaustive]
+pub struct EndpointStats {
+    /// Connections currently associated with the endpoint, including those still handshaking or
+    /// draining
+    pub connections: u64,
+    /// Connections whose handshake is yet to complete
+    pub handshaking: u64,
+    /// Incoming connection attempts that were accepted
+    pub accepted_connections: u64,
+    /// Incoming connection attempts that were refused, or that failed before a connection could
+    /// be created
+    pub refused_connections: u64,
+    /// Retry packets sent to validate the address of an incoming connection attempt
+    pub retries_sent: u64,
+    /// Version negotiation packets sent in response to packets of an unsupported version
+    pub version_negotiations_sent: u64,
+    /// Stateless resets sent in response to packets for unknown connections
+    pub stateless_resets_sent: u64,
+    /// Packets for unknown connections that weren't answered with a stateless reset, being too
+    /// small to answer with a smaller reset or carrying no connection ID to reset
+    pub stateless_resets_suppressed: u64,
+    /// Datagrams dropped without being passed to a connection
+    pub dropped: DroppedDatagrams,
+}
+
+/// Number of datagrams dropped by an endpoint, by reason
+///
+/// Each dropped datagram is counted under exactly one reason.
+#[derive(Debug, Default, Copy, Clone)]
+#[non_exhaustive]
+pub struct DroppedDatagrams {
+    /// The packet header could not be parsed
+    pub malformed_header: u64,
+    /// A client received a packet of a QUIC version it doesn't support
+    pub unsupported_version: u64,
+    /// The packet was not addressed to a known connection, and could not start a new one
+    pub unknown_connection: u64,
+    /// An Initial packet that could start a connection was too short, or failed authentication
+    pub invalid_initial: u64,
+    /// A server received a packet of an unsupported version in a datagram too small to answer
+    /// with version negotiation, as the response could amplify traffic to a spoofed address
+    pub anti_amplification: u64,
+}
+
 #[derive(Debug)]
 pub(crate) struct ConnectionMeta {
     init_cid: ConnectionId,
@@ -768,6 +1266,8 @@ pub(crate) struct ConnectionMeta {
     /// Reset token provided by the peer for the CID we're currently sending to, and the address
     /// being sent to
     reset_token: Option<(SocketAddr, ResetToken)>,
+    /// Whether the connection's handshake is yet to complete
+    handshaking: bool,
 }
 
 /// Internal identifier for a `Connection` currently associated with an endpoint
@@ -798,12 +1298,150 @@ pub enum DatagramEvent<S>
 where
     S: crypto::Session,
 {
-    /// The datagram is redirected to its `Connection`
-    ConnectionEvent(ConnectionEvent),
-    /// The datagram has resulted in starting a new `Connection`
-    NewConnection(Connection<S>),
+    /// The datagram is redirected to the `Connection` with the given handle
+    ConnectionEvent(ConnectionHandle, ConnectionEvent),
+    /// The datagram is an attempt to start a new `Connection`
+    NewConnection(Incoming<S>),
 }
 
+/// An incoming connection attempt that has yet to be accepted or turned away
+///
+/// Must be passed to exactly one of [`Endpoint::accept()`], [`Endpoint::refuse()`],
+/// [`Endpoint::retry()`] or [`Endpoint::ignore()`]. Until then, further packets the peer sends for
+/// the attempt are buffered by the endpoint, and the attempt counts towards
+/// [`ServerConfig::concurrent_connections`](crate::generic::ServerConfig::concurrent_connections).
+/// An attempt left undecided for longer than the server's
+/// [`TransportConfig::max_idle_timeout`](crate::TransportConfig::max_idle_timeout) (or 10 seconds,
+/// if that's disabled) is expired when the next attempt arrives, freeing its buffer and slot; it
+/// may still be accepted afterwards, but further packets sent for it in the meantime are lost.
+pub struct Incoming<S>
+where
+    S: crypto::Session,
+{
+    received_at: Instant,
+    remote: SocketAddr,
+    local_ip: Option<IpAddr>,
+    ecn: Option<EcnCodepoint>,
+    /// The decrypted first packet
+    packet: Packet,
+    packet_number: u64,
+    rest: Option<BytesMut>,
+    allocation_size: usize,
+    crypto: Keys<S>,
+    src_cid: ConnectionId,
+    dst_cid: ConnectionId,
+    /// Retry source CID and original destination CID established by a valid retry token
+    retry: Option<(ConnectionId, ConnectionId)>,
+    client_hello: ClientHello,
+    /// Index of the attempt's entry in `Endpoint::incoming_buffers`
+    buffer: usize,
+}
+
+impl<S> Incoming<S>
+where
+    S: crypto::Session,
+{
+    /// The peer's UDP address
+    pub fn remote_address(&self) -> SocketAddr {
+        self.remote
+    }
+
+    /// The local IP address the attempt was sent to, if known
+    pub fn local_ip(&self) -> Option<IpAddr> {
+        self.local_ip
+    }
+
+    /// Whether the peer has proven that it can receive packets at its address
+    ///
+    /// True only if the attempt carries a token from an earlier [`Endpoint::retry()`], or from
+    /// [`ServerConfig::use_stateless_retry`](crate::generic::ServerConfig::use_stateless_retry).
+    pub fn remote_address_validated(&self) -> bool {
+        self.retry.is_some()
+    }
+
+    /// The server name the peer asked for, if it could be determined from its first packet
+    pub fn server_name(&self) -> Option<&str> {
+        self.client_hello.server_name.as_deref()
+    }
+
+    /// The application protocols offered by the peer, if they could be determined from its first
+    /// packet
+    pub fn alpn_protocols(&self) -> Option<&[Vec<u8>]> {
+        self.client_hello.alpn_protocols.as_deref()
+    }
+}
+
+impl<S> fmt::Debug for Incoming<S>
+where
+    S: crypto::Session,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Incoming")
+            .field("remote", &self.remote)
+            .field("local_ip", &self.local_ip)
+            .field("icid", &self.dst_cid)
+            .field("validated", &self.retry.is_some())
+            .field("server_name", &self.client_hello.server_name)
+            .finish()
+    }
+}
+
+/// Error returned by [`Endpoint::retry()`] for a connection attempt that was already retried
+pub struct RetryError<S: crypto::Session>(Box<Incoming<S>>);
+
+impl<S> RetryError<S>
+where
+    S: crypto::Session,
+{
+    /// Get back the connection attempt, to be accepted, refused or ignored instead
+    pub fn into_incoming(self) -> Incoming<S> {
+        *self.0
+    }
+}
+
+impl<S> fmt::Debug for RetryError<S>
+where
+    S: crypto::Session,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("RetryError").field(&self.0).finish()
+    }
+}
+
+impl<S> fmt::Display for RetryError<S>
+where
+    S: crypto::Session,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("connection attempt was already retried")
+    }
+}
+
+impl<S> std::error::Error for RetryError<S> where S: crypto::Session {}
+
+/// Packets received for a connection attempt that is pending a decision
+#[derive(Debug)]
+struct IncomingBuffer {
+    dst_cid: ConnectionId,
+    /// When the attempt's first packet was received
+    received_at: Instant,
+    datagrams: Vec<ConnectionEvent>,
+    /// Total length of the attempt's datagrams, including the first
+    bytes: usize,
+}
+
+/// Upper bound on the receive buffer space pinned by a single pending connection attempt
+const MAX_INCOMING_BUFFER: usize = 64 * 1024;
+
+/// How long a pending connection attempt is kept when the server's idle timeout is disabled
+const INCOMING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most crypto configurations kept for protocols chosen by a server's ALPN selector
+const MAX_PROTOCOL_CONFIGS: usize = 16;
+
+/// TLS alert sent when no application protocol acceptable to both sides was offered
+const NO_APPLICATION_PROTOCOL: u8 = 0x78;
+
 enum ConnectionOpts<S: crypto::Session> {
     Client {
         config: ClientConfig<S>,
@@ -812,6 +1450,10 @@ enum ConnectionOpts<S: crypto::Session> {
     Server {
         retry_src_cid: Option<ConnectionId>,
         orig_dst_cid: ConnectionId,
+        /// Overrides the server's transport configuration if set
+        transport: Option<Arc<TransportConfig>>,
+        /// Application protocol chosen by the server's ALPN selector
+        protocol: Option<Vec<u8>>,
     },
 }
 
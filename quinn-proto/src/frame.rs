@@ -45,6 +45,12 @@ impl coding::Codec for Type {
     }
 }
 
+impl From<Type> for u64 {
+    fn from(x: Type) -> Self {
+        x.0
+    }
+}
+
 pub(crate) trait FrameStruct {
     /// Smallest number of bytes this type of frame is guaranteed to fit within.
     const SIZE_BOUND: usize;
@@ -141,21 +147,42 @@ pub enum Frame {
     ResetStream(ResetStream),
     StopSending(StopSending),
     Crypto(Crypto),
-    NewToken { token: Bytes },
+    NewToken {
+        token: Bytes,
+    },
     Stream(Stream),
     MaxData(VarInt),
-    MaxStreamData { id: StreamId, offset: u64 },
-    MaxStreams { dir: Dir, count: u64 },
-    DataBlocked { offset: u64 },
-    StreamDataBlocked { id: StreamId, offset: u64 },
-    StreamsBlocked { dir: Dir, limit: u64 },
+    MaxStreamData {
+        id: StreamId,
+        offset: u64,
+    },
+    MaxStreams {
+        dir: Dir,
+        count: u64,
+    },
+    DataBlocked {
+        offset: u64,
+    },
+    StreamDataBlocked {
+        id: StreamId,
+        offset: u64,
+    },
+    StreamsBlocked {
+        dir: Dir,
+        limit: u64,
+    },
     NewConnectionId(NewConnectionId),
-    RetireConnectionId { sequence: u64 },
+    RetireConnectionId {
+        sequence: u64,
+    },
     PathChallenge(u64),
     PathResponse(u64),
     Close(Close),
     Datagram(Datagram),
-    Invalid { ty: Type, reason: &'static str },
+    Invalid {
+        ty: Option<Type>,
+        reason: &'static str,
+    },
     HandshakeDone,
 }
 
@@ -195,7 +222,8 @@ impl Frame {
             Crypto(_) => Type::CRYPTO,
             NewToken { .. } => Type::NEW_TOKEN,
             Datagram(_) => Type(*DATAGRAM_TYS.start()),
-            Invalid { ty, .. } => ty,
+            // Only a truncated payload leaves the type unknown; treat the remainder as padding
+            Invalid { ty, .. } => ty.unwrap_or(Type::PADDING),
             HandshakeDone => Type::HANDSHAKE_DONE,
         }
     }
@@ -517,6 +545,7 @@ impl Iter {
     }
 
     fn try_next(&mut self) -> Result<Frame, IterErr> {
+        self.last_ty = None;
         let ty = self.bytes.get::<Type>()?;
         self.last_ty = Some(ty);
         Ok(match ty {
@@ -685,7 +714,7 @@ impl Iterator for Iter {
                 // Corrupt frame, skip it and everything that follows
                 self.bytes = io::Cursor::new(Bytes::new());
                 Some(Frame::Invalid {
-                    ty: self.last_ty.unwrap(),
+                    ty: self.last_ty,
                     reason: e.reason(),
                 })
             }
@@ -855,4 +884,12 @@ mod test {
             ref x => panic!("incorrect frame {:?}", x),
         }
     }
+
+    #[test]
+    fn truncated_type() {
+        // A PING followed by the first byte of a two-byte frame type
+        let frames = Iter::new(Bytes::from_static(&[0x01, 0x40])).collect::<Vec<_>>();
+        assert_eq!(frames.len(), 2);
+        assert!(matches!(frames[1], Frame::Invalid { ty: None, .. }));
+    }
 }
@@ -11,6 +11,11 @@
 //! a single socket and mostly manages configuration and dispatches incoming datagrams to the
 //! related `Connection`. `Connection` types contain the bulk of the protocol logic related to
 //! managing a single connection and all the related state (such as streams).
+//!
+//! quinn-proto also runs on `wasm32-unknown-unknown`, driven by a host that provides its own
+//! datagram transport. As the standard library can't read a clock there, timestamps are this
+//! crate's [`Instant`], constructed from the host's clock, and randomness must be registered with
+//! `getrandom::register_custom_getrandom!`.
 
 #![cfg_attr(not(fuzzing), warn(missing_docs))]
 #![cfg_attr(test, allow(dead_code))]
@@ -26,8 +31,9 @@ use std::{
     time::Duration,
 };
 
+mod buffer_pool;
 mod cid_queue;
-#[doc(hidden)]
+mod client_hello;
 pub mod coding;
 mod constant_time;
 mod packet;
@@ -40,7 +46,10 @@ mod varint;
 pub use varint::{VarInt, VarIntBoundsExceeded};
 
 mod connection;
-pub use crate::connection::{Chunk, ConnectionError, ConnectionStats, Event, SendDatagramError};
+pub use crate::connection::{
+    AddressValidation, Chunk, ConnectionError, ConnectionErrorKind, ConnectionStats, Event,
+    PathInfo, RecvStreamStats, SendDatagramError, SpaceStats,
+};
 pub use crate::connection::{FinishError, ReadError, StreamEvent, UnknownStream, WriteError};
 
 mod config;
@@ -55,11 +64,21 @@ use crate::frame::Frame;
 pub use crate::frame::{ApplicationClose, ConnectionClose, Datagram};
 
 mod endpoint;
-pub use crate::endpoint::{ConnectError, ConnectionHandle, DatagramEvent};
+pub use crate::endpoint::{
+    ConnectError, ConnectionHandle, DatagramEvent, DroppedDatagrams, EndpointStats,
+};
 
 mod shared;
 pub use crate::shared::{ConnectionEvent, ConnectionId, EcnCodepoint, EndpointEvent};
 
+mod time;
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub use crate::time::SystemClock;
+pub use crate::time::{Clock, Instant};
+
+mod random;
+pub use crate::random::{OsRandom, RandomSource, SeededRandom};
+
 mod transport_error;
 pub use crate::transport_error::{Code as TransportErrorCode, Error as TransportError};
 
@@ -68,6 +87,23 @@ pub mod congestion;
 mod cid_generator;
 pub use crate::cid_generator::{ConnectionIdGenerator, RandomConnectionIdGenerator};
 
+mod qlog;
+pub use crate::qlog::{QlogFactory, QlogStream, QlogStreamStats};
+
+mod resumption;
+pub use crate::resumption::{InvalidResumptionState, ResumptionStore, ServerState};
+
+#[cfg(feature = "conformance")]
+pub mod conformance;
+
+pub mod driver;
+
+#[cfg(feature = "simulation")]
+pub mod simulation;
+
+#[cfg(feature = "tap")]
+pub mod tap;
+
 mod token;
 use token::{ResetToken, RetryToken};
 
@@ -76,7 +112,7 @@ pub mod generic {
     pub use crate::{
         config::{ClientConfig, EndpointConfig, ServerConfig},
         connection::Connection,
-        endpoint::Endpoint,
+        endpoint::{Endpoint, Incoming, RetryError},
     };
 }
 
@@ -94,6 +130,8 @@ mod rustls_impls {
     pub type ServerConfig = generic::ServerConfig<crypto::rustls::TlsSession>;
     /// A `EndpointConfig` using rustls keys
     pub type EndpointConfig = generic::EndpointConfig<crypto::rustls::TlsSession>;
+    /// An `Incoming` connection attempt using rustls for the cryptography protocol
+    pub type Incoming = generic::Incoming<crypto::rustls::TlsSession>;
 }
 
 #[cfg(feature = "rustls")]
@@ -102,16 +140,23 @@ pub use crate::rustls_impls::*;
 #[cfg(feature = "arbitrary")]
 use arbitrary::Arbitrary;
 
+/// Internals exposed for benchmarks; not part of the public API
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::connection::{Assembler, SentPackets};
+    pub use crate::packet::bench::{decode_short, encode_short};
+}
+
 #[doc(hidden)]
 #[cfg(fuzzing)]
 pub mod fuzzing {
-    pub use crate::connection::{FinishError, Streams};
-    pub use crate::frame::ResetStream;
+    pub use crate::connection::{Assembler, FinishError, Streams};
+    pub use crate::frame::{Frame, Iter as FrameIter, ResetStream};
     pub use crate::packet::PartialDecode;
     pub use crate::transport_parameters::TransportParameters;
     use crate::MAX_CID_SIZE;
     use arbitrary::{Arbitrary, Result, Unstructured};
-    pub use bytes::{BufMut, BytesMut};
+    pub use bytes::{BufMut, Bytes, BytesMut};
 
     impl Arbitrary for TransportParameters {
         fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self> {
@@ -297,6 +342,8 @@ const RESET_TOKEN_SIZE: usize = 16;
 const MAX_CID_SIZE: usize = 20;
 const MIN_INITIAL_SIZE: usize = 1200;
 const MIN_MTU: u16 = 1232;
+/// Largest UDP payload an IPv4 datagram can carry, and the most `max_udp_payload_size` may be
+const MAX_UDP_PAYLOAD: u16 = 65527;
 const TIMER_GRANULARITY: Duration = Duration::from_millis(1);
 /// Maximum number of streams that can be uniquely identified by a stream ID
 const MAX_STREAM_COUNT: u64 = 1 << 60;
@@ -737,12 +737,14 @@ const KEY_PHASE_BIT: u8 = 0x04;
 pub enum SpaceId {
     /// Unprotected packets, used to bootstrap the handshake
     Initial = 0,
+    /// Packets protected with keys derived during the handshake
     Handshake = 1,
     /// Application data space, used for 0-RTT and post-handshake/1-RTT packets
     Data = 2,
 }
 
 impl SpaceId {
+    /// Every packet number space, in the order they're used during a connection
     pub fn iter() -> impl Iterator<Item = Self> {
         [SpaceId::Initial, SpaceId::Handshake, SpaceId::Data]
             .iter()
@@ -750,6 +752,65 @@ impl SpaceId {
     }
 }
 
+/// Packet protection round trips, exposed for benchmarks
+#[doc(hidden)]
+pub mod bench {
+    use super::*;
+
+    /// Append a protected 1-RTT packet carrying `payload` to `buf`
+    pub fn encode_short<H, K>(
+        dst_cid: &ConnectionId,
+        number: u64,
+        payload: &[u8],
+        header_crypto: &H,
+        packet_crypto: &K,
+        buf: &mut Vec<u8>,
+    ) where
+        H: crypto::HeaderKey,
+        K: crypto::PacketKey,
+    {
+        let header = Header::Short {
+            spin: false,
+            key_phase: false,
+            dst_cid: *dst_cid,
+            number: PacketNumber::new(number, number.saturating_sub(1)),
+        };
+        let partial_encode = header.encode(buf);
+        let start = partial_encode.start;
+        buf.extend_from_slice(payload);
+        buf.resize(buf.len() + packet_crypto.tag_len(), 0);
+        partial_encode.finish(
+            &mut buf[start..],
+            header_crypto,
+            Some((number, packet_crypto)),
+        );
+    }
+
+    /// Unprotect a 1-RTT packet, returning its plaintext payload
+    ///
+    /// `expected` is the packet number the receiver expects next, used to expand the truncated
+    /// packet number on the wire.
+    pub fn decode_short<H, K>(
+        packet: BytesMut,
+        local_cid_len: usize,
+        expected: u64,
+        header_crypto: &H,
+        packet_crypto: &K,
+    ) -> Option<BytesMut>
+    where
+        H: crypto::HeaderKey,
+        K: crypto::PacketKey,
+    {
+        let (partial_decode, _) = PartialDecode::new(packet, local_cid_len).ok()?;
+        let mut packet = partial_decode.finish(Some(header_crypto)).ok()?;
+        let number = packet.header.number()?.expand(expected);
+        packet_crypto
+            .decrypt(number, &packet.header_data, &mut packet.payload)
+            .ok()?;
+        Some(packet.payload)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
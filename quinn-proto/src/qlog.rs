@@ -0,0 +1,653 @@
+//! Connection traces in the qlog format
+//!
+//! Traces are written as [JSON-SEQ] records, one per event, following the [qlog] main schema and
+//! its QUIC event definitions. They can be loaded into tools such as [qvis] to visualize and
+//! compare the behavior of different QUIC implementations.
+//!
+//! Traces of long-lived connections can be inspected as they run by streaming them to a collector
+//! through a [`QlogStream`], which keeps a slow or stalled sink from holding up the connection.
+//!
+//! [JSON-SEQ]: https://www.rfc-editor.org/rfc/rfc7464
+//! [qlog]: https://datatracker.ietf.org/doc/draft-ietf-quic-qlog-main-schema/
+//! [qvis]: https://qvis.quictools.info/
+
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    io,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+use tracing::warn;
+
+use crate::{
+    packet::{Header, LongType},
+    shared::ConnectionId,
+    transport_parameters::TransportParameters,
+    Instant, Side,
+};
+
+/// Creates qlog sinks for new connections
+///
+/// Implemented for closures with a compatible signature.
+pub trait QlogFactory: Send + Sync {
+    /// Construct the sink a connection's trace will be written to, or `None` to not trace it
+    ///
+    /// `initial_dst_cid` is the destination CID of the client's first Initial packet, which is
+    /// the conventional identifier for a trace.
+    fn create(
+        &self,
+        side: Side,
+        initial_dst_cid: &ConnectionId,
+    ) -> Option<Box<dyn io::Write + Send>>;
+}
+
+impl<F> QlogFactory for F
+where
+    F: Fn(Side, &ConnectionId) -> Option<Box<dyn io::Write + Send>> + Send + Sync,
+{
+    fn create(
+        &self,
+        side: Side,
+        initial_dst_cid: &ConnectionId,
+    ) -> Option<Box<dyn io::Write + Send>> {
+        self(side, initial_dst_cid)
+    }
+}
+
+/// A connection's qlog trace
+pub(crate) struct Qlog {
+    writer: Box<dyn io::Write + Send>,
+    /// Instant event times are measured relative to
+    start: Instant,
+    /// Identifies this connection's events among those of others sharing the writer
+    group_id: String,
+    /// Most recently reported recovery metrics, so only changes are written
+    metrics: Metrics,
+    /// Scratch space for the record being written
+    buf: String,
+}
+
+impl Qlog {
+    pub(crate) fn new(
+        writer: Box<dyn io::Write + Send>,
+        side: Side,
+        initial_dst_cid: &ConnectionId,
+        now: Instant,
+    ) -> Self {
+        let mut this = Self {
+            writer,
+            start: now,
+            group_id: initial_dst_cid.to_string(),
+            metrics: Metrics::default(),
+            buf: String::new(),
+        };
+        let vantage_point = match side {
+            Side::Client => "client",
+            Side::Server => "server",
+        };
+        let mut header = Object::record(&mut this.buf);
+        header
+            .str("qlog_version", "0.3")
+            .str("qlog_format", "JSON-SEQ")
+            .str("title", "quinn")
+            .object("trace", |trace| {
+                trace
+                    .object("vantage_point", |x| {
+                        x.str("type", vantage_point);
+                    })
+                    .object("common_fields", |x| {
+                        x.display("ODCID", initial_dst_cid)
+                            .display("group_id", initial_dst_cid)
+                            .str("time_format", "relative");
+                    });
+            });
+        header.finish();
+        this.flush_record();
+        this
+    }
+
+    pub(crate) fn packet_sent(&mut self, now: Instant, ty: &str, number: u64, len: usize) {
+        self.packet(now, "transport:packet_sent", ty, Some(number), len);
+    }
+
+    pub(crate) fn packet_received(
+        &mut self,
+        now: Instant,
+        ty: &str,
+        number: Option<u64>,
+        len: usize,
+    ) {
+        self.packet(now, "transport:packet_received", ty, number, len);
+    }
+
+    fn packet(&mut self, now: Instant, name: &str, ty: &str, number: Option<u64>, len: usize) {
+        self.event(now, name, |data| {
+            data.object("header", |header| {
+                header.str("packet_type", ty);
+                if let Some(number) = number {
+                    header.u64("packet_number", number);
+                }
+            })
+            .object("raw", |raw| {
+                raw.u64("length", len as u64);
+            });
+        });
+    }
+
+    /// Report recovery state, if any of it changed since the last report
+    pub(crate) fn metrics_updated(&mut self, now: Instant, metrics: Metrics) {
+        let old = self.metrics;
+        if old == metrics {
+            return;
+        }
+        self.metrics = metrics;
+        self.event(now, "recovery:metrics_updated", |data| {
+            let rtts = [
+                ("min_rtt", old.min_rtt, metrics.min_rtt),
+                ("smoothed_rtt", old.smoothed_rtt, metrics.smoothed_rtt),
+                ("latest_rtt", old.latest_rtt, metrics.latest_rtt),
+                ("rtt_variance", old.rtt_variance, metrics.rtt_variance),
+            ];
+            for &(name, old, new) in &rtts {
+                if old != new {
+                    data.millis(name, new);
+                }
+            }
+            if old.congestion_window != metrics.congestion_window {
+                data.u64("congestion_window", metrics.congestion_window);
+            }
+            if old.bytes_in_flight != metrics.bytes_in_flight {
+                data.u64("bytes_in_flight", metrics.bytes_in_flight);
+            }
+        });
+    }
+
+    /// Report the transport parameters sent (if `local`) or received
+    pub(crate) fn parameters_set(
+        &mut self,
+        now: Instant,
+        local: bool,
+        params: &TransportParameters,
+    ) {
+        self.event(now, "transport:parameters_set", |data| {
+            data.str("owner", if local { "local" } else { "remote" });
+            params.for_each_integer(|name, value| {
+                data.u64(name, value);
+            });
+            data.bool("disable_active_migration", params.disable_active_migration);
+            if let Some(x) = params.max_datagram_frame_size {
+                data.u64("max_datagram_frame_size", x.into());
+            }
+            if let Some(ref x) = params.initial_src_cid {
+                data.display("initial_source_connection_id", x);
+            }
+            if let Some(ref x) = params.original_dst_cid {
+                data.display("original_destination_connection_id", x);
+            }
+            if let Some(ref x) = params.retry_src_cid {
+                data.display("retry_source_connection_id", x);
+            }
+            if let Some(ref x) = params.stateless_reset_token {
+                data.display("stateless_reset_token", x);
+            }
+        });
+    }
+
+    fn event(&mut self, now: Instant, name: &str, data: impl FnOnce(&mut Object)) {
+        let time = now.saturating_duration_since(self.start);
+        let mut event = Object::record(&mut self.buf);
+        event
+            .millis("time", time)
+            .str("name", name)
+            .object("data", data)
+            .str("group_id", &self.group_id);
+        event.finish();
+        self.flush_record();
+    }
+
+    /// Write out the record begun in `buf` by [`Object::record()`]
+    ///
+    /// The record is passed to the writer in a single call, which [`QlogStream`] relies on.
+    fn flush_record(&mut self) {
+        self.buf.push('\n');
+        let result = self.writer.write_all(self.buf.as_bytes());
+        self.buf.clear();
+        if let Err(e) = result {
+            warn!("failed to write qlog record: {}", e);
+            // Disable further output rather than emitting a stream of errors
+            self.writer = Box::new(io::sink());
+        }
+    }
+}
+
+/// Streams qlog records to a writer from a background thread
+///
+/// Connections hand records to a [`sink()`](Self::sink) without blocking. Up to `capacity` bytes
+/// of records are buffered while the writer catches up; records arriving when the buffer is full
+/// are dropped whole and counted in [`stats()`](Self::stats), so the output remains well-formed.
+/// If the writer fails, further records are dropped as well.
+///
+/// A single stream may serve every connection of an endpoint. Their traces are then interleaved,
+/// each beginning with its own header record, so every record carries a `group_id` naming the
+/// connection it belongs to; split the output by `group_id` before loading it into tools that
+/// expect one trace per file.
+///
+/// # Example
+/// ```no_run
+/// # use quinn_proto::*; use std::{io, net::TcpStream, sync::Arc};
+/// let stream = QlogStream::new(TcpStream::connect("127.0.0.1:9000")?, 1 << 20)?;
+/// let mut config = TransportConfig::default();
+/// config.qlog_factory(Some(Arc::new(move |_: Side, _: &ConnectionId| {
+///     Some(stream.sink())
+/// })));
+/// # Ok::<_, io::Error>(())
+/// ```
+pub struct QlogStream {
+    shared: Arc<StreamShared>,
+}
+
+impl QlogStream {
+    /// Start a thread writing to `writer`, buffering up to `capacity` bytes of records
+    ///
+    /// Fails if the thread can't be spawned, as on targets without threads such as
+    /// `wasm32-unknown-unknown`.
+    pub fn new<W: io::Write + Send + 'static>(writer: W, capacity: usize) -> io::Result<Self> {
+        let shared = Arc::new(StreamShared {
+            state: Mutex::new(StreamState {
+                queue: VecDeque::new(),
+                queued_bytes: 0,
+                capacity,
+                writing: false,
+                failed: false,
+                handles: 1,
+                stats: QlogStreamStats::default(),
+            }),
+            changed: Condvar::new(),
+        });
+        let background = shared.clone();
+        thread::Builder::new()
+            .name("qlog".into())
+            .spawn(move || background.run(writer))?;
+        Ok(Self { shared })
+    }
+
+    /// A writer enqueuing each call's data as a single record
+    pub fn sink(&self) -> Box<dyn io::Write + Send> {
+        Box::new(self.clone())
+    }
+
+    /// Counts of records written and dropped so far
+    pub fn stats(&self) -> QlogStreamStats {
+        self.shared.state.lock().unwrap().stats
+    }
+
+    /// Block until every record buffered so far has been written and the writer flushed
+    pub fn flush(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        while !state.queue.is_empty() || state.writing {
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+}
+
+impl io::Write for QlogStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.failed || state.queued_bytes + buf.len() > state.capacity {
+            state.stats.dropped_records += 1;
+            state.stats.dropped_bytes += buf.len() as u64;
+        } else {
+            state.queued_bytes += buf.len();
+            state.queue.push_back(buf.to_vec());
+            self.shared.changed.notify_all();
+        }
+        // Dropping is the intended response to backpressure, not an error
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Clone for QlogStream {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().handles += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for QlogStream {
+    fn drop(&mut self) {
+        // Avoid a double panic if the lock was poisoned by a panicking writer
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.handles -= 1;
+            self.shared.changed.notify_all();
+        }
+    }
+}
+
+impl std::fmt::Debug for QlogStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QlogStream")
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+/// Statistics of a [`QlogStream`]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct QlogStreamStats {
+    /// Records passed to the writer
+    pub written_records: u64,
+    /// Records discarded because the buffer was full or the writer failed
+    pub dropped_records: u64,
+    /// Total size of the discarded records
+    pub dropped_bytes: u64,
+}
+
+struct StreamShared {
+    state: Mutex<StreamState>,
+    /// Signalled when records are queued or the queue drains
+    changed: Condvar,
+}
+
+impl StreamShared {
+    /// Write queued records until the writer fails or every handle is dropped
+    fn run<W: io::Write>(&self, mut writer: W) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let record = match state.queue.pop_front() {
+                Some(x) => x,
+                None if state.handles == 0 => return,
+                None => {
+                    state = self.changed.wait(state).unwrap();
+                    continue;
+                }
+            };
+            state.writing = true;
+            state.queued_bytes -= record.len();
+            drop(state);
+
+            let mut result = writer.write_all(&record);
+            if result.is_ok() {
+                // Keep the collector up to date rather than waiting for a full buffer
+                result = writer.flush();
+            }
+
+            state = self.state.lock().unwrap();
+            state.writing = false;
+            match result {
+                Ok(()) => state.stats.written_records += 1,
+                Err(e) => {
+                    warn!("abandoning qlog stream: {}", e);
+                    state.failed = true;
+                    state.stats.dropped_records += 1 + state.queue.len() as u64;
+                    state.stats.dropped_bytes += (record.len() + state.queued_bytes) as u64;
+                    state.queue.clear();
+                    state.queued_bytes = 0;
+                }
+            }
+            self.changed.notify_all();
+            if state.failed {
+                return;
+            }
+        }
+    }
+}
+
+struct StreamState {
+    queue: VecDeque<Vec<u8>>,
+    queued_bytes: usize,
+    capacity: usize,
+    /// Whether a record is being written
+    writing: bool,
+    /// Whether the writer has returned an error
+    failed: bool,
+    /// Number of live `QlogStream`s, including sinks
+    handles: usize,
+    stats: QlogStreamStats,
+}
+
+/// Recovery state reported by `recovery:metrics_updated` events
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct Metrics {
+    pub(crate) min_rtt: Duration,
+    pub(crate) smoothed_rtt: Duration,
+    pub(crate) latest_rtt: Duration,
+    pub(crate) rtt_variance: Duration,
+    pub(crate) congestion_window: u64,
+    pub(crate) bytes_in_flight: u64,
+}
+
+/// The qlog name for the type of a packet sent or received with `header`
+pub(crate) fn packet_type(header: &Header) -> &'static str {
+    match *header {
+        Header::Initial { .. } => "initial",
+        Header::Long {
+            ty: LongType::Handshake,
+            ..
+        } => "handshake",
+        Header::Long {
+            ty: LongType::ZeroRtt,
+            ..
+        } => "0RTT",
+        Header::Retry { .. } => "retry",
+        Header::Short { .. } => "1RTT",
+        Header::VersionNegotiate { .. } => "version_negotiation",
+    }
+}
+
+/// Minimal writer for a JSON object
+pub(crate) struct Object<'a> {
+    buf: &'a mut String,
+    empty: bool,
+}
+
+impl<'a> Object<'a> {
+    fn new(buf: &'a mut String) -> Self {
+        buf.push('{');
+        Self { buf, empty: true }
+    }
+
+    /// Begin a top-level object as a JSON-SEQ record
+    fn record(buf: &'a mut String) -> Self {
+        buf.push('\x1e');
+        Self::new(buf)
+    }
+
+    fn key(&mut self, key: &str) {
+        if !self.empty {
+            self.buf.push(',');
+        }
+        self.empty = false;
+        self.string(key);
+        self.buf.push(':');
+    }
+
+    fn string(&mut self, value: &str) {
+        self.buf.push('"');
+        for c in value.chars() {
+            match c {
+                '"' => self.buf.push_str("\\\""),
+                '\\' => self.buf.push_str("\\\\"),
+                c if (c as u32) < 0x20 => {
+                    let _ = write!(self.buf, "\\u{:04x}", c as u32);
+                }
+                c => self.buf.push(c),
+            }
+        }
+        self.buf.push('"');
+    }
+
+    fn str(&mut self, key: &str, value: &str) -> &mut Self {
+        self.key(key);
+        self.string(value);
+        self
+    }
+
+    fn display(&mut self, key: &str, value: &impl std::fmt::Display) -> &mut Self {
+        self.str(key, &value.to_string())
+    }
+
+    fn u64(&mut self, key: &str, value: u64) -> &mut Self {
+        self.key(key);
+        let _ = write!(self.buf, "{}", value);
+        self
+    }
+
+    fn bool(&mut self, key: &str, value: bool) -> &mut Self {
+        self.key(key);
+        self.buf.push_str(if value { "true" } else { "false" });
+        self
+    }
+
+    /// Write a duration as fractional milliseconds, qlog's default time unit
+    fn millis(&mut self, key: &str, value: Duration) -> &mut Self {
+        self.key(key);
+        let _ = write!(self.buf, "{}", value.as_secs_f64() * 1000.0);
+        self
+    }
+
+    fn object(&mut self, key: &str, f: impl FnOnce(&mut Object)) -> &mut Self {
+        self.key(key);
+        let mut inner = Object::new(self.buf);
+        f(&mut inner);
+        inner.finish();
+        self
+    }
+
+    fn finish(self) {
+        self.buf.push('}');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, sync::mpsc};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn records() {
+        let out = Shared::default();
+        let now = Instant::now();
+        let cid = ConnectionId::new(&[0xab, 0xcd]);
+        let mut qlog = Qlog::new(Box::new(out.clone()), Side::Client, &cid, now);
+        qlog.packet_sent(now + Duration::from_millis(2), "initial", 0, 1200);
+        let metrics = Metrics {
+            smoothed_rtt: Duration::from_micros(1500),
+            congestion_window: 12000,
+            ..Metrics::default()
+        };
+        qlog.metrics_updated(now, metrics);
+        // Unchanged metrics aren't reported again
+        qlog.metrics_updated(now, metrics);
+
+        let out = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let records = out.split('\x1e').collect::<Vec<_>>();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0], "");
+        assert_eq!(
+            records[1],
+            "{\"qlog_version\":\"0.3\",\"qlog_format\":\"JSON-SEQ\",\"title\":\"quinn\",\
+             \"trace\":{\"vantage_point\":{\"type\":\"client\"},\
+             \"common_fields\":{\"ODCID\":\"abcd\",\"group_id\":\"abcd\",\"time_format\":\"relative\"}}}\n"
+        );
+        assert_eq!(
+            records[2],
+            "{\"time\":2,\"name\":\"transport:packet_sent\",\"data\":{\
+             \"header\":{\"packet_type\":\"initial\",\"packet_number\":0},\
+             \"raw\":{\"length\":1200}},\"group_id\":\"abcd\"}\n"
+        );
+        assert_eq!(
+            records[3],
+            "{\"time\":0,\"name\":\"recovery:metrics_updated\",\"data\":{\
+             \"smoothed_rtt\":1.5,\"congestion_window\":12000},\"group_id\":\"abcd\"}\n"
+        );
+    }
+
+    #[test]
+    fn stream_drops_when_full() {
+        /// Blocks each write until a token arrives on `gate`
+        struct Gated {
+            gate: mpsc::Receiver<()>,
+            out: Shared,
+        }
+
+        impl io::Write for Gated {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.gate.recv().unwrap();
+                self.out.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let out = Shared::default();
+        let (open, gate) = mpsc::channel();
+        let stream = QlogStream::new(
+            Gated {
+                gate,
+                out: out.clone(),
+            },
+            10,
+        )
+        .unwrap();
+        let mut sink = stream.sink();
+        // The first record may be taken by the writer before the rest are queued, so the buffer
+        // holds either two or three of these records
+        for _ in 0..4 {
+            sink.write_all(b"abcd").unwrap();
+        }
+        let stats = stream.stats();
+        assert!(
+            stats.dropped_records == 1 || stats.dropped_records == 2,
+            "{:?}",
+            stats
+        );
+        assert_eq!(stats.dropped_bytes, 4 * stats.dropped_records);
+
+        for _ in 0..4 {
+            open.send(()).unwrap();
+        }
+        stream.flush();
+        let stats = stream.stats();
+        assert_eq!(stats.written_records + stats.dropped_records, 4);
+        // Only whole records are written
+        assert_eq!(
+            &out.0.lock().unwrap()[..],
+            &b"abcdabcdabcdabcd"[..4 * stats.written_records as usize]
+        );
+    }
+
+    #[test]
+    fn escaping() {
+        let mut buf = String::new();
+        let mut x = Object::new(&mut buf);
+        x.str("a\"b", "c\\d\n");
+        x.finish();
+        assert_eq!(buf, "{\"a\\\"b\":\"c\\\\d\\u000a\"}");
+    }
+}
@@ -0,0 +1,60 @@
+//! Sources of the randomness that endpoints and their connections depend on
+//!
+//! Every random choice quinn-proto makes is derived from a [`RandomSource`], so that supplying a
+//! [`SeededRandom`] in place of the default [`OsRandom`] makes the protocol's behavior
+//! reproducible.
+
+use std::{fmt, sync::Mutex};
+
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+/// A source of random bytes
+pub trait RandomSource: Send + Sync {
+    /// Fill `dest` with random bytes
+    fn fill_bytes(&self, dest: &mut [u8]);
+}
+
+/// Randomness drawn from the operating system
+///
+/// On `wasm32-unknown-unknown`, this is whatever the host registered with
+/// `getrandom::register_custom_getrandom!`.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct OsRandom;
+
+impl RandomSource for OsRandom {
+    fn fill_bytes(&self, dest: &mut [u8]) {
+        rand::thread_rng().fill_bytes(dest);
+    }
+}
+
+/// A deterministic stream of bytes expanded from a fixed seed
+///
+/// Not suitable for production use: anyone who learns the seed can predict connection IDs,
+/// address validation tokens, and everything else that must be unguessable.
+pub struct SeededRandom(Mutex<StdRng>);
+
+impl SeededRandom {
+    /// Construct a source that always produces the same bytes for the same `seed`
+    pub fn new(seed: [u8; 32]) -> Self {
+        Self(Mutex::new(StdRng::from_seed(seed)))
+    }
+}
+
+impl RandomSource for SeededRandom {
+    fn fill_bytes(&self, dest: &mut [u8]) {
+        self.0.lock().unwrap().fill_bytes(dest);
+    }
+}
+
+impl fmt::Debug for SeededRandom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SeededRandom").finish()
+    }
+}
+
+/// Seed a fast generator of our own from `source`
+pub(crate) fn rng_from(source: &dyn RandomSource) -> StdRng {
+    let mut seed = [0; 32];
+    source.fill_bytes(&mut seed);
+    StdRng::from_seed(seed)
+}
@@ -3,14 +3,23 @@ use std::{
     cmp::Ordering,
     collections::{
         btree_map, BTreeMap,
-        Bound::{Excluded, Included},
+        Bound::{Excluded, Included, Unbounded},
     },
     ops::Range,
+    slice,
 };
 
+use smallvec::SmallVec;
+
+/// Number of ranges stored inline before switching to a tree
+///
+/// Most sets, such as pending ACKs or the received data of a stream, hold only a handful of ranges.
+/// A short sorted array is searched faster than a tree is traversed, and needs no allocation.
+const INLINE: usize = 4;
+
 /// A set of u64 values optimized for long runs and random insert/delete/contains
 #[derive(Debug, Default, Clone)]
-pub struct RangeSet(BTreeMap<u64, u64>);
+pub struct RangeSet(Repr);
 
 impl RangeSet {
     pub fn new() -> Self {
@@ -30,11 +39,11 @@ impl RangeSet {
                 }
                 Ordering::Equal => {
                     // Extend existing
-                    self.0.remove(&start);
+                    self.0.remove(start);
                     let mut new_end = x + 1;
                     if let Some((next_start, next_end)) = self.succ(x) {
                         if next_start == new_end {
-                            self.0.remove(&next_start);
+                            self.0.remove(next_start);
                             new_end = next_end;
                         }
                     }
@@ -47,7 +56,7 @@ impl RangeSet {
         let mut new_end = x + 1;
         if let Some((next_start, next_end)) = self.succ(x) {
             if next_start == new_end {
-                self.0.remove(&next_start);
+                self.0.remove(next_start);
                 new_end = next_end;
             }
         }
@@ -65,7 +74,7 @@ impl RangeSet {
                 return false;
             } else if end >= x.start {
                 // Extend overlapping predecessor
-                self.0.remove(&start);
+                self.0.remove(start);
                 x.start = start;
             }
         }
@@ -74,7 +83,7 @@ impl RangeSet {
                 break;
             }
             // Overlaps with successor
-            self.0.remove(&next_start);
+            self.0.remove(next_start);
             x.end = cmp::max(next_end, x.end);
         }
         self.0.insert(x.start, x.end);
@@ -83,25 +92,18 @@ impl RangeSet {
 
     /// Find closest range to `x` that begins at or before it
     fn pred(&self, x: u64) -> Option<(u64, u64)> {
-        self.0
-            .range((Included(0), Included(x)))
-            .rev()
-            .next()
-            .map(|(&x, &y)| (x, y))
+        self.0.pred(x)
     }
 
     /// Find the closest range to `x` that begins after it
     fn succ(&self, x: u64) -> Option<(u64, u64)> {
-        self.0
-            .range((Excluded(x), Included(u64::max_value())))
-            .next()
-            .map(|(&x, &y)| (x, y))
+        self.0.succ(x)
     }
 
     pub fn remove(&mut self, x: Range<u64>) -> bool {
         let before = match self.pred(x.start) {
             Some((start, end)) if end > x.start => {
-                self.0.remove(&start);
+                self.0.remove(start);
                 if start < x.start {
                     self.0.insert(start, x.start);
                 }
@@ -122,7 +124,7 @@ impl RangeSet {
                 break;
             }
             after = true;
-            self.0.remove(&start);
+            self.0.remove(start);
             if end > x.end {
                 self.0.insert(x.end, end);
                 break;
@@ -137,7 +139,7 @@ impl RangeSet {
             .pred(range.start)
             .filter(|&(_, end)| end >= range.start)
         {
-            self.0.remove(&prev_start);
+            self.0.remove(prev_start);
             let replaced_start = range.start;
             range.start = range.start.min(prev_start);
             let replaced_end = range.end.min(prev_end);
@@ -157,69 +159,208 @@ impl RangeSet {
         }
     }
 
+    /// Iterate over the ranges intersecting `x`, in ascending order
+    pub fn intersecting(&self, x: Range<u64>) -> impl Iterator<Item = Range<u64>> + '_ {
+        let (pred, rest) = if x.start < x.end {
+            let pred = self
+                .pred(x.start)
+                .filter(|&(start, end)| start < x.start && end > x.start)
+                .map(|(start, end)| start..end);
+            (pred, Some(self.0.range(x.start..x.end)))
+        } else {
+            (None, None)
+        };
+        pred.into_iter().chain(rest.into_iter().flatten())
+    }
+
     pub fn add(&mut self, other: &RangeSet) {
-        for (&start, &end) in &other.0 {
-            self.insert(start..end);
+        for range in other {
+            self.insert(range);
         }
     }
 
     pub fn subtract(&mut self, other: &RangeSet) {
-        for (&start, &end) in &other.0 {
-            self.remove(start..end);
+        for range in other {
+            self.remove(range);
         }
     }
 
     pub fn is_empty(&self) -> bool {
-        self.0.is_empty()
+        self.0.len() == 0
     }
 
     pub fn min(&self) -> Option<u64> {
         self.iter().next().map(|x| x.start)
     }
     pub fn max(&self) -> Option<u64> {
-        self.iter().rev().next().map(|x| x.end - 1)
+        self.iter().next_back().map(|x| x.end - 1)
     }
 
     pub fn len(&self) -> usize {
         self.0.len()
     }
     pub fn iter(&self) -> Iter<'_> {
-        Iter(self.0.iter())
+        self.0.iter()
     }
     pub fn elts(&self) -> EltIter<'_> {
         EltIter {
-            inner: self.0.iter(),
+            inner: self.iter(),
             next: 0,
             end: 0,
         }
     }
 
     pub fn peek_min(&self) -> Option<Range<u64>> {
-        let (&start, &end) = self.0.iter().next()?;
-        Some(start..end)
+        self.iter().peek()
     }
 
     pub fn pop_min(&mut self) -> Option<Range<u64>> {
         let result = self.peek_min()?;
-        self.0.remove(&result.start);
+        self.0.remove(result.start);
         Some(result)
     }
 }
 
-pub struct Iter<'a>(btree_map::Iter<'a, u64, u64>);
+/// Storage for the ranges of a `RangeSet`, as `(start, end)` pairs ordered by `start`
+#[derive(Debug, Clone)]
+enum Repr {
+    /// At most `INLINE` ranges, sorted by start
+    Inline(SmallVec<[(u64, u64); INLINE]>),
+    /// Ranges keyed by start
+    Tree(BTreeMap<u64, u64>),
+}
+
+impl Repr {
+    fn pred(&self, x: u64) -> Option<(u64, u64)> {
+        match *self {
+            Repr::Inline(ref ranges) => ranges.iter().rev().find(|r| r.0 <= x).copied(),
+            Repr::Tree(ref ranges) => ranges
+                .range((Included(0), Included(x)))
+                .next_back()
+                .map(|(&x, &y)| (x, y)),
+        }
+    }
+
+    fn succ(&self, x: u64) -> Option<(u64, u64)> {
+        match *self {
+            Repr::Inline(ref ranges) => ranges.iter().find(|r| r.0 > x).copied(),
+            Repr::Tree(ref ranges) => ranges
+                .range((Excluded(x), Unbounded))
+                .next()
+                .map(|(&x, &y)| (x, y)),
+        }
+    }
+
+    /// Insert the range `start..end`, replacing any range beginning at `start`
+    fn insert(&mut self, start: u64, end: u64) {
+        match *self {
+            Repr::Inline(ref mut ranges) => {
+                let i = ranges.partition_point(|r| r.0 < start);
+                if i < ranges.len() && ranges[i].0 == start {
+                    ranges[i].1 = end;
+                } else if ranges.len() < INLINE {
+                    ranges.insert(i, (start, end));
+                } else {
+                    let mut tree = ranges.iter().copied().collect::<BTreeMap<_, _>>();
+                    tree.insert(start, end);
+                    *self = Repr::Tree(tree);
+                }
+            }
+            Repr::Tree(ref mut ranges) => {
+                ranges.insert(start, end);
+            }
+        }
+    }
+
+    /// Remove the range beginning at `start`, if any
+    fn remove(&mut self, start: u64) {
+        match *self {
+            Repr::Inline(ref mut ranges) => {
+                if let Ok(i) = ranges.binary_search_by_key(&start, |r| r.0) {
+                    ranges.remove(i);
+                }
+            }
+            Repr::Tree(ref mut ranges) => {
+                ranges.remove(&start);
+                // Return to inline storage with some hysteresis, so sets hovering around the
+                // threshold don't convert back and forth on every operation
+                if ranges.len() <= INLINE / 2 {
+                    let inline = ranges.iter().map(|(&x, &y)| (x, y)).collect();
+                    *self = Repr::Inline(inline);
+                }
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        match *self {
+            Repr::Inline(ref ranges) => ranges.len(),
+            Repr::Tree(ref ranges) => ranges.len(),
+        }
+    }
+
+    fn iter(&self) -> Iter<'_> {
+        Iter(match *self {
+            Repr::Inline(ref ranges) => IterInner::Inline(ranges.iter()),
+            Repr::Tree(ref ranges) => IterInner::Tree(ranges.range(..)),
+        })
+    }
+
+    /// Iterate over the ranges beginning within `starts`
+    fn range(&self, starts: Range<u64>) -> Iter<'_> {
+        Iter(match *self {
+            Repr::Inline(ref ranges) => {
+                let first = ranges.partition_point(|r| r.0 < starts.start);
+                let last = ranges.partition_point(|r| r.0 < starts.end);
+                IterInner::Inline(ranges[first..last].iter())
+            }
+            Repr::Tree(ref ranges) => IterInner::Tree(ranges.range(starts)),
+        })
+    }
+}
+
+impl Default for Repr {
+    fn default() -> Self {
+        Repr::Inline(SmallVec::new())
+    }
+}
+
+/// Iterator over the ranges of a `RangeSet`
+#[derive(Clone)]
+pub struct Iter<'a>(IterInner<'a>);
+
+#[derive(Clone)]
+enum IterInner<'a> {
+    Inline(slice::Iter<'a, (u64, u64)>),
+    Tree(btree_map::Range<'a, u64, u64>),
+}
+
+impl Iter<'_> {
+    /// The range that `next` will return, without advancing
+    pub fn peek(&self) -> Option<Range<u64>> {
+        match self.0 {
+            IterInner::Inline(ref iter) => iter.as_slice().first().map(|&(x, y)| x..y),
+            IterInner::Tree(ref iter) => iter.clone().next().map(|(&x, &y)| x..y),
+        }
+    }
+}
 
 impl<'a> Iterator for Iter<'a> {
     type Item = Range<u64>;
     fn next(&mut self) -> Option<Range<u64>> {
-        let (&start, &end) = self.0.next()?;
-        Some(start..end)
+        match self.0 {
+            IterInner::Inline(ref mut iter) => iter.next().map(|&(x, y)| x..y),
+            IterInner::Tree(ref mut iter) => iter.next().map(|(&x, &y)| x..y),
+        }
     }
 }
 
 impl<'a> DoubleEndedIterator for Iter<'a> {
     fn next_back(&mut self) -> Option<Range<u64>> {
-        let (&start, &end) = self.0.next_back()?;
-        Some(start..end)
+        match self.0 {
+            IterInner::Inline(ref mut iter) => iter.next_back().map(|&(x, y)| x..y),
+            IterInner::Tree(ref mut iter) => iter.next_back().map(|(&x, &y)| x..y),
+        }
     }
 }
 
@@ -232,7 +373,7 @@ impl<'a> IntoIterator for &'a RangeSet {
 }
 
 pub struct EltIter<'a> {
-    inner: btree_map::Iter<'a, u64, u64>,
+    inner: Iter<'a>,
     next: u64,
     end: u64,
 }
@@ -241,9 +382,9 @@ impl<'a> Iterator for EltIter<'a> {
     type Item = u64;
     fn next(&mut self) -> Option<u64> {
         if self.next == self.end {
-            let (&start, &end) = self.inner.next()?;
-            self.next = start;
-            self.end = end;
+            let range = self.inner.next()?;
+            self.next = range.start;
+            self.end = range.end;
         }
         let x = self.next;
         self.next += 1;
@@ -254,9 +395,9 @@ impl<'a> Iterator for EltIter<'a> {
 impl<'a> DoubleEndedIterator for EltIter<'a> {
     fn next_back(&mut self) -> Option<u64> {
         if self.next == self.end {
-            let (&start, &end) = self.inner.next_back()?;
-            self.next = start;
-            self.end = end;
+            let range = self.inner.next_back()?;
+            self.next = range.start;
+            self.end = range.end;
         }
         self.end -= 1;
         Some(self.end)
@@ -290,7 +431,7 @@ impl Iterator for Replace<'_> {
             return None;
         }
         // Remove the redundant range...
-        self.set.0.remove(&next_start);
+        self.set.0.remove(next_start);
         // ...and handle the case where the redundant range ends later than the new range.
         let replaced_end = self.range.end.min(next_end);
         self.range.end = self.range.end.max(next_end);
@@ -406,6 +547,18 @@ mod tests {
         assert!(set.is_empty());
     }
 
+    #[test]
+    fn intersecting() {
+        let mut set = RangeSet::new();
+        set.insert(0..2);
+        set.insert(4..6);
+        set.insert(8..10);
+        assert_eq!(set.intersecting(1..5).collect::<Vec<_>>(), &[0..2, 4..6]);
+        assert_eq!(set.intersecting(2..4).collect::<Vec<_>>(), &[]);
+        assert_eq!(set.intersecting(4..10).collect::<Vec<_>>(), &[4..6, 8..10]);
+        assert_eq!(set.intersecting(5..5).collect::<Vec<_>>(), &[]);
+    }
+
     #[test]
     fn replace_contained() {
         let mut set = RangeSet::new();
@@ -470,4 +623,41 @@ mod tests {
         assert!(!set.insert(0..0));
         assert_eq!(set.len(), 0);
     }
+
+    #[test]
+    fn spill_and_return_inline() {
+        let mut set = RangeSet::new();
+        for i in 0..INLINE as u64 + 2 {
+            assert!(set.insert(i * 2..i * 2 + 1));
+        }
+        assert!(matches!(set.0, Repr::Tree(_)));
+        assert_eq!(
+            set.elts().collect::<Vec<_>>(),
+            (0..INLINE as u64 + 2).map(|i| i * 2).collect::<Vec<_>>()
+        );
+        assert_eq!(set.intersecting(3..7).collect::<Vec<_>>(), &[4..5, 6..7]);
+        assert!(set.remove(0..7));
+        assert!(matches!(set.0, Repr::Inline(_)));
+        assert_eq!(set.iter().collect::<Vec<_>>(), &[8..9, 10..11]);
+        assert!(set.insert(9..10));
+        assert_eq!(set.len(), 1);
+        assert_eq!(set.peek_min(), Some(8..11));
+    }
+
+    #[test]
+    fn peek() {
+        for &count in &[2, INLINE as u64 * 2] {
+            let mut set = RangeSet::new();
+            for i in 0..count {
+                set.insert(i * 2..i * 2 + 1);
+            }
+            let mut iter = set.iter();
+            assert_eq!(iter.peek(), Some(0..1));
+            assert_eq!(iter.next(), Some(0..1));
+            assert_eq!(iter.peek(), Some(2..3));
+            assert_eq!(iter.next_back(), Some((count - 1) * 2..(count - 1) * 2 + 1));
+            assert_eq!(iter.by_ref().count() as u64, count - 2);
+            assert_eq!(iter.peek(), None);
+        }
+    }
 }
@@ -0,0 +1,289 @@
+//! Remembering what a client learned about servers, across restarts
+//!
+//! A client that connected to a server before can reconnect faster: a TLS session ticket allows
+//! sending 0-RTT data under the transport parameters the server advertised last time, a token from
+//! a NEW_TOKEN frame lets the server skip address validation, and the round-trip time and
+//! congestion window measured last time spare the new connection from starting cold.
+//!
+//! A [`ResumptionStore`] set as [`ClientConfig::resumption`] collects all of this for every server
+//! a client connects to, keyed by server name, and can be exported as a single blob to be imported
+//! again after a restart.
+//!
+//! [`ClientConfig::resumption`]: crate::generic::ClientConfig::resumption
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, Bytes};
+use thiserror::Error;
+
+use crate::coding::{BufExt, BufMutExt, UnexpectedEnd};
+
+/// What a client learned about a server over its most recent connection
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerState {
+    /// Token from the server's most recent NEW_TOKEN frame, not yet used
+    pub token: Option<Bytes>,
+    /// Smoothed round-trip time when the connection ended
+    pub rtt: Option<Duration>,
+    /// Congestion window when the connection ended, in bytes
+    pub congestion_window: Option<u64>,
+}
+
+/// Resumption state for the servers a client has connected to
+///
+/// When the `rustls` feature is enabled, this can also serve as the session ticket store of a
+/// rustls client configuration, which
+/// [`ClientConfig::resumption_store()`](crate::generic::ClientConfig::resumption_store) sets up.
+#[derive(Default)]
+pub struct ResumptionStore {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Opaque entries from the TLS implementation
+    tls: HashMap<Vec<u8>, Vec<u8>>,
+    servers: HashMap<String, ServerState>,
+}
+
+impl ResumptionStore {
+    /// Create an empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// What's known about the server named `server_name`
+    pub fn server(&self, server_name: &str) -> Option<ServerState> {
+        self.inner.lock().unwrap().servers.get(server_name).cloned()
+    }
+
+    /// Replace what's known about the server named `server_name`
+    pub fn set_server(&self, server_name: &str, state: ServerState) {
+        let inner = &mut *self.inner.lock().unwrap();
+        insert_bounded(&mut inner.servers, server_name.into(), state);
+    }
+
+    /// Serialize the entire store
+    pub fn export(&self) -> Vec<u8> {
+        let inner = self.inner.lock().unwrap();
+        let mut buf = Vec::new();
+        buf.write(VERSION);
+        buf.write_var(inner.tls.len() as u64);
+        for (key, value) in &inner.tls {
+            put_bytes(&mut buf, key);
+            put_bytes(&mut buf, value);
+        }
+        buf.write_var(inner.servers.len() as u64);
+        for (name, state) in &inner.servers {
+            put_bytes(&mut buf, name.as_bytes());
+            match state.token {
+                Some(ref token) => {
+                    buf.write(1u8);
+                    put_bytes(&mut buf, token);
+                }
+                None => buf.write(0u8),
+            }
+            put_optional_var(&mut buf, state.rtt.map(|x| x.as_micros() as u64));
+            put_optional_var(&mut buf, state.congestion_window);
+        }
+        buf
+    }
+
+    /// Deserialize a store from the output of [`export()`](Self::export)
+    pub fn import(mut data: &[u8]) -> Result<Self, InvalidResumptionState> {
+        let buf = &mut data;
+        if buf.get::<u8>()? != VERSION {
+            return Err(InvalidResumptionState);
+        }
+        let mut inner = Inner::default();
+        for _ in 0..buf.get_var()? {
+            let key = get_bytes(buf)?.to_vec();
+            let value = get_bytes(buf)?.to_vec();
+            insert_bounded(&mut inner.tls, key, value);
+        }
+        for _ in 0..buf.get_var()? {
+            let name =
+                String::from_utf8(get_bytes(buf)?.to_vec()).map_err(|_| InvalidResumptionState)?;
+            let token = match buf.get::<u8>()? {
+                0 => None,
+                1 => Some(Bytes::copy_from_slice(get_bytes(buf)?)),
+                _ => return Err(InvalidResumptionState),
+            };
+            let state = ServerState {
+                token,
+                rtt: get_optional_var(buf)?.map(Duration::from_micros),
+                congestion_window: get_optional_var(buf)?,
+            };
+            insert_bounded(&mut inner.servers, name, state);
+        }
+        if buf.has_remaining() {
+            return Err(InvalidResumptionState);
+        }
+        Ok(Self {
+            inner: Mutex::new(inner),
+        })
+    }
+
+    /// Look up what's known about `server_name` for a new connection, consuming its token
+    pub(crate) fn resume(&self, server_name: &str) -> ServerState {
+        let inner = &mut *self.inner.lock().unwrap();
+        match inner.servers.get_mut(server_name) {
+            Some(state) => ServerState {
+                // Tokens are single-use, lest they allow connections to be linked
+                token: state.token.take(),
+                ..state.clone()
+            },
+            None => ServerState::default(),
+        }
+    }
+
+    /// Remember a token from a NEW_TOKEN frame
+    pub(crate) fn set_token(&self, server_name: &str, token: Bytes) {
+        self.update(server_name, |state| state.token = Some(token));
+    }
+
+    /// Remember the path characteristics at the end of a connection
+    pub(crate) fn set_path(&self, server_name: &str, rtt: Duration, congestion_window: u64) {
+        self.update(server_name, |state| {
+            state.rtt = Some(rtt);
+            state.congestion_window = Some(congestion_window);
+        });
+    }
+
+    fn update(&self, server_name: &str, f: impl FnOnce(&mut ServerState)) {
+        let inner = &mut *self.inner.lock().unwrap();
+        if let Some(state) = inner.servers.get_mut(server_name) {
+            f(state);
+            return;
+        }
+        let mut state = ServerState::default();
+        f(&mut state);
+        insert_bounded(&mut inner.servers, server_name.into(), state);
+    }
+}
+
+impl fmt::Debug for ResumptionStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        f.debug_struct("ResumptionStore")
+            .field("tls_entries", &inner.tls.len())
+            .field("servers", &inner.servers.len())
+            .finish()
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl rustls::StoresClientSessions for ResumptionStore {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        let inner = &mut *self.inner.lock().unwrap();
+        insert_bounded(&mut inner.tls, key, value);
+        true
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.inner.lock().unwrap().tls.get(key).cloned()
+    }
+}
+
+/// A client connection's link to the store it resumed from
+pub(crate) struct Resumption {
+    pub(crate) store: Arc<ResumptionStore>,
+    pub(crate) server_name: String,
+}
+
+/// Error returned by [`ResumptionStore::import()`] for data it didn't export
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+#[error("invalid resumption state")]
+pub struct InvalidResumptionState;
+
+impl From<UnexpectedEnd> for InvalidResumptionState {
+    fn from(_: UnexpectedEnd) -> Self {
+        Self
+    }
+}
+
+/// Insert into `map`, evicting an arbitrary entry if it's full
+fn insert_bounded<K, V>(map: &mut HashMap<K, V>, key: K, value: V)
+where
+    K: std::hash::Hash + Eq + Clone,
+{
+    if map.len() >= MAX_ENTRIES && !map.contains_key(&key) {
+        let victim = map.keys().next().cloned().unwrap();
+        map.remove(&victim);
+    }
+    map.insert(key, value);
+}
+
+fn put_bytes(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.write_var(data.len() as u64);
+    buf.put_slice(data);
+}
+
+fn get_bytes<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8], InvalidResumptionState> {
+    let len = buf.get_var()? as usize;
+    if buf.len() < len {
+        return Err(InvalidResumptionState);
+    }
+    let (data, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(data)
+}
+
+fn put_optional_var(buf: &mut Vec<u8>, x: Option<u64>) {
+    // Zero is reserved for `None`
+    buf.write_var(x.map_or(0, |x| x.saturating_add(1)));
+}
+
+fn get_optional_var(buf: &mut &[u8]) -> Result<Option<u64>, InvalidResumptionState> {
+    Ok(buf.get_var()?.checked_sub(1))
+}
+
+/// Format version written by `export`
+const VERSION: u8 = 1;
+
+/// Bound on the number of entries of each kind, so that connecting to many servers can't exhaust
+/// memory
+const MAX_ENTRIES: usize = 256;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn export_import() {
+        let store = ResumptionStore::new();
+        store.set_token("example.com", Bytes::from_static(b"token"));
+        store.set_path("example.com", Duration::from_millis(25), 1_000_000);
+        store.set_path("example.org", Duration::from_millis(80), 14_720);
+        #[cfg(feature = "rustls")]
+        rustls::StoresClientSessions::put(&store, b"key".to_vec(), b"ticket".to_vec());
+
+        let imported = ResumptionStore::import(&store.export()).unwrap();
+        assert_eq!(imported.server("example.com"), store.server("example.com"));
+        assert_eq!(imported.server("example.org"), store.server("example.org"));
+        #[cfg(feature = "rustls")]
+        assert_eq!(
+            rustls::StoresClientSessions::get(&imported, b"key"),
+            Some(b"ticket".to_vec())
+        );
+
+        assert_eq!(
+            imported.resume("example.com").token,
+            Some(Bytes::from_static(b"token"))
+        );
+        assert_eq!(imported.resume("example.com").token, None);
+
+        let mut data = store.export();
+        data.push(0);
+        assert_eq!(
+            ResumptionStore::import(&data).unwrap_err(),
+            InvalidResumptionState
+        );
+        assert!(ResumptionStore::import(&data[..data.len() - 3]).is_err());
+    }
+}
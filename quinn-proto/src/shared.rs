@@ -1,8 +1,8 @@
-use std::{fmt, net::SocketAddr, time::Instant};
+use std::{fmt, net::SocketAddr};
 
 use bytes::{Buf, BufMut, BytesMut};
 
-use crate::{coding::BufExt, packet::PartialDecode, ResetToken, MAX_CID_SIZE};
+use crate::{coding::BufExt, packet::PartialDecode, Instant, ResetToken, MAX_CID_SIZE};
 
 /// Events sent from an Endpoint to a Connection
 #[derive(Debug)]
@@ -17,6 +17,8 @@ pub(crate) enum ConnectionEventInner {
         ecn: Option<EcnCodepoint>,
         first_decode: PartialDecode,
         remaining: Option<BytesMut>,
+        /// Size of the receive buffer backing the datagram
+        allocation_size: usize,
     },
     /// New connection identifiers have been issued for the Connection
     NewIdentifiers(Vec<IssuedCid>, Instant),
@@ -47,6 +49,8 @@ impl EndpointEvent {
 pub(crate) enum EndpointEventInner {
     /// The connection has been drained
     Drained,
+    /// The connection's handshake has completed
+    Established,
     /// The reset token and/or address eligible for generating resets has been updated
     ResetToken(SocketAddr, ResetToken),
     /// The connection needs connection identifiers
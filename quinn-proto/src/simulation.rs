@@ -0,0 +1,531 @@
+//! Deterministic simulation of endpoints communicating over an unreliable network
+//!
+//! A [`Network`] drives any number of [`Endpoint`]s and their connections against a virtual clock,
+//! each through a [`Driver`], carrying datagrams between them over links with configurable delay, jitter, loss, reordering
+//! and bandwidth. Every decision made by the network is drawn from a seeded random number
+//! generator, so a scenario run twice with the same seed sees the same datagrams dropped, delayed
+//! and reordered. This makes it possible to test application protocols built on top of QUIC under
+//! hostile network conditions without flakiness or real timers.
+//!
+//! The network can only be as deterministic as the traffic it carries: endpoints should be given
+//! a [`SeededRandom`] as their [`EndpointConfig::random_source()`], and on bandwidth-limited links timing may still differ
+//! slightly between runs where the cryptographic handshake produces messages of varying length.
+//!
+//! ```ignore
+//! let mut net = Network::new(42);
+//! let server = net.add_node(Endpoint::new(Default::default(), Some(server_config)));
+//! let client = net.add_node(Endpoint::new(Default::default(), None));
+//! net.set_default_link(LinkConfig::default().delay(Duration::from_millis(50)).loss(0.1).clone());
+//! let ch = net.connect(client, server, client_config, "localhost")?;
+//! net.run();
+//! while let Some(event) = net.node_mut(client).poll(ch) {
+//!     // ...
+//! }
+//! ```
+//!
+//! [`Endpoint`]: crate::generic::Endpoint
+//! [`Driver`]: crate::driver::Driver
+//! [`SeededRandom`]: crate::SeededRandom
+//! [`EndpointConfig::random_source()`]: crate::generic::EndpointConfig::random_source
+
+use std::{
+    cmp::{self, Reverse},
+    collections::{BinaryHeap, HashMap, VecDeque},
+    net::{Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tracing::{info_span, trace};
+
+use crate::{
+    config::ClientConfig,
+    connection::Connection,
+    crypto,
+    driver::{Driver, Output},
+    endpoint::Endpoint,
+    ConnectError, ConnectionHandle, EcnCodepoint, Event, Instant,
+};
+
+/// Identifies a node within a [`Network`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct NodeId(usize);
+
+/// Characteristics of a unidirectional link between two nodes
+///
+/// The default is a perfect link, delivering every datagram instantly.
+#[derive(Debug, Clone)]
+pub struct LinkConfig {
+    delay: Duration,
+    jitter: Duration,
+    loss: f64,
+    reorder: f64,
+    bandwidth: Option<u64>,
+}
+
+impl LinkConfig {
+    /// One-way propagation delay applied to every datagram
+    pub fn delay(&mut self, value: Duration) -> &mut Self {
+        self.delay = value;
+        self
+    }
+
+    /// Maximum additional delay, chosen uniformly at random for each datagram
+    ///
+    /// Jitter never causes reordering by itself; datagrams are held back as needed to preserve the
+    /// order in which they were sent.
+    pub fn jitter(&mut self, value: Duration) -> &mut Self {
+        self.jitter = value;
+        self
+    }
+
+    /// Probability in `[0, 1]` that a datagram is dropped
+    pub fn loss(&mut self, value: f64) -> &mut Self {
+        self.loss = value;
+        self
+    }
+
+    /// Probability in `[0, 1]` that a datagram is delayed by an extra round of `delay`, causing it
+    /// to arrive after datagrams sent later
+    pub fn reorder(&mut self, value: f64) -> &mut Self {
+        self.reorder = value;
+        self
+    }
+
+    /// Link capacity in bytes per second, or `None` for unlimited
+    ///
+    /// Datagrams sent faster than the link can carry queue up behind each other.
+    pub fn bandwidth(&mut self, value: Option<u64>) -> &mut Self {
+        self.bandwidth = value;
+        self
+    }
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            loss: 0.0,
+            reorder: 0.0,
+            bandwidth: None,
+        }
+    }
+}
+
+/// A set of simulated endpoints and the links between them
+pub struct Network<S>
+where
+    S: crypto::Session,
+{
+    nodes: Vec<Node<S>>,
+    default_link: LinkConfig,
+    links: HashMap<(NodeId, NodeId), Link>,
+    in_flight: BinaryHeap<Reverse<InFlight>>,
+    /// Tiebreaker preserving send order among datagrams due at the same instant
+    next_seq: u64,
+    rng: StdRng,
+    start: Instant,
+    now: Instant,
+    /// Datagrams sent since recording was enabled
+    recorded: Option<Vec<SentDatagram>>,
+}
+
+impl<S> Network<S>
+where
+    S: crypto::Session,
+{
+    /// Create an empty network whose random decisions are derived from `seed`
+    pub fn new(seed: u64) -> Self {
+        #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+        let now = Instant::now();
+        // Simulated time may as well start at the origin where there's no clock to read
+        #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+        let now = Instant::from_duration_since_origin(Duration::from_secs(0));
+        Self {
+            nodes: Vec::new(),
+            default_link: LinkConfig::default(),
+            links: HashMap::new(),
+            in_flight: BinaryHeap::new(),
+            next_seq: 0,
+            rng: StdRng::seed_from_u64(seed),
+            start: now,
+            now,
+            recorded: None,
+        }
+    }
+
+    /// Add an endpoint to the network, assigning it a unique address
+    pub fn add_node(&mut self, endpoint: Endpoint<S>) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        let ip = Ipv6Addr::from(0xfd00 << 112 | (id.0 as u128 + 1));
+        self.nodes.push(Node {
+            driver: Driver::new(endpoint),
+            addr: SocketAddr::new(ip.into(), 4433),
+            timeout: None,
+            accepted: VecDeque::new(),
+            events: HashMap::new(),
+            inbound: Vec::new(),
+        });
+        id
+    }
+
+    /// Characteristics of links not configured through `set_link`
+    pub fn set_default_link(&mut self, config: LinkConfig) {
+        self.default_link = config;
+    }
+
+    /// Characteristics of the link carrying datagrams from `from` to `to`
+    pub fn set_link(&mut self, from: NodeId, to: NodeId, config: LinkConfig) {
+        self.links.entry((from, to)).or_default().config = Some(config);
+    }
+
+    /// Initiate a connection from `from` to `to`
+    pub fn connect(
+        &mut self,
+        from: NodeId,
+        to: NodeId,
+        config: ClientConfig<S>,
+        server_name: &str,
+    ) -> Result<ConnectionHandle, ConnectError> {
+        let remote = self.nodes[to.0].addr;
+        self.nodes[from.0]
+            .driver
+            .connect(self.now, config, remote, server_name)
+    }
+
+    /// Access a node
+    pub fn node(&self, id: NodeId) -> &Node<S> {
+        &self.nodes[id.0]
+    }
+
+    /// Access a node mutably
+    ///
+    /// Changes made to a node's connections, such as writing to streams, take effect on the next
+    /// call to `step` or `run`.
+    pub fn node_mut(&mut self, id: NodeId) -> &mut Node<S> {
+        &mut self.nodes[id.0]
+    }
+
+    /// Put `contents` on the wire as if `from` had sent it to `destination`
+    ///
+    /// The datagram is subject to the same link conditions as those sent by the node itself.
+    pub fn send_raw(&mut self, from: NodeId, destination: SocketAddr, contents: Vec<u8>) {
+        self.send(from, destination, None, contents);
+    }
+
+    /// Start or stop recording every datagram put on the wire
+    ///
+    /// Starting discards anything recorded previously.
+    pub fn record(&mut self, enabled: bool) {
+        self.recorded = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    /// Datagrams sent since recording was last started, in the order they were sent
+    ///
+    /// Datagrams later lost by their link are included.
+    pub fn recorded(&self) -> &[SentDatagram] {
+        self.recorded.as_deref().unwrap_or(&[])
+    }
+
+    /// The current virtual time
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// Virtual time elapsed since the network was created
+    pub fn elapsed(&self) -> Duration {
+        self.now - self.start
+    }
+
+    /// Process everything due at the current time, then advance the clock to the next event
+    ///
+    /// Returns `false` if nothing remains scheduled: no datagrams are in flight and no connection
+    /// has a timer running.
+    pub fn step(&mut self) -> bool {
+        self.process();
+        match self.next_wakeup() {
+            Some(time) => {
+                self.now = cmp::max(self.now, time);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Run until no datagrams are in flight and every connection is waiting on only its idle and
+    /// keep-alive timers
+    pub fn run(&mut self) {
+        loop {
+            self.process();
+            if self.in_flight.is_empty() && self.nodes.iter().all(Node::is_idle) {
+                return;
+            }
+            match self.next_wakeup() {
+                Some(time) => self.now = cmp::max(self.now, time),
+                None => return,
+            }
+        }
+    }
+
+    /// Run until `duration` of virtual time has passed, processing every event scheduled before then
+    pub fn run_for(&mut self, duration: Duration) {
+        let deadline = self.now + duration;
+        loop {
+            self.process();
+            match self.next_wakeup() {
+                Some(time) if time <= deadline => self.now = cmp::max(self.now, time),
+                _ => break,
+            }
+        }
+        self.now = deadline;
+    }
+
+    fn next_wakeup(&self) -> Option<Instant> {
+        let delivery = self.in_flight.peek().map(|x| x.0.time);
+        let timeout = self.nodes.iter().filter_map(|node| node.timeout).min();
+        match (delivery, timeout) {
+            (Some(x), Some(y)) => Some(cmp::min(x, y)),
+            (x, None) | (None, x) => x,
+        }
+    }
+
+    /// Deliver due datagrams and drive all connections at the current time
+    fn process(&mut self) {
+        while matches!(self.in_flight.peek(), Some(x) if x.0.time <= self.now) {
+            let Reverse(datagram) = self.in_flight.pop().unwrap();
+            self.nodes[datagram.to.0].inbound.push(datagram);
+        }
+
+        for i in 0..self.nodes.len() {
+            let span = info_span!("node", id = i);
+            let _guard = span.enter();
+            let mut outbound = Vec::new();
+            self.nodes[i].drive(self.now, &mut outbound);
+            for (destination, ecn, contents) in outbound {
+                self.send(NodeId(i), destination, ecn, contents);
+            }
+        }
+    }
+
+    /// Put a datagram on the wire
+    fn send(
+        &mut self,
+        from: NodeId,
+        destination: SocketAddr,
+        ecn: Option<EcnCodepoint>,
+        contents: Vec<u8>,
+    ) {
+        if let Some(ref mut recorded) = self.recorded {
+            recorded.push(SentDatagram {
+                from,
+                destination,
+                contents: contents.clone(),
+            });
+        }
+        let to = match self.nodes.iter().position(|node| node.addr == destination) {
+            Some(x) => NodeId(x),
+            None => {
+                trace!(%destination, "dropping datagram to unknown address");
+                return;
+            }
+        };
+        let default_link = &self.default_link;
+        let link = self.links.entry((from, to)).or_default();
+        let config = link.config.as_ref().unwrap_or(default_link);
+
+        // Serialization onto the link happens whether or not the datagram survives the trip
+        let now = self.now;
+        let mut departure = link.busy_until.map_or(now, |x| cmp::max(x, now));
+        if let Some(bandwidth) = config.bandwidth {
+            departure += Duration::from_secs_f64(contents.len() as f64 / bandwidth as f64);
+            link.busy_until = Some(departure);
+        }
+        if config.loss > 0.0 && self.rng.gen_bool(config.loss) {
+            trace!(len = contents.len(), "datagram lost");
+            return;
+        }
+        let mut arrival = departure + config.delay;
+        if config.jitter > Duration::from_millis(0) {
+            arrival += self.rng.gen_range(Duration::from_millis(0)..=config.jitter);
+        }
+        // Keep jitter from reordering datagrams; only deliberately reordered ones skip ahead
+        if let Some(last) = link.last_arrival {
+            arrival = cmp::max(arrival, last);
+        }
+        link.last_arrival = Some(arrival);
+        if config.reorder > 0.0 && self.rng.gen_bool(config.reorder) {
+            trace!(len = contents.len(), "datagram reordered");
+            arrival += cmp::max(config.delay, Duration::from_millis(1));
+        }
+
+        self.in_flight.push(Reverse(InFlight {
+            time: arrival,
+            seq: self.next_seq,
+            from: self.nodes[from.0].addr,
+            to,
+            ecn,
+            contents,
+        }));
+        self.next_seq += 1;
+    }
+}
+
+/// A datagram recorded by a [`Network`]
+#[derive(Debug, Clone)]
+pub struct SentDatagram {
+    /// The node that sent the datagram
+    pub from: NodeId,
+    /// The address the datagram was sent to
+    pub destination: SocketAddr,
+    /// Contents of the datagram
+    pub contents: Vec<u8>,
+}
+
+/// An endpoint attached to a [`Network`], together with its connections
+pub struct Node<S>
+where
+    S: crypto::Session,
+{
+    driver: Driver<S>,
+    addr: SocketAddr,
+    /// The deadline last reported by `driver`
+    timeout: Option<Instant>,
+    accepted: VecDeque<ConnectionHandle>,
+    /// Events yielded by `driver` that have yet to be taken with `poll`
+    events: HashMap<ConnectionHandle, VecDeque<Event>>,
+    inbound: Vec<InFlight>,
+}
+
+impl<S> Node<S>
+where
+    S: crypto::Session,
+{
+    /// The address other nodes reach this node at
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The node's endpoint
+    pub fn endpoint(&self) -> &Endpoint<S> {
+        self.driver.endpoint()
+    }
+
+    /// The node's endpoint, mutably
+    pub fn endpoint_mut(&mut self) -> &mut Endpoint<S> {
+        self.driver.endpoint_mut()
+    }
+
+    /// Take the oldest connection initiated by a peer that has not been accepted yet
+    pub fn accept(&mut self) -> Option<ConnectionHandle> {
+        self.accepted.pop_front()
+    }
+
+    /// Take the oldest event on `ch` that has not been returned yet
+    ///
+    /// Events remain available after the connection is drained.
+    pub fn poll(&mut self, ch: ConnectionHandle) -> Option<Event> {
+        self.events.get_mut(&ch)?.pop_front()
+    }
+
+    /// Look up a connection, whether initiated or accepted by this node
+    pub fn connection(&self, ch: ConnectionHandle) -> Option<&Connection<S>> {
+        self.driver.connection(ch)
+    }
+
+    /// Look up a connection mutably
+    pub fn connection_mut(&mut self, ch: ConnectionHandle) -> Option<&mut Connection<S>> {
+        self.driver.connection_mut(ch)
+    }
+
+    /// Handles of all of the node's connections that have not yet been drained
+    pub fn connections(&self) -> impl Iterator<Item = ConnectionHandle> + '_ {
+        self.driver.connections()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.driver
+            .connections()
+            .filter_map(|ch| self.driver.connection(ch))
+            .all(|x| x.is_idle())
+    }
+
+    fn drive(
+        &mut self,
+        now: Instant,
+        outbound: &mut Vec<(SocketAddr, Option<EcnCodepoint>, Vec<u8>)>,
+    ) {
+        for datagram in self.inbound.drain(..) {
+            let data = BytesMut::from(&datagram.contents[..]);
+            self.driver
+                .handle(now, datagram.from, None, datagram.ecn, data);
+        }
+        if matches!(self.timeout, Some(x) if x <= now) {
+            self.driver.handle_timeout(now);
+        }
+
+        while let Some(output) = self.driver.poll(now) {
+            match output {
+                Output::Transmit(x) => split(x, outbound),
+                Output::Timeout(x) => self.timeout = x,
+                Output::NewConnection(ch) => self.accepted.push_back(ch),
+                Output::Event { connection, event } => {
+                    self.events.entry(connection).or_default().push_back(event);
+                }
+                Output::Drained(_) => {}
+            }
+        }
+    }
+}
+
+/// Break a possibly segmented transmit into individual datagrams
+fn split(
+    transmit: crate::Transmit,
+    outbound: &mut Vec<(SocketAddr, Option<EcnCodepoint>, Vec<u8>)>,
+) {
+    let segment_size = transmit.segment_size.unwrap_or(transmit.contents.len());
+    for segment in transmit.contents.chunks(cmp::max(segment_size, 1)) {
+        outbound.push((transmit.destination, transmit.ecn, segment.to_vec()));
+    }
+}
+
+/// State of a unidirectional link
+#[derive(Default)]
+struct Link {
+    /// Overrides the network's default configuration, if set
+    config: Option<LinkConfig>,
+    /// When the link finishes transmitting the last datagram queued on it
+    busy_until: Option<Instant>,
+    /// Arrival time of the last datagram sent over the link that wasn't reordered
+    last_arrival: Option<Instant>,
+}
+
+struct InFlight {
+    time: Instant,
+    seq: u64,
+    from: SocketAddr,
+    to: NodeId,
+    ecn: Option<EcnCodepoint>,
+    contents: Vec<u8>,
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &Self) -> bool {
+        (self.time, self.seq) == (other.time, other.seq)
+    }
+}
+
+impl Eq for InFlight {}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InFlight {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        (self.time, self.seq).cmp(&(other.time, other.seq))
+    }
+}
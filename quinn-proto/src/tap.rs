@@ -0,0 +1,91 @@
+//! Observation of decrypted packet contents, for protocol debugging
+//!
+//! A [`PacketTap`] registered through [`TransportConfig::packet_tap()`] or
+//! [`Connection::set_packet_tap()`] sees the plaintext payload of every packet a connection sends
+//! or successfully decrypts, and can decode it into frames. Taps run synchronously on the
+//! connection's critical path, so should be kept cheap.
+//!
+//! [`TransportConfig::packet_tap()`]: crate::TransportConfig::packet_tap
+//! [`Connection::set_packet_tap()`]: crate::generic::Connection::set_packet_tap
+
+use std::fmt;
+
+use bytes::Bytes;
+
+use crate::{frame, shared::ConnectionId};
+
+pub use crate::packet::SpaceId;
+
+/// Receives the decrypted contents of a connection's packets
+///
+/// Implemented for closures with a compatible signature.
+pub trait PacketTap: Send + Sync {
+    /// Called for each packet, after it is encrypted for sending or decrypted on receipt
+    fn packet(&self, packet: &TappedPacket<'_>);
+}
+
+impl<F> PacketTap for F
+where
+    F: Fn(&TappedPacket<'_>) + Send + Sync,
+{
+    fn packet(&self, packet: &TappedPacket<'_>) {
+        self(packet)
+    }
+}
+
+/// Whether a tapped packet was sent or received
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Direction {
+    /// The packet was sent by the connection the tap is registered on
+    Sent,
+    /// The packet was received from the peer
+    Received,
+}
+
+/// A packet observed by a [`PacketTap`]
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct TappedPacket<'a> {
+    /// Whether the packet was sent or received
+    pub direction: Direction,
+    /// Destination CID of the client's first Initial packet, identifying the connection
+    pub initial_dst_cid: ConnectionId,
+    /// Packet number space the packet belongs to
+    pub space: SpaceId,
+    /// Packet number
+    pub number: u64,
+    /// Plaintext payload, consisting of a sequence of frames
+    pub payload: &'a [u8],
+}
+
+impl TappedPacket<'_> {
+    /// Decode the frames in the payload
+    ///
+    /// Decoding stops after the first malformed frame, which is reported as such.
+    pub fn frames(&self) -> impl Iterator<Item = TappedFrame> {
+        frame::Iter::new(Bytes::copy_from_slice(self.payload)).map(TappedFrame)
+    }
+}
+
+/// A decoded frame
+///
+/// The `Debug` representation describes the frame's complete contents.
+pub struct TappedFrame(frame::Frame);
+
+impl TappedFrame {
+    /// The frame's type, as named in the QUIC specification, e.g. `"ACK"`
+    pub fn type_name(&self) -> String {
+        self.0.ty().to_string()
+    }
+
+    /// Whether this is a PADDING frame
+    pub fn is_padding(&self) -> bool {
+        matches!(self.0, frame::Frame::Padding)
+    }
+}
+
+impl fmt::Debug for TappedFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
@@ -1,11 +1,12 @@
 use std::{
+    io, iter,
     net::{Ipv4Addr, Ipv6Addr, SocketAddr},
-    sync::Arc,
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 
 use assert_matches::assert_matches;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use hex_literal::hex;
 use rand::RngCore;
 use ring::hmac;
@@ -24,14 +25,10 @@ fn version_negotiate_server() {
     let client_addr = "[::2]:7890".parse().unwrap();
     let mut server = Endpoint::new(Default::default(), Some(Arc::new(server_config())));
     let now = Instant::now();
-    let event = server.handle(
-        now,
-        client_addr,
-        None,
-        None,
-        // Long-header packet with reserved version number
-        hex!("80 0a1a2a3a 04 00000000 04 00000000 00")[..].into(),
-    );
+    // Long-header packet with reserved version number, padded to avoid amplification
+    let mut packet = hex!("80 0a1a2a3a 04 00000000 04 00000000 00")[..].to_vec();
+    packet.resize(MIN_INITIAL_SIZE, 0);
+    let event = server.handle(now, client_addr, None, None, packet[..].into());
     assert!(event.is_none());
 
     let io = server.poll_transmit();
@@ -42,6 +39,292 @@ fn version_negotiate_server() {
         assert!(contents[15..].chunks(4).any(is_supported_version));
     }
     assert_matches!(server.poll_transmit(), None);
+    assert_eq!(server.stats().version_negotiations_sent, 1);
+}
+
+#[test]
+fn dropped_datagram_reasons() {
+    let _guard = subscribe();
+    let client_addr = "[::2]:7890".parse().unwrap();
+    let mut server = Endpoint::new(Default::default(), Some(Arc::new(server_config())));
+    let now = Instant::now();
+    let mut handle = |data: &[u8]| {
+        assert!(server
+            .handle(now, client_addr, None, None, data.into())
+            .is_none());
+    };
+    // Too short to answer with version negotiation
+    handle(&hex!("80 0a1a2a3a 04 00000000 04 00000000 00"));
+    // Truncated long header
+    handle(&hex!("c0 00000001 04"));
+    // Short-header packets for unknown connections, too small to be reset, and large enough
+    handle(&hex!("40 0102030405060708 00"));
+    handle(&[0x40; 64]);
+    let stats = server.stats();
+    assert_eq!(stats.dropped.anti_amplification, 1);
+    assert_eq!(stats.dropped.malformed_header, 1);
+    assert_eq!(stats.dropped.unknown_connection, 2);
+    assert_eq!(stats.stateless_resets_suppressed, 1);
+    assert_eq!(stats.stateless_resets_sent, 1);
+    assert_eq!(stats.version_negotiations_sent, 0);
+}
+
+/// Endpoints sharing an RNG seed choose the same initial destination CID
+#[test]
+fn seeded_rng() {
+    let _guard = subscribe();
+    let server_addr = "[::2]:7890".parse().unwrap();
+    let now = Instant::now();
+    let initial_dcid = |seed| {
+        let mut config = EndpointConfig::default();
+        config.random_source(Arc::new(SeededRandom::new(seed)));
+        let mut client = Endpoint::new(Arc::new(config), None);
+        let (_, mut conn) = client
+            .connect_at(now, client_config(), server_addr, "localhost")
+            .unwrap();
+        let transmit = conn.poll_transmit(now).unwrap();
+        // First byte, version, and DCID length precede the DCID
+        transmit.contents[6..6 + MAX_CID_SIZE].to_vec()
+    };
+    assert_eq!(initial_dcid([1; 32]), initial_dcid([1; 32]));
+    assert_ne!(initial_dcid([1; 32]), initial_dcid([2; 32]));
+}
+
+/// `Endpoint::connect` takes the time from the configured clock
+#[test]
+fn configured_clock() {
+    struct Fixed(Instant);
+    impl Clock for Fixed {
+        fn now(&self) -> Instant {
+            self.0
+        }
+    }
+
+    let _guard = subscribe();
+    let server_addr = "[::2]:7890".parse().unwrap();
+    let now = Instant::now() + Duration::from_secs(3600);
+    let mut config = EndpointConfig::default();
+    config.clock(Arc::new(Fixed(now)));
+    let mut client = Endpoint::new(Arc::new(config), None);
+    let (_, mut conn) = client
+        .connect(client_config(), server_addr, "localhost")
+        .unwrap();
+    let (_, mut reference) = client
+        .connect_at(now, client_config(), server_addr, "localhost")
+        .unwrap();
+    conn.poll_transmit(now).unwrap();
+    reference.poll_transmit(now).unwrap();
+    assert_eq!(conn.poll_timeout(), reference.poll_timeout());
+}
+
+#[cfg(feature = "tap")]
+#[test]
+fn packet_tap() {
+    use crate::tap::{Direction, SpaceId, TappedPacket};
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let sink = seen.clone();
+    pair.server_conn_mut(server_ch)
+        .set_packet_tap(Some(Arc::new(move |packet: &TappedPacket<'_>| {
+            let frames = packet
+                .frames()
+                .filter(|x| !x.is_padding())
+                .map(|x| x.type_name())
+                .collect::<Vec<_>>();
+            sink.lock()
+                .unwrap()
+                .push((packet.direction, packet.space, frames));
+        })));
+    pair.client_conn_mut(client_ch).ping();
+    pair.drive();
+
+    let seen = seen.lock().unwrap();
+    assert!(seen
+        .iter()
+        .any(|(dir, space, frames)| *dir == Direction::Received
+            && *space == SpaceId::Data
+            && frames.iter().any(|x| x == "PING")));
+    assert!(seen
+        .iter()
+        .any(|(dir, _, frames)| *dir == Direction::Sent && frames.iter().any(|x| x == "ACK")));
+}
+
+#[cfg(feature = "conformance")]
+#[test]
+fn conformance_suite() {
+    use crate::conformance::{self, Frames, Harness};
+    let _guard = subscribe();
+    let setup = || {
+        Harness::new(
+            0,
+            Endpoint::new(Default::default(), None),
+            Endpoint::new(Default::default(), Some(Arc::new(server_config()))),
+            client_config(),
+            "localhost",
+        )
+        .unwrap()
+    };
+
+    // Injecting valid frames is harmless
+    let mut harness = setup();
+    assert!(harness.handshake());
+    harness
+        .inject(Side::Client, Frames::new().ping().padding(10).build())
+        .unwrap();
+    assert_matches!(harness.error(Side::Server), None);
+    assert_eq!(
+        harness.inject(Side::Client, Frames::new().padding(1001).build()),
+        Err(conformance::FramesTooLarge)
+    );
+
+    let results = conformance::run_all(setup);
+    assert_eq!(
+        results.len(),
+        conformance::checks::<crypto::rustls::TlsSession>().len()
+    );
+    for (name, result) in results {
+        if let Err(e) = result {
+            panic!("{}: {}", name, e);
+        }
+    }
+}
+
+#[cfg(feature = "simulation")]
+#[test]
+fn simulated_lossy_network() {
+    use crate::simulation::{LinkConfig, Network};
+    let _guard = subscribe();
+
+    /// Upload a message from each of two clients to a shared server, returning how long it took
+    /// and how many packets were lost
+    fn run(seed: u64) -> (Duration, u64) {
+        let endpoint_config = |n| {
+            let mut config = EndpointConfig::default();
+            config.random_source(Arc::new(SeededRandom::new([n; 32])));
+            Arc::new(config)
+        };
+        let mut net = Network::new(seed);
+        let server = net.add_node(Endpoint::new(
+            endpoint_config(0),
+            Some(Arc::new(server_config())),
+        ));
+        let clients = [
+            net.add_node(Endpoint::new(endpoint_config(1), None)),
+            net.add_node(Endpoint::new(endpoint_config(2), None)),
+        ];
+        net.set_default_link(
+            LinkConfig::default()
+                .delay(Duration::from_millis(20))
+                .jitter(Duration::from_millis(5))
+                .loss(0.05)
+                .reorder(0.05)
+                .clone(),
+        );
+
+        const MSG: &[u8] = &[0xab; 50_000];
+        let mut handles = Vec::new();
+        for &client in &clients {
+            let ch = net
+                .connect(client, server, client_config(), "localhost")
+                .unwrap();
+            handles.push(ch);
+        }
+        net.run();
+        for (&client, &ch) in clients.iter().zip(&handles) {
+            let node = net.node_mut(client);
+            assert_matches!(node.poll(ch), Some(Event::HandshakeDataReady));
+            assert_matches!(node.poll(ch), Some(Event::Connected));
+            let conn = node.connection_mut(ch).unwrap();
+            let s = conn.open(Dir::Uni).unwrap();
+            conn.write(s, MSG).unwrap();
+            conn.finish(s).unwrap();
+        }
+        net.run();
+
+        let mut received = Vec::new();
+        while let Some(ch) = net.node_mut(server).accept() {
+            let conn = net.node_mut(server).connection_mut(ch).unwrap();
+            let s = conn.accept(Dir::Uni).unwrap();
+            let mut data = Vec::new();
+            while let Some(chunk) = conn.read(s, usize::MAX, true).unwrap() {
+                data.extend_from_slice(&chunk.bytes);
+            }
+            received.push(data);
+        }
+        let lost = clients
+            .iter()
+            .zip(&handles)
+            .map(|(&client, &ch)| {
+                let conn = net.node_mut(client).connection_mut(ch).unwrap();
+                conn.stats().path.lost_packets
+            })
+            .sum();
+        assert_eq!(received, vec![MSG.to_vec(); 2]);
+        (net.elapsed(), lost)
+    }
+
+    let first = run(1);
+    assert!(first.1 > 0, "no packets were lost");
+    assert_eq!(first, run(1));
+}
+
+#[test]
+fn qlog() {
+    let _guard = subscribe();
+
+    #[derive(Clone, Default)]
+    struct Trace(Arc<Mutex<Vec<u8>>>);
+    impl io::Write for Trace {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let trace = Trace::default();
+    let sink = trace.clone();
+    let mut transport = TransportConfig::default();
+    transport.qlog_factory(Some(Arc::new(move |_: Side, _: &ConnectionId| {
+        Some(Box::new(sink.clone()) as Box<dyn io::Write + Send>)
+    })));
+    let mut config = client_config();
+    config.transport = Arc::new(transport);
+
+    let mut pair = Pair::default();
+    let client_ch = pair.begin_connect(config);
+    pair.drive();
+    pair.server.assert_accept();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::HandshakeDataReady)
+    );
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::Connected { .. })
+    );
+
+    let trace = String::from_utf8(trace.0.lock().unwrap().clone()).unwrap();
+    let records = trace.split('\x1e').skip(1).collect::<Vec<_>>();
+    assert!(records[0].contains("\"vantage_point\":{\"type\":\"client\"}"));
+    assert!(records
+        .iter()
+        .all(|x| x.starts_with('{') && x.ends_with("}\n")));
+    for needle in &[
+        "\"owner\":\"local\"",
+        "\"owner\":\"remote\"",
+        "\"name\":\"transport:packet_sent\",\"data\":{\"header\":{\"packet_type\":\"initial\"",
+        "\"name\":\"transport:packet_received\",\"data\":{\"header\":{\"packet_type\":\"handshake\"",
+        "\"packet_type\":\"1RTT\"",
+        "\"name\":\"recovery:metrics_updated\"",
+    ] {
+        assert!(records.iter().any(|x| x.contains(needle)), "{} missing", needle);
+    }
 }
 
 #[test]
@@ -73,7 +356,7 @@ fn version_negotiate_client() {
         )[..]
             .into(),
     );
-    if let Some((_, DatagramEvent::ConnectionEvent(event))) = opt_event {
+    if let Some(DatagramEvent::ConnectionEvent(_, event)) = opt_event {
         client_conn.handle_event(event);
     }
     assert_matches!(
@@ -122,7 +405,144 @@ fn stateless_retry() {
             ..server_config()
         },
     );
-    pair.connect();
+    let (_, server_ch) = pair.connect();
+    assert_eq!(
+        pair.server_conn_mut(server_ch)
+            .path_info()
+            .address_validation,
+        AddressValidation::Retry
+    );
+    let stats = pair.server.stats();
+    assert_eq!(stats.retries_sent, 1);
+    assert_eq!(stats.accepted_connections, 1);
+    assert_eq!(stats.refused_connections, 0);
+    assert_eq!(stats.connections, 1);
+    assert_eq!(stats.handshaking, 0);
+    assert_eq!(pair.client.stats().handshaking, 0);
+}
+
+#[test]
+fn incoming_triage() {
+    let _guard = subscribe();
+    let mut server_config = server_config();
+    Arc::get_mut(&mut server_config.crypto)
+        .unwrap()
+        .set_protocols(&["foo".into(), "bar".into()]);
+    let mut pair = Pair::new(Default::default(), server_config);
+    pair.server.manual_accept = true;
+    let mut client_config = client_config();
+    Arc::get_mut(&mut client_config.crypto)
+        .unwrap()
+        .set_protocols(&["bar".into()]);
+    let client_ch = pair.begin_connect(client_config);
+    pair.drive_client();
+    pair.drive_server();
+
+    let incoming = pair.server.incoming.pop_front().unwrap();
+    assert_eq!(incoming.remote_address(), pair.client.addr);
+    assert!(!incoming.remote_address_validated());
+    assert_eq!(incoming.server_name(), Some("localhost"));
+    assert_eq!(incoming.alpn_protocols(), Some(&[b"bar".to_vec()][..]));
+    pair.server.endpoint.retry(incoming).unwrap();
+    pair.drive_server();
+    pair.drive_client();
+    pair.drive_server();
+
+    let incoming = pair.server.incoming.pop_front().unwrap();
+    assert!(incoming.remote_address_validated());
+    assert_eq!(incoming.server_name(), Some("localhost"));
+    let incoming = pair
+        .server
+        .endpoint
+        .retry(incoming)
+        .unwrap_err()
+        .into_incoming();
+
+    // Retransmissions are held for the connection until it's accepted
+    pair.time = pair.client.next_wakeup().unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    assert!(pair.server.incoming.is_empty());
+
+    let (server_ch, server_conn) = pair.server.endpoint.accept(incoming, pair.time).unwrap();
+    pair.server.connections.insert(server_ch, server_conn);
+    pair.drive();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::HandshakeDataReady)
+    );
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::Connected)
+    );
+    let stats = pair.server.stats();
+    assert_eq!(stats.retries_sent, 1);
+    assert_eq!(stats.accepted_connections, 1);
+    assert_eq!(stats.refused_connections, 0);
+}
+
+#[test]
+fn incoming_refuse() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    pair.server.manual_accept = true;
+    let client_ch = pair.begin_connect(client_config());
+    pair.drive_client();
+    pair.drive_server();
+
+    let incoming = pair.server.incoming.pop_front().unwrap();
+    pair.server.endpoint.refuse(incoming);
+    pair.drive();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::ConnectionLost {
+            reason: ConnectionError::ConnectionClosed(frame::ConnectionClose {
+                error_code: TransportErrorCode::CONNECTION_REFUSED,
+                ..
+            }),
+        })
+    );
+    assert!(pair.server.incoming.is_empty());
+    assert_eq!(pair.server.known_connections(), 0);
+    let stats = pair.server.stats();
+    assert_eq!(stats.refused_connections, 1);
+    assert_eq!(stats.accepted_connections, 0);
+}
+
+#[test]
+fn accept_with_transport() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    pair.server.manual_accept = true;
+    let client_ch = pair.begin_connect(client_config());
+    pair.drive_client();
+    pair.drive_server();
+
+    let incoming = pair.server.incoming.pop_front().unwrap();
+    let transport = Arc::new(TransportConfig {
+        max_concurrent_bidi_streams: 1u32.into(),
+        ..TransportConfig::default()
+    });
+    let (server_ch, server_conn) = pair
+        .server
+        .endpoint
+        .accept_with(incoming, pair.time, transport)
+        .unwrap();
+    pair.server.connections.insert(server_ch, server_conn);
+    pair.drive();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::HandshakeDataReady)
+    );
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::Connected)
+    );
+    assert!(pair.client_conn_mut(client_ch).open(Dir::Bi).is_some());
+    assert!(pair.client_conn_mut(client_ch).open(Dir::Bi).is_none());
+    // Limits not overridden are unaffected
+    assert!(pair.client_conn_mut(client_ch).open(Dir::Uni).is_some());
+    assert!(pair.client_conn_mut(client_ch).open(Dir::Uni).is_some());
 }
 
 #[test]
@@ -152,6 +572,9 @@ fn server_stateless_reset() {
             reason: ConnectionError::Reset
         })
     );
+    let stats = pair.server.stats();
+    assert!(stats.stateless_resets_sent > 0);
+    assert!(stats.dropped.unknown_connection >= stats.stateless_resets_sent);
 }
 
 #[test]
@@ -383,6 +806,47 @@ fn congestion() {
         .unwrap();
 }
 
+#[test]
+fn ecn_congestion_experienced() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+    assert!(pair.client_conn_mut(client_ch).using_ecn());
+
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_ch)
+        .write(s, &[42; 1024])
+        .unwrap();
+    pair.drive_client();
+    // Simulate a congested router marking everything in flight
+    for (_, ecn, _) in pair.server.inbound.iter_mut() {
+        *ecn = Some(EcnCodepoint::CE);
+    }
+    pair.drive();
+
+    let server = pair.server_conn_mut(server_ch).stats();
+    assert!(server.path.ce_received > 0);
+    let client = pair.client_conn_mut(client_ch).stats();
+    assert_eq!(client.path.ce_reported, server.path.ce_received);
+    assert_eq!(client.path.ecn_congestion_events, 1);
+    assert_eq!(client.path.congestion_events, 0);
+}
+
+#[test]
+fn app_limited_time() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, _) = pair.connect();
+    pair.drive();
+    pair.time += Duration::from_millis(10);
+    pair.client_conn_mut(client_ch).ping();
+    pair.drive();
+    let stats = pair.client_conn_mut(client_ch).stats();
+    assert!(stats.path.app_limited >= Duration::from_millis(10));
+    assert_eq!(stats.path.congestion_limited, Duration::from_secs(0));
+    assert_eq!(stats.path.flow_control_limited, Duration::from_secs(0));
+}
+
 #[allow(clippy::field_reassign_with_default)] // https://github.com/rust-lang/rust-clippy/issues/6527
 #[test]
 fn high_latency_handshake() {
@@ -439,6 +903,133 @@ fn zero_rtt_happypath() {
     assert_eq!(pair.client_conn_mut(client_ch).lost_packets(), 0);
 }
 
+#[test]
+fn poll_transmits_batch() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, _) = pair.connect();
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_ch)
+        .write(s, &[0xab; 8000])
+        .unwrap();
+
+    let now = pair.time;
+    let conn = pair.client_conn_mut(client_ch);
+    let mut transmits = Vec::new();
+    assert_eq!(conn.poll_transmits(now, &mut transmits, 2), 2);
+    assert_eq!(transmits.len(), 2);
+    let rest = conn.poll_transmits(now, &mut transmits, usize::MAX);
+    assert!(rest > 0);
+    assert_eq!(transmits.len(), 2 + rest);
+    assert_eq!(conn.poll_transmits(now, &mut transmits, usize::MAX), 0);
+    assert!(conn.poll_transmit(now).is_none());
+}
+
+#[test]
+fn zero_rtt_read_before_handshake() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let config = client_config();
+
+    // Establish normal connection
+    let client_ch = pair.begin_connect(config.clone());
+    pair.drive();
+    pair.server.assert_accept();
+    pair.client
+        .connections
+        .get_mut(&client_ch)
+        .unwrap()
+        .close(pair.time, VarInt(0), [][..].into());
+    pair.drive();
+
+    pair.client.addr = SocketAddr::new(
+        Ipv6Addr::LOCALHOST.into(),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+    );
+    info!("resuming session");
+    let client_ch = pair.begin_connect(config);
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    const MSG: &[u8] = b"Hello, 0-RTT!";
+    pair.client_conn_mut(client_ch).write(s, MSG).unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    let server_ch = pair.server.assert_accept();
+    assert!(pair.server_conn_mut(server_ch).is_handshaking());
+    assert_eq!(pair.server_conn_mut(server_ch).accept(Dir::Uni), Some(s));
+    assert_matches!(
+        pair.server_conn_mut(server_ch).read(s, usize::MAX, false),
+        Ok(Some(chunk)) if chunk.offset == 0 && chunk.bytes == MSG
+    );
+    assert_eq!(pair.server_conn_mut(server_ch).received_0rtt(s), Ok(true));
+
+    pair.drive();
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_ch).write(s, MSG).unwrap();
+    pair.drive();
+    assert_eq!(pair.server_conn_mut(server_ch).accept(Dir::Uni), Some(s));
+    assert_eq!(pair.server_conn_mut(server_ch).received_0rtt(s), Ok(false));
+}
+
+#[test]
+fn resumed_window_capped() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let store = Arc::new(ResumptionStore::new());
+    store.set_server(
+        "localhost",
+        ServerState {
+            congestion_window: Some(u64::MAX),
+            ..ServerState::default()
+        },
+    );
+    let mut config = client_config();
+    config.resumption_store(store);
+    let client_ch = pair.begin_connect(config);
+    let factory = Arc::new(congestion::NewRenoConfig::default());
+    let initial_window = congestion::ControllerFactory::build(&factory, pair.time).initial_window();
+    let cwnd = pair.client_conn_mut(client_ch).stats().path.cwnd;
+    assert!(cwnd > initial_window);
+    assert!(cwnd <= 10 * initial_window);
+}
+
+#[test]
+fn resumption_across_restart() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let store = Arc::new(ResumptionStore::new());
+    let mut config = client_config();
+    config.resumption_store(store.clone());
+
+    let client_ch = pair.begin_connect(config);
+    pair.drive();
+    pair.server.assert_accept();
+    pair.client
+        .connections
+        .get_mut(&client_ch)
+        .unwrap()
+        .close(pair.time, VarInt(0), [][..].into());
+    pair.drive();
+    let state = store.server("localhost").unwrap();
+    let rtt = state.rtt.unwrap();
+    assert!(state.congestion_window.is_some());
+
+    info!("restarting");
+    let store = Arc::new(ResumptionStore::import(&store.export()).unwrap());
+    let mut config = client_config();
+    config.resumption_store(store);
+    pair.client.addr = SocketAddr::new(
+        Ipv6Addr::LOCALHOST.into(),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+    );
+    let client_ch = pair.begin_connect(config);
+    assert_eq!(pair.client_conn_mut(client_ch).rtt(), rtt);
+    assert!(pair.client_conn_mut(client_ch).has_0rtt());
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_ch).write(s, b"hi").unwrap();
+    pair.drive();
+    assert!(pair.client_conn_mut(client_ch).accepted_0rtt());
+}
+
 #[test]
 fn zero_rtt_rejection() {
     let _guard = subscribe();
@@ -491,6 +1082,10 @@ fn zero_rtt_rejection() {
     pair.client_conn_mut(client_ch).write(s, MSG).unwrap();
     pair.drive();
     assert!(!pair.client_conn_mut(client_ch).accepted_0rtt());
+    assert!(
+        std::iter::from_fn(|| pair.client_conn_mut(client_ch).poll())
+            .any(|x| matches!(x, Event::ZeroRttRejected))
+    );
     let server_conn = pair.server.assert_accept();
     assert_matches!(
         pair.server_conn_mut(server_conn).poll(),
@@ -543,6 +1138,19 @@ fn alpn_success() {
         .handshake_data()
         .unwrap();
     assert_eq!(hd.protocol.unwrap(), &b"bar"[..]);
+    assert_eq!(hd.server_name.as_deref(), Some("localhost"));
+    assert_eq!(hd.tls_version, Some(0x0304));
+    assert!(hd.cipher_suite.is_some());
+    assert_eq!(hd.peer_certificates.unwrap().iter().count(), 1);
+
+    // Servers see no certificates from clients that weren't asked to authenticate
+    let hd = pair
+        .server_conn_mut(server_conn)
+        .crypto_session()
+        .handshake_data()
+        .unwrap();
+    assert_eq!(hd.server_name.as_deref(), Some("localhost"));
+    assert!(hd.peer_certificates.is_none());
 }
 
 #[test]
@@ -599,6 +1207,111 @@ fn alpn_mismatch() {
     );
 }
 
+#[test]
+fn alpn_selector() {
+    let _guard = subscribe();
+    let mut server_config = server_config();
+    Arc::get_mut(&mut server_config.crypto)
+        .unwrap()
+        .set_protocols(&["foo".into(), "bar".into()]);
+    server_config.alpn_selector(|server_name, offered| {
+        assert_eq!(server_name, Some("localhost"));
+        // Reverse the client's preference, overriding the server's static one as well
+        offered.iter().rev().find(|x| *x != b"quux").cloned()
+    });
+    let mut pair = Pair::new(Arc::new(EndpointConfig::default()), server_config.clone());
+    let mut client_config = client_config();
+    Arc::get_mut(&mut client_config.crypto)
+        .unwrap()
+        .set_protocols(&["foo".into(), "bar".into()]);
+
+    let client_ch = pair.begin_connect(client_config.clone());
+    pair.drive();
+    let server_ch = pair.server.assert_accept();
+    let hd = pair
+        .client_conn_mut(client_ch)
+        .crypto_session()
+        .handshake_data()
+        .unwrap();
+    assert_eq!(hd.protocol.unwrap(), b"bar");
+    let hd = pair
+        .server_conn_mut(server_ch)
+        .crypto_session()
+        .handshake_data()
+        .unwrap();
+    assert_eq!(hd.protocol.unwrap(), b"bar");
+
+    // Refused when the selector finds nothing acceptable
+    let mut pair = Pair::new(Arc::new(EndpointConfig::default()), server_config.clone());
+    let mut quux_config = client_config.clone();
+    Arc::make_mut(&mut quux_config.crypto).set_protocols(&["quux".into()]);
+    let client_ch = pair.begin_connect(quux_config);
+    pair.drive();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::ConnectionLost { reason: ConnectionError::ConnectionClosed(err) }) if err.error_code == TransportErrorCode::crypto(0x78)
+    );
+
+    // Refused when the selector chooses a protocol the client didn't offer
+    server_config.alpn_selector(|_, _| Some(b"foo".to_vec()));
+    let mut pair = Pair::new(Arc::new(EndpointConfig::default()), server_config);
+    Arc::make_mut(&mut client_config.crypto).set_protocols(&["bar".into()]);
+    let client_ch = pair.begin_connect(client_config);
+    pair.drive();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::ConnectionLost { reason: ConnectionError::ConnectionClosed(err) }) if err.error_code == TransportErrorCode::crypto(0x78)
+    );
+}
+
+#[test]
+fn connection_error_accessors() {
+    let _guard = subscribe();
+    let mut server_config = server_config();
+    Arc::get_mut(&mut server_config.crypto)
+        .unwrap()
+        .set_protocols(&["foo".into()]);
+    let mut pair = Pair::new(Arc::new(EndpointConfig::default()), server_config);
+    let mut client_config = client_config();
+    Arc::get_mut(&mut client_config.crypto)
+        .unwrap()
+        .set_protocols(&["bar".into()]);
+
+    let client_conn = pair.begin_connect(client_config);
+    pair.drive();
+    let reason = match pair.client_conn_mut(client_conn).poll() {
+        Some(Event::ConnectionLost { reason }) => reason,
+        x => panic!("unexpected event: {:?}", x),
+    };
+    assert_eq!(reason.kind(), ConnectionErrorKind::ConnectionClosed);
+    assert_eq!(reason.kind() as u8, 3);
+    assert!(reason.is_remote());
+    // no_application_protocol
+    assert_eq!(
+        reason.transport_error_code().and_then(|x| x.crypto_alert()),
+        Some(0x78)
+    );
+    assert_eq!(reason.application_error_code(), None);
+
+    let closed = ConnectionError::ApplicationClosed(ApplicationClose {
+        error_code: VarInt(42),
+        reason: Bytes::from_static(b"bye"),
+    });
+    assert_eq!(closed.application_error_code(), Some(VarInt(42)));
+    assert_eq!(closed.transport_error_code(), None);
+    assert_eq!(closed.reason(), Some(&b"bye"[..]));
+    assert_eq!(closed.to_string(), "closed by peer: bye (code 42)");
+
+    let error = ConnectionError::from(TransportError::PROTOCOL_VIOLATION("oops"));
+    assert!(!error.is_remote());
+    assert_eq!(
+        error.transport_error_code(),
+        Some(TransportErrorCode::PROTOCOL_VIOLATION)
+    );
+    assert_eq!(error.frame_type(), None);
+    assert_eq!(error.reason(), Some(&b"oops"[..]));
+}
+
 #[test]
 fn stream_id_limit() {
     let _guard = subscribe();
@@ -718,11 +1431,23 @@ fn key_update_simple() {
     pair.drive();
 
     assert_matches!(pair.server_conn_mut(server_ch).poll(), Some(Event::Stream(StreamEvent::Readable { id })) if id == s);
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::KeysUpdated {
+            initiated_by_peer: true
+        })
+    );
     assert_matches!(pair.server_conn_mut(server_ch).poll(), None);
     assert_matches!(
         pair.server_conn_mut(server_ch).read(s, usize::MAX, false),
         Ok(Some(chunk)) if chunk.offset == 6 && chunk.bytes == MSG2
     );
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::KeysUpdated {
+            initiated_by_peer: false
+        })
+    );
 
     assert_eq!(pair.client_conn_mut(client_ch).lost_packets(), 0);
     assert_eq!(pair.server_conn_mut(server_ch).lost_packets(), 0);
@@ -920,9 +1645,110 @@ fn concurrent_connections_full() {
                 }),
         })
     );
-    assert_eq!(pair.server.connections.len(), 0);
-    assert_eq!(pair.server.known_connections(), 0);
-    assert_eq!(pair.server.known_cids(), 0);
+    assert_eq!(pair.server.connections.len(), 0);
+    assert_eq!(pair.server.known_connections(), 0);
+    assert_eq!(pair.server.known_cids(), 0);
+    let stats = pair.server.stats();
+    assert_eq!(stats.refused_connections, 1);
+    assert_eq!(stats.accepted_connections, 0);
+    assert_eq!(stats.connections, 0);
+}
+
+#[test]
+fn concurrent_connections_pending() {
+    let _guard = subscribe();
+    let mut pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            concurrent_connections: 1,
+            ..server_config()
+        },
+    );
+    pair.server.manual_accept = true;
+    pair.begin_connect(client_config());
+    pair.drive_client();
+    pair.drive_server();
+    assert_eq!(pair.server.incoming.len(), 1);
+
+    // The undecided attempt occupies the only slot
+    let second = pair.begin_connect(client_config());
+    pair.drive_client();
+    pair.drive_server();
+    pair.drive_client();
+    assert_eq!(pair.server.incoming.len(), 1);
+    assert_matches!(
+        pair.client_conn_mut(second).poll(),
+        Some(Event::ConnectionLost {
+            reason: ConnectionError::ConnectionClosed(frame::ConnectionClose {
+                error_code: TransportErrorCode::CONNECTION_REFUSED,
+                ..
+            }),
+        })
+    );
+    assert_eq!(pair.server.stats().refused_connections, 1);
+
+    // Deciding on the pending attempt frees its slot for the connection itself
+    let incoming = pair.server.incoming.pop_front().unwrap();
+    assert!(pair.server.endpoint.accept(incoming, pair.time).is_ok());
+    assert_eq!(pair.server.stats().accepted_connections, 1);
+}
+
+#[test]
+fn incoming_buffer_large_datagrams() {
+    let _guard = subscribe();
+    let mut endpoint_config = EndpointConfig::default();
+    endpoint_config.max_udp_payload_size(65527).unwrap();
+    let mut pair = Pair::new(Arc::new(endpoint_config), server_config());
+    pair.server.manual_accept = true;
+    pair.begin_connect(client_config());
+    pair.drive_client();
+    let (_, ecn, packet) = pair.server.inbound.pop_front().unwrap();
+
+    // Receive buffers sized for the largest datagram mustn't crowd out follow-up packets
+    let receive = |pair: &mut Pair| {
+        let mut buf = BytesMut::with_capacity(65527);
+        buf.extend_from_slice(&packet);
+        let (time, remote) = (pair.time, pair.client.addr);
+        pair.server.endpoint.handle(time, remote, None, ecn, buf)
+    };
+    assert!(matches!(
+        receive(&mut pair),
+        Some(DatagramEvent::NewConnection(_))
+    ));
+    for _ in 0..4 {
+        assert!(receive(&mut pair).is_none());
+    }
+    assert_eq!(pair.server.stats().dropped.unknown_connection, 0);
+}
+
+#[test]
+fn incoming_expiry() {
+    let _guard = subscribe();
+    let mut pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            concurrent_connections: 1,
+            ..server_config()
+        },
+    );
+    pair.server.manual_accept = true;
+    pair.begin_connect(client_config());
+    pair.drive_client();
+    pair.drive_server();
+    let stale = pair.server.incoming.pop_front().unwrap();
+
+    // An attempt left undecided past the idle timeout no longer occupies the only slot
+    pair.time += Duration::from_secs(10);
+    pair.begin_connect(client_config());
+    pair.drive_client();
+    pair.drive_server();
+    assert_eq!(pair.server.incoming.len(), 1);
+    assert_eq!(pair.server.stats().refused_connections, 0);
+
+    // Deciding on the expired attempt leaves the fresh one's buffer alone
+    pair.server.endpoint.ignore(stale);
+    let incoming = pair.server.incoming.pop_front().unwrap();
+    assert!(pair.server.endpoint.accept(incoming, pair.time).is_ok());
 }
 
 #[test]
@@ -949,6 +1775,9 @@ fn migration() {
     let _guard = subscribe();
     let mut pair = Pair::default();
     let (client_ch, server_ch) = pair.connect();
+    let info = pair.server_conn_mut(server_ch).path_info();
+    assert_eq!(info.address_validation, AddressValidation::Implicit);
+    assert_eq!(info.migrations, 0);
     pair.client.addr = SocketAddr::new(
         Ipv4Addr::new(127, 0, 0, 1).into(),
         CLIENT_PORTS.lock().unwrap().next().unwrap(),
@@ -960,6 +1789,47 @@ fn migration() {
         pair.server_conn_mut(server_ch).remote_address(),
         pair.client.addr
     );
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::Migrated { remote }) if remote == pair.client.addr
+    );
+    let info = pair.server_conn_mut(server_ch).path_info();
+    assert_eq!(info.remote, pair.client.addr);
+    assert_eq!(
+        info.address_validation,
+        AddressValidation::Implicit,
+        "new path validated"
+    );
+    assert_eq!(info.migrations, 1);
+    assert_eq!(
+        pair.client_conn_mut(client_ch)
+            .path_info()
+            .address_validation,
+        AddressValidation::Implicit
+    );
+}
+
+#[test]
+fn migration_after_retry() {
+    let _guard = subscribe();
+    let mut pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            use_stateless_retry: true,
+            ..server_config()
+        },
+    );
+    let (client_ch, server_ch) = pair.connect();
+    pair.client.addr = SocketAddr::new(
+        Ipv4Addr::new(127, 0, 0, 1).into(),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+    );
+    pair.client_conn_mut(client_ch).ping();
+    pair.drive();
+    let info = pair.server_conn_mut(server_ch).path_info();
+    assert_eq!(info.migrations, 1);
+    assert_eq!(info.address_validation, AddressValidation::Implicit);
+    assert_eq!(info.handshake_validation, AddressValidation::Retry);
 }
 
 fn test_flow_control(config: TransportConfig, window_size: usize) {
@@ -1081,6 +1951,97 @@ fn conn_flow_control() {
     );
 }
 
+#[test]
+fn runtime_receive_windows() {
+    let _guard = subscribe();
+    let server = ServerConfig {
+        transport: Arc::new(TransportConfig {
+            receive_window: 2000u32.into(),
+            stream_receive_window: 1000u32.into(),
+            ..TransportConfig::default()
+        }),
+        ..server_config()
+    };
+    let mut pair = Pair::new(Default::default(), server);
+    let (client_ch, server_ch) = pair.connect();
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    const DATA: &[u8] = &[0xab; 4000];
+    assert_eq!(
+        pair.client_conn_mut(client_ch).write(s, DATA).unwrap(),
+        1000
+    );
+    pair.drive();
+
+    // Growth is granted straight away
+    pair.server_conn_mut(server_ch)
+        .set_stream_receive_window(3000u32.into());
+    pair.drive();
+    assert_eq!(
+        pair.client_conn_mut(client_ch).write(s, DATA).unwrap(),
+        1000
+    );
+    pair.server_conn_mut(server_ch)
+        .set_receive_window(5000u32.into());
+    pair.drive();
+    assert_eq!(
+        pair.client_conn_mut(client_ch).write(s, DATA).unwrap(),
+        1000
+    );
+    pair.drive();
+
+    // Reductions take effect as data is read
+    pair.server_conn_mut(server_ch)
+        .set_stream_receive_window(500u32.into());
+    assert_eq!(pair.server_conn_mut(server_ch).accept(Dir::Uni), Some(s));
+    let mut read = 0;
+    while let Ok(Some(chunk)) = pair.server_conn_mut(server_ch).read(s, usize::MAX, true) {
+        read += chunk.bytes.len();
+    }
+    assert_eq!(read, 3000);
+    pair.drive();
+    assert_eq!(pair.client_conn_mut(client_ch).write(s, DATA).unwrap(), 500);
+}
+
+#[test]
+fn runtime_stream_limits() {
+    let _guard = subscribe();
+    let server = ServerConfig {
+        transport: Arc::new(TransportConfig {
+            max_concurrent_uni_streams: 1u32.into(),
+            ..TransportConfig::default()
+        }),
+        ..server_config()
+    };
+    let mut pair = Pair::new(Default::default(), server);
+    let (client_ch, server_ch) = pair.connect();
+    let first = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    assert_eq!(pair.client_conn_mut(client_ch).open(Dir::Uni), None);
+
+    pair.server_conn_mut(server_ch)
+        .set_max_concurrent_streams(Dir::Uni, 3u32.into());
+    pair.drive();
+    assert!(pair.client_conn_mut(client_ch).open(Dir::Uni).is_some());
+    assert!(pair.client_conn_mut(client_ch).open(Dir::Uni).is_some());
+    assert_eq!(pair.client_conn_mut(client_ch).open(Dir::Uni), None);
+
+    // Closing a stream no longer makes room for another after a reduction
+    pair.server_conn_mut(server_ch)
+        .set_max_concurrent_streams(Dir::Uni, 2u32.into());
+    pair.client_conn_mut(client_ch).finish(first).unwrap();
+    pair.drive();
+    assert_eq!(
+        pair.server_conn_mut(server_ch).accept(Dir::Uni),
+        Some(first)
+    );
+    assert_matches!(
+        pair.server_conn_mut(server_ch)
+            .read(first, usize::MAX, true),
+        Ok(None)
+    );
+    pair.drive();
+    assert_eq!(pair.client_conn_mut(client_ch).open(Dir::Uni), None);
+}
+
 #[test]
 fn stop_opens_bidi() {
     let _guard = subscribe();
@@ -1172,6 +2133,88 @@ fn zero_length_cid() {
     pair.connect();
 }
 
+#[test]
+fn mtu_discovery() {
+    let _guard = subscribe();
+    let mut endpoint_config = EndpointConfig::default();
+    endpoint_config.max_udp_payload_size(9000).unwrap();
+    // The loopback path would otherwise be assumed to carry the largest size straight away
+    let mut transport = TransportConfig::default();
+    transport
+        .mtu_discovery_interval(Some(Duration::from_secs(600)))
+        .local_path_filter(|_| false);
+    let server = ServerConfig {
+        transport: Arc::new(transport),
+        ..server_config()
+    };
+    let mut pair = Pair::new(Arc::new(endpoint_config), server);
+    pair.mtu = 1500;
+    let mut transport = TransportConfig::default();
+    transport.local_path_filter(|_| false);
+    let client = ClientConfig {
+        transport: Arc::new(transport),
+        ..client_config()
+    };
+    let (client_ch, server_ch) = pair.connect_with(client);
+    pair.drive();
+    let stats = pair.server_conn_mut(server_ch).stats();
+    assert!(stats.path.current_mtu <= 1500);
+    assert!(stats.path.current_mtu > 1450, "{}", stats.path.current_mtu);
+    assert_eq!(
+        stats.path.congestion_events, 0,
+        "lost probes are not congestion"
+    );
+    assert_eq!(
+        pair.client_conn_mut(client_ch).stats().path.current_mtu,
+        MIN_MTU
+    );
+
+    // Stream data is sent in packets of the discovered size
+    let s = pair.server_conn_mut(server_ch).open(Dir::Uni).unwrap();
+    const MSG: &[u8] = &[0xab; 10_000];
+    pair.server_conn_mut(server_ch).write(s, MSG).unwrap();
+    pair.server_conn_mut(server_ch).finish(s).unwrap();
+    let sent = pair.server_conn_mut(server_ch).stats().udp_tx;
+    pair.drive();
+    let sent = pair.server_conn_mut(server_ch).stats().udp_tx.datagrams - sent.datagrams;
+    assert!(sent < 10_000 / MIN_MTU as u64, "{} datagrams", sent);
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::Stream(StreamEvent::Opened { dir: Dir::Uni }))
+    );
+    let s = pair.client_conn_mut(client_ch).accept(Dir::Uni).unwrap();
+    let mut received = 0;
+    while let Ok(Some(chunk)) = pair.client_conn_mut(client_ch).read(s, usize::MAX, true) {
+        received += chunk.bytes.len();
+    }
+    assert_eq!(received, MSG.len());
+}
+
+#[test]
+fn local_path() {
+    let _guard = subscribe();
+    let mut endpoint_config = EndpointConfig::default();
+    endpoint_config.max_udp_payload_size(9000).unwrap();
+    let mut transport = TransportConfig::default();
+    transport.local_path_filter(|_| false);
+    let server = ServerConfig {
+        transport: Arc::new(transport),
+        ..server_config()
+    };
+    let mut pair = Pair::new(Arc::new(endpoint_config), server);
+    let (client_ch, server_ch) = pair.connect();
+    assert_eq!(
+        pair.client_conn_mut(client_ch).stats().path.current_mtu,
+        9000,
+        "loopback paths start at the peer's limit by default"
+    );
+    pair.drive();
+    assert_eq!(
+        pair.server_conn_mut(server_ch).stats().path.current_mtu,
+        MIN_MTU
+    );
+}
+
 #[test]
 fn keep_alive() {
     let _guard = subscribe();
@@ -1199,13 +2242,91 @@ fn keep_alive() {
     }
 }
 
+#[test]
+fn keep_alive_only_when_active() {
+    let _guard = subscribe();
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+    let client = ClientConfig {
+        transport: Arc::new(TransportConfig {
+            keep_alive_interval: Some(IDLE_TIMEOUT / 2),
+            keep_alive_only_when_active: true,
+            max_idle_timeout: Some(IDLE_TIMEOUT),
+            ..TransportConfig::default()
+        }),
+        ..client_config()
+    };
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect_with(client);
+    // Whether the client's connection is still open a good while longer than the idle timeout
+    let survives = |pair: &mut Pair| {
+        let end = pair.time + 20 * IDLE_TIMEOUT;
+        while pair.time < end {
+            if !pair.step() {
+                match min_opt(pair.client.next_wakeup(), pair.server.next_wakeup()) {
+                    Some(time) => pair.time = time,
+                    None => break,
+                }
+            }
+            if pair.client_conn_mut(client_ch).is_closed() {
+                break;
+            }
+        }
+        !pair.client_conn_mut(client_ch).is_closed()
+    };
+
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_ch).write(s, b"hello").unwrap();
+    assert!(
+        survives(&mut pair),
+        "an open stream keeps the connection alive"
+    );
+
+    pair.client_conn_mut(client_ch).finish(s).unwrap();
+    pair.drive();
+    assert_matches!(pair.server_conn_mut(server_ch).accept(Dir::Uni), Some(stream) if stream == s);
+    assert_matches!(
+        pair.server_conn_mut(server_ch).read(s, usize::MAX, false),
+        Ok(Some(_))
+    );
+    assert_matches!(
+        pair.server_conn_mut(server_ch).read(s, usize::MAX, false),
+        Ok(None)
+    );
+    pair.drive();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::Stream(StreamEvent::Finished { id })) if id == s
+    );
+    pair.client_conn_mut(client_ch).set_awaiting_peer(true);
+    assert!(
+        survives(&mut pair),
+        "awaiting the peer keeps the connection alive"
+    );
+
+    pair.client_conn_mut(client_ch).set_awaiting_peer(false);
+    assert!(!survives(&mut pair), "an inactive connection times out");
+    assert!(
+        iter::from_fn(|| pair.client_conn_mut(client_ch).poll()).any(|e| matches!(
+            e,
+            Event::ConnectionLost {
+                reason: ConnectionError::TimedOut
+            }
+        ))
+    );
+}
+
 #[test]
 fn cid_rotation() {
     let _guard = subscribe();
     const CID_TIMEOUT: Duration = Duration::from_secs(2);
 
-    let cid_generator_factory: fn() -> Box<dyn ConnectionIdGenerator> =
-        || Box::new(*RandomConnectionIdGenerator::new(8).set_lifetime(CID_TIMEOUT));
+    let cid_generator_factory: fn() -> Box<dyn ConnectionIdGenerator> = || {
+        Box::new(
+            RandomConnectionIdGenerator::new(8)
+                .set_lifetime(CID_TIMEOUT)
+                .clone(),
+        )
+    };
 
     // Only test cid rotation on server side to have a clear output trace
     let server = Endpoint::new(
@@ -1291,6 +2412,34 @@ fn cid_retirement() {
     );
 }
 
+#[test]
+fn ack_reordered_packets() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+
+    pair.client_conn_mut(client_ch).write(s, b"a").unwrap();
+    pair.drive_client();
+    pair.client_conn_mut(client_ch).write(s, b"b").unwrap();
+    pair.client.drive(pair.time, pair.server.addr);
+    pair.client.delay_outbound(); // Reorder the second packet after the third
+    pair.client_conn_mut(client_ch).write(s, b"c").unwrap();
+    pair.drive_client();
+
+    // The server's ACK of the first and third packets is itself acknowledged once the second has
+    // arrived, which mustn't stop the second from being acknowledged too
+    let server_s = pair.server_conn_mut(server_ch).open(Dir::Uni).unwrap();
+    pair.server_conn_mut(server_ch)
+        .write(server_s, b"hello")
+        .unwrap();
+    pair.drive_server();
+    pair.client.finish_delay();
+    pair.drive();
+
+    assert_eq!(pair.client_conn_mut(client_ch).stats().path.lost_packets, 0);
+}
+
 #[test]
 fn finish_stream_flow_control_reordered() {
     let _guard = subscribe();
@@ -1360,12 +2509,49 @@ fn handshake_1rtt_handling() {
     pair.drive();
 
     assert!(pair.client_conn_mut(client_ch).lost_packets() != 0);
+    let stats = pair.client_conn_mut(client_ch).stats();
+    assert_eq!(
+        stats.initial.lost + stats.handshake.lost + stats.data.lost,
+        pair.client_conn_mut(client_ch).lost_packets()
+    );
+    assert_eq!(
+        stats.path.lost_packets,
+        pair.client_conn_mut(client_ch).lost_packets()
+    );
+    assert!(stats.path.lost_bytes > 0);
+    assert!(stats.path.retransmitted_stream_bytes >= MSG.len() as u64);
+    assert!(stats.path.congestion_events > 0);
     assert_matches!(
         pair.server_conn_mut(server_ch).read(s, usize::MAX, false),
         Ok(Some(chunk)) if chunk.offset == 0 && chunk.bytes == MSG
     );
 }
 
+#[test]
+fn space_stats() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+    for stats in &[
+        pair.client_conn_mut(client_ch).stats(),
+        pair.server_conn_mut(server_ch).stats(),
+    ] {
+        for space in &[stats.initial, stats.handshake, stats.data] {
+            assert!(space.sent > 0);
+            assert!(space.acked <= space.sent);
+            assert_eq!(space.lost, 0);
+            assert!(space.latest_ack_delay <= space.max_ack_delay);
+        }
+        assert!(stats.initial.acked > 0);
+        assert_eq!(
+            stats.path.sent_packets,
+            stats.initial.sent + stats.handshake.sent + stats.data.sent
+        );
+        assert_eq!(stats.path.lost_bytes, 0);
+        assert!(stats.path.min_rtt <= stats.path.rtt);
+    }
+}
+
 #[test]
 fn stop_before_finish() {
     let _guard = subscribe();
@@ -1806,3 +2992,177 @@ fn read_chunks() {
         pair.drive();
     }
 }
+
+#[test]
+fn driver_outputs() {
+    type Driver = driver::Driver<crypto::rustls::TlsSession>;
+
+    /// Exchange datagrams until neither side has anything left to send
+    fn exchange(
+        now: Instant,
+        drivers: &mut [(SocketAddr, Driver, Vec<driver::Output>); 2],
+    ) -> Option<Instant> {
+        let mut timeouts = [None; 2];
+        loop {
+            let mut idle = true;
+            for i in 0..2 {
+                let (addr, ref mut driver, ref mut outputs) = drivers[i];
+                let mut sent = Vec::new();
+                while let Some(output) = driver.poll(now) {
+                    match output {
+                        driver::Output::Transmit(x) => sent.push(x),
+                        driver::Output::Timeout(x) => timeouts[i] = x,
+                        x => outputs.push(x),
+                    }
+                }
+                let peer = &mut drivers[1 - i].1;
+                for x in sent {
+                    idle = false;
+                    peer.handle(now, addr, None, x.ecn, x.contents[..].into());
+                }
+            }
+            if idle {
+                return timeouts.iter().flatten().min().cloned();
+            }
+        }
+    }
+
+    let _guard = subscribe();
+    let client_addr = "[::2]:7890".parse().unwrap();
+    let server_addr = "[::1]:4433".parse().unwrap();
+    let mut now = Instant::now();
+    let mut client = Driver::new(Endpoint::new(Default::default(), None));
+    let client_ch = client
+        .connect(now, client_config(), server_addr, "localhost")
+        .unwrap();
+    let server = Driver::new(Endpoint::new(
+        Default::default(),
+        Some(Arc::new(server_config())),
+    ));
+    let mut drivers = [
+        (client_addr, client, Vec::new()),
+        (server_addr, server, Vec::new()),
+    ];
+
+    exchange(now, &mut drivers);
+    assert_matches!(
+        drivers[0].2.drain(..).collect::<Vec<_>>()[..],
+        [
+            driver::Output::Event {
+                event: Event::HandshakeDataReady,
+                ..
+            },
+            driver::Output::Event {
+                event: Event::Connected,
+                ..
+            },
+        ]
+    );
+    let server_ch = match drivers[1].2.drain(..).collect::<Vec<_>>()[..] {
+        [driver::Output::NewConnection(ch), driver::Output::Event {
+            connection,
+            event: Event::HandshakeDataReady,
+        }, driver::Output::Event {
+            event: Event::Connected,
+            ..
+        }] => {
+            assert_eq!(ch, connection);
+            ch
+        }
+        ref x => panic!("unexpected outputs {:?}", x),
+    };
+
+    let conn = drivers[0].1.connection_mut(client_ch).unwrap();
+    let s = conn.open(Dir::Uni).unwrap();
+    conn.write(s, b"hello").unwrap();
+    conn.finish(s).unwrap();
+    exchange(now, &mut drivers);
+    let outputs = drivers[1].2.drain(..).collect::<Vec<_>>();
+    assert_matches!(
+        outputs[..],
+        [
+            driver::Output::Event {
+                event: Event::Stream(StreamEvent::Opened { dir: Dir::Uni }),
+                ..
+            },
+            ..
+        ]
+    );
+    let conn = drivers[1].1.connection_mut(server_ch).unwrap();
+    assert_eq!(conn.accept(Dir::Uni), Some(s));
+    assert_matches!(conn.read(s, usize::MAX, true), Ok(Some(chunk)) if chunk.bytes == b"hello"[..]);
+
+    drivers[0]
+        .1
+        .connection_mut(client_ch)
+        .unwrap()
+        .close(now, VarInt(0), Bytes::new());
+    while let Some(timeout) = exchange(now, &mut drivers) {
+        now = now.max(timeout);
+        for (_, driver, _) in drivers.iter_mut() {
+            driver.handle_timeout(now);
+        }
+    }
+    assert_matches!(
+        drivers[0].2.last(),
+        Some(driver::Output::Drained(ch)) if *ch == client_ch
+    );
+    assert_matches!(
+        drivers[1].2.last(),
+        Some(driver::Output::Drained(ch)) if *ch == server_ch
+    );
+    assert_eq!(drivers[0].1.connections().count(), 0);
+    assert_eq!(drivers[1].1.connections().count(), 0);
+}
+
+#[test]
+fn close_after_flush() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+
+    const MSG: &[u8] = &[0xab; 32 * 1024];
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    assert_eq!(pair.client_conn_mut(client_ch).write(s, MSG), Ok(MSG.len()));
+    pair.client_conn_mut(client_ch).finish(s).unwrap();
+    let now = pair.time;
+    pair.client_conn_mut(client_ch)
+        .close_after_flush(now, VarInt(42), Bytes::new());
+    assert!(!pair.client_conn_mut(client_ch).is_closed());
+    pair.drive();
+
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::Stream(StreamEvent::Finished { id })) if id == s
+    );
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::ConnectionLost {
+            reason: ConnectionError::LocallyClosed
+        })
+    );
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::Stream(StreamEvent::Opened { dir: Dir::Uni }))
+    );
+    assert_eq!(pair.server_conn_mut(server_ch).accept(Dir::Uni), Some(s));
+    let mut received = 0;
+    while let Ok(Some(chunk)) = pair.server_conn_mut(server_ch).read(s, usize::MAX, true) {
+        received += chunk.bytes.len();
+    }
+    assert_eq!(received, MSG.len());
+    let reason = loop {
+        match pair.server_conn_mut(server_ch).poll() {
+            Some(Event::ConnectionLost { reason }) => break reason,
+            Some(_) => {}
+            None => panic!("connection not closed"),
+        }
+    };
+    assert_matches!(
+        reason,
+        ConnectionError::ApplicationClosed(ApplicationClose {
+            error_code: VarInt(42),
+            ..
+        })
+    );
+}
@@ -26,6 +26,8 @@ pub struct Pair {
     /// Number of spin bit flips
     pub spins: u64,
     last_spin: bool,
+    /// Largest datagram the network carries; larger ones are dropped
+    pub mtu: usize,
 }
 
 impl Pair {
@@ -52,6 +54,7 @@ impl Pair {
             latency: Duration::new(0, 0),
             spins: 0,
             last_spin: false,
+            mtu: usize::MAX,
         }
     }
 
@@ -103,7 +106,7 @@ impl Pair {
             if let Some(ref socket) = self.client.socket {
                 socket.send_to(&x.contents, x.destination).unwrap();
             }
-            if self.server.addr == x.destination {
+            if self.server.addr == x.destination && x.contents.len() <= self.mtu {
                 self.server
                     .inbound
                     .push_back((self.time + self.latency, x.ecn, x.contents));
@@ -119,7 +122,7 @@ impl Pair {
             if let Some(ref socket) = self.server.socket {
                 socket.send_to(&x.contents, x.destination).unwrap();
             }
-            if self.client.addr == x.destination {
+            if self.client.addr == x.destination && x.contents.len() <= self.mtu {
                 self.client
                     .inbound
                     .push_back((self.time + self.latency, x.ecn, x.contents));
@@ -128,8 +131,12 @@ impl Pair {
     }
 
     pub fn connect(&mut self) -> (ConnectionHandle, ConnectionHandle) {
+        self.connect_with(client_config())
+    }
+
+    pub fn connect_with(&mut self, config: ClientConfig) -> (ConnectionHandle, ConnectionHandle) {
         info!("connecting");
-        let client_ch = self.begin_connect(client_config());
+        let client_ch = self.begin_connect(config);
         self.drive();
         let server_ch = self.server.assert_accept();
         assert_matches!(
@@ -157,7 +164,7 @@ impl Pair {
         let _guard = span.enter();
         let (client_ch, client_conn) = self
             .client
-            .connect(config, self.server.addr, "localhost")
+            .connect_at(self.time, config, self.server.addr, "localhost")
             .unwrap();
         self.client.connections.insert(client_ch, client_conn);
         client_ch
@@ -189,6 +196,9 @@ pub struct TestEndpoint {
     accepted: Option<ConnectionHandle>,
     pub connections: HashMap<ConnectionHandle, Connection>,
     conn_events: HashMap<ConnectionHandle, VecDeque<ConnectionEvent>>,
+    /// Whether incoming connection attempts are queued in `incoming` rather than accepted
+    pub manual_accept: bool,
+    pub incoming: VecDeque<Incoming>,
 }
 
 impl TestEndpoint {
@@ -213,6 +223,8 @@ impl TestEndpoint {
             accepted: None,
             connections: HashMap::default(),
             conn_events: HashMap::default(),
+            manual_accept: false,
+            incoming: VecDeque::new(),
         }
     }
 
@@ -228,16 +240,20 @@ impl TestEndpoint {
 
         while self.inbound.front().map_or(false, |x| x.0 <= now) {
             let (_, ecn, packet) = self.inbound.pop_front().unwrap();
-            if let Some((ch, event)) =
+            if let Some(event) =
                 self.endpoint
                     .handle(now, remote, None, ecn, packet.as_slice().into())
             {
                 match event {
-                    DatagramEvent::NewConnection(conn) => {
-                        self.connections.insert(ch, conn);
-                        self.accepted = Some(ch);
+                    DatagramEvent::NewConnection(incoming) => {
+                        if self.manual_accept {
+                            self.incoming.push_back(incoming);
+                        } else if let Ok((ch, conn)) = self.endpoint.accept(incoming, now) {
+                            self.connections.insert(ch, conn);
+                            self.accepted = Some(ch);
+                        }
                     }
-                    DatagramEvent::ConnectionEvent(event) => {
+                    DatagramEvent::ConnectionEvent(ch, event) => {
                         self.conn_events
                             .entry(ch)
                             .or_insert_with(VecDeque::new)
@@ -258,8 +274,8 @@ impl TestEndpoint {
                 conn.handle_timeout(now);
             }
 
-            for (_, mut events) in self.conn_events.drain() {
-                for event in events.drain(..) {
+            if let Some(events) = self.conn_events.remove(ch) {
+                for event in events {
                     conn.handle_event(event);
                 }
             }
@@ -373,6 +389,7 @@ pub fn client_config() -> ClientConfig {
     ClientConfig {
         transport: Default::default(),
         crypto,
+        resumption: None,
     }
 }
 
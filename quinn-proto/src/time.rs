@@ -0,0 +1,161 @@
+//! Timestamps supplied by the caller
+//!
+//! Every operation that depends on the time takes it as an argument, so quinn-proto never reads a
+//! clock itself, save through the [`Clock`] configured for `Endpoint::connect`. Timestamps are
+//! [`std::time::Instant`]s, except on `wasm32-unknown-unknown`, where the standard library has no
+//! clock to produce them from. There, [`Instant`] is a type of this crate that the host constructs
+//! from a clock of its own.
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+use std::{
+    ops::{Add, AddAssign, Sub, SubAssign},
+    time::{Duration, UNIX_EPOCH},
+};
+use std::{sync::Arc, time::SystemTime};
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub use std::time::Instant;
+
+/// A measurement of a monotonically nondecreasing clock kept by the host
+///
+/// Stands in for [`std::time::Instant`], with the same semantics. All instants passed to an
+/// endpoint and its connections must be measured from the same origin, which is otherwise
+/// arbitrary.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(Duration);
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+impl Instant {
+    /// The instant `duration` after the host clock's origin
+    pub const fn from_duration_since_origin(duration: Duration) -> Self {
+        Self(duration)
+    }
+
+    /// The time elapsed since the host clock's origin
+    pub const fn duration_since_origin(&self) -> Duration {
+        self.0
+    }
+
+    /// The time elapsed from `earlier` to `self`, or zero if `earlier` is later
+    pub fn duration_since(&self, earlier: Self) -> Duration {
+        self.saturating_duration_since(earlier)
+    }
+
+    /// The time elapsed from `earlier` to `self`, or `None` if `earlier` is later
+    pub fn checked_duration_since(&self, earlier: Self) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+
+    /// The time elapsed from `earlier` to `self`, or zero if `earlier` is later
+    pub fn saturating_duration_since(&self, earlier: Self) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    /// `self + duration`, or `None` if that can't be represented
+    pub fn checked_add(&self, duration: Duration) -> Option<Self> {
+        self.0.checked_add(duration).map(Self)
+    }
+
+    /// `self - duration`, or `None` if that precedes the origin
+    pub fn checked_sub(&self, duration: Duration) -> Option<Self> {
+        self.0.checked_sub(duration).map(Self)
+    }
+}
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+impl Add<Duration> for Instant {
+    type Output = Self;
+    fn add(self, rhs: Duration) -> Self {
+        self.checked_add(rhs)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+impl Sub<Duration> for Instant {
+    type Output = Self;
+    fn sub(self, rhs: Duration) -> Self {
+        self.checked_sub(rhs)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+    fn sub(self, rhs: Self) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+/// Wall-clock time at `now`, for stamping address validation tokens
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub(crate) fn system_time(_now: Instant) -> SystemTime {
+    SystemTime::now()
+}
+
+/// Wall-clock time at `now`, for stamping address validation tokens
+///
+/// Tokens are only ever validated by the endpoint that issued them, so it's enough for this to
+/// advance with the host clock.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub(crate) fn system_time(now: Instant) -> SystemTime {
+    UNIX_EPOCH + now.0
+}
+
+/// A source of the current time
+///
+/// Only consulted by operations that don't take the time as an argument, so that a simulation can
+/// run an endpoint against a virtual clock.
+pub trait Clock: Send + Sync {
+    /// The current time, which must never be earlier than that previously returned
+    fn now(&self) -> Instant;
+}
+
+/// The host's monotonic clock, as read by [`std::time::Instant::now`]
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemClock;
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub(crate) fn default_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+/// Stands in for a clock until the host configures one, there being none to read by default
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+struct NoClock;
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+impl Clock for NoClock {
+    fn now(&self) -> Instant {
+        panic!("no clock configured; see EndpointConfig::clock")
+    }
+}
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub(crate) fn default_clock() -> Arc<dyn Clock> {
+    Arc::new(NoClock)
+}
@@ -159,13 +159,13 @@ mod test {
         use crate::cid_generator::{ConnectionIdGenerator, RandomConnectionIdGenerator};
         use crate::{crypto, MAX_CID_SIZE};
 
-        use rand::RngCore;
+        use crate::{OsRandom, RandomSource};
         use std::{
             net::Ipv6Addr,
             time::{Duration, UNIX_EPOCH},
         };
 
-        let rng = &mut rand::thread_rng();
+        let rng = OsRandom;
 
         let mut master_key = [0; 64];
         rng.fill_bytes(&mut master_key);
@@ -199,10 +199,10 @@ mod test {
         use super::*;
         use crate::cid_generator::{ConnectionIdGenerator, RandomConnectionIdGenerator};
         use crate::{crypto, MAX_CID_SIZE};
-        use rand::RngCore;
+        use crate::{OsRandom, RandomSource};
         use std::net::Ipv6Addr;
 
-        let rng = &mut rand::thread_rng();
+        let rng = OsRandom;
 
         let mut master_key = [0; 64];
         rng.fill_bytes(&mut master_key);
@@ -219,14 +219,14 @@ mod test {
         invalid_token.put_slice(&random_bytes);
 
         let mut random_data = [0; 32];
-        rand::thread_rng().fill_bytes(&mut random_data);
+        rng.fill_bytes(&mut random_data);
         invalid_token.put_slice(&random_data);
 
         // Assert: garbage sealed data with valid random bytes returns err
         assert!(RetryToken::from_bytes(&prk, &addr, &retry_src_cid, &invalid_token).is_err());
 
         let invalid_token = [0; 31];
-        rand::thread_rng().fill_bytes(&mut random_bytes);
+        rng.fill_bytes(&mut random_bytes);
 
         // Assert: completely invalid retry token returns error
         assert!(RetryToken::from_bytes(&prk, &addr, &retry_src_cid, &invalid_token).is_err());
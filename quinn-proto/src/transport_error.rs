@@ -52,6 +52,15 @@ impl Code {
     pub(crate) fn crypto(code: u8) -> Self {
         Code(0x100 | u64::from(code))
     }
+
+    /// The TLS alert code, if this code reports a failure of the cryptographic handshake
+    pub fn crypto_alert(self) -> Option<u8> {
+        if (0x100..0x200).contains(&self.0) {
+            Some(self.0 as u8)
+        } else {
+            None
+        }
+    }
 }
 
 impl coding::Codec for Code {
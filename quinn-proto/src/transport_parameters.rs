@@ -17,7 +17,7 @@ use thiserror::Error;
 use crate::{
     cid_generator::ConnectionIdGenerator,
     cid_queue::CidQueue,
-    coding::{BufExt, BufMutExt, UnexpectedEnd},
+    coding::{BufExt, BufMutExt, Codec, UnexpectedEnd},
     config::{EndpointConfig, ServerConfig, TransportConfig},
     crypto,
     shared::ConnectionId,
@@ -115,6 +115,16 @@ macro_rules! make_struct {
 apply_params!(make_struct);
 
 impl TransportParameters {
+    /// Call `f` with the name and value of each integer parameter
+    pub(crate) fn for_each_integer(&self, mut f: impl FnMut(&'static str, u64)) {
+        macro_rules! visit_params {
+            {$($(#[$doc:meta])* $name:ident ($code:expr) = $default:expr,)*} => {
+                $(f(stringify!($name), self.$name.into());)*
+            }
+        }
+        apply_params!(visit_params);
+    }
+
     pub(crate) fn new<S>(
         config: &TransportConfig,
         endpoint_config: &EndpointConfig<S>,
@@ -385,10 +395,11 @@ impl TransportParameters {
                 0x0f => decode_cid(len, &mut params.initial_src_cid, r)?,
                 0x10 => decode_cid(len, &mut params.retry_src_cid, r)?,
                 0x20 => {
-                    if len > 8 || params.max_datagram_frame_size.is_some() {
+                    let value = r.get::<VarInt>()?;
+                    if len != value.size() || params.max_datagram_frame_size.is_some() {
                         return Err(Error::Malformed);
                     }
-                    params.max_datagram_frame_size = Some(r.get().unwrap());
+                    params.max_datagram_frame_size = Some(value);
                 }
                 _ => {
                     macro_rules! parse {
@@ -426,6 +437,126 @@ impl TransportParameters {
     }
 }
 
+/// Iterator over the individual parameters of an encoded set of transport parameters
+///
+/// Allows reading parameters defined by extensions, which [`TransportParameters::read()`] skips.
+/// Ends after the first error.
+///
+/// ```
+/// # use quinn_proto::{transport_parameters::{ParameterReader, ParameterWriter}, VarInt};
+/// let mut buf = Vec::new();
+/// ParameterWriter::new(&mut buf)
+///     .integer(VarInt::from_u32(0x2ab2), VarInt::from_u32(1))
+///     .bytes(VarInt::from_u32(0x2ab3), b"hello");
+///
+/// let mut params = ParameterReader::new(&buf);
+/// let param = params.next().unwrap().unwrap();
+/// assert_eq!(param.id, VarInt::from_u32(0x2ab2));
+/// assert_eq!(param.integer().unwrap(), VarInt::from_u32(1));
+/// assert_eq!(params.next().unwrap().unwrap().value, b"hello");
+/// assert!(params.next().is_none());
+/// ```
+#[derive(Debug, Clone)]
+pub struct ParameterReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> ParameterReader<'a> {
+    /// Read the parameters encoded in `buf`
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn read(&mut self) -> Result<RawParameter<'a>, Error> {
+        let id = VarInt::decode(&mut self.buf)?;
+        let len = self.buf.get_var()?;
+        if (self.buf.len() as u64) < len {
+            return Err(Error::Malformed);
+        }
+        let (value, rest) = self.buf.split_at(len as usize);
+        self.buf = rest;
+        Ok(RawParameter { id, value })
+    }
+}
+
+impl<'a> Iterator for ParameterReader<'a> {
+    type Item = Result<RawParameter<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let result = self.read();
+        if result.is_err() {
+            self.buf = &[];
+        }
+        Some(result)
+    }
+}
+
+/// A single transport parameter, as read by [`ParameterReader`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RawParameter<'a> {
+    /// Identifies the parameter
+    pub id: VarInt,
+    /// The encoded value
+    pub value: &'a [u8],
+}
+
+impl RawParameter<'_> {
+    /// Decode the value of an integer parameter
+    pub fn integer(&self) -> Result<VarInt, Error> {
+        let mut value = self.value;
+        let x = VarInt::decode(&mut value)?;
+        if value.has_remaining() {
+            return Err(Error::Malformed);
+        }
+        Ok(x)
+    }
+}
+
+/// Appends individual transport parameters to a buffer
+///
+/// Allows encoding parameters defined by extensions alongside, or instead of, those written by
+/// [`TransportParameters::write()`]. See [`ParameterReader`] for an example.
+#[derive(Debug)]
+pub struct ParameterWriter<W> {
+    buf: W,
+}
+
+impl<W: BufMut> ParameterWriter<W> {
+    /// Append parameters to `buf`
+    pub fn new(buf: W) -> Self {
+        Self { buf }
+    }
+
+    /// Write a parameter with an arbitrary value
+    pub fn bytes(&mut self, id: VarInt, value: &[u8]) -> &mut Self {
+        self.buf.write(id);
+        self.buf.write_var(value.len() as u64);
+        self.buf.put_slice(value);
+        self
+    }
+
+    /// Write a parameter whose value is an integer
+    pub fn integer(&mut self, id: VarInt, value: VarInt) -> &mut Self {
+        self.buf.write(id);
+        self.buf.write_var(value.size() as u64);
+        self.buf.write(value);
+        self
+    }
+
+    /// Write a parameter with an empty value, whose presence alone is meaningful
+    pub fn flag(&mut self, id: VarInt) -> &mut Self {
+        self.bytes(id, &[])
+    }
+
+    /// Recover the underlying buffer
+    pub fn into_inner(self) -> W {
+        self.buf
+    }
+}
+
 fn decode_cid(len: usize, value: &mut Option<ConnectionId>, r: &mut impl Buf) -> Result<(), Error> {
     if len > MAX_CID_SIZE || value.is_some() || r.remaining() < len {
         return Err(Error::Malformed);
@@ -464,6 +595,44 @@ mod test {
         );
     }
 
+    #[test]
+    fn truncated_max_datagram_frame_size() {
+        // max_datagram_frame_size with a length of 1 but a two-byte varint value
+        let buf = [0x20, 0x01, 0x40];
+        assert_eq!(
+            TransportParameters::read(Side::Client, &mut &buf[..]),
+            Err(Error::Malformed)
+        );
+    }
+
+    #[test]
+    fn extension_parameters() {
+        let mut buf = Vec::new();
+        TransportParameters {
+            initial_max_data: 1234u32.into(),
+            ..TransportParameters::default()
+        }
+        .write(&mut buf);
+        let extension = VarInt::from_u32(0xff_0001);
+        ParameterWriter::new(&mut buf).flag(extension);
+
+        // Standard parameters are unaffected
+        let params = TransportParameters::read(Side::Client, &mut buf.as_slice()).unwrap();
+        assert_eq!(params.initial_max_data, 1234u32.into());
+
+        let raw = ParameterReader::new(&buf)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let initial_max_data = raw.iter().find(|x| x.id.into_inner() == 0x04).unwrap();
+        assert_eq!(initial_max_data.integer(), Ok(1234u32.into()));
+        assert_eq!(raw.last().unwrap().id, extension);
+        assert_eq!(raw.last().unwrap().integer(), Err(Error::Malformed));
+
+        let mut truncated = ParameterReader::new(&buf[..buf.len() - 1]);
+        assert!(truncated.by_ref().any(|x| x.is_err()));
+        assert!(truncated.next().is_none());
+    }
+
     #[test]
     fn resumption_params_validation() {
         let high_limit = TransportParameters {
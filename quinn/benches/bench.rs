@@ -10,14 +10,17 @@ use tokio::runtime::{Builder, Runtime};
 use tracing::error_span;
 use tracing_futures::Instrument as _;
 
-use quinn::{ClientConfigBuilder, Endpoint, ServerConfigBuilder};
+use quinn::{memory::MemoryNetwork, ClientConfigBuilder, Endpoint, ServerConfigBuilder};
 
 benchmark_group!(
     benches,
     large_data_1_stream,
     large_data_10_streams,
     small_data_1_stream,
-    small_data_100_streams
+    small_data_100_streams,
+    large_data_1_stream_memory,
+    large_data_10_streams_memory,
+    small_data_100_streams_memory
 );
 benchmark_main!(benches);
 
@@ -37,10 +40,31 @@ fn small_data_100_streams(bench: &mut Bencher) {
     send_data(bench, SMALL_DATA, 100);
 }
 
+fn large_data_1_stream_memory(bench: &mut Bencher) {
+    send_data_with(bench, Network::memory(), LARGE_DATA, 1);
+}
+
+fn large_data_10_streams_memory(bench: &mut Bencher) {
+    send_data_with(bench, Network::memory(), LARGE_DATA, 10);
+}
+
+fn small_data_100_streams_memory(bench: &mut Bencher) {
+    send_data_with(bench, Network::memory(), SMALL_DATA, 100);
+}
+
 fn send_data(bench: &mut Bencher, data: &'static [u8], concurrent_streams: usize) {
+    send_data_with(bench, Network::Udp, data, concurrent_streams);
+}
+
+fn send_data_with(
+    bench: &mut Bencher,
+    network: Network,
+    data: &'static [u8],
+    concurrent_streams: usize,
+) {
     let _ = tracing_subscriber::fmt::try_init();
 
-    let ctx = Context::new();
+    let ctx = Context::new(network);
     let (addr, thread) = ctx.spawn_server();
     let (endpoint, client, runtime) = ctx.make_client(addr);
     let client = Arc::new(client);
@@ -69,14 +93,40 @@ fn send_data(bench: &mut Bencher, data: &'static [u8], concurrent_streams: usize
     thread.join().unwrap()
 }
 
+/// How the benchmarked endpoints exchange datagrams
+#[derive(Clone)]
+enum Network {
+    /// UDP sockets on the loopback interface
+    Udp,
+    /// An in-process network, isolating quinn from the operating system's network stack
+    Memory(MemoryNetwork),
+}
+
+impl Network {
+    fn memory() -> Self {
+        Network::Memory(MemoryNetwork::new())
+    }
+
+    /// Bind an endpoint to a fresh local address; must be called within a runtime context
+    fn bind(&self, endpoint: quinn::EndpointBuilder) -> (quinn::Endpoint, quinn::Incoming) {
+        let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0);
+        match self {
+            Network::Udp => endpoint.with_socket(UdpSocket::bind(addr).unwrap()),
+            Network::Memory(network) => endpoint.with_datagram_socket(network.bind(addr).unwrap()),
+        }
+        .unwrap()
+    }
+}
+
 struct Context {
+    network: Network,
     server_config: quinn::ServerConfig,
     client_config: quinn::ClientConfig,
 }
 
 impl Context {
     #[allow(clippy::field_reassign_with_default)] // https://github.com/rust-lang/rust-clippy/issues/6527
-    fn new() -> Self {
+    fn new(network: Network) -> Self {
         let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
         let key = quinn::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
         let cert = quinn::Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
@@ -93,23 +143,25 @@ impl Context {
         client_config.add_certificate_authority(cert).unwrap();
 
         Self {
+            network,
             server_config: server_config.build(),
             client_config: client_config.build(),
         }
     }
 
     pub fn spawn_server(&self) -> (SocketAddr, thread::JoinHandle<()>) {
-        let sock = UdpSocket::bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
-        let addr = sock.local_addr().unwrap();
+        let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+        let network = self.network.clone();
         let config = self.server_config.clone();
         let handle = thread::spawn(move || {
             let mut endpoint = Endpoint::builder();
             endpoint.listen(config);
             let runtime = rt();
-            let (_, mut incoming) = {
+            let (endpoint, mut incoming) = {
                 let _guard = runtime.enter();
-                endpoint.with_socket(sock).unwrap()
+                network.bind(endpoint)
             };
+            addr_tx.send(endpoint.local_addr().unwrap()).unwrap();
             let handle = runtime.spawn(
                 async move {
                     let quinn::NewConnection {
@@ -136,7 +188,7 @@ impl Context {
             );
             runtime.block_on(handle).unwrap();
         });
-        (addr, handle)
+        (addr_rx.recv().unwrap(), handle)
     }
 
     pub fn make_client(
@@ -146,9 +198,7 @@ impl Context {
         let runtime = rt();
         let (endpoint, _) = {
             let _guard = runtime.enter();
-            Endpoint::builder()
-                .bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0))
-                .unwrap()
+            self.network.bind(Endpoint::builder())
         };
         let quinn::NewConnection { connection, .. } = runtime
             .block_on(async {
@@ -141,7 +141,7 @@ async fn run(options: Opt) -> Result<()> {
     Ok(())
 }
 
-async fn handle_connection(root: Arc<Path>, conn: quinn::Connecting) -> Result<()> {
+async fn handle_connection(root: Arc<Path>, conn: quinn::IncomingConnection) -> Result<()> {
     let quinn::NewConnection {
         connection,
         mut bi_streams,
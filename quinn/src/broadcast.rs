@@ -53,6 +53,14 @@ impl Broadcast {
             waker.wake();
         }
     }
+
+    /// Move all known `Waker`s into `out`, to be woken by the caller
+    ///
+    /// Equivalent to `wake`, but lets the wakeups be deferred until a lock is released.
+    pub fn drain_into(&mut self, out: &mut Vec<Waker>) {
+        self.generation = self.generation.wrapping_add(1);
+        out.append(&mut self.wakers);
+    }
 }
 
 /// State maintained by each interested task
@@ -9,10 +9,12 @@ use tracing::error;
 
 use crate::{
     endpoint::{Endpoint, EndpointDriver, EndpointRef, Incoming},
+    pcap::PcapWriter,
     platform::UdpSocket,
+    socket::DatagramSocket,
 };
 #[cfg(feature = "rustls")]
-use crate::{Certificate, CertificateChain, PrivateKey};
+use crate::{Certificate, CertificateChain, PrivateKey, ResumptionStore};
 
 /// A helper for constructing an [`Endpoint`].
 ///
@@ -28,6 +30,7 @@ where
     server_config: Option<ServerConfig<S>>,
     config: EndpointConfig<S>,
     default_client_config: ClientConfig<S>,
+    capture: Option<PcapWriter>,
 }
 
 #[allow(missing_docs)]
@@ -41,6 +44,7 @@ where
             server_config: None,
             config,
             default_client_config,
+            capture: None,
         }
     }
 
@@ -68,10 +72,34 @@ where
     ) -> Result<(Endpoint<S>, Incoming<S>), EndpointError> {
         let addr = socket.local_addr().map_err(EndpointError::Socket)?;
         let socket = UdpSocket::from_std(socket).map_err(EndpointError::Socket)?;
+        Ok(self.with_transport(Box::new(socket), addr.is_ipv6()))
+    }
+
+    /// Build an endpoint around a custom datagram transport
+    ///
+    /// Must be called from within a tokio runtime context. To avoid consuming the
+    /// `EndpointBuilder`, call `clone()` first.
+    pub fn with_datagram_socket<T: DatagramSocket>(
+        self,
+        socket: T,
+    ) -> Result<(Endpoint<S>, Incoming<S>), EndpointError> {
+        let addr = socket.local_addr().map_err(EndpointError::Socket)?;
+        Ok(self.with_transport(Box::new(socket), addr.is_ipv6()))
+    }
+
+    fn with_transport(
+        self,
+        socket: Box<dyn DatagramSocket>,
+        ipv6: bool,
+    ) -> (Endpoint<S>, Incoming<S>) {
+        let socket = match self.capture {
+            Some(ref capture) => capture.socket(socket),
+            None => socket,
+        };
         let rc = EndpointRef::new(
             socket,
             proto::generic::Endpoint::new(Arc::new(self.config), self.server_config.map(Arc::new)),
-            addr.is_ipv6(),
+            ipv6,
         );
         let driver = EndpointDriver(rc.clone());
         tokio::spawn(async {
@@ -79,13 +107,21 @@ where
                 error!("I/O error: {}", e);
             }
         });
-        Ok((
+        (
             Endpoint {
                 inner: rc.clone(),
                 default_client_config: self.default_client_config,
             },
             Incoming::new(rc),
-        ))
+        )
+    }
+
+    /// Record all datagrams sent and received by the endpoint to `capture`
+    ///
+    /// See [`pcap`](crate::pcap) for details.
+    pub fn capture(&mut self, capture: PcapWriter) -> &mut Self {
+        self.capture = Some(capture);
+        self
     }
 
     /// Accept incoming connections.
@@ -125,6 +161,7 @@ where
             server_config: None,
             config: EndpointConfig::default(),
             default_client_config: ClientConfig::default(),
+            capture: None,
         }
     }
 }
@@ -168,6 +205,17 @@ where
         self.config.use_stateless_retry(enabled);
         self
     }
+
+    /// Choose the application protocol of each connection by calling `selector`
+    ///
+    /// See [`ServerConfig::alpn_selector()`](crate::generic::ServerConfig::alpn_selector).
+    pub fn alpn_selector<F>(&mut self, selector: F) -> &mut Self
+    where
+        F: Fn(Option<&str>, &[Vec<u8>]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    {
+        self.config.alpn_selector(selector);
+        self
+    }
 }
 
 #[cfg(feature = "rustls")]
@@ -180,6 +228,14 @@ impl ServerConfigBuilder<proto::crypto::rustls::TlsSession> {
         self
     }
 
+    /// Log cryptographic keys to `key_log`
+    ///
+    /// Pass a [`PcapWriter`] to embed the keys in a packet capture.
+    pub fn key_log(&mut self, key_log: Arc<dyn rustls::KeyLog>) -> &mut Self {
+        Arc::make_mut(&mut self.config.crypto).key_log = key_log;
+        self
+    }
+
     /// Set the certificate chain that will be presented to clients.
     pub fn certificate(
         &mut self,
@@ -298,6 +354,14 @@ impl ClientConfigBuilder<proto::crypto::rustls::TlsSession> {
         self
     }
 
+    /// Log cryptographic keys to `key_log`
+    ///
+    /// Pass a [`PcapWriter`] to embed the keys in a packet capture.
+    pub fn key_log(&mut self, key_log: Arc<dyn rustls::KeyLog>) -> &mut Self {
+        Arc::make_mut(&mut self.config.crypto).key_log = key_log;
+        self
+    }
+
     /// Set the application-layer protocols to accept, in order of descending preference.
     ///
     /// When set, clients which don't declare support for at least one of the supplied protocols will be rejected.
@@ -316,6 +380,15 @@ impl ClientConfigBuilder<proto::crypto::rustls::TlsSession> {
         Arc::make_mut(&mut self.config.crypto).enable_early_data = true;
         self
     }
+
+    /// Keep session tickets, tokens and path characteristics of servers in `store`
+    ///
+    /// Export the store before shutting down and import it on start-up to speed up connections
+    /// across restarts.
+    pub fn resumption_store(&mut self, store: Arc<ResumptionStore>) -> &mut Self {
+        self.config.resumption_store(store);
+        self
+    }
 }
 
 impl<S> Clone for ClientConfigBuilder<S>
@@ -1,11 +1,11 @@
 use std::{
-    collections::HashMap,
+    collections::{hash_map, HashMap},
     fmt,
     future::Future,
     mem,
     net::{IpAddr, SocketAddr},
     pin::Pin,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, MutexGuard},
     task::{Context, Poll, Waker},
     time::{Duration, Instant},
 };
@@ -15,17 +15,35 @@ use futures::{
     channel::{mpsc, oneshot},
     FutureExt, StreamExt,
 };
-use proto::{ConnectionError, ConnectionHandle, ConnectionStats, Dir, StreamEvent, StreamId};
+use proto::{
+    ConnectionError, ConnectionHandle, ConnectionStats, Dir, PathInfo, StreamEvent, StreamId,
+};
 use thiserror::Error;
 use tokio::time::{sleep_until, Instant as TokioInstant, Sleep};
-use tracing::info_span;
+use tracing::{debug_span, field, info_span, Span};
 
 use crate::{
     broadcast::{self, Broadcast},
+    extensions::Extensions,
+    metrics::{ConnectionMetrics, Counters},
+    platform::BATCH_SIZE,
+    replay::Replay,
     streams::{RecvStream, SendStream, WriteError},
+    transmit_queue::BatchPool,
     ConnectionEvent, EndpointEvent, VarInt,
 };
 
+/// Endpoint state handed to each of its connections
+#[derive(Debug)]
+pub(crate) struct EndpointHandles {
+    /// Events for the endpoint driver
+    pub(crate) events: mpsc::UnboundedSender<(ConnectionHandle, EndpointEvent)>,
+    /// Emptied batches of transmits to reuse
+    pub(crate) batches: Arc<BatchPool>,
+    /// Counters updated by every connection
+    pub(crate) metrics: Arc<Counters>,
+}
+
 /// In-progress connection attempt future
 #[derive(Debug)]
 pub struct Connecting<S>
@@ -44,21 +62,28 @@ where
     pub(crate) fn new(
         handle: ConnectionHandle,
         conn: proto::generic::Connection<S>,
-        endpoint_events: mpsc::UnboundedSender<(ConnectionHandle, EndpointEvent)>,
+        endpoint: EndpointHandles,
         conn_events: mpsc::UnboundedReceiver<ConnectionEvent>,
+        label: Option<&str>,
     ) -> Connecting<S> {
         let (on_handshake_data_send, on_handshake_data_recv) = oneshot::channel();
         let (on_connected_send, on_connected_recv) = oneshot::channel();
         let conn = ConnectionRef::new(
             handle,
             conn,
-            endpoint_events,
+            endpoint,
             conn_events,
             on_handshake_data_send,
             on_connected_send,
         );
+        if let Some(label) = label {
+            conn.lock().unwrap().set_label(label);
+        }
 
-        tokio::spawn(ConnectionDriver(conn.clone()));
+        tokio::spawn(ConnectionDriver {
+            conn: conn.clone(),
+            wakes: Vec::new(),
+        });
 
         Connecting {
             conn: Some(conn),
@@ -85,6 +110,11 @@ where
     /// intercepted by a man-in-the-middle. If this occurs, the handshake will not complete
     /// successfully.
     ///
+    /// Incoming connections also yield streams the client opened in 0-RTT, whose data can be read
+    /// right away. That data might be replayed, and [`RecvStream::received_0rtt()`] tells it apart.
+    ///
+    /// [`RecvStream::received_0rtt()`]: crate::generic::RecvStream::received_0rtt
+    ///
     /// # Errors
     ///
     /// Outgoing connections are only 0-RTT-capable when a cryptographic session ticket cached from
@@ -106,6 +136,50 @@ where
         }
     }
 
+    /// Convert into a 0-RTT connection that sends its early data again if the server rejects it
+    ///
+    /// Like [`into_0rtt()`](Self::into_0rtt), except that streams opened before the handshake
+    /// completes and marked with [`SendStream::mark_idempotent()`] remain usable either way: if the
+    /// server rejects 0-RTT, they're opened again and everything written to them is sent again once
+    /// the handshake completes, rather than failing with `ZeroRttRejected`. Other streams fail as
+    /// they would after `into_0rtt()`. Data the server sent on rejected streams is lost, so reads
+    /// start over too. This holds a copy of all data written until the server's answer is known,
+    /// and of the marked streams' data until it has been sent again.
+    ///
+    /// If no 0-RTT key is available, nothing is attempted and the streams are simply used once the
+    /// handshake completes. The [`ZeroRttOutcome`] future tells these cases apart.
+    ///
+    /// # Security
+    ///
+    /// Data written before the handshake completes might be replayed by an attacker even when the
+    /// server accepts 0-RTT, and data on marked streams is sent twice to the server when it rejects
+    /// 0-RTT, so it should never invoke non-idempotent operations.
+    ///
+    /// [`SendStream::mark_idempotent()`]: crate::generic::SendStream::mark_idempotent
+    ///
+    /// # Panics
+    ///
+    /// Panics on incoming connections, which have no early data to replay.
+    pub fn into_0rtt_with_replay(mut self) -> (NewConnection<S>, ZeroRttOutcome) {
+        let conn = self.conn.take().unwrap();
+        let attempted = {
+            let mut inner = conn.lock().unwrap();
+            assert!(
+                inner.inner.side().is_client(),
+                "only outgoing connections replay 0-RTT data"
+            );
+            inner.replay = Some(Replay::new());
+            inner.inner.has_0rtt()
+        };
+        (
+            NewConnection::new(conn),
+            ZeroRttOutcome {
+                connected: self.connected,
+                attempted,
+            },
+        )
+    }
+
     /// Parameters negotiated during the handshake
     pub async fn handshake_data(&mut self) -> Result<S::HandshakeData, ConnectionError> {
         // Taking &mut self allows us to use a single oneshot channel rather than dealing with
@@ -148,6 +222,60 @@ where
 
         inner.inner.local_ip()
     }
+
+    /// The `tracing` span in which events relating to this connection are recorded
+    ///
+    /// See [`Connection::span()`].
+    pub fn span(&self) -> Span {
+        self.conn.as_ref().unwrap().lock().unwrap().span.clone()
+    }
+
+    /// Record future events relating to this connection in `span`
+    ///
+    /// See [`Connection::set_span()`].
+    pub fn set_span(&self, span: Span) {
+        self.conn.as_ref().unwrap().lock().unwrap().set_span(span);
+    }
+
+    /// Identify the connection as `label` in future events
+    ///
+    /// See [`Connection::set_label()`].
+    pub fn set_label(&self, label: &str) {
+        self.conn.as_ref().unwrap().lock().unwrap().set_label(label);
+    }
+
+    /// The label assigned by the application, if any
+    pub fn label(&self) -> Option<String> {
+        self.conn.as_ref().unwrap().lock().unwrap().label.clone()
+    }
+
+    /// Application state attached to this connection
+    ///
+    /// Allows state to be attached before the handshake completes, e.g. by a server as it accepts
+    /// the connection. See [`Connection::extensions()`].
+    pub fn extensions(&self) -> MutexGuard<'_, Extensions> {
+        self.conn.as_ref().unwrap().extensions()
+    }
+
+    pub(crate) fn stable_id(&self) -> usize {
+        self.conn.as_ref().unwrap().stable_id()
+    }
+
+    /// Subscribe to the connection's lifecycle events, including completion of the handshake
+    ///
+    /// See [`Connection::lifecycle_events()`].
+    pub fn lifecycle_events(&self) -> LifecycleEvents {
+        self.conn.as_ref().unwrap().lock().unwrap().subscribe()
+    }
+
+    /// Observe the decrypted contents of packets sent and received from now on
+    ///
+    /// See [`Connection::set_packet_tap()`].
+    #[cfg(feature = "tap")]
+    pub fn set_packet_tap(&self, tap: Option<Arc<dyn proto::tap::PacketTap>>) {
+        let conn = &mut *self.conn.as_ref().unwrap().lock().unwrap();
+        conn.inner.set_packet_tap(tap);
+    }
 }
 
 impl<S> Future for Connecting<S>
@@ -198,6 +326,39 @@ impl Future for ZeroRttAccepted {
     }
 }
 
+/// Future that completes when a connection from [`Connecting::into_0rtt_with_replay()`] is fully
+/// established, with what became of its early data
+///
+/// Completes with [`ZeroRttResult::NotAttempted`] if the connection failed instead.
+pub struct ZeroRttOutcome {
+    connected: oneshot::Receiver<bool>,
+    attempted: bool,
+}
+
+impl Future for ZeroRttOutcome {
+    type Output = ZeroRttResult;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let attempted = self.attempted;
+        self.connected.poll_unpin(cx).map(|x| match x {
+            Ok(true) => ZeroRttResult::Accepted,
+            Ok(false) if attempted => ZeroRttResult::Replayed,
+            _ => ZeroRttResult::NotAttempted,
+        })
+    }
+}
+
+/// What became of the early data of a connection from [`Connecting::into_0rtt_with_replay()`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ZeroRttResult {
+    /// The server accepted the data sent in 0-RTT
+    Accepted,
+    /// The server rejected 0-RTT, so the early data of streams marked idempotent was sent again
+    /// after the handshake
+    Replayed,
+    /// No 0-RTT key was available, so nothing was sent before the handshake completed
+    NotAttempted,
+}
+
 /// Components of a newly established connection
 ///
 /// All fields of this struct, in addition to any other handles constructed later, must be dropped
@@ -269,7 +430,11 @@ where
 /// packets still in flight from the peer are handled gracefully.
 #[must_use = "connection drivers must be spawned for their connections to function"]
 #[derive(Debug)]
-struct ConnectionDriver<S: proto::crypto::Session>(ConnectionRef<S>);
+struct ConnectionDriver<S: proto::crypto::Session> {
+    conn: ConnectionRef<S>,
+    /// Wakeups collected while the connection was locked, retained to avoid reallocation
+    wakes: Vec<Waker>,
+}
 
 impl<S> Future for ConnectionDriver<S>
 where
@@ -279,36 +444,23 @@ where
 
     #[allow(unused_mut)] // MSRV
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        let conn = &mut *self.0.lock().unwrap();
+        let this = &mut *self;
+        let mut lock = this.conn.lock().unwrap();
+        let conn = &mut *lock;
 
-        let span = info_span!("drive", id = conn.handle.0);
+        let span = conn.span.clone();
         let _guard = span.enter();
 
-        loop {
-            let mut keep_going = false;
-            if let Err(e) = conn.process_conn_events(cx) {
-                conn.terminate(e);
-                return Poll::Ready(());
-            }
-            conn.drive_transmit();
-            // If a timer expires, there might be more to transmit. When we transmit something, we
-            // might need to reset a timer. Hence, we must loop until neither happens.
-            keep_going |= conn.drive_timer(cx);
-            conn.forward_endpoint_events();
-            conn.forward_app_events();
-            if !keep_going || conn.inner.is_drained() {
-                break;
-            }
-        }
-
-        if !conn.inner.is_drained() {
-            conn.driver = Some(cx.waker().clone());
-            return Poll::Pending;
-        }
-        if conn.error.is_none() {
-            unreachable!("drained connections always have an error");
+        let result = conn.drive(cx);
+        conn.report_metrics();
+        // Tasks woken while we hold the lock would immediately contend for it, so defer their
+        // wakeups until it's released
+        mem::swap(&mut conn.pending_wakes, &mut this.wakes);
+        drop(lock);
+        for waker in this.wakes.drain(..) {
+            waker.wake();
         }
-        Poll::Ready(())
+        result
     }
 }
 
@@ -373,6 +525,26 @@ where
         conn.close(error_code, Bytes::copy_from_slice(reason));
     }
 
+    /// Close the connection once all data written to its streams has been delivered
+    ///
+    /// Unlike [`close()`], the peer is only notified once everything written to [`SendStream`]s so
+    /// far, including the end of finished streams, has been acknowledged, so that the tail of a
+    /// response can't be lost to the close. Streams remain usable until then, after which pending
+    /// operations fail with [`ConnectionError::LocallyClosed`].
+    ///
+    /// A peer that stops reading can hold the close off until the idle timeout; call [`close()`]
+    /// to give up waiting.
+    ///
+    /// [`close()`]: Connection::close
+    /// [`ConnectionError::LocallyClosed`]: crate::ConnectionError::LocallyClosed
+    /// [`SendStream`]: crate::generic::SendStream
+    pub fn close_after_flush(&self, error_code: VarInt, reason: &[u8]) {
+        let conn = &mut *self.0.lock().unwrap();
+        conn.inner
+            .close_after_flush(Instant::now(), error_code, Bytes::copy_from_slice(reason));
+        conn.wake();
+    }
+
     /// Transmit `data` as an unreliable, unordered application datagram
     ///
     /// Application datagrams are a low-level primitive. They may be lost or delivered out of order,
@@ -412,6 +584,67 @@ where
         self.0.lock().unwrap().inner.max_datagram_size()
     }
 
+    /// Change the connection-level receive window
+    ///
+    /// Growth is granted to the peer immediately, while a reduction takes effect as data is read.
+    pub fn set_receive_window(&self, value: VarInt) {
+        let conn = &mut *self.0.lock().unwrap();
+        conn.inner.set_receive_window(value);
+        conn.wake();
+    }
+
+    /// Change the per-stream receive window, for open streams as well as future ones
+    pub fn set_stream_receive_window(&self, value: VarInt) {
+        let conn = &mut *self.0.lock().unwrap();
+        conn.inner.set_stream_receive_window(value);
+        conn.wake();
+    }
+
+    /// Change the number of bidirectional streams the peer may have open concurrently
+    ///
+    /// A reduction takes effect as the peer's open streams are closed.
+    pub fn set_max_concurrent_bidi_streams(&self, count: VarInt) {
+        let conn = &mut *self.0.lock().unwrap();
+        conn.inner.set_max_concurrent_streams(Dir::Bi, count);
+        conn.wake();
+    }
+
+    /// Change the number of unidirectional streams the peer may have open concurrently
+    ///
+    /// A reduction takes effect as the peer's open streams are closed.
+    pub fn set_max_concurrent_uni_streams(&self, count: VarInt) {
+        let conn = &mut *self.0.lock().unwrap();
+        conn.inner.set_max_concurrent_streams(Dir::Uni, count);
+        conn.wake();
+    }
+
+    /// Change the keep-alive interval, or disable keep-alives with `None`
+    pub fn set_keep_alive_interval(&self, interval: Option<Duration>) {
+        let conn = &mut *self.0.lock().unwrap();
+        conn.inner.set_keep_alive_interval(Instant::now(), interval);
+        conn.wake();
+    }
+
+    /// Change the space available for buffering received datagrams
+    ///
+    /// Has no effect if datagrams were disabled by the transport configuration.
+    pub fn set_datagram_receive_buffer_size(&self, size: usize) {
+        self.0
+            .lock()
+            .unwrap()
+            .inner
+            .set_datagram_receive_buffer_size(size);
+    }
+
+    /// Change the space available for buffering outgoing datagrams
+    pub fn set_datagram_send_buffer_size(&self, size: usize) {
+        self.0
+            .lock()
+            .unwrap()
+            .inner
+            .set_datagram_send_buffer_size(size);
+    }
+
     /// The peer's UDP address
     ///
     /// If `ServerConfig::migration` is `true`, clients may change addresses at will, e.g. when
@@ -448,6 +681,21 @@ where
         self.0.lock().unwrap().inner.stats()
     }
 
+    /// Get a snapshot of the metrics exported for this connection
+    ///
+    /// See [`ConnectionMetrics::write_prometheus()`] to export them.
+    pub fn metrics(&self) -> ConnectionMetrics {
+        ConnectionMetrics::new(&self.stats())
+    }
+
+    /// Summary of the path this connection currently uses
+    ///
+    /// Includes the current MTU, how the peer's address was validated, and how many times the peer
+    /// migrated, e.g. to be logged once per session by security-sensitive applications.
+    pub fn path_info(&self) -> PathInfo {
+        self.0.lock().unwrap().inner.path_info()
+    }
+
     /// Parameters negotiated during the handshake
     ///
     /// Guaranteed to return `Some` on fully established connections or after
@@ -481,6 +729,85 @@ where
         self.0.stable_id()
     }
 
+    /// Whether the connection has been closed or lost, and can no longer be used
+    pub(crate) fn is_closed(&self) -> bool {
+        self.0.lock().unwrap().inner.is_closed()
+    }
+
+    /// The application protocol negotiated with the peer, if any
+    pub(crate) fn application_protocol(&self) -> Option<Vec<u8>> {
+        self.0
+            .lock()
+            .unwrap()
+            .inner
+            .crypto_session()
+            .application_protocol()
+    }
+
+    /// The `tracing` span in which events relating to this connection are recorded
+    ///
+    /// Carries the connection's `stable_id` and initial destination CID. Instrumenting
+    /// application tasks with this span correlates their events with the connection's.
+    pub fn span(&self) -> Span {
+        self.0.lock().unwrap().span.clone()
+    }
+
+    /// Record future events relating to this connection in `span`
+    ///
+    /// Allows applications to attach their own identifiers to the connection's events. Streams
+    /// opened or accepted afterwards are recorded in children of `span`, while existing streams
+    /// keep their current span. If `span` declares a `label` field, it is filled in with the
+    /// connection's label.
+    pub fn set_span(&self, span: Span) {
+        self.0.lock().unwrap().set_span(span);
+    }
+
+    /// Identify the connection as `label` in future events
+    ///
+    /// The label is recorded as the `label` field of the connection's span, so that operational
+    /// logs can refer to connections by application-meaningful names such as a peer's user ID.
+    /// Events recorded beforehand lack the label; to cover an outgoing connection's entire
+    /// handshake, use [`Endpoint::connect_labeled()`] instead.
+    ///
+    /// [`Endpoint::connect_labeled()`]: crate::generic::Endpoint::connect_labeled
+    pub fn set_label(&self, label: &str) {
+        self.0.lock().unwrap().set_label(label);
+    }
+
+    /// The label assigned by the application, if any
+    pub fn label(&self) -> Option<String> {
+        self.0.lock().unwrap().label.clone()
+    }
+
+    /// Application state attached to this connection
+    ///
+    /// Shared by every handle to the connection, including its streams, so that e.g. a server can
+    /// look up the peer's authenticated identity from any stream it accepts. The state lives as
+    /// long as the connection. Locking it doesn't lock the connection, but the lock must not be held
+    /// across an `.await`.
+    pub fn extensions(&self) -> MutexGuard<'_, Extensions> {
+        self.0.extensions()
+    }
+
+    /// Subscribe to the connection's lifecycle events
+    ///
+    /// The stream yields each [`LifecycleEvent`] that occurs after the call, ending with
+    /// [`LifecycleEvent::Closed`]. Subscribing to a connection that has already been closed yields
+    /// only the latter. Any number of subscriptions may exist at once.
+    pub fn lifecycle_events(&self) -> LifecycleEvents {
+        self.0.lock().unwrap().subscribe()
+    }
+
+    /// Observe the decrypted contents of packets sent and received from now on
+    ///
+    /// Replaces any tap set previously or by the transport configuration. See the [`tap`] module.
+    ///
+    /// [`tap`]: crate::tap
+    #[cfg(feature = "tap")]
+    pub fn set_packet_tap(&self, tap: Option<Arc<dyn proto::tap::PacketTap>>) {
+        self.0.lock().unwrap().inner.set_packet_tap(tap);
+    }
+
     // Update traffic keys spontaneously for testing purposes.
     #[doc(hidden)]
     pub fn force_key_update(&self) {
@@ -542,8 +869,9 @@ where
         let mut conn = self.0.lock().unwrap();
         if let Some(x) = conn.inner.accept(Dir::Uni) {
             conn.wake(); // To send additional stream ID credit
+            let span = conn.stream_span(x);
             mem::drop(conn); // Release the lock so clone can take it
-            Poll::Ready(Some(Ok(RecvStream::new(self.0.clone(), x, false))))
+            Poll::Ready(Some(Ok(RecvStream::new(self.0.clone(), x, false, span))))
         } else if let Some(ConnectionError::LocallyClosed) = conn.error {
             Poll::Ready(None)
         } else if let Some(ref e) = conn.error {
@@ -572,10 +900,11 @@ where
         if let Some(x) = conn.inner.accept(Dir::Bi) {
             let is_0rtt = conn.inner.is_handshaking();
             conn.wake(); // To send additional stream ID credit
+            let span = conn.stream_span(x);
             mem::drop(conn); // Release the lock so clone can take it
             Poll::Ready(Some(Ok((
-                SendStream::new(self.0.clone(), x, is_0rtt),
-                RecvStream::new(self.0.clone(), x, is_0rtt),
+                SendStream::new(self.0.clone(), x, is_0rtt, span.clone()),
+                RecvStream::new(self.0.clone(), x, is_0rtt, span),
             ))))
         } else if let Some(ConnectionError::LocallyClosed) = conn.error {
             Poll::Ready(None)
@@ -589,6 +918,10 @@ where
 }
 
 /// Stream of unordered, unreliable datagrams sent by the peer
+///
+/// Waiting for a datagram counts as activity for [`TransportConfig::keep_alive_only_when_active()`].
+///
+/// [`TransportConfig::keep_alive_only_when_active()`]: crate::TransportConfig::keep_alive_only_when_active
 #[derive(Debug)]
 pub struct Datagrams<S: proto::crypto::Session>(ConnectionRef<S>);
 
@@ -608,11 +941,71 @@ where
             Poll::Ready(Some(Err(e.clone())))
         } else {
             conn.datagram_reader = Some(cx.waker().clone());
+            conn.inner.set_awaiting_peer(true);
             Poll::Pending
         }
     }
 }
 
+impl<S> Drop for Datagrams<S>
+where
+    S: proto::crypto::Session,
+{
+    fn drop(&mut self) {
+        let conn = &mut *self.0.lock().unwrap();
+        conn.datagram_reader = None;
+        conn.inner.set_awaiting_peer(false);
+    }
+}
+
+/// Notable changes in the state of a connection, for audit logging and monitoring
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum LifecycleEvent {
+    /// The handshake completed and the peer was authenticated
+    HandshakeCompleted,
+    /// The server accepted the 0-RTT data we sent
+    ///
+    /// Only reported by clients, immediately after [`HandshakeCompleted`](Self::HandshakeCompleted).
+    ZeroRttAccepted,
+    /// The server rejected the 0-RTT data we sent
+    ///
+    /// Only reported by clients, before [`HandshakeCompleted`](Self::HandshakeCompleted).
+    ZeroRttRejected,
+    /// The peer began sending from a new address
+    ///
+    /// The new path may yet fail validation, in which case the previous one is restored.
+    Migrated {
+        /// The peer's new address
+        remote: SocketAddr,
+    },
+    /// The 1-RTT packet protection keys were replaced with the next generation
+    KeysUpdated {
+        /// Whether the update was initiated by the peer rather than by us
+        initiated_by_peer: bool,
+    },
+    /// The connection was closed, and no further events will occur
+    Closed {
+        /// Reason that the connection was closed
+        reason: ConnectionError,
+    },
+}
+
+/// Stream of a connection's [`LifecycleEvent`]s
+///
+/// Obtained from [`Connection::lifecycle_events()`]. Events are buffered until read, so a
+/// subscription that is no longer of interest should be dropped.
+#[derive(Debug)]
+pub struct LifecycleEvents(mpsc::UnboundedReceiver<LifecycleEvent>);
+
+impl futures::Stream for LifecycleEvents {
+    type Item = LifecycleEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.0.poll_next_unpin(cx)
+    }
+}
+
 /// A future that will resolve into an opened outgoing unidirectional stream
 pub struct OpenUni<S>
 where
@@ -636,8 +1029,12 @@ where
         }
         if let Some(id) = conn.inner.open(Dir::Uni) {
             let is_0rtt = conn.inner.side().is_client() && conn.inner.is_handshaking();
+            if let (true, Some(replay)) = (is_0rtt, conn.replay.as_mut()) {
+                replay.opened(id);
+            }
+            let span = conn.stream_span(id);
             drop(conn); // Release lock for clone
-            return Poll::Ready(Ok(SendStream::new(this.conn.clone(), id, is_0rtt)));
+            return Poll::Ready(Ok(SendStream::new(this.conn.clone(), id, is_0rtt, span)));
         }
         conn.uni_opening.register(cx, &mut this.state);
         Poll::Pending
@@ -667,10 +1064,14 @@ where
         }
         if let Some(id) = conn.inner.open(Dir::Bi) {
             let is_0rtt = conn.inner.side().is_client() && conn.inner.is_handshaking();
+            if let (true, Some(replay)) = (is_0rtt, conn.replay.as_mut()) {
+                replay.opened(id);
+            }
+            let span = conn.stream_span(id);
             drop(conn); // Release lock for clone
             return Poll::Ready(Ok((
-                SendStream::new(this.conn.clone(), id, is_0rtt),
-                RecvStream::new(this.conn.clone(), id, is_0rtt),
+                SendStream::new(this.conn.clone(), id, is_0rtt, span.clone()),
+                RecvStream::new(this.conn.clone(), id, is_0rtt, span),
             )));
         }
         conn.bi_opening.register(cx, &mut this.state);
@@ -678,8 +1079,25 @@ where
     }
 }
 
+/// Application state of a bidirectional stream, shared by its halves
+#[derive(Default)]
+struct StreamExtensions {
+    /// Allocated when either half first uses them
+    extensions: Option<Arc<Mutex<Extensions>>>,
+    /// Whether one of the halves has already been dropped
+    half_dropped: bool,
+}
+
+/// Shared handle to a connection's state
+///
+/// The driver doesn't wake application tasks until it has released the lock, so woken tasks
+/// don't immediately contend with it.
 #[derive(Debug)]
-pub struct ConnectionRef<S: proto::crypto::Session>(Arc<Mutex<ConnectionInner<S>>>);
+pub struct ConnectionRef<S: proto::crypto::Session>(
+    Arc<Mutex<ConnectionInner<S>>>,
+    /// Application state, locked separately so that it can be used while the connection is busy
+    Arc<Mutex<Extensions>>,
+);
 
 impl<S> ConnectionRef<S>
 where
@@ -688,39 +1106,85 @@ where
     fn new(
         handle: ConnectionHandle,
         conn: proto::generic::Connection<S>,
-        endpoint_events: mpsc::UnboundedSender<(ConnectionHandle, EndpointEvent)>,
+        endpoint: EndpointHandles,
         conn_events: mpsc::UnboundedReceiver<ConnectionEvent>,
         on_handshake_data: oneshot::Sender<()>,
         on_connected: oneshot::Sender<bool>,
     ) -> Self {
-        Self(Arc::new(Mutex::new(ConnectionInner {
-            inner: conn,
-            driver: None,
-            handle,
-            on_handshake_data: Some(on_handshake_data),
-            on_connected: Some(on_connected),
-            connected: false,
-            timer: None,
-            timer_deadline: None,
-            conn_events,
-            endpoint_events,
-            blocked_writers: HashMap::new(),
-            blocked_readers: HashMap::new(),
-            uni_opening: Broadcast::new(),
-            bi_opening: Broadcast::new(),
-            incoming_uni_streams_reader: None,
-            incoming_bi_streams_reader: None,
-            datagram_reader: None,
-            finishing: HashMap::new(),
-            stopped: HashMap::new(),
-            error: None,
-            ref_count: 0,
-        })))
+        let EndpointHandles {
+            events: endpoint_events,
+            batches,
+            metrics,
+        } = endpoint;
+        metrics.connection_started();
+        let span = info_span!(
+            "connection",
+            id = field::Empty,
+            label = field::Empty,
+            odcid = %conn.initial_dst_cid(),
+            side = ?conn.side()
+        );
+        let this = Self(
+            Arc::new(Mutex::new(ConnectionInner {
+                inner: conn,
+                driver: None,
+                handle,
+                on_handshake_data: Some(on_handshake_data),
+                on_connected: Some(on_connected),
+                connected: false,
+                timer: None,
+                timer_deadline: None,
+                conn_events,
+                endpoint_events,
+                batch: Vec::new(),
+                batches,
+                blocked_writers: HashMap::new(),
+                blocked_readers: HashMap::new(),
+                uni_opening: Broadcast::new(),
+                bi_opening: Broadcast::new(),
+                incoming_uni_streams_reader: None,
+                incoming_bi_streams_reader: None,
+                datagram_reader: None,
+                finishing: HashMap::new(),
+                stopped: HashMap::new(),
+                stream_extensions: HashMap::new(),
+                pending_wakes: Vec::new(),
+                error: None,
+                ref_count: 0,
+                span,
+                label: None,
+                metrics,
+                reported_stats: ConnectionStats::default(),
+                lifecycle_subscribers: Vec::new(),
+                replay: None,
+            })),
+            Arc::default(),
+        );
+        this.lock().unwrap().span.record("id", this.stable_id());
+        this
     }
 
     fn stable_id(&self) -> usize {
         &*self.0 as *const _ as usize
     }
+
+    pub(crate) fn extensions(&self) -> MutexGuard<'_, Extensions> {
+        self.1.lock().unwrap()
+    }
+
+    /// Allocate the application state of stream `id`, or find that of its other half
+    pub(crate) fn stream_extensions(&self, id: StreamId) -> Arc<Mutex<Extensions>> {
+        if id.dir() == Dir::Uni {
+            return Arc::default();
+        }
+        let mut conn = self.lock().unwrap();
+        conn.stream_extensions
+            .entry(id)
+            .or_default()
+            .extensions
+            .get_or_insert_with(Arc::default)
+            .clone()
+    }
 }
 
 impl<S> Clone for ConnectionRef<S>
@@ -729,7 +1193,7 @@ where
 {
     fn clone(&self) -> Self {
         self.0.lock().unwrap().ref_count += 1;
-        Self(self.0.clone())
+        Self(self.0.clone(), self.1.clone())
     }
 }
 
@@ -776,6 +1240,10 @@ where
     timer_deadline: Option<TokioInstant>,
     conn_events: mpsc::UnboundedReceiver<ConnectionEvent>,
     endpoint_events: mpsc::UnboundedSender<(ConnectionHandle, EndpointEvent)>,
+    /// Transmits to hand to the endpoint, filled in place so that idle drives allocate nothing
+    batch: Vec<proto::Transmit>,
+    /// Where the endpoint returns batches once it's taken their transmits
+    batches: Arc<BatchPool>,
     pub(crate) blocked_writers: HashMap<StreamId, Waker>,
     pub(crate) blocked_readers: HashMap<StreamId, Waker>,
     uni_opening: Broadcast,
@@ -785,23 +1253,78 @@ where
     datagram_reader: Option<Waker>,
     pub(crate) finishing: HashMap<StreamId, oneshot::Sender<Option<WriteError>>>,
     pub(crate) stopped: HashMap<StreamId, Waker>,
+    /// Extensions of bidirectional streams, kept until both halves are dropped
+    stream_extensions: HashMap<StreamId, StreamExtensions>,
+    /// Application tasks to wake once the lock is released
+    ///
+    /// Populated in response to connection events, and drained by the driver at the end of each
+    /// poll.
+    pending_wakes: Vec<Waker>,
     /// Always set to Some before the connection becomes drained
     pub(crate) error: Option<ConnectionError>,
     /// Number of live handles that can be used to initiate or handle I/O; excludes the driver
     ref_count: usize,
+    /// Span that events relating to this connection are recorded in
+    span: Span,
+    /// Application-supplied identifier recorded in `span`
+    label: Option<String>,
+    /// Endpoint-wide counters this connection's activity is added to
+    metrics: Arc<Counters>,
+    /// Statistics as of the last update to `metrics`
+    reported_stats: ConnectionStats,
+    lifecycle_subscribers: Vec<mpsc::UnboundedSender<LifecycleEvent>>,
+    /// Early data to send again if the server rejects 0-RTT, for connections that asked for that
+    pub(crate) replay: Option<Replay>,
 }
 
 impl<S> ConnectionInner<S>
 where
     S: proto::crypto::Session,
 {
+    fn drive(&mut self, cx: &mut Context) -> Poll<()> {
+        loop {
+            let mut keep_going = false;
+            if let Err(e) = self.process_conn_events(cx) {
+                self.terminate(e);
+                return Poll::Ready(());
+            }
+            self.drive_transmit();
+            // If a timer expires, there might be more to transmit. When we transmit something, we
+            // might need to reset a timer. Hence, we must loop until neither happens.
+            keep_going |= self.drive_timer(cx);
+            self.forward_endpoint_events();
+            // Replaying rejected 0-RTT data leaves new packets to transmit
+            keep_going |= self.forward_app_events();
+            if !keep_going || self.inner.is_drained() {
+                break;
+            }
+        }
+
+        if !self.inner.is_drained() {
+            self.driver = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        if self.error.is_none() {
+            unreachable!("drained connections always have an error");
+        }
+        Poll::Ready(())
+    }
+
     fn drive_transmit(&mut self) {
         let now = Instant::now();
-        while let Some(t) = self.inner.poll_transmit(now) {
+        loop {
+            if self.batch.capacity() == 0 {
+                self.batch = self.batches.get();
+            }
+            // Batches match what the endpoint can send in one system call
+            if self.inner.poll_transmits(now, &mut self.batch, BATCH_SIZE) == 0 {
+                break;
+            }
+            let batch = mem::take(&mut self.batch);
             // If the endpoint driver is gone, noop.
             let _ = self
                 .endpoint_events
-                .unbounded_send((self.handle, EndpointEvent::Transmit(t)));
+                .unbounded_send((self.handle, EndpointEvent::Transmits(batch)));
         }
     }
 
@@ -838,7 +1361,9 @@ where
         }
     }
 
-    fn forward_app_events(&mut self) {
+    /// Returns whether data was written on the application's behalf
+    fn forward_app_events(&mut self) -> bool {
+        let mut wrote = false;
         while let Some(event) = self.inner.poll() {
             use proto::Event::*;
             match event {
@@ -849,37 +1374,76 @@ where
                 }
                 Connected => {
                     self.connected = true;
+                    self.metrics.handshake_completed();
+                    self.notify(LifecycleEvent::HandshakeCompleted);
+                    if self.inner.side().is_client() && self.inner.accepted_0rtt() {
+                        self.notify(LifecycleEvent::ZeroRttAccepted);
+                        self.replay = None;
+                    } else if let Some(ref mut replay) = self.replay {
+                        replay.connected();
+                        // Streams and writes blocked for want of 0-RTT keys can proceed
+                        self.pending_wakes
+                            .extend(self.blocked_writers.drain().map(|(_, x)| x));
+                        self.uni_opening.drain_into(&mut self.pending_wakes);
+                        self.bi_opening.drain_into(&mut self.pending_wakes);
+                    }
                     if let Some(x) = self.on_connected.take() {
                         // We don't care if the on-connected future was dropped
                         let _ = x.send(self.inner.accepted_0rtt());
                     }
                 }
+                Migrated { remote } => {
+                    self.notify(LifecycleEvent::Migrated { remote });
+                }
+                KeysUpdated { initiated_by_peer } => {
+                    self.notify(LifecycleEvent::KeysUpdated { initiated_by_peer });
+                }
+                ZeroRttRejected => {
+                    if let Some(ref mut replay) = self.replay {
+                        replay.rejected(&mut self.inner);
+                        wrote = true;
+                        // Streams are usable again, whatever their tasks were waiting for
+                        let wakes = &mut self.pending_wakes;
+                        wakes.extend(self.blocked_writers.drain().map(|(_, x)| x));
+                        wakes.extend(self.blocked_readers.drain().map(|(_, x)| x));
+                        wakes.extend(self.stopped.drain().map(|(_, x)| x));
+                    }
+                    self.notify(LifecycleEvent::ZeroRttRejected);
+                }
                 ConnectionLost { reason } => {
                     self.terminate(reason);
                 }
                 Stream(StreamEvent::Writable { id }) => {
+                    if let Some(ref mut replay) = self.replay {
+                        wrote = true;
+                        if !replay.flush(&mut self.inner, id) {
+                            continue;
+                        }
+                    }
                     if let Some(writer) = self.blocked_writers.remove(&id) {
-                        writer.wake();
+                        self.pending_wakes.push(writer);
                     }
                 }
                 Stream(StreamEvent::Opened { dir: Dir::Uni }) => {
                     if let Some(x) = self.incoming_uni_streams_reader.take() {
-                        x.wake();
+                        self.pending_wakes.push(x);
                     }
                 }
                 Stream(StreamEvent::Opened { dir: Dir::Bi }) => {
                     if let Some(x) = self.incoming_bi_streams_reader.take() {
-                        x.wake();
+                        self.pending_wakes.push(x);
                     }
                 }
                 DatagramReceived => {
                     if let Some(x) = self.datagram_reader.take() {
-                        x.wake();
+                        self.pending_wakes.push(x);
+                        // The reader waits again, if it still wants to, once it's polled
+                        self.inner.set_awaiting_peer(false);
                     }
                 }
                 Stream(StreamEvent::Readable { id }) => {
                     if let Some(reader) = self.blocked_readers.remove(&id) {
-                        reader.wake();
+                        self.pending_wakes.push(reader);
                     }
                 }
                 Stream(StreamEvent::Available { dir }) => {
@@ -887,7 +1451,7 @@ where
                         Dir::Uni => &mut self.uni_opening,
                         Dir::Bi => &mut self.bi_opening,
                     };
-                    tasks.wake();
+                    tasks.drain_into(&mut self.pending_wakes);
                 }
                 Stream(StreamEvent::Finished { id }) => {
                     if let Some(finishing) = self.finishing.remove(&id) {
@@ -897,17 +1461,20 @@ where
                 }
                 Stream(StreamEvent::Stopped { id, error_code }) => {
                     if let Some(stopped) = self.stopped.remove(&id) {
-                        stopped.wake();
+                        self.pending_wakes.push(stopped);
                     }
                     if let Some(finishing) = self.finishing.remove(&id) {
                         let _ = finishing.send(Some(WriteError::Stopped(error_code)));
                     }
                     if let Some(writer) = self.blocked_writers.remove(&id) {
-                        writer.wake();
+                        self.pending_wakes.push(writer);
                     }
                 }
+                // Events this wrapper doesn't surface yet
+                _ => {}
             }
         }
+        wrote
     }
 
     fn drive_timer(&mut self, cx: &mut Context) -> bool {
@@ -960,6 +1527,42 @@ where
         true
     }
 
+    fn set_span(&mut self, span: Span) {
+        if let Some(ref label) = self.label {
+            span.record("label", label.as_str());
+        }
+        self.span = span;
+    }
+
+    fn set_label(&mut self, label: &str) {
+        self.span.record("label", label);
+        self.label = Some(label.into());
+    }
+
+    /// Create the span in which events relating to stream `id` are recorded
+    fn stream_span(&self, id: StreamId) -> Span {
+        debug_span!(parent: &self.span, "stream", %id)
+    }
+
+    /// Note that a half of stream `id` was dropped, forgetting its extensions with the second
+    pub(crate) fn release_stream_extensions(&mut self, id: StreamId) {
+        if id.dir() == Dir::Uni {
+            return;
+        }
+        match self.stream_extensions.entry(id) {
+            hash_map::Entry::Occupied(e) if e.get().half_dropped => {
+                e.remove();
+            }
+            hash_map::Entry::Occupied(mut e) => e.get_mut().half_dropped = true,
+            hash_map::Entry::Vacant(e) => {
+                e.insert(StreamExtensions {
+                    extensions: None,
+                    half_dropped: true,
+                });
+            }
+        }
+    }
+
     /// Wake up a blocked `Driver` task to process I/O
     pub(crate) fn wake(&mut self) {
         if let Some(x) = self.driver.take() {
@@ -967,25 +1570,53 @@ where
         }
     }
 
+    /// Add activity since the previous call to the endpoint's metrics
+    fn report_metrics(&mut self) {
+        let stats = self.inner.stats();
+        self.metrics.record(&self.reported_stats, &stats);
+        self.reported_stats = stats;
+    }
+
+    fn subscribe(&mut self) -> LifecycleEvents {
+        let (send, recv) = mpsc::unbounded();
+        match self.error {
+            // Dropping the sender ends the stream after this event
+            Some(ref reason) => {
+                let _ = send.unbounded_send(LifecycleEvent::Closed {
+                    reason: reason.clone(),
+                });
+            }
+            None => self.lifecycle_subscribers.push(send),
+        }
+        LifecycleEvents(recv)
+    }
+
+    /// Report `event` to lifecycle subscribers, forgetting those that have gone away
+    fn notify(&mut self, event: LifecycleEvent) {
+        self.lifecycle_subscribers
+            .retain(|x| x.unbounded_send(event.clone()).is_ok());
+    }
+
     /// Used to wake up all blocked futures when the connection becomes closed for any reason
     fn terminate(&mut self, reason: ConnectionError) {
         self.error = Some(reason.clone());
-        for (_, writer) in self.blocked_writers.drain() {
-            writer.wake()
-        }
-        for (_, reader) in self.blocked_readers.drain() {
-            reader.wake()
-        }
-        self.uni_opening.wake();
-        self.bi_opening.wake();
+        self.notify(LifecycleEvent::Closed {
+            reason: reason.clone(),
+        });
+        self.lifecycle_subscribers.clear();
+        let wakes = &mut self.pending_wakes;
+        wakes.extend(self.blocked_writers.drain().map(|(_, x)| x));
+        wakes.extend(self.blocked_readers.drain().map(|(_, x)| x));
+        self.uni_opening.drain_into(wakes);
+        self.bi_opening.drain_into(wakes);
         if let Some(x) = self.incoming_uni_streams_reader.take() {
-            x.wake();
+            self.pending_wakes.push(x);
         }
         if let Some(x) = self.incoming_bi_streams_reader.take() {
-            x.wake();
+            self.pending_wakes.push(x);
         }
         if let Some(x) = self.datagram_reader.take() {
-            x.wake();
+            self.pending_wakes.push(x);
         }
         for (_, x) in self.finishing.drain() {
             let _ = x.send(Some(WriteError::ConnectionClosed(reason.clone())));
@@ -993,14 +1624,19 @@ where
         if let Some(x) = self.on_connected.take() {
             let _ = x.send(false);
         }
-        for (_, waker) in self.stopped.drain() {
-            waker.wake();
-        }
+        self.pending_wakes
+            .extend(self.stopped.drain().map(|(_, x)| x));
     }
 
     fn close(&mut self, error_code: VarInt, reason: Bytes) {
         self.inner.close(Instant::now(), error_code, reason);
         self.terminate(ConnectionError::LocallyClosed);
+        if self.inner.is_drained() {
+            // The driver has exited, so won't wake these on our behalf
+            for waker in self.pending_wakes.drain(..) {
+                waker.wake();
+            }
+        }
         self.wake();
     }
 
@@ -1009,10 +1645,11 @@ where
         self.close(0u32.into(), Bytes::new());
     }
 
-    pub(crate) fn check_0rtt(&self) -> Result<(), ()> {
+    pub(crate) fn check_0rtt(&self, id: StreamId) -> Result<(), ()> {
         if self.inner.is_handshaking()
             || self.inner.accepted_0rtt()
             || self.inner.side().is_server()
+            || matches!(self.replay, Some(ref x) if x.is_usable(id))
         {
             Ok(())
         } else {
@@ -1033,6 +1670,11 @@ where
                 EndpointEvent::Proto(proto::EndpointEvent::drained()),
             ));
         }
+        self.report_metrics();
+        if !self.connected {
+            self.metrics.handshake_failed();
+        }
+        self.metrics.connection_stopped();
     }
 }
 
@@ -0,0 +1,129 @@
+use std::io;
+
+use futures::future::try_join;
+use proto::VarInt;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::streams::{ReadError, RecvStream, SendStream, WriteError};
+
+/// Size of the buffer data is read into from an `AsyncRead`
+const BUF_SIZE: usize = 16 * 1024;
+
+/// Copy all data from `reader` to `send`, then finish `send`
+///
+/// Only reads from `reader` once `send` has accepted everything read before, so a slow peer or
+/// exhausted flow control credit holds back `reader` rather than growing a buffer. If `reader`
+/// fails, `send` is reset with `error_code` so that the peer doesn't mistake the truncated data
+/// for all of it. If the peer stops `send`, the error is returned without reading further.
+///
+/// Completes with the number of bytes copied once the peer has acknowledged all of them.
+pub async fn copy_to_stream<R, S>(
+    reader: &mut R,
+    send: &mut SendStream<S>,
+    error_code: VarInt,
+) -> Result<u64, CopyError>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    S: proto::crypto::Session,
+{
+    let mut buf = vec![0; BUF_SIZE];
+    let mut copied = 0;
+    loop {
+        let n = match reader.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                let _ = send.reset(error_code);
+                return Err(CopyError::Io(e));
+            }
+        };
+        send.write_all(&buf[..n]).await?;
+        copied += n as u64;
+    }
+    send.finish().await?;
+    Ok(copied)
+}
+
+/// Copy all data from `recv` to `writer`, then flush `writer`
+///
+/// Only reads from `recv` once `writer` has accepted everything read before, so flow control
+/// holds back the peer while `writer` is slow. If `writer` fails, `recv` is stopped with
+/// `error_code` so that the peer stops sending. If the peer resets `recv`, the error is returned
+/// without flushing or shutting down `writer`, which must not be taken to have received all data.
+///
+/// Completes with the number of bytes copied. `writer` is not shut down.
+pub async fn copy_from_stream<W, S>(
+    recv: &mut RecvStream<S>,
+    writer: &mut W,
+    error_code: VarInt,
+) -> Result<u64, CopyError>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+    S: proto::crypto::Session,
+{
+    let mut copied = 0;
+    while let Some(chunk) = recv.read_chunk(usize::MAX, true).await? {
+        if let Err(e) = writer.write_all(&chunk.bytes).await {
+            let _ = recv.stop(error_code);
+            return Err(CopyError::Io(e));
+        }
+        copied += chunk.bytes.len() as u64;
+    }
+    if let Err(e) = writer.flush().await {
+        let _ = recv.stop(error_code);
+        return Err(CopyError::Io(e));
+    }
+    Ok(copied)
+}
+
+/// Copy data in both directions between `stream` and a QUIC bidirectional stream
+///
+/// Data read from `stream` is written to `send`, and data read from `recv` is written to
+/// `stream`, as by [`copy_to_stream()`] and [`copy_from_stream()`]. Each direction is closed
+/// independently: once `recv` is finished, `stream` is shut down for writing, and once `stream`
+/// reaches the end of its data, `send` is finished, while the other direction carries on.
+///
+/// If either direction fails, the other is abandoned: `send` is reset and `recv` stopped with
+/// `error_code`, so that the peer sees the whole stream abort rather than a clean end.
+///
+/// Completes with the number of bytes copied from `stream` to `send` and from `recv` to
+/// `stream`, in that order, once both directions are done.
+pub async fn copy_bidirectional<T, S>(
+    stream: &mut T,
+    send: &mut SendStream<S>,
+    recv: &mut RecvStream<S>,
+    error_code: VarInt,
+) -> Result<(u64, u64), CopyError>
+where
+    T: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    S: proto::crypto::Session,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let result = try_join(copy_to_stream(&mut reader, &mut *send, error_code), async {
+        let copied = copy_from_stream(&mut *recv, &mut writer, error_code).await?;
+        writer.shutdown().await?;
+        Ok::<_, CopyError>(copied)
+    })
+    .await;
+    if result.is_err() {
+        // Either may already be closed, in which case there's nothing left to abort
+        let _ = send.reset(error_code);
+        let _ = recv.stop(error_code);
+    }
+    result
+}
+
+/// Reasons why copying between a QUIC stream and an I/O object might fail
+#[derive(Debug, Error)]
+pub enum CopyError {
+    /// Reading from the QUIC stream failed, e.g. because the peer reset it
+    #[error("reading from stream failed: {0}")]
+    Read(#[from] ReadError),
+    /// Writing to the QUIC stream failed, e.g. because the peer stopped it
+    #[error("writing to stream failed: {0}")]
+    Write(#[from] WriteError),
+    /// The I/O object failed
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
@@ -1,27 +1,36 @@
 use std::{
+    cmp,
     collections::{HashMap, VecDeque},
+    fmt,
     future::Future,
     io,
-    io::IoSliceMut,
-    mem::MaybeUninit,
-    net::{SocketAddr, SocketAddrV6},
+    net::{IpAddr, SocketAddr, SocketAddrV6},
     pin::Pin,
     str,
     sync::{Arc, Mutex},
     task::{Context, Poll, Waker},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
 use futures::{channel::mpsc, StreamExt};
-use proto::{self as proto, generic::ClientConfig, ConnectError, ConnectionHandle, DatagramEvent};
+use proto::{
+    self as proto, generic::ClientConfig, ConnectError, ConnectionError, ConnectionHandle,
+    DatagramEvent, EndpointStats,
+};
+
+use tokio::time::{sleep_until, Instant as TokioInstant, Sleep};
 
 use crate::{
     broadcast::{self, Broadcast},
     builders::EndpointBuilder,
-    connection::Connecting,
-    platform::{RecvMeta, UdpSocket, BATCH_SIZE},
-    ConnectionEvent, EndpointEvent, VarInt, IO_LOOP_BOUND,
+    connection::{Connecting, EndpointHandles, NewConnection},
+    metrics::{Counters, EndpointMetrics},
+    platform::{self, RecvMeta, UdpSocket, BATCH_SIZE},
+    recv_pool::RecvPool,
+    socket::DatagramSocket,
+    transmit_queue::{BatchPool, TransmitQueue},
+    ConnectionEvent, EndpointEvent, TransportConfig, VarInt, IO_LOOP_BOUND,
 };
 
 /// A QUIC endpoint.
@@ -74,6 +83,32 @@ where
         config: ClientConfig<S>,
         addr: &SocketAddr,
         server_name: &str,
+    ) -> Result<Connecting<S>, ConnectError> {
+        self.connect_inner(config, addr, server_name, None)
+    }
+
+    /// Connect to a remote endpoint, identifying the connection as `label` in all of its events
+    ///
+    /// See [`connect()`] for details, and [`Connection::set_label()`] for the meaning of `label`.
+    ///
+    /// [`connect()`]: Endpoint::connect
+    /// [`Connection::set_label()`]: crate::generic::Connection::set_label
+    pub fn connect_labeled(
+        &self,
+        config: ClientConfig<S>,
+        addr: &SocketAddr,
+        server_name: &str,
+        label: &str,
+    ) -> Result<Connecting<S>, ConnectError> {
+        self.connect_inner(config, addr, server_name, Some(label))
+    }
+
+    fn connect_inner(
+        &self,
+        config: ClientConfig<S>,
+        addr: &SocketAddr,
+        server_name: &str,
+        label: Option<&str>,
     ) -> Result<Connecting<S>, ConnectError> {
         let mut endpoint = self.inner.lock().unwrap();
         if endpoint.driver_lost {
@@ -88,7 +123,7 @@ where
             *addr
         };
         let (ch, conn) = endpoint.inner.connect(config, addr, server_name)?;
-        Ok(endpoint.connections.insert(ch, conn))
+        Ok(endpoint.connections.insert(ch, conn, label))
     }
 
     /// Switch to a new UDP socket
@@ -101,7 +136,7 @@ where
         let addr = socket.local_addr()?;
         let socket = UdpSocket::from_std(socket)?;
         let mut inner = self.inner.lock().unwrap();
-        inner.socket = socket;
+        inner.socket = Box::new(socket);
         inner.ipv6 = addr.is_ipv6();
         Ok(())
     }
@@ -111,6 +146,16 @@ where
         self.inner.lock().unwrap().socket.local_addr()
     }
 
+    /// Get statistics about the endpoint's handling of incoming datagrams and connection attempts
+    pub fn stats(&self) -> EndpointStats {
+        self.inner.lock().unwrap().inner.stats()
+    }
+
+    /// Get a snapshot of metrics aggregated over all of this endpoint's connections
+    pub fn metrics(&self) -> EndpointMetrics {
+        self.inner.lock().unwrap().connections.metrics.snapshot()
+    }
+
     /// Close all of this endpoint's connections immediately and cease accepting new connections.
     ///
     /// See [`Connection::close()`] for details.
@@ -156,6 +201,88 @@ where
         })
         .await;
     }
+
+    /// Wait for all connections on the endpoint to be cleanly shut down, giving up on each one
+    /// after `timeout`
+    ///
+    /// Like [`wait_idle()`], but bounded: each connection is allowed `timeout` from the call, or
+    /// from its creation if later, to drain. Connections that don't are no longer waited for, and
+    /// are returned so that the application can tell which peers may not have been notified.
+    ///
+    /// [`wait_idle()`]: Endpoint::wait_idle
+    pub async fn wait_idle_timeout(&self, timeout: Duration) -> Vec<UndrainedConnection> {
+        let start = TokioInstant::now();
+        let mut state = broadcast::State::default();
+        let mut undrained = Vec::<UndrainedConnection>::new();
+        let mut timer: Option<Pin<Box<Sleep>>> = None;
+        futures::future::poll_fn(|cx| loop {
+            let endpoint = &mut *self.inner.lock().unwrap();
+            let now = TokioInstant::now();
+            let mut next = None;
+            for info in endpoint.connections.info.values() {
+                if undrained.iter().any(|x| x.stable_id == info.stable_id) {
+                    continue;
+                }
+                let deadline = cmp::max(start, info.created) + timeout;
+                if deadline <= now {
+                    undrained.push(info.clone());
+                } else {
+                    next = Some(next.map_or(deadline, |x| cmp::min(x, deadline)));
+                }
+            }
+            let next = match next {
+                Some(x) => x,
+                None => return Poll::Ready(()),
+            };
+            endpoint.idle.register(cx, &mut state);
+            match timer {
+                Some(ref mut timer) => timer.as_mut().reset(next),
+                None => timer = Some(Box::pin(sleep_until(next))),
+            }
+            if timer.as_mut().unwrap().as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        })
+        .await;
+        undrained
+    }
+
+    /// Gracefully shut down the endpoint, closing whatever connections remain by `deadline`
+    ///
+    /// New connection attempts are refused immediately, and [`Incoming`] ends once connections
+    /// already queued on it have been yielded. If `notify` is set, every connection is closed
+    /// straight away with that error code and reason, so that only the closing handshake is waited
+    /// for; otherwise, the application is given until `deadline` to finish its business and close
+    /// connections itself. Connections that still haven't drained by then are closed with
+    /// `notify`, or code 0 and no reason if unset, without waiting for their peers any further.
+    ///
+    /// Returns the number of connections that had to be closed at the deadline.
+    ///
+    /// [`Incoming`]: crate::generic::Incoming
+    pub async fn shutdown(&self, deadline: Instant, notify: Option<(VarInt, &[u8])>) -> usize {
+        {
+            let endpoint = &mut *self.inner.lock().unwrap();
+            endpoint.inner.reject_new_connections();
+            endpoint.shutting_down = true;
+            if let Some(task) = endpoint.incoming_reader.take() {
+                task.wake();
+            }
+        }
+        if let Some((error_code, reason)) = notify {
+            self.close(error_code, reason);
+        }
+        let deadline = TokioInstant::from_std(deadline);
+        if tokio::time::timeout_at(deadline, self.wait_idle())
+            .await
+            .is_ok()
+        {
+            return 0;
+        }
+        let remaining = self.inner.lock().unwrap().connections.senders.len();
+        let (error_code, reason) = notify.unwrap_or((VarInt::from_u32(0), &[]));
+        self.close(error_code, reason);
+        remaining
+    }
 }
 
 impl<S> Clone for Endpoint<S>
@@ -240,10 +367,13 @@ pub(crate) struct EndpointInner<S>
 where
     S: proto::crypto::Session,
 {
-    socket: UdpSocket,
+    socket: Box<dyn DatagramSocket>,
     inner: proto::generic::Endpoint<S>,
+    /// Transmits being handed to the socket
     outgoing: VecDeque<proto::Transmit>,
-    incoming: VecDeque<Connecting<S>>,
+    /// Transmits from connections awaiting their turn for the socket
+    transmits: TransmitQueue,
+    incoming: VecDeque<proto::generic::Incoming<S>>,
     incoming_reader: Option<Waker>,
     driver: Option<Waker>,
     ipv6: bool,
@@ -252,43 +382,39 @@ where
     /// Number of live handles that can be used to initiate or handle I/O; excludes the driver
     ref_count: usize,
     driver_lost: bool,
-    recv_buf: Box<[u8]>,
+    recv_pool: RecvPool,
+    /// Woken whenever a connection is drained
     idle: Broadcast,
+    /// Set once `Endpoint::shutdown` has been called
+    shutting_down: bool,
 }
 
 impl<S> EndpointInner<S>
 where
     S: proto::crypto::Session + 'static,
 {
-    fn drive_recv<'a>(&'a mut self, cx: &mut Context, now: Instant) -> Result<bool, io::Error> {
+    fn drive_recv(&mut self, cx: &mut Context, now: Instant) -> Result<bool, io::Error> {
         let mut recvd = 0;
         let mut metas = [RecvMeta::default(); BATCH_SIZE];
-        let mut iovs = MaybeUninit::<[IoSliceMut<'a>; BATCH_SIZE]>::uninit();
-        self.recv_buf
-            .chunks_mut(self.recv_buf.len() / BATCH_SIZE)
-            .enumerate()
-            .for_each(|(i, buf)| unsafe {
-                iovs.as_mut_ptr()
-                    .cast::<IoSliceMut>()
-                    .add(i)
-                    .write(IoSliceMut::<'a>::new(buf));
-            });
-        let mut iovs = unsafe { iovs.assume_init() };
         loop {
-            match self.socket.poll_recv(cx, &mut iovs, &mut metas) {
+            let result = self
+                .socket
+                .poll_recv(cx, &mut self.recv_pool.iovs(), &mut metas);
+            match result {
                 Poll::Ready(Ok(msgs)) => {
                     recvd += msgs;
-                    for (meta, buf) in metas.iter().zip(iovs.iter()).take(msgs) {
-                        let data = buf[0..meta.len].into();
+                    for (i, meta) in metas.iter().enumerate().take(msgs) {
+                        let data = self.recv_pool.take(i, meta.len);
                         match self
                             .inner
                             .handle(now, meta.addr, meta.dst_ip, meta.ecn, data)
                         {
-                            Some((handle, DatagramEvent::NewConnection(conn))) => {
-                                let conn = self.connections.insert(handle, conn);
-                                self.incoming.push_back(conn);
+                            Some(DatagramEvent::NewConnection(incoming)) => {
+                                // Bounded by `ServerConfig::concurrent_connections`, which counts
+                                // attempts until they're decided on
+                                self.incoming.push_back(incoming);
                             }
-                            Some((handle, DatagramEvent::ConnectionEvent(event))) => {
+                            Some(DatagramEvent::ConnectionEvent(handle, event)) => {
                                 // Ignoring errors from dropped connections that haven't yet been cleaned up
                                 let _ = self
                                     .connections
@@ -324,7 +450,8 @@ where
         let mut calls = 0;
         loop {
             while self.outgoing.len() < BATCH_SIZE {
-                match self.inner.poll_transmit() {
+                // Stateless packets from the endpoint itself are rare and go out first
+                match self.inner.poll_transmit().or_else(|| self.transmits.pop()) {
                     Some(x) => self.outgoing.push_back(x),
                     None => break,
                 }
@@ -334,7 +461,9 @@ where
             }
             match self.socket.poll_send(cx, self.outgoing.as_slices().0) {
                 Poll::Ready(Ok(n)) => {
-                    self.outgoing.drain(..n);
+                    for transmit in self.outgoing.drain(..n) {
+                        self.inner.recycle(transmit.contents);
+                    }
                     calls += 1;
                     if calls == IO_LOOP_BOUND {
                         return Ok(true);
@@ -346,6 +475,11 @@ where
                 Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::PermissionDenied => {
                     return Ok(false);
                 }
+                Poll::Ready(Err(ref e)) if platform::is_too_large(e) => {
+                    // Typically an MTU probe, which the connection will deem lost
+                    let transmit = self.outgoing.pop_front().unwrap();
+                    self.inner.recycle(transmit.contents);
+                }
                 Poll::Ready(Err(e)) => {
                     return Err(e);
                 }
@@ -361,9 +495,8 @@ where
                     Proto(e) => {
                         if e.is_drained() {
                             self.connections.senders.remove(&ch);
-                            if self.connections.is_empty() {
-                                self.idle.wake();
-                            }
+                            self.connections.info.remove(&ch);
+                            self.idle.wake();
                         }
                         if let Some(event) = self.inner.handle_event(ch, e) {
                             // Ignoring errors from dropped connections that haven't yet been cleaned up
@@ -375,7 +508,12 @@ where
                                 .unbounded_send(ConnectionEvent::Proto(event));
                         }
                     }
-                    Transmit(t) => self.outgoing.push_back(t),
+                    Transmits(mut ts) => {
+                        for t in ts.drain(..) {
+                            self.transmits.push(ch, t);
+                        }
+                        self.connections.batches.put(ts);
+                    }
                 },
                 Poll::Ready(None) => unreachable!("EndpointInner owns one sender"),
                 Poll::Pending => {
@@ -386,6 +524,18 @@ where
     }
 }
 
+impl<S> EndpointInner<S>
+where
+    S: proto::crypto::Session,
+{
+    /// Wake the driver to send any stateless packets queued outside of it
+    fn wake_driver(&mut self) {
+        if let Some(task) = self.driver.take() {
+            task.wake();
+        }
+    }
+}
+
 #[derive(Debug)]
 struct ConnectionSet {
     /// Senders for communicating with the endpoint's connections
@@ -394,6 +544,12 @@ struct ConnectionSet {
     sender: mpsc::UnboundedSender<(ConnectionHandle, EndpointEvent)>,
     /// Set if the endpoint has been manually closed
     close: Option<(VarInt, Bytes)>,
+    /// Counters updated by every connection
+    metrics: Arc<Counters>,
+    /// Emptied batches of transmits for connections to reuse
+    batches: Arc<BatchPool>,
+    /// Identifying details of each connection in `senders`
+    info: HashMap<ConnectionHandle, UndrainedConnection>,
 }
 
 impl ConnectionSet {
@@ -401,6 +557,7 @@ impl ConnectionSet {
         &mut self,
         handle: ConnectionHandle,
         conn: proto::generic::Connection<S>,
+        label: Option<&str>,
     ) -> Connecting<S> {
         let (send, recv) = mpsc::unbounded();
         if let Some((error_code, ref reason)) = self.close {
@@ -411,7 +568,22 @@ impl ConnectionSet {
             .unwrap();
         }
         self.senders.insert(handle, send);
-        Connecting::new(handle, conn, self.sender.clone(), recv)
+        let remote_address = conn.remote_address();
+        let endpoint = EndpointHandles {
+            events: self.sender.clone(),
+            batches: self.batches.clone(),
+            metrics: self.metrics.clone(),
+        };
+        let connecting = Connecting::new(handle, conn, endpoint, recv, label);
+        self.info.insert(
+            handle,
+            UndrainedConnection {
+                stable_id: connecting.stable_id(),
+                remote_address,
+                created: TokioInstant::now(),
+            },
+        );
+        connecting
     }
 
     fn is_empty(&self) -> bool {
@@ -419,6 +591,26 @@ impl ConnectionSet {
     }
 }
 
+/// A connection that failed to drain within the time allowed by [`Endpoint::wait_idle_timeout()`]
+#[derive(Debug, Clone)]
+pub struct UndrainedConnection {
+    stable_id: usize,
+    remote_address: SocketAddr,
+    created: TokioInstant,
+}
+
+impl UndrainedConnection {
+    /// The connection's [`stable_id()`](crate::generic::Connection::stable_id)
+    pub fn stable_id(&self) -> usize {
+        self.stable_id
+    }
+
+    /// The peer's address when the connection was created
+    pub fn remote_address(&self) -> SocketAddr {
+        self.remote_address
+    }
+}
+
 fn ensure_ipv6(x: SocketAddr) -> SocketAddrV6 {
     match x {
         SocketAddr::V6(x) => x,
@@ -427,6 +619,8 @@ fn ensure_ipv6(x: SocketAddr) -> SocketAddrV6 {
 }
 
 /// Stream of incoming connections.
+///
+/// Each attempt is yielded as an [`IncomingConnection`], to be accepted or turned away.
 #[derive(Debug)]
 pub struct Incoming<S: proto::crypto::Session>(EndpointRef<S>);
 
@@ -443,16 +637,22 @@ impl<S> futures::Stream for Incoming<S>
 where
     S: proto::crypto::Session,
 {
-    type Item = Connecting<S>;
+    type Item = IncomingConnection<S>;
 
     #[allow(unused_mut)] // MSRV
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         let endpoint = &mut *self.0.lock().unwrap();
         if endpoint.driver_lost {
             Poll::Ready(None)
-        } else if let Some(conn) = endpoint.incoming.pop_front() {
-            Poll::Ready(Some(conn))
-        } else if endpoint.connections.close.is_some() {
+        } else if let Some(incoming) = endpoint.incoming.pop_front() {
+            // Equivalent to `self.0.clone()`, which would deadlock on `endpoint`
+            endpoint.ref_count += 1;
+            Poll::Ready(Some(IncomingConnection {
+                endpoint: EndpointRef(self.0 .0.clone()),
+                incoming: Some(incoming),
+                connecting: None,
+            }))
+        } else if endpoint.connections.close.is_some() || endpoint.shutting_down {
             Poll::Ready(None)
         } else {
             endpoint.incoming_reader = Some(cx.waker().clone());
@@ -469,9 +669,222 @@ where
         let endpoint = &mut *self.0.lock().unwrap();
         endpoint.inner.reject_new_connections();
         endpoint.incoming_reader = None;
+        while let Some(incoming) = endpoint.incoming.pop_front() {
+            endpoint.inner.refuse(incoming);
+        }
+        endpoint.wake_driver();
     }
 }
 
+/// A connection attempt yielded by [`Incoming`], awaiting a decision from the application
+///
+/// Exposes what is known about the peer before any per-connection state is committed to it, so
+/// that servers can choose to [`accept()`], [`refuse()`], [`retry()`] or [`ignore()`] it.
+/// Awaiting an `IncomingConnection` accepts it and waits for the handshake to complete, and
+/// dropping one without a decision refuses it.
+///
+/// [`accept()`]: IncomingConnection::accept
+/// [`refuse()`]: IncomingConnection::refuse
+/// [`retry()`]: IncomingConnection::retry
+/// [`ignore()`]: IncomingConnection::ignore
+#[derive(Debug)]
+pub struct IncomingConnection<S>
+where
+    S: proto::crypto::Session,
+{
+    endpoint: EndpointRef<S>,
+    incoming: Option<proto::generic::Incoming<S>>,
+    /// Set once accepted by polling
+    connecting: Option<Connecting<S>>,
+}
+
+impl<S> IncomingConnection<S>
+where
+    S: proto::crypto::Session + 'static,
+{
+    /// Accept the connection, beginning the handshake
+    ///
+    /// Fails if the endpoint can no longer take on new connections, or the peer's first packet
+    /// turns out to be invalid. The peer is notified in either case.
+    pub fn accept(mut self) -> Result<Connecting<S>, ConnectionError> {
+        self.accept_inner(None, None)
+    }
+
+    /// Accept the connection using `transport` in place of the server's transport configuration
+    ///
+    /// Allows flow control windows, stream limits, timeouts and so on to be chosen for a
+    /// particular peer, for instance by its [`remote_address()`](Self::remote_address) or
+    /// [`server_name()`](Self::server_name). Otherwise behaves like [`accept()`](Self::accept).
+    pub fn accept_with(
+        mut self,
+        transport: Arc<TransportConfig>,
+    ) -> Result<Connecting<S>, ConnectionError> {
+        self.accept_inner(Some(transport), None)
+    }
+
+    /// Accept the connection, identifying it as `label` in all of its events
+    ///
+    /// Unlike a later [`Connecting::set_label()`], the label is in place before the connection's
+    /// driver first runs, so none of its events go unlabeled. `transport` optionally replaces the
+    /// server's transport configuration, as in [`accept_with()`](Self::accept_with).
+    pub fn accept_labeled(
+        mut self,
+        transport: Option<Arc<TransportConfig>>,
+        label: &str,
+    ) -> Result<Connecting<S>, ConnectionError> {
+        self.accept_inner(transport, Some(label))
+    }
+
+    /// Turn the connection away, notifying the peer with a `CONNECTION_REFUSED` error
+    pub fn refuse(mut self) {
+        let endpoint = &mut *self.endpoint.lock().unwrap();
+        endpoint.inner.refuse(self.incoming.take().unwrap());
+        endpoint.wake_driver();
+    }
+
+    /// Ask the peer to prove that it can receive packets at its address before it may connect
+    ///
+    /// The peer responds with a new connection attempt for which
+    /// [`remote_address_validated()`](Self::remote_address_validated) is `true`. Fails, handing
+    /// back `self`, if that is already the case.
+    pub fn retry(mut self) -> Result<(), RetryError<S>> {
+        let incoming = self.incoming.take().unwrap();
+        let result = {
+            let endpoint = &mut *self.endpoint.lock().unwrap();
+            endpoint.wake_driver();
+            endpoint.inner.retry(incoming)
+        };
+        result.map_err(|e| {
+            self.incoming = Some(e.into_incoming());
+            RetryError(Box::new(self))
+        })
+    }
+
+    /// Discard the connection attempt without notifying the peer
+    pub fn ignore(mut self) {
+        let incoming = self.incoming.take().unwrap();
+        self.endpoint.lock().unwrap().inner.ignore(incoming);
+    }
+
+    /// The peer's UDP address
+    pub fn remote_address(&self) -> SocketAddr {
+        self.incoming().remote_address()
+    }
+
+    /// The local IP address the peer sent its connection attempt to, if known
+    ///
+    /// See [`Connecting::local_ip()`] for platform support.
+    pub fn local_ip(&self) -> Option<IpAddr> {
+        self.incoming().local_ip()
+    }
+
+    /// Whether the peer has proven that it can receive packets at its address, by responding to a
+    /// [`retry()`](Self::retry)
+    pub fn remote_address_validated(&self) -> bool {
+        self.incoming().remote_address_validated()
+    }
+
+    /// The server name requested by the peer, if known
+    pub fn server_name(&self) -> Option<&str> {
+        self.incoming().server_name()
+    }
+
+    /// The application protocols offered by the peer, if known
+    pub fn alpn_protocols(&self) -> Option<&[Vec<u8>]> {
+        self.incoming().alpn_protocols()
+    }
+
+    fn incoming(&self) -> &proto::generic::Incoming<S> {
+        self.incoming
+            .as_ref()
+            .expect("connection attempt already accepted by polling")
+    }
+
+    fn accept_inner(
+        &mut self,
+        transport: Option<Arc<TransportConfig>>,
+        label: Option<&str>,
+    ) -> Result<Connecting<S>, ConnectionError> {
+        let incoming = self.incoming.take().unwrap();
+        let endpoint = &mut *self.endpoint.lock().unwrap();
+        let now = Instant::now();
+        let result = match transport {
+            Some(transport) => endpoint.inner.accept_with(incoming, now, transport),
+            None => endpoint.inner.accept(incoming, now),
+        };
+        endpoint.wake_driver();
+        let (handle, conn) = result?;
+        Ok(endpoint.connections.insert(handle, conn, label))
+    }
+}
+
+// Never pinned structurally
+impl<S> Unpin for IncomingConnection<S> where S: proto::crypto::Session {}
+
+impl<S> Future for IncomingConnection<S>
+where
+    S: proto::crypto::Session + 'static,
+{
+    type Output = Result<NewConnection<S>, ConnectionError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if self.connecting.is_none() {
+            let connecting = self.accept_inner(None, None)?;
+            self.connecting = Some(connecting);
+        }
+        Pin::new(self.connecting.as_mut().unwrap()).poll(cx)
+    }
+}
+
+impl<S> Drop for IncomingConnection<S>
+where
+    S: proto::crypto::Session,
+{
+    fn drop(&mut self) {
+        if let Some(incoming) = self.incoming.take() {
+            let endpoint = &mut *self.endpoint.lock().unwrap();
+            endpoint.inner.refuse(incoming);
+            endpoint.wake_driver();
+        }
+    }
+}
+
+/// Error returned by [`IncomingConnection::retry()`] for a connection attempt that was already
+/// retried
+pub struct RetryError<S>(Box<IncomingConnection<S>>)
+where
+    S: proto::crypto::Session;
+
+impl<S> RetryError<S>
+where
+    S: proto::crypto::Session,
+{
+    /// Get back the connection attempt, to be accepted, refused or ignored instead
+    pub fn into_incoming(self) -> IncomingConnection<S> {
+        *self.0
+    }
+}
+
+impl<S> fmt::Debug for RetryError<S>
+where
+    S: proto::crypto::Session,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("RetryError").field(&self.0.incoming).finish()
+    }
+}
+
+impl<S> fmt::Display for RetryError<S>
+where
+    S: proto::crypto::Session,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("connection attempt was already retried")
+    }
+}
+
+impl<S> std::error::Error for RetryError<S> where S: proto::crypto::Session {}
+
 #[derive(Debug)]
 pub(crate) struct EndpointRef<S: proto::crypto::Session>(Arc<Mutex<EndpointInner<S>>>);
 
@@ -479,9 +892,13 @@ impl<S> EndpointRef<S>
 where
     S: proto::crypto::Session,
 {
-    pub(crate) fn new(socket: UdpSocket, inner: proto::generic::Endpoint<S>, ipv6: bool) -> Self {
-        let recv_buf =
-            vec![0; inner.config().get_max_udp_payload_size().min(64 * 1024) as usize * BATCH_SIZE];
+    pub(crate) fn new(
+        socket: Box<dyn DatagramSocket>,
+        inner: proto::generic::Endpoint<S>,
+        ipv6: bool,
+    ) -> Self {
+        let recv_pool =
+            RecvPool::new(inner.config().get_max_udp_payload_size().min(64 * 1024) as usize);
         let (sender, events) = mpsc::unbounded();
         Self(Arc::new(Mutex::new(EndpointInner {
             socket,
@@ -489,6 +906,7 @@ where
             ipv6,
             events,
             outgoing: VecDeque::new(),
+            transmits: TransmitQueue::new(),
             incoming: VecDeque::new(),
             incoming_reader: None,
             driver: None,
@@ -496,11 +914,15 @@ where
                 senders: HashMap::new(),
                 sender,
                 close: None,
+                metrics: Arc::new(Counters::default()),
+                batches: Arc::default(),
+                info: HashMap::new(),
             },
             ref_count: 0,
             driver_lost: false,
-            recv_buf: recv_buf.into(),
+            recv_pool,
             idle: Broadcast::new(),
+            shutting_down: false,
         })))
     }
 }
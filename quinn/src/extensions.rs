@@ -0,0 +1,54 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+};
+
+/// Application state attached to a QUIC object, holding at most one value of each type
+///
+/// Lets an application keep whatever it associates with a connection or stream alongside that
+/// object, rather than in a map of its own that must be cleaned up when the object goes away. Using a
+/// private type as the key, e.g. a newtype, avoids clashes with other users of the same object.
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    /// Create an empty map
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Attach `value`, returning the value of the same type attached before, if any
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .map(|x| *x.downcast().expect("value stored under another type's ID"))
+    }
+
+    /// The attached value of type `T`, if any
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.map.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+
+    /// Mutable access to the attached value of type `T`, if any
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.map.get_mut(&TypeId::of::<T>())?.downcast_mut()
+    }
+
+    /// Detach the value of type `T`, if any
+    pub fn remove<T: 'static>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .map(|x| *x.downcast().expect("value stored under another type's ID"))
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.len())
+            .finish()
+    }
+}
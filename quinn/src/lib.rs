@@ -53,17 +53,46 @@ let (endpoint, _) = builder.bind(&\"[::]:0\".parse().unwrap()).unwrap();
 mod broadcast;
 mod builders;
 mod connection;
+mod copy;
 mod endpoint;
+mod extensions;
+pub mod masque;
+pub mod memory;
+mod metrics;
+pub mod pcap;
 mod platform;
+mod pool;
+mod recv_pool;
+mod replay;
+#[cfg(feature = "tower")]
+pub mod service;
+mod socket;
 mod streams;
+mod transmit_queue;
+pub mod webtransport;
 
+#[cfg(feature = "tap")]
+pub use proto::tap;
 pub use proto::{
-    crypto, ApplicationClose, Certificate, CertificateChain, Chunk, ConnectError, ConnectionClose,
-    ConnectionError, ParseError, PrivateKey, StreamId, Transmit, TransportConfig, VarInt,
+    coding, crypto, AddressValidation, ApplicationClose, Certificate, CertificateChain, Chunk,
+    ConnectError, ConnectionClose, ConnectionError, ConnectionErrorKind, ConnectionId,
+    DroppedDatagrams, EndpointStats, InvalidResumptionState, ParseError, PathInfo, PrivateKey,
+    QlogFactory, QlogStream, QlogStreamStats, RecvStreamStats, ResumptionStore, ServerState, Side,
+    StreamId, Transmit, TransportConfig, VarInt,
 };
 
 pub use crate::builders::EndpointError;
-pub use crate::connection::{SendDatagramError, ZeroRttAccepted};
+pub use crate::connection::{
+    LifecycleEvent, LifecycleEvents, SendDatagramError, ZeroRttAccepted, ZeroRttOutcome,
+    ZeroRttResult,
+};
+pub use crate::copy::{copy_bidirectional, copy_from_stream, copy_to_stream, CopyError};
+pub use crate::endpoint::UndrainedConnection;
+pub use crate::extensions::Extensions;
+pub use crate::metrics::{ConnectionMetrics, EndpointMetrics};
+pub use crate::platform::RecvMeta;
+pub use crate::pool::PoolError;
+pub use crate::socket::{AddressMap, DatagramSocket};
 pub use crate::streams::{ReadError, ReadExactError, ReadToEndError, StoppedError, WriteError};
 
 /// Types that are generic over the crypto protocol implementation
@@ -73,7 +102,8 @@ pub mod generic {
         Connecting, Connection, Datagrams, IncomingBiStreams, IncomingUniStreams, NewConnection,
         OpenBi, OpenUni,
     };
-    pub use crate::endpoint::{Endpoint, Incoming};
+    pub use crate::endpoint::{Endpoint, Incoming, IncomingConnection, RetryError};
+    pub use crate::pool::ConnectionPool;
     pub use crate::streams::{Read, ReadExact, ReadToEnd, RecvStream, SendStream};
     pub use proto::generic::{ClientConfig, ServerConfig};
 }
@@ -116,6 +146,13 @@ mod rustls_impls {
     pub type Endpoint = generic::Endpoint<TlsSession>;
     /// An `Incoming` using rustls for the cryptography protocol
     pub type Incoming = generic::Incoming<TlsSession>;
+    /// An `IncomingConnection` using rustls for the cryptography protocol
+    pub type IncomingConnection = generic::IncomingConnection<TlsSession>;
+    /// A `RetryError` using rustls for the cryptography protocol
+    pub type RetryError = generic::RetryError<TlsSession>;
+
+    /// A `ConnectionPool` using rustls for the cryptography protocol
+    pub type ConnectionPool = generic::ConnectionPool<TlsSession>;
 
     /// A `Read` using rustls for the cryptography protocol
     pub type Read<'a> = generic::Read<'a, TlsSession>;
@@ -147,7 +184,7 @@ enum ConnectionEvent {
 #[derive(Debug)]
 enum EndpointEvent {
     Proto(proto::EndpointEvent),
-    Transmit(proto::Transmit),
+    Transmits(Vec<proto::Transmit>),
 }
 
 /// Maximum number of send/recv calls to make before moving on to other processing
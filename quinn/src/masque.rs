@@ -0,0 +1,256 @@
+//! Tunnelling QUIC through a MASQUE proxy with CONNECT-UDP
+//!
+//! Where direct UDP is blocked, a client can ask an HTTP/3 proxy to relay UDP payloads to a
+//! target on its behalf. The tunnel is established by an extended CONNECT request with the
+//! `connect-udp` protocol, after which each UDP payload travels as an HTTP/3 datagram prefixed
+//! with the request stream's quarter stream ID and a context ID of zero.
+//!
+//! As with [`webtransport`](crate::webtransport), negotiating the request is left to an HTTP/3
+//! layer, and the same [`Session`] and [`DatagramRouter`] are used to send and receive the
+//! encapsulated datagrams once it's been accepted. A client then builds an endpoint on a
+//! [`ProxiedSocket`] with
+//! [`EndpointBuilder::with_datagram_socket()`](crate::generic::EndpointBuilder::with_datagram_socket),
+//! and connects to the target as usual. A proxy relays a request's datagrams with [`forward()`].
+//!
+//! [`DatagramRouter`]: crate::webtransport::DatagramRouter
+
+use std::{
+    fmt, io,
+    io::IoSliceMut,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures::{FutureExt, Stream, StreamExt};
+use proto::Transmit;
+use tokio::net::UdpSocket;
+use tracing::{debug, trace};
+
+use crate::{
+    connection::SendDatagramError,
+    platform::RecvMeta,
+    socket::DatagramSocket,
+    webtransport::{Session, SessionDatagrams},
+};
+
+/// Context ID of datagrams carrying UDP payloads
+const UDP_PAYLOAD_CONTEXT: u8 = 0;
+
+/// Largest UDP payload a proxy can receive from the target
+const MAX_UDP_PAYLOAD: usize = 65527;
+
+/// A [`DatagramSocket`] whose datagrams are relayed to a single target by a MASQUE proxy
+pub struct ProxiedSocket<S>
+where
+    S: proto::crypto::Session,
+{
+    session: Session<S>,
+    datagrams: Mutex<SessionDatagrams>,
+    target: SocketAddr,
+}
+
+impl<S> ProxiedSocket<S>
+where
+    S: proto::crypto::Session + 'static,
+{
+    /// Send and receive through the accepted CONNECT-UDP request `session`, which relays to
+    /// `target`
+    ///
+    /// `datagrams` must be registered with the router for the proxy connection's datagrams.
+    /// Datagrams sent anywhere but `target` are discarded, and received ones appear to come from
+    /// it.
+    ///
+    /// QUIC needs its paths to carry UDP payloads of at least 1200 bytes, so no connection through
+    /// the tunnel can be established while [`max_payload_size()`](Self::max_payload_size) is
+    /// smaller. That takes a proxy connection whose path MTU is a little larger than the minimum,
+    /// and which isn't hampered by the proxy's `max_datagram_frame_size`. A datagram too large for
+    /// the tunnel fails to send as one too large for a UDP socket's path MTU would.
+    pub fn new(session: Session<S>, datagrams: SessionDatagrams, target: SocketAddr) -> Self {
+        Self {
+            session,
+            datagrams: Mutex::new(datagrams),
+            target,
+        }
+    }
+
+    /// The address datagrams are relayed to
+    pub fn target(&self) -> SocketAddr {
+        self.target
+    }
+
+    /// Largest UDP payload the tunnel can currently carry, or `None` if the proxy connection has
+    /// datagrams disabled
+    ///
+    /// Tracks the proxy connection's [`max_datagram_size()`](Session::max_datagram_size), less
+    /// the encapsulation's overhead.
+    pub fn max_payload_size(&self) -> Option<usize> {
+        self.session
+            .max_datagram_size()
+            .map(|x| x.saturating_sub(1))
+    }
+}
+
+impl<S> DatagramSocket for ProxiedSocket<S>
+where
+    S: proto::crypto::Session + 'static,
+{
+    fn poll_send(&self, _cx: &mut Context, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
+        for (i, transmit) in transmits.iter().enumerate() {
+            if transmit.destination != self.target {
+                trace!(destination = %transmit.destination, "discarding datagram for non-target");
+                continue;
+            }
+            let segment_size = transmit.segment_size.unwrap_or(transmit.contents.len());
+            // Checked before sending any of a transmit's segments, so that it's never left half
+            // sent. Reported like a datagram exceeding a UDP socket's path MTU, once the transmits
+            // before it have been accounted for.
+            let max = self.max_payload_size();
+            if matches!(max, Some(max) if segment_size > max) {
+                if i > 0 {
+                    return Poll::Ready(Ok(i));
+                }
+                debug!(
+                    len = segment_size,
+                    ?max,
+                    "datagram too large for the proxy connection"
+                );
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    SendDatagramError::TooLarge,
+                )));
+            }
+            for segment in transmit.contents.chunks(segment_size.max(1)) {
+                match self.session.send_datagram(&encapsulate(segment)) {
+                    Ok(()) => {}
+                    Err(SendDatagramError::ConnectionClosed(e)) => {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            e,
+                        )));
+                    }
+                    // Includes a segment the proxy connection's path shrank under since the check
+                    // above, lost like any other datagram
+                    Err(e) => trace!("discarding datagram: {}", e),
+                }
+            }
+        }
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let datagrams = &mut *self.datagrams.lock().unwrap();
+        let mut count = 0;
+        while count < bufs.len() {
+            let datagram = match Pin::new(&mut *datagrams).poll_next(cx) {
+                Poll::Ready(Some(x)) => x,
+                Poll::Ready(None) if count == 0 => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "proxy request closed",
+                    )));
+                }
+                Poll::Ready(None) | Poll::Pending => break,
+            };
+            let payload = match decapsulate(datagram) {
+                Some(x) => x,
+                None => continue,
+            };
+            // Excess data is discarded, as for a real UDP socket with a short buffer
+            let len = payload.len().min(bufs[count].len());
+            bufs[count][..len].copy_from_slice(&payload[..len]);
+            meta[count] = RecvMeta {
+                addr: self.target,
+                len,
+                ecn: None,
+                dst_ip: None,
+            };
+            count += 1;
+        }
+        if count == 0 {
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(count))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        // Matching the target's family keeps the endpoint from mapping it into IPv6
+        Ok(match self.target {
+            SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+        })
+    }
+}
+
+impl<S> fmt::Debug for ProxiedSocket<S>
+where
+    S: proto::crypto::Session + 'static,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxiedSocket")
+            .field("session", &self.session.id())
+            .field("target", &self.target)
+            .finish()
+    }
+}
+
+/// Relay the datagrams of the accepted CONNECT-UDP request `session` to and from `socket`
+///
+/// `socket` should be connected to the request's target, and `datagrams` registered with the
+/// router for the client connection's datagrams. Completes once `datagrams` ends, when the
+/// request is unregistered, or with the first error from `socket` or the client connection.
+pub async fn forward<S>(
+    session: &Session<S>,
+    datagrams: SessionDatagrams,
+    socket: &UdpSocket,
+) -> io::Result<()>
+where
+    S: proto::crypto::Session + 'static,
+{
+    let mut datagrams = datagrams.fuse();
+    let mut buf = vec![0; MAX_UDP_PAYLOAD];
+    loop {
+        futures::select! {
+            datagram = datagrams.next() => {
+                let datagram = match datagram {
+                    Some(x) => x,
+                    None => return Ok(()),
+                };
+                if let Some(payload) = decapsulate(datagram) {
+                    socket.send(&payload).await?;
+                }
+            }
+            len = socket.recv(&mut buf).fuse() => {
+                match session.send_datagram(&encapsulate(&buf[..len?])) {
+                    Ok(()) => {}
+                    Err(SendDatagramError::ConnectionClosed(e)) => {
+                        return Err(io::Error::new(io::ErrorKind::ConnectionAborted, e));
+                    }
+                    Err(e) => trace!("discarding datagram: {}", e),
+                }
+            }
+        }
+    }
+}
+
+fn encapsulate(payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(1 + payload.len());
+    buf.push(UDP_PAYLOAD_CONTEXT);
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Extract the UDP payload from a datagram, ignoring those with other context IDs
+fn decapsulate(mut datagram: Bytes) -> Option<Bytes> {
+    if datagram.first() != Some(&UDP_PAYLOAD_CONTEXT) {
+        return None;
+    }
+    Some(datagram.split_off(1))
+}
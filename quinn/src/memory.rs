@@ -0,0 +1,287 @@
+//! In-process datagram transport for deterministic testing
+//!
+//! A [`MemoryNetwork`] routes datagrams between [`MemorySocket`]s entirely in memory, without
+//! touching the operating system's network stack. Datagrams are delivered in the order they were
+//! sent and are never duplicated or reordered, nor lost unless the receiving socket's queue is
+//! full, which makes it well suited for unit testing protocols built on top of quinn. Combined with tokio's paused clock, tests become fully
+//! independent of wall-clock timing.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let network = quinn::memory::MemoryNetwork::new();
+//! let server_socket = network.bind("127.0.0.1:4433".parse()?)?;
+//! let client_socket = network.bind("127.0.0.1:0".parse()?)?;
+//! let (server, incoming) = quinn::Endpoint::builder().with_datagram_socket(server_socket)?;
+//! let (client, _) = quinn::Endpoint::builder().with_datagram_socket(client_socket)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::{hash_map, HashMap, VecDeque},
+    fmt, io,
+    io::IoSliceMut,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use proto::{EcnCodepoint, Transmit};
+
+use crate::{platform::RecvMeta, socket::DatagramSocket};
+
+/// First port handed out when binding to port 0
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+/// Most datagrams queued for a socket that hasn't received them yet; any more are dropped
+pub(crate) const MAILBOX_CAPACITY: usize = 1024;
+
+/// A set of [`MemorySocket`]s which can exchange datagrams with each other
+///
+/// May be cloned to obtain another handle to the same network.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+impl MemoryNetwork {
+    /// Create an empty network
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a socket bound to `addr` on this network
+    ///
+    /// If the port of `addr` is 0, an unused port is chosen. Fails with
+    /// [`io::ErrorKind::AddrInUse`] if another live socket is already bound to `addr`.
+    ///
+    /// As on a real host, a socket bound to an unspecified address receives datagrams sent to any
+    /// address with its port that no socket is bound to specifically, and one bound to `[::]` also
+    /// receives those sent to IPv4 addresses. Each socket queues up to 1024 datagrams it hasn't
+    /// received yet, dropping any beyond that.
+    pub fn bind(&self, mut addr: SocketAddr) -> io::Result<MemorySocket> {
+        let mut state = self.state.lock().unwrap();
+        if addr.port() == 0 {
+            addr.set_port(state.ephemeral_port(addr.ip())?);
+        }
+        match state.sockets.entry(normalize(addr)) {
+            hash_map::Entry::Occupied(_) => Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "address already bound on this network",
+            )),
+            hash_map::Entry::Vacant(e) => {
+                e.insert(Mailbox::default());
+                Ok(MemorySocket {
+                    network: self.clone(),
+                    addr,
+                })
+            }
+        }
+    }
+
+    /// Number of datagrams delivered to sockets on this network so far
+    pub fn datagrams_delivered(&self) -> u64 {
+        self.state.lock().unwrap().delivered
+    }
+
+    /// Number of datagrams dropped so far because the receiving socket's queue was full
+    pub fn datagrams_dropped(&self) -> u64 {
+        self.state.lock().unwrap().dropped
+    }
+}
+
+impl fmt::Debug for MemoryNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("MemoryNetwork")
+            .field("sockets", &state.sockets.len())
+            .field("delivered", &state.delivered)
+            .field("dropped", &state.dropped)
+            .finish()
+    }
+}
+
+/// A datagram socket attached to a [`MemoryNetwork`]
+///
+/// Pass to [`EndpointBuilder::with_datagram_socket()`] to run an endpoint on the network. The
+/// socket's address is released when it is dropped.
+///
+/// [`EndpointBuilder::with_datagram_socket()`]: crate::generic::EndpointBuilder::with_datagram_socket
+#[derive(Debug)]
+pub struct MemorySocket {
+    network: MemoryNetwork,
+    addr: SocketAddr,
+}
+
+impl MemorySocket {
+    /// The address this socket is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Present sources the way a dual-stack socket would, so remote addresses stay consistent
+    /// with those the endpoint sends to
+    fn map_source(&self, source: SocketAddr) -> SocketAddr {
+        match (self.addr, source) {
+            (SocketAddr::V6(_), SocketAddr::V4(x)) => {
+                SocketAddrV6::new(x.ip().to_ipv6_mapped(), x.port(), 0, 0).into()
+            }
+            _ => source,
+        }
+    }
+}
+
+impl DatagramSocket for MemorySocket {
+    fn poll_send(&self, _cx: &mut Context, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
+        let state = &mut *self.network.state.lock().unwrap();
+        for transmit in transmits {
+            let mailbox = match state.route(transmit.destination) {
+                Some(addr) => state.sockets.get_mut(&addr).unwrap(),
+                // Like UDP, datagrams sent to nobody are silently discarded
+                None => continue,
+            };
+            let segment_size = transmit.segment_size.unwrap_or(transmit.contents.len());
+            // `chunks` panics on a zero chunk size, which only an empty `contents` could produce
+            for segment in transmit.contents.chunks(segment_size.max(1)) {
+                if mailbox.queue.len() >= MAILBOX_CAPACITY {
+                    state.dropped += 1;
+                    continue;
+                }
+                mailbox.queue.push_back(Datagram {
+                    source: self.addr,
+                    destination: transmit.destination,
+                    ecn: transmit.ecn,
+                    contents: segment.to_vec(),
+                });
+            }
+            if let Some(waker) = mailbox.waker.take() {
+                waker.wake();
+            }
+        }
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        debug_assert!(!bufs.is_empty());
+        let mut state = self.network.state.lock().unwrap();
+        let mailbox = state
+            .sockets
+            .get_mut(&normalize(self.addr))
+            .expect("live sockets remain registered");
+        if mailbox.queue.is_empty() {
+            mailbox.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let mut count = 0;
+        for (buf, meta) in bufs.iter_mut().zip(meta.iter_mut()) {
+            let datagram = match mailbox.queue.pop_front() {
+                Some(x) => x,
+                None => break,
+            };
+            // Excess data is discarded, as for a real UDP socket with a short buffer
+            let len = datagram.contents.len().min(buf.len());
+            buf[..len].copy_from_slice(&datagram.contents[..len]);
+            *meta = RecvMeta {
+                addr: self.map_source(datagram.source),
+                len,
+                ecn: datagram.ecn,
+                dst_ip: Some(datagram.destination.ip()),
+            };
+            count += 1;
+        }
+        state.delivered += count as u64;
+        Poll::Ready(Ok(count))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for MemorySocket {
+    fn drop(&mut self) {
+        // Avoid a double panic if the lock was poisoned by a panicking test
+        if let Ok(mut state) = self.network.state.lock() {
+            state.sockets.remove(&normalize(self.addr));
+        }
+    }
+}
+
+#[derive(Default)]
+struct NetworkState {
+    sockets: HashMap<SocketAddr, Mailbox>,
+    /// Next candidate port for binding to port 0
+    next_port: u16,
+    delivered: u64,
+    dropped: u64,
+}
+
+impl NetworkState {
+    /// The address of the socket that receives datagrams sent to `destination`, if any
+    fn route(&self, destination: SocketAddr) -> Option<SocketAddr> {
+        let destination = normalize(destination);
+        let port = destination.port();
+        let v4 = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port);
+        let v6 = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port);
+        let candidates: &[SocketAddr] = match destination {
+            SocketAddr::V4(_) => &[destination, v4, v6],
+            SocketAddr::V6(_) => &[destination, v6],
+        };
+        candidates
+            .iter()
+            .copied()
+            .find(|x| self.sockets.contains_key(x))
+    }
+
+    fn ephemeral_port(&mut self, ip: IpAddr) -> io::Result<u16> {
+        for _ in EPHEMERAL_PORT_START..=u16::MAX {
+            if self.next_port < EPHEMERAL_PORT_START {
+                self.next_port = EPHEMERAL_PORT_START;
+            }
+            let port = self.next_port;
+            self.next_port = self.next_port.wrapping_add(1);
+            if !self
+                .sockets
+                .contains_key(&normalize(SocketAddr::new(ip, port)))
+            {
+                return Ok(port);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "no ephemeral ports available",
+        ))
+    }
+}
+
+#[derive(Default)]
+struct Mailbox {
+    queue: VecDeque<Datagram>,
+    waker: Option<Waker>,
+}
+
+struct Datagram {
+    source: SocketAddr,
+    destination: SocketAddr,
+    ecn: Option<EcnCodepoint>,
+    contents: Vec<u8>,
+}
+
+/// Map IPv4-mapped IPv6 addresses to plain IPv4 so either form reaches the same socket
+fn normalize(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(x) => match x.ip().to_ipv4() {
+            Some(ip) if x.ip().segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+                SocketAddr::new(ip.into(), x.port())
+            }
+            _ => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
@@ -0,0 +1,281 @@
+//! Counters and gauges for monitoring
+//!
+//! Every endpoint maintains a set of counters aggregated over all of its connections, available
+//! through [`Endpoint::metrics()`]. They are updated with relaxed atomic operations as connections
+//! are driven, so collecting them costs nothing beyond the occasional snapshot. The state of an
+//! individual connection, such as its RTT and congestion window, is available through
+//! [`Connection::metrics()`].
+//!
+//! [`Endpoint::metrics()`]: crate::generic::Endpoint::metrics
+//! [`Connection::metrics()`]: crate::generic::Connection::metrics
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use proto::ConnectionStats;
+
+/// Snapshot of an endpoint's metrics
+///
+/// Counters (fields other than `active_connections`) only ever increase, so rates such as
+/// handshakes per second may be computed from the difference between two snapshots.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct EndpointMetrics {
+    /// Number of connections currently being driven
+    pub active_connections: u64,
+    /// Number of connections that completed their handshake
+    pub handshakes: u64,
+    /// Number of connections that were closed before completing their handshake
+    pub failed_handshakes: u64,
+    /// Number of UDP datagrams sent by connections
+    pub udp_tx_datagrams: u64,
+    /// Number of UDP payload bytes sent by connections
+    pub udp_tx_bytes: u64,
+    /// Number of UDP datagrams received by connections
+    pub udp_rx_datagrams: u64,
+    /// Number of UDP payload bytes received by connections
+    pub udp_rx_bytes: u64,
+    /// Number of packets declared lost
+    pub lost_packets: u64,
+    /// Number of probe timeouts that fired
+    pub ptos: u64,
+}
+
+impl EndpointMetrics {
+    /// Write the metrics in the Prometheus text exposition format
+    ///
+    /// Each metric's name is `prefix` followed by an underscore and the name of the corresponding
+    /// field, with counters suffixed by `_total`.
+    pub fn write_prometheus<W: fmt::Write>(&self, out: &mut W, prefix: &str) -> fmt::Result {
+        let metrics: [(&str, &str, &str, u64); 9] = [
+            (
+                "active_connections",
+                "gauge",
+                "Connections currently being driven",
+                self.active_connections,
+            ),
+            (
+                "handshakes_total",
+                "counter",
+                "Connections that completed their handshake",
+                self.handshakes,
+            ),
+            (
+                "failed_handshakes_total",
+                "counter",
+                "Connections closed before completing their handshake",
+                self.failed_handshakes,
+            ),
+            (
+                "udp_tx_datagrams_total",
+                "counter",
+                "UDP datagrams sent",
+                self.udp_tx_datagrams,
+            ),
+            (
+                "udp_tx_bytes_total",
+                "counter",
+                "UDP payload bytes sent",
+                self.udp_tx_bytes,
+            ),
+            (
+                "udp_rx_datagrams_total",
+                "counter",
+                "UDP datagrams received",
+                self.udp_rx_datagrams,
+            ),
+            (
+                "udp_rx_bytes_total",
+                "counter",
+                "UDP payload bytes received",
+                self.udp_rx_bytes,
+            ),
+            (
+                "lost_packets_total",
+                "counter",
+                "Packets declared lost",
+                self.lost_packets,
+            ),
+            ("ptos_total", "counter", "Probe timeouts fired", self.ptos),
+        ];
+        for &(name, ty, help, value) in &metrics {
+            writeln!(out, "# HELP {}_{} {}", prefix, name, help)?;
+            writeln!(out, "# TYPE {}_{} {}", prefix, name, ty)?;
+            writeln!(out, "{}_{} {}", prefix, name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Snapshot of a single connection's metrics
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct ConnectionMetrics {
+    /// Current best estimate of the connection's round-trip time
+    pub rtt: Duration,
+    /// Current congestion window
+    pub cwnd: u64,
+    /// Number of bytes sent in packets that are neither acknowledged nor declared lost
+    pub bytes_in_flight: u64,
+    /// Number of packets sent
+    pub sent_packets: u64,
+    /// Number of packets declared lost
+    pub lost_packets: u64,
+    /// Number of bytes in packets declared lost
+    pub lost_bytes: u64,
+    /// Number of probe timeouts that fired
+    pub ptos: u64,
+}
+
+impl ConnectionMetrics {
+    pub(crate) fn new(stats: &ConnectionStats) -> Self {
+        Self {
+            rtt: stats.path.rtt,
+            cwnd: stats.path.cwnd,
+            bytes_in_flight: stats.path.bytes_in_flight,
+            sent_packets: stats.path.sent_packets,
+            lost_packets: stats.path.lost_packets,
+            lost_bytes: stats.path.lost_bytes,
+            ptos: stats.path.ptos,
+        }
+    }
+
+    /// Write the metrics of `connections` in the Prometheus text exposition format
+    ///
+    /// Each connection's samples are distinguished by a `connection` label with the value it's
+    /// paired with, e.g. its [`label()`](crate::generic::Connection::label). Metric names are
+    /// formed as for [`EndpointMetrics::write_prometheus()`], with `_connection` inserted after
+    /// `prefix` and the RTT measured in seconds.
+    pub fn write_prometheus<W: fmt::Write>(
+        out: &mut W,
+        prefix: &str,
+        connections: &[(&str, ConnectionMetrics)],
+    ) -> fmt::Result {
+        type Value = fn(&ConnectionMetrics) -> f64;
+        let metrics: [(&str, &str, &str, Value); 7] = [
+            ("rtt_seconds", "gauge", "Estimated round-trip time", |x| {
+                x.rtt.as_secs_f64()
+            }),
+            ("cwnd_bytes", "gauge", "Congestion window", |x| {
+                x.cwnd as f64
+            }),
+            (
+                "bytes_in_flight",
+                "gauge",
+                "Bytes sent but neither acknowledged nor lost",
+                |x| x.bytes_in_flight as f64,
+            ),
+            ("sent_packets_total", "counter", "Packets sent", |x| {
+                x.sent_packets as f64
+            }),
+            (
+                "lost_packets_total",
+                "counter",
+                "Packets declared lost",
+                |x| x.lost_packets as f64,
+            ),
+            (
+                "lost_bytes_total",
+                "counter",
+                "Bytes in packets declared lost",
+                |x| x.lost_bytes as f64,
+            ),
+            ("ptos_total", "counter", "Probe timeouts fired", |x| {
+                x.ptos as f64
+            }),
+        ];
+        for &(name, ty, help, value) in &metrics {
+            writeln!(out, "# HELP {}_connection_{} {}", prefix, name, help)?;
+            writeln!(out, "# TYPE {}_connection_{} {}", prefix, name, ty)?;
+            for (label, metrics) in connections {
+                write!(out, "{}_connection_{}{{connection=\"", prefix, name)?;
+                for c in label.chars() {
+                    match c {
+                        '\\' => out.write_str("\\\\")?,
+                        '"' => out.write_str("\\\"")?,
+                        '\n' => out.write_str("\\n")?,
+                        c => out.write_char(c)?,
+                    }
+                }
+                writeln!(out, "\"}} {}", value(metrics))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Live counters shared by an endpoint and its connections
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    active_connections: AtomicU64,
+    handshakes: AtomicU64,
+    failed_handshakes: AtomicU64,
+    udp_tx_datagrams: AtomicU64,
+    udp_tx_bytes: AtomicU64,
+    udp_rx_datagrams: AtomicU64,
+    udp_rx_bytes: AtomicU64,
+    lost_packets: AtomicU64,
+    ptos: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn connection_started(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_stopped(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn handshake_completed(&self) {
+        self.handshakes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn handshake_failed(&self) {
+        self.failed_handshakes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Account for a connection's activity between two snapshots of its statistics
+    pub(crate) fn record(&self, old: &ConnectionStats, new: &ConnectionStats) {
+        fn add(counter: &AtomicU64, old: u64, new: u64) {
+            if new != old {
+                counter.fetch_add(new - old, Ordering::Relaxed);
+            }
+        }
+        add(
+            &self.udp_tx_datagrams,
+            old.udp_tx.datagrams,
+            new.udp_tx.datagrams,
+        );
+        add(&self.udp_tx_bytes, old.udp_tx.bytes, new.udp_tx.bytes);
+        add(
+            &self.udp_rx_datagrams,
+            old.udp_rx.datagrams,
+            new.udp_rx.datagrams,
+        );
+        add(&self.udp_rx_bytes, old.udp_rx.bytes, new.udp_rx.bytes);
+        add(
+            &self.lost_packets,
+            old.path.lost_packets,
+            new.path.lost_packets,
+        );
+        add(&self.ptos, old.path.ptos, new.path.ptos);
+    }
+
+    pub(crate) fn snapshot(&self) -> EndpointMetrics {
+        EndpointMetrics {
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            handshakes: self.handshakes.load(Ordering::Relaxed),
+            failed_handshakes: self.failed_handshakes.load(Ordering::Relaxed),
+            udp_tx_datagrams: self.udp_tx_datagrams.load(Ordering::Relaxed),
+            udp_tx_bytes: self.udp_tx_bytes.load(Ordering::Relaxed),
+            udp_rx_datagrams: self.udp_rx_datagrams.load(Ordering::Relaxed),
+            udp_rx_bytes: self.udp_rx_bytes.load(Ordering::Relaxed),
+            lost_packets: self.lost_packets.load(Ordering::Relaxed),
+            ptos: self.ptos.load(Ordering::Relaxed),
+        }
+    }
+}
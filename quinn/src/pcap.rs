@@ -0,0 +1,396 @@
+//! Packet captures with embedded decryption secrets
+//!
+//! A [`PcapWriter`] records every datagram an endpoint sends and receives to a [pcapng] file.
+//! When also installed as the TLS key log, it embeds the session secrets in the capture as
+//! Decryption Secrets Blocks, so the resulting file opens decrypted in Wireshark without any
+//! further setup. This makes it suitable for attaching to support tickets.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let capture = quinn::pcap::PcapWriter::create("quinn.pcapng")?;
+//! let mut client_config = quinn::ClientConfigBuilder::default();
+//! client_config.key_log(std::sync::Arc::new(capture.clone()));
+//! let mut builder = quinn::Endpoint::builder();
+//! builder
+//!     .default_client_config(client_config.build())
+//!     .capture(capture);
+//! let (endpoint, _) = builder.bind(&"[::]:0".parse().unwrap())?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Datagrams are wrapped in synthetic IP and UDP headers built from the addresses known to the
+//! endpoint. Secrets are written as soon as they are derived, which may be after the first
+//! datagram protected with them was received; tools that decrypt in a single pass, such as
+//! `tshark` without `-2`, may therefore leave a handful of handshake packets undecrypted.
+//!
+//! Captures contain everything needed to read the application data exchanged, and must be
+//! handled with the same care as the data itself.
+//!
+//! [pcapng]: https://datatracker.ietf.org/doc/draft-tuexen-opsawg-pcapng/
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, IoSliceMut, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use proto::{EcnCodepoint, Transmit};
+use tracing::warn;
+
+use crate::{platform::RecvMeta, socket::DatagramSocket};
+
+/// Writes datagrams and TLS secrets to a pcapng capture
+///
+/// Cloning a `PcapWriter` yields a handle to the same capture, so that one file can be shared
+/// between the endpoint and the key logs of its client and server configurations. If writing
+/// fails, a warning is logged and the capture is abandoned; the endpoint is unaffected.
+#[derive(Clone)]
+pub struct PcapWriter {
+    inner: Arc<Mutex<Option<Box<dyn Write + Send>>>>,
+}
+
+impl PcapWriter {
+    /// Start a capture on `writer`
+    ///
+    /// Every block is flushed as soon as it is written, so the capture remains readable if the
+    /// process terminates abnormally.
+    pub fn new<W: Write + Send + 'static>(mut writer: W) -> io::Result<Self> {
+        let mut buf = Vec::with_capacity(SHB_LEN + IDB_LEN);
+        // Section Header Block
+        block_header(&mut buf, SHB_TYPE, SHB_LEN);
+        buf.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        buf.extend_from_slice(&1u16.to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes());
+        // Section length unknown
+        buf.extend_from_slice(&(-1i64).to_le_bytes());
+        block_trailer(&mut buf, SHB_LEN);
+        // Interface Description Block
+        block_header(&mut buf, IDB_TYPE, IDB_LEN);
+        buf.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes());
+        // No snapshot length limit
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        block_trailer(&mut buf, IDB_LEN);
+
+        writer.write_all(&buf)?;
+        writer.flush()?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Some(Box::new(writer)))),
+        })
+    }
+
+    /// Start a capture in a new file at `path`, truncating any existing file
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(File::create(path)?)
+    }
+
+    /// Record a datagram from `source` to `destination`
+    fn datagram(
+        &self,
+        source: SocketAddr,
+        destination: SocketAddr,
+        ecn: Option<EcnCodepoint>,
+        payload: &[u8],
+    ) {
+        let mut packet = Vec::with_capacity(IPV6_HEADER_LEN + UDP_HEADER_LEN + payload.len());
+        encode_ip_udp(&mut packet, source, destination, ecn, payload);
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |x| x.as_micros() as u64);
+        let len = EPB_LEN + padded(packet.len());
+        let mut buf = Vec::with_capacity(len);
+        block_header(&mut buf, EPB_TYPE, len);
+        // Interface ID
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        buf.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        buf.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&packet);
+        pad(&mut buf);
+        block_trailer(&mut buf, len);
+        self.write(&buf);
+    }
+
+    /// Record a line of NSS key log data
+    fn secret(&self, line: &[u8]) {
+        let len = DSB_LEN + padded(line.len());
+        let mut buf = Vec::with_capacity(len);
+        block_header(&mut buf, DSB_TYPE, len);
+        buf.extend_from_slice(&SECRETS_TYPE_TLS.to_le_bytes());
+        buf.extend_from_slice(&(line.len() as u32).to_le_bytes());
+        buf.extend_from_slice(line);
+        pad(&mut buf);
+        block_trailer(&mut buf, len);
+        self.write(&buf);
+    }
+
+    fn write(&self, block: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        let writer = match inner.as_mut() {
+            Some(x) => x,
+            None => return,
+        };
+        if let Err(e) = writer.write_all(block).and_then(|()| writer.flush()) {
+            warn!("abandoning packet capture: {}", e);
+            *inner = None;
+        }
+    }
+
+    /// Wrap `socket` so that all traffic passing through it is recorded
+    pub(crate) fn socket(&self, socket: Box<dyn DatagramSocket>) -> Box<dyn DatagramSocket> {
+        Box::new(PcapSocket {
+            inner: socket,
+            capture: self.clone(),
+        })
+    }
+}
+
+impl fmt::Debug for PcapWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let active = matches!(self.inner.lock().as_deref(), Ok(Some(_)));
+        f.debug_struct("PcapWriter")
+            .field("active", &active)
+            .finish()
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl rustls::KeyLog for PcapWriter {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let mut line =
+            String::with_capacity(label.len() + 2 * (client_random.len() + secret.len()) + 3);
+        line.push_str(label);
+        line.push(' ');
+        hex(&mut line, client_random);
+        line.push(' ');
+        hex(&mut line, secret);
+        line.push('\n');
+        self.secret(line.as_bytes());
+    }
+}
+
+/// A socket recording all traffic to a capture before passing it on
+#[derive(Debug)]
+struct PcapSocket {
+    inner: Box<dyn DatagramSocket>,
+    capture: PcapWriter,
+}
+
+impl DatagramSocket for PcapSocket {
+    fn poll_send(&self, cx: &mut Context, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
+        let sent = match self.inner.poll_send(cx, transmits) {
+            Poll::Ready(Ok(n)) => n,
+            x => return x,
+        };
+        let local = self.inner.local_addr().ok();
+        for transmit in &transmits[..sent] {
+            let source = SocketAddr::new(
+                transmit
+                    .src_ip
+                    .or_else(|| local.map(|x| x.ip()))
+                    .unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+                local.map_or(0, |x| x.port()),
+            );
+            let segment_size = transmit.segment_size.unwrap_or(transmit.contents.len());
+            for segment in transmit.contents.chunks(segment_size.max(1)) {
+                self.capture
+                    .datagram(source, transmit.destination, transmit.ecn, segment);
+            }
+        }
+        Poll::Ready(Ok(sent))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let received = match self.inner.poll_recv(cx, bufs, meta) {
+            Poll::Ready(Ok(n)) => n,
+            x => return x,
+        };
+        let local = self.inner.local_addr().ok();
+        for (buf, meta) in bufs.iter().zip(meta.iter()).take(received) {
+            let destination = SocketAddr::new(
+                meta.dst_ip
+                    .or_else(|| local.map(|x| x.ip()))
+                    .unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+                local.map_or(0, |x| x.port()),
+            );
+            self.capture
+                .datagram(meta.addr, destination, meta.ecn, &buf[..meta.len]);
+        }
+        Poll::Ready(Ok(received))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+/// Write IP and UDP headers followed by `payload`
+///
+/// Both addresses are expressed in the same family, preferring IPv4 where possible, so that
+/// traffic through dual-stack sockets is shown the way it appeared on the wire.
+fn encode_ip_udp(
+    buf: &mut Vec<u8>,
+    source: SocketAddr,
+    destination: SocketAddr,
+    ecn: Option<EcnCodepoint>,
+    payload: &[u8],
+) {
+    let ecn = ecn.map_or(0, |x| x as u8);
+    let udp_len = (UDP_HEADER_LEN + payload.len()) as u16;
+    let mut pseudo_header = Vec::with_capacity(40);
+    match (to_ipv4(source.ip()), to_ipv4(destination.ip())) {
+        (Some(src), Some(dst)) => {
+            let start = buf.len();
+            buf.push(0x45);
+            buf.push(ecn);
+            buf.extend_from_slice(&(IPV4_HEADER_LEN as u16 + udp_len).to_be_bytes());
+            // Identification
+            buf.extend_from_slice(&0u16.to_be_bytes());
+            // Don't fragment
+            buf.extend_from_slice(&0x4000u16.to_be_bytes());
+            // TTL
+            buf.push(64);
+            buf.push(IPPROTO_UDP);
+            let checksum_pos = buf.len();
+            buf.extend_from_slice(&0u16.to_be_bytes());
+            buf.extend_from_slice(&src.octets());
+            buf.extend_from_slice(&dst.octets());
+            let checksum = checksum(&[&buf[start..]]);
+            buf[checksum_pos..checksum_pos + 2].copy_from_slice(&checksum.to_be_bytes());
+
+            pseudo_header.extend_from_slice(&src.octets());
+            pseudo_header.extend_from_slice(&dst.octets());
+            pseudo_header.push(0);
+            pseudo_header.push(IPPROTO_UDP);
+            pseudo_header.extend_from_slice(&udp_len.to_be_bytes());
+        }
+        _ => {
+            let src = to_ipv6(source.ip());
+            let dst = to_ipv6(destination.ip());
+            buf.extend_from_slice(&(0x6000_0000u32 | u32::from(ecn) << 20).to_be_bytes());
+            buf.extend_from_slice(&udp_len.to_be_bytes());
+            buf.push(IPPROTO_UDP);
+            // Hop limit
+            buf.push(64);
+            buf.extend_from_slice(&src.octets());
+            buf.extend_from_slice(&dst.octets());
+
+            pseudo_header.extend_from_slice(&src.octets());
+            pseudo_header.extend_from_slice(&dst.octets());
+            pseudo_header.extend_from_slice(&u32::from(udp_len).to_be_bytes());
+            pseudo_header.extend_from_slice(&[0, 0, 0, IPPROTO_UDP]);
+        }
+    }
+
+    let mut udp_header = [0; UDP_HEADER_LEN];
+    udp_header[0..2].copy_from_slice(&source.port().to_be_bytes());
+    udp_header[2..4].copy_from_slice(&destination.port().to_be_bytes());
+    udp_header[4..6].copy_from_slice(&udp_len.to_be_bytes());
+    let checksum = match checksum(&[&pseudo_header, &udp_header, payload]) {
+        // Zero means "no checksum", so a computed zero is sent as its ones' complement equivalent
+        0 => 0xffff,
+        x => x,
+    };
+    udp_header[6..8].copy_from_slice(&checksum.to_be_bytes());
+    buf.extend_from_slice(&udp_header);
+    buf.extend_from_slice(payload);
+}
+
+fn to_ipv4(ip: IpAddr) -> Option<Ipv4Addr> {
+    match ip {
+        IpAddr::V4(x) => Some(x),
+        IpAddr::V6(x) => match x.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+                Some(Ipv4Addr::new(a, b, c, d))
+            }
+            _ => None,
+        },
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(x) => x.to_ipv6_mapped(),
+        IpAddr::V6(x) => x,
+    }
+}
+
+/// The Internet checksum of the concatenation of `data`
+///
+/// Every element but the last must have even length.
+fn checksum(data: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in data {
+        let mut words = chunk.chunks_exact(2);
+        for word in &mut words {
+            sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+        }
+        if let [x] = *words.remainder() {
+            sum += u32::from(x) << 8;
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(feature = "rustls")]
+fn hex(out: &mut String, data: &[u8]) {
+    use std::fmt::Write;
+    for byte in data {
+        write!(out, "{:02x}", byte).unwrap();
+    }
+}
+
+fn block_header(buf: &mut Vec<u8>, ty: u32, len: usize) {
+    buf.extend_from_slice(&ty.to_le_bytes());
+    buf.extend_from_slice(&(len as u32).to_le_bytes());
+}
+
+fn block_trailer(buf: &mut Vec<u8>, len: usize) {
+    buf.extend_from_slice(&(len as u32).to_le_bytes());
+}
+
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    buf.resize(padded(buf.len()), 0);
+}
+
+const SHB_TYPE: u32 = 0x0A0D_0D0A;
+const IDB_TYPE: u32 = 0x0000_0001;
+const EPB_TYPE: u32 = 0x0000_0006;
+const DSB_TYPE: u32 = 0x0000_000A;
+/// Length of a Section Header Block without options
+const SHB_LEN: usize = 28;
+/// Length of an Interface Description Block without options
+const IDB_LEN: usize = 20;
+/// Length of an Enhanced Packet Block without packet data or options
+const EPB_LEN: usize = 32;
+/// Length of a Decryption Secrets Block without secrets data or options
+const DSB_LEN: usize = 20;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+/// Packets begin with an IPv4 or IPv6 header
+const LINKTYPE_RAW: u16 = 101;
+/// NSS key log format
+const SECRETS_TYPE_TLS: u32 = 0x544c_534b;
+const IPPROTO_UDP: u8 = 17;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
@@ -6,6 +6,8 @@ use std::{
 
 use proto::{EcnCodepoint, Transmit};
 
+use crate::connection::SendDatagramError;
+
 #[cfg(unix)]
 mod cmsg;
 #[cfg(unix)]
@@ -28,6 +30,31 @@ pub fn caps() -> UdpCapabilities {
 /// Number of UDP packets to send/receive at a time
 pub const BATCH_SIZE: usize = imp::BATCH_SIZE;
 
+/// Whether a send failed because the datagram exceeded the path MTU known to the host
+///
+/// Only that datagram is affected, so it should be dropped rather than failing the socket. Besides
+/// the OS's error, a [`SendDatagramError::TooLarge`] from a tunnelling socket is recognized.
+pub fn is_too_large(e: &io::Error) -> bool {
+    if let Some(SendDatagramError::TooLarge) = e.get_ref().and_then(|x| x.downcast_ref()) {
+        return true;
+    }
+    #[cfg(unix)]
+    {
+        e.raw_os_error() == Some(libc::EMSGSIZE)
+    }
+    #[cfg(windows)]
+    {
+        /// `WSAEMSGSIZE`, which Windows reports for datagrams exceeding the MTU
+        const WSAEMSGSIZE: i32 = 10040;
+        e.raw_os_error() == Some(WSAEMSGSIZE)
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = e;
+        false
+    }
+}
+
 pub trait UdpExt {
     fn init_ext(&self) -> io::Result<()>;
     fn send_ext(&self, transmits: &[Transmit]) -> io::Result<usize>;
@@ -41,10 +68,14 @@ pub struct UdpCapabilities {
     pub gso: bool,
 }
 
+/// Metadata describing a received datagram
 #[derive(Debug, Copy, Clone)]
 pub struct RecvMeta {
+    /// The source address of the datagram
     pub addr: SocketAddr,
+    /// The length of the datagram in bytes
     pub len: usize,
+    /// The ECN codepoint of the datagram, if available
     pub ecn: Option<EcnCodepoint>,
     /// The destination IP address which was encoded in this datagram
     pub dst_ip: Option<IpAddr>,
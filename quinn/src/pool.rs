@@ -0,0 +1,316 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use futures::channel::oneshot;
+use proto::{generic::ClientConfig, ConnectError, ConnectionError};
+use thiserror::Error;
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, trace};
+
+use crate::{connection::Connection, endpoint::Endpoint};
+
+/// Shares established client connections between the tasks that need them
+///
+/// Connections are cached per server name, address and application protocol. Requests for a
+/// connection that is still being established wait for that handshake rather than starting
+/// another. A connection that was neither handed out nor carried stream data or datagrams for the
+/// pool's idle timeout is removed from the pool, and closed once every other handle to it has been
+/// dropped.
+///
+/// Only the [`Connection`] is kept, so any streams or datagrams the server sends unprompted on a
+/// pooled connection are ignored. May be cloned to obtain another handle to the same pool.
+pub struct ConnectionPool<S>
+where
+    S: proto::crypto::Session,
+{
+    endpoint: Endpoint<S>,
+    idle_timeout: Duration,
+    shared: Arc<Mutex<Pool<S>>>,
+}
+
+impl<S> ConnectionPool<S>
+where
+    S: proto::crypto::Session + 'static,
+{
+    /// Create a pool of connections made from `endpoint`, evicting those idle for `idle_timeout`
+    pub fn new(endpoint: Endpoint<S>, idle_timeout: Duration) -> Self {
+        Self {
+            endpoint,
+            idle_timeout,
+            shared: Arc::new(Mutex::new(Pool {
+                entries: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Get a connection to `server_name` at `addr` that negotiated `protocol`
+    ///
+    /// If there's none in the pool, a new one is established with `config`, which should offer
+    /// `protocol` and nothing else; a connection negotiating anything else is closed and fails
+    /// with [`PoolError::ProtocolMismatch`]. See [`Endpoint::connect()`] for the meaning of `addr`
+    /// and `server_name`.
+    pub async fn get(
+        &self,
+        config: ClientConfig<S>,
+        protocol: &[u8],
+        addr: &SocketAddr,
+        server_name: &str,
+    ) -> Result<Connection<S>, PoolError> {
+        let key = Key {
+            server_name: server_name.into(),
+            addr: *addr,
+            protocol: protocol.into(),
+        };
+        loop {
+            let waiter = {
+                let pool = &mut *self.shared.lock().unwrap();
+                match pool.entries.get_mut(&key) {
+                    Some(Entry::Ready {
+                        connection,
+                        last_used,
+                        ..
+                    }) if !connection.is_closed() => {
+                        *last_used = Instant::now();
+                        return Ok(connection.clone());
+                    }
+                    Some(Entry::Dialing(waiters)) => {
+                        let (send, recv) = oneshot::channel();
+                        waiters.push(send);
+                        recv
+                    }
+                    _ => {
+                        pool.entries.insert(key.clone(), Entry::Dialing(Vec::new()));
+                        break;
+                    }
+                }
+            };
+            match waiter.await {
+                Ok(result) => return result,
+                // The task dialing was cancelled, so take over
+                Err(oneshot::Canceled) => continue,
+            }
+        }
+
+        let mut dial = Dial {
+            shared: &self.shared,
+            key: &key,
+            finished: false,
+        };
+        let result = self.connect(config, &key).await;
+        dial.finish(result.clone(), self.idle_timeout);
+        result
+    }
+
+    async fn connect(
+        &self,
+        config: ClientConfig<S>,
+        key: &Key,
+    ) -> Result<Connection<S>, PoolError> {
+        trace!(server_name = %key.server_name, addr = %key.addr, "dialing");
+        let new_conn = self
+            .endpoint
+            .connect_with(config, &key.addr, &key.server_name)?
+            .await?;
+        let connection = new_conn.connection;
+        // Caching under the wrong protocol would hand the connection to tasks that can't use it
+        if connection.application_protocol().as_deref() != Some(&key.protocol[..]) {
+            debug!(server_name = %key.server_name, "pooled connection negotiated another protocol");
+            connection.close(0u32.into(), b"");
+            return Err(PoolError::ProtocolMismatch);
+        }
+        Ok(connection)
+    }
+}
+
+impl<S> Clone for ConnectionPool<S>
+where
+    S: proto::crypto::Session,
+{
+    fn clone(&self) -> Self {
+        Self {
+            endpoint: self.endpoint.clone(),
+            idle_timeout: self.idle_timeout,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<S> fmt::Debug for ConnectionPool<S>
+where
+    S: proto::crypto::Session,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionPool")
+            .field("idle_timeout", &self.idle_timeout)
+            .field("entries", &self.shared.lock().unwrap().entries.len())
+            .finish()
+    }
+}
+
+struct Pool<S>
+where
+    S: proto::crypto::Session,
+{
+    entries: HashMap<Key, Entry<S>>,
+}
+
+enum Entry<S>
+where
+    S: proto::crypto::Session,
+{
+    /// A connection is being established, on behalf of these waiters among others
+    Dialing(Vec<oneshot::Sender<Result<Connection<S>, PoolError>>>),
+    Ready {
+        connection: Connection<S>,
+        /// When the connection was last handed out or seen to be in use
+        last_used: Instant,
+        /// The value of `activity()` as of `last_used`
+        activity: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    server_name: String,
+    addr: SocketAddr,
+    protocol: Vec<u8>,
+}
+
+/// Hands the outcome of a dial to the pool, or lets the waiters retry if it's abandoned
+struct Dial<'a, S>
+where
+    S: proto::crypto::Session,
+{
+    shared: &'a Arc<Mutex<Pool<S>>>,
+    key: &'a Key,
+    finished: bool,
+}
+
+impl<S> Dial<'_, S>
+where
+    S: proto::crypto::Session + 'static,
+{
+    fn finish(&mut self, result: Result<Connection<S>, PoolError>, idle_timeout: Duration) {
+        self.finished = true;
+        let waiters = {
+            let pool = &mut *self.shared.lock().unwrap();
+            let waiters = match pool.entries.remove(self.key) {
+                Some(Entry::Dialing(waiters)) => waiters,
+                _ => unreachable!("dialing entry removed by another task"),
+            };
+            if let Ok(ref connection) = result {
+                pool.entries.insert(
+                    self.key.clone(),
+                    Entry::Ready {
+                        connection: connection.clone(),
+                        last_used: Instant::now(),
+                        activity: activity(connection),
+                    },
+                );
+                tokio::spawn(evict_when_idle(
+                    Arc::downgrade(self.shared),
+                    self.key.clone(),
+                    connection.stable_id(),
+                    idle_timeout,
+                ));
+            }
+            waiters
+        };
+        if let Err(ref e) = result {
+            debug!(server_name = %self.key.server_name, "pooled connection failed: {}", e);
+        }
+        for waiter in waiters {
+            let _ = waiter.send(result.clone());
+        }
+    }
+}
+
+impl<S> Drop for Dial<'_, S>
+where
+    S: proto::crypto::Session,
+{
+    fn drop(&mut self) {
+        if !self.finished {
+            // Dropping the waiters' senders wakes them up to dial themselves
+            self.shared.lock().unwrap().entries.remove(self.key);
+        }
+    }
+}
+
+/// Remove the connection identified by `stable_id` from the pool once it's been idle long enough
+async fn evict_when_idle<S>(
+    shared: Weak<Mutex<Pool<S>>>,
+    key: Key,
+    stable_id: usize,
+    idle_timeout: Duration,
+) where
+    S: proto::crypto::Session,
+{
+    let mut deadline = Instant::now() + idle_timeout;
+    loop {
+        sleep_until(deadline).await;
+        let shared = match shared.upgrade() {
+            Some(x) => x,
+            None => return,
+        };
+        let pool = &mut *shared.lock().unwrap();
+        let expiry = match pool.entries.get_mut(&key) {
+            Some(Entry::Ready {
+                connection,
+                last_used,
+                activity: last_activity,
+            }) if connection.stable_id() == stable_id => {
+                if connection.is_closed() {
+                    None
+                } else {
+                    // Tasks holding the connection may still be using it
+                    let current = activity(connection);
+                    if current != *last_activity {
+                        *last_activity = current;
+                        *last_used = Instant::now();
+                    }
+                    Some(*last_used + idle_timeout)
+                }
+            }
+            // Replaced by another connection, which has an eviction task of its own
+            _ => return,
+        };
+        match expiry {
+            Some(x) if x > Instant::now() => deadline = x,
+            _ => {
+                trace!(server_name = %key.server_name, "evicting idle connection");
+                pool.entries.remove(&key);
+                return;
+            }
+        }
+    }
+}
+
+/// Number of stream and datagram frames `connection` has sent and received
+fn activity<S>(connection: &Connection<S>) -> u64
+where
+    S: proto::crypto::Session,
+{
+    let stats = connection.stats();
+    let (tx, rx) = (stats.frame_tx, stats.frame_rx);
+    tx.stream + rx.stream + tx.datagram + rx.datagram
+}
+
+/// Reasons why [`ConnectionPool::get()`] might fail
+#[derive(Debug, Error, Clone)]
+pub enum PoolError {
+    /// The connection couldn't be started
+    #[error("connect failed: {0}")]
+    Connect(#[from] ConnectError),
+    /// The connection failed during the handshake
+    #[error("connection failed: {0}")]
+    Connection(#[from] ConnectionError),
+    /// The server chose an application protocol other than the one requested
+    #[error("negotiated an unexpected application protocol")]
+    ProtocolMismatch,
+}
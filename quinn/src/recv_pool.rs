@@ -0,0 +1,65 @@
+use std::{io::IoSliceMut, mem::MaybeUninit};
+
+use bytes::BytesMut;
+
+use crate::platform::BATCH_SIZE;
+
+/// Refcounted blocks that datagrams are received into
+///
+/// Each received datagram is handed to the protocol layer as a view into its block rather than a
+/// copy, so decryption happens in place and stream data is buffered as slices of the block. A
+/// block is reused for the next receive once every view into it has been dropped; otherwise a
+/// fresh block is allocated and the old one is freed along with its last view.
+#[derive(Debug)]
+pub(crate) struct RecvPool {
+    slots: Vec<BytesMut>,
+    block_size: usize,
+}
+
+impl RecvPool {
+    /// Create a pool with one slot per datagram in a receive batch
+    pub(crate) fn new(block_size: usize) -> Self {
+        Self {
+            slots: (0..BATCH_SIZE)
+                .map(|_| BytesMut::with_capacity(block_size))
+                .collect(),
+            block_size,
+        }
+    }
+
+    /// Prepare every slot to receive a datagram
+    ///
+    /// Slots whose previous datagram was taken are re-armed, reclaiming their block if it is no
+    /// longer referenced.
+    pub(crate) fn iovs(&mut self) -> [IoSliceMut<'_>; BATCH_SIZE] {
+        let mut iovs = MaybeUninit::<[IoSliceMut<'_>; BATCH_SIZE]>::uninit();
+        for (i, slot) in self.slots.iter_mut().enumerate() {
+            if slot.len() < self.block_size {
+                slot.reserve(self.block_size);
+                // Zeroing the block for every datagram would cost as much as receiving it. Nothing
+                // reads the block before the kernel writes to it, and `take` only ever yields the
+                // bytes a receive reported having written.
+                unsafe {
+                    slot.set_len(self.block_size);
+                }
+            }
+            unsafe {
+                iovs.as_mut_ptr()
+                    .cast::<IoSliceMut>()
+                    .add(i)
+                    .write(IoSliceMut::new(slot));
+            }
+        }
+        unsafe { iovs.assume_init() }
+    }
+
+    /// Take the `len`-byte datagram received into slot `i`
+    ///
+    /// The result retains the block's full capacity, so that consumers can account for the memory
+    /// it pins.
+    pub(crate) fn take(&mut self, i: usize, len: usize) -> BytesMut {
+        let mut data = self.slots[i].split();
+        data.truncate(len);
+        data
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    mem,
+};
+
+use bytes::{Buf, Bytes};
+use proto::{Dir, StreamId, VarInt};
+use tracing::debug;
+
+/// Early data kept by a client so that it can be sent again if the server rejects 0-RTT
+#[derive(Debug)]
+pub(crate) enum Replay {
+    /// The server's answer isn't known yet, so what's done with these streams is recorded
+    ///
+    /// Streams are kept in the order they were opened.
+    Recording(Vec<(StreamId, Pending)>),
+    /// The streams in `usable` remain usable whatever the server's answer, and those in `pending`
+    /// have early data left to send again
+    Replaying {
+        usable: HashSet<StreamId>,
+        pending: HashMap<StreamId, Pending>,
+    },
+    /// All early data has been sent again, leaving only which streams remain usable
+    Replayed(HashSet<StreamId>),
+}
+
+/// What's left to do to a stream to catch up with what the application did in 0-RTT
+#[derive(Debug, Default)]
+pub(crate) struct Pending {
+    data: VecDeque<Bytes>,
+    finish: bool,
+    reset: Option<VarInt>,
+    stop: Option<VarInt>,
+    /// Whether the application allowed the stream to be replayed
+    idempotent: bool,
+}
+
+impl Pending {
+    fn is_done(&self) -> bool {
+        self.data.is_empty() && !self.finish
+    }
+}
+
+impl Replay {
+    pub(crate) fn new() -> Self {
+        Replay::Recording(Vec::new())
+    }
+
+    /// Whether `id` can be used even though 0-RTT wasn't accepted
+    pub(crate) fn is_usable(&self, id: StreamId) -> bool {
+        match *self {
+            Replay::Recording(_) => false,
+            Replay::Replaying { ref usable, .. } | Replay::Replayed(ref usable) => {
+                usable.contains(&id)
+            }
+        }
+    }
+
+    pub(crate) fn opened(&mut self, id: StreamId) {
+        match *self {
+            Replay::Recording(ref mut streams) => streams.push((id, Pending::default())),
+            // Nothing was sent on the stream in 0-RTT, so it's safe to use
+            Replay::Replaying { ref mut usable, .. } | Replay::Replayed(ref mut usable) => {
+                usable.insert(id);
+            }
+        }
+    }
+
+    /// Allow `id`'s early data to be sent again should the server reject 0-RTT
+    pub(crate) fn mark_idempotent(&mut self, id: StreamId) {
+        if let Some(pending) = self.recording(id) {
+            pending.idempotent = true;
+        }
+    }
+
+    pub(crate) fn wrote(&mut self, id: StreamId, data: &[u8]) {
+        if let Some(pending) = self.recording(id) {
+            pending.data.push_back(Bytes::copy_from_slice(data));
+        }
+    }
+
+    pub(crate) fn finished(&mut self, id: StreamId) {
+        if let Some(pending) = self.recording(id) {
+            pending.finish = true;
+        }
+    }
+
+    pub(crate) fn reset(&mut self, id: StreamId, error_code: VarInt) {
+        let pending = match *self {
+            Replay::Recording(ref mut streams) => match streams.iter_mut().find(|x| x.0 == id) {
+                Some(x) => {
+                    x.1.reset = Some(error_code);
+                    &mut x.1
+                }
+                None => return,
+            },
+            Replay::Replaying {
+                ref mut pending, ..
+            } => {
+                // The early data needn't be sent any more
+                pending.remove(&id);
+                self.check_replayed();
+                return;
+            }
+            Replay::Replayed(_) => return,
+        };
+        pending.data.clear();
+        pending.finish = false;
+    }
+
+    pub(crate) fn stopped(&mut self, id: StreamId, error_code: VarInt) {
+        if let Some(pending) = self.recording(id) {
+            pending.stop = Some(error_code);
+        }
+    }
+
+    /// Whether `id` still has early data to send again, in which case it's finished after that
+    pub(crate) fn defer_finish(&mut self, id: StreamId) -> bool {
+        match *self {
+            Replay::Replaying {
+                ref mut pending, ..
+            } => match pending.get_mut(&id) {
+                Some(pending) if !pending.data.is_empty() => {
+                    pending.finish = true;
+                    true
+                }
+                _ => false,
+            },
+            Replay::Recording(_) | Replay::Replayed(_) => false,
+        }
+    }
+
+    /// The handshake completed without 0-RTT being rejected
+    ///
+    /// Either 0-RTT wasn't attempted, leaving the streams as they were, or it was accepted, in which
+    /// case this need not be kept at all.
+    pub(crate) fn connected(&mut self) {
+        if let Replay::Recording(ref mut streams) = *self {
+            let usable = streams.drain(..).map(|(id, _)| id).collect();
+            *self = Replay::Replayed(usable);
+        }
+    }
+
+    /// Open the recorded streams again after the server rejected 0-RTT, and send the data of those
+    /// marked idempotent
+    pub(crate) fn rejected<S>(&mut self, conn: &mut proto::generic::Connection<S>)
+    where
+        S: proto::crypto::Session,
+    {
+        let recorded = match *self {
+            Replay::Recording(ref mut streams) => mem::take(streams),
+            Replay::Replaying { .. } | Replay::Replayed(_) => return,
+        };
+        // Streams past the last idempotent one in each direction needn't be opened at all
+        let last_idempotent = |dir: Dir| {
+            recorded
+                .iter()
+                .filter(|x| x.0.dir() == dir && x.1.idempotent)
+                .map(|x| x.0.index())
+                .max()
+        };
+        let (last_bi, last_uni) = (last_idempotent(Dir::Bi), last_idempotent(Dir::Uni));
+        let mut usable = HashSet::with_capacity(recorded.len());
+        let mut pending = HashMap::with_capacity(recorded.len());
+        for (id, mut stream) in recorded {
+            let last = match id.dir() {
+                Dir::Bi => last_bi,
+                Dir::Uni => last_uni,
+            };
+            if !matches!(last, Some(x) if id.index() <= x) {
+                continue;
+            }
+            // Stream numbering starts over, so opening in the original order yields the same IDs
+            match conn.open(id.dir()) {
+                Some(x) => debug_assert_eq!(x, id, "replayed stream reopened out of order"),
+                None => {
+                    debug!(stream = %id, "stream limit too low to replay 0-RTT stream");
+                    continue;
+                }
+            }
+            if !stream.idempotent {
+                // Only holds a place for the streams after it, having failed for the application
+                let _ = conn.reset(id, 0u32.into());
+                if id.dir() == Dir::Bi {
+                    let _ = conn.stop(id, 0u32.into());
+                }
+                continue;
+            }
+            if let Some(error_code) = stream.stop.take() {
+                let _ = conn.stop(id, error_code);
+            }
+            if let Some(error_code) = stream.reset.take() {
+                let _ = conn.reset(id, error_code);
+            }
+            usable.insert(id);
+            if !stream.is_done() {
+                pending.insert(id, stream);
+            }
+        }
+        let ids = pending.keys().cloned().collect::<Vec<_>>();
+        *self = Replay::Replaying { usable, pending };
+        for id in ids {
+            self.flush(conn, id);
+        }
+        self.check_replayed();
+    }
+
+    /// Send as much of `id`'s remaining early data as flow control allows
+    ///
+    /// Returns whether none is left, so that the application may write more.
+    pub(crate) fn flush<S>(
+        &mut self,
+        conn: &mut proto::generic::Connection<S>,
+        id: StreamId,
+    ) -> bool
+    where
+        S: proto::crypto::Session,
+    {
+        let pending = match *self {
+            Replay::Replaying {
+                ref mut pending, ..
+            } => match pending.get_mut(&id) {
+                Some(x) => x,
+                None => return true,
+            },
+            Replay::Recording(_) | Replay::Replayed(_) => return true,
+        };
+        while let Some(chunk) = pending.data.front_mut() {
+            match conn.write(id, chunk) {
+                Ok(n) => {
+                    chunk.advance(n);
+                    if chunk.is_empty() {
+                        pending.data.pop_front();
+                    }
+                }
+                Err(proto::WriteError::Blocked) => return false,
+                // Stopped by the peer, which the application learns from its next write
+                Err(_) => {
+                    pending.data.clear();
+                    pending.finish = false;
+                }
+            }
+        }
+        if mem::replace(&mut pending.finish, false) {
+            let _ = conn.finish(id);
+        }
+        if let Replay::Replaying {
+            ref mut pending, ..
+        } = *self
+        {
+            pending.remove(&id);
+        }
+        self.check_replayed();
+        true
+    }
+
+    /// Let go of the early data once all of it has been sent again
+    fn check_replayed(&mut self) {
+        if let Replay::Replaying {
+            ref mut usable,
+            ref pending,
+        } = *self
+        {
+            if pending.is_empty() {
+                *self = Replay::Replayed(mem::take(usable));
+            }
+        }
+    }
+
+    fn recording(&mut self, id: StreamId) -> Option<&mut Pending> {
+        match *self {
+            Replay::Recording(ref mut streams) => {
+                streams.iter_mut().find(|x| x.0 == id).map(|x| &mut x.1)
+            }
+            Replay::Replaying { .. } | Replay::Replayed(_) => None,
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Serving bidirectional streams with a [`tower_service::Service`]
+//!
+//! RPC-style protocols commonly use one bidirectional stream per request. [`serve_bi_streams()`]
+//! accepts such streams from a connection and hands each one to a single call of a `Service`, so
+//! that such servers can be assembled from tower middleware for timeouts, load shedding,
+//! concurrency limits or metrics.
+//!
+//! ```no_run
+//! # use std::{convert::Infallible, future::Future, pin::Pin, task::{Context, Poll}};
+//! # use quinn::{RecvStream, SendStream};
+//! struct Echo;
+//!
+//! impl tower_service::Service<(SendStream, RecvStream)> for Echo {
+//!     type Response = ();
+//!     type Error = Box<dyn std::error::Error + Send + Sync>;
+//!     type Future = Pin<Box<dyn Future<Output = Result<(), Self::Error>> + Send>>;
+//!
+//!     fn poll_ready(&mut self, _: &mut Context) -> Poll<Result<(), Self::Error>> {
+//!         Poll::Ready(Ok(()))
+//!     }
+//!
+//!     fn call(&mut self, (mut send, recv): (SendStream, RecvStream)) -> Self::Future {
+//!         Box::pin(async move {
+//!             let request = recv.read_to_end(64 * 1024).await?;
+//!             send.write_all(&request).await?;
+//!             send.finish().await?;
+//!             Ok(())
+//!         })
+//!     }
+//! }
+//!
+//! # async fn f(new_conn: quinn::NewConnection) {
+//! quinn::service::serve_bi_streams(new_conn.bi_streams, Echo).await.unwrap();
+//! # }
+//! ```
+
+use std::fmt;
+
+use futures::{future::poll_fn, StreamExt};
+use thiserror::Error;
+use tower_service::Service;
+use tracing::debug;
+
+use crate::{
+    connection::IncomingBiStreams,
+    streams::{RecvStream, SendStream},
+    ConnectionError,
+};
+
+/// Call `service` once for every bidirectional stream the peer opens
+///
+/// Streams are only accepted while `service` is ready, so a service applying backpressure also
+/// stops the peer from opening streams beyond the connection's concurrency limit. Each call runs
+/// in a task of its own, and errors from it are logged.
+///
+/// Completes once the connection is closed locally, or fails with the reason it was lost or the
+/// error that made `service` unable to accept requests.
+pub async fn serve_bi_streams<S, T>(
+    mut streams: IncomingBiStreams<S>,
+    mut service: T,
+) -> Result<(), ServeError<T::Error>>
+where
+    S: proto::crypto::Session + 'static,
+    T: Service<(SendStream<S>, RecvStream<S>)>,
+    T::Future: Send + 'static,
+    T::Error: fmt::Display,
+{
+    loop {
+        poll_fn(|cx| service.poll_ready(cx))
+            .await
+            .map_err(ServeError::Service)?;
+        let (send, recv) = match streams.next().await {
+            Some(x) => x.map_err(ServeError::ConnectionLost)?,
+            None => return Ok(()),
+        };
+        let call = service.call((send, recv));
+        tokio::spawn(async move {
+            if let Err(e) = call.await {
+                debug!("stream service failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Reasons for [`serve_bi_streams()`] to stop early
+#[derive(Debug, Error)]
+pub enum ServeError<E> {
+    /// The connection was lost
+    #[error("connection lost: {0}")]
+    ConnectionLost(#[source] ConnectionError),
+    /// The service can no longer accept requests
+    #[error("service failed: {0}")]
+    Service(#[source] E),
+}
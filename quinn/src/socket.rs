@@ -0,0 +1,173 @@
+//! Abstraction over the datagram carrier underlying an endpoint
+//!
+//! QUIC only requires an unreliable datagram service from the layer below it. Implementing
+//! [`DatagramSocket`] allows an [`Endpoint`] to run over anything providing such a service, such
+//! as UNIX datagram sockets, SCTP in unordered unreliable mode, or a custom radio link.
+//!
+//! Connections identify peers by [`SocketAddr`]. Carriers with other addressing schemes can use an
+//! [`AddressMap`] to assign each peer a stable synthetic address.
+//!
+//! [`Endpoint`]: crate::generic::Endpoint
+
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    io,
+    io::IoSliceMut,
+    net::{Ipv6Addr, SocketAddr, SocketAddrV6},
+    task::{Context, Poll},
+};
+
+use proto::Transmit;
+
+use crate::platform::{RecvMeta, UdpSocket};
+
+/// An unreliable datagram transport which an endpoint can send and receive on
+///
+/// Implementations must be usable from the endpoint's driver task, but need not be `Sync`: the
+/// endpoint serializes all access.
+pub trait DatagramSocket: Send + fmt::Debug + 'static {
+    /// Send as many of `transmits` as possible, returning the number sent
+    ///
+    /// A transmit with `segment_size` set holds multiple datagrams of that size, the last of which
+    /// may be shorter. Implementations should register `cx` for wakeup when returning `Pending`.
+    ///
+    /// A datagram that's too large for the underlying transport should fail with the OS's error for
+    /// one exceeding the path MTU, or with an error wrapping [`SendDatagramError::TooLarge`], so
+    /// that the endpoint drops just that datagram. Most other errors stop the endpoint.
+    ///
+    /// [`SendDatagramError::TooLarge`]: crate::SendDatagramError::TooLarge
+    fn poll_send(&self, cx: &mut Context, transmits: &[Transmit]) -> Poll<io::Result<usize>>;
+
+    /// Receive up to `bufs.len()` datagrams, returning the number received
+    ///
+    /// The datagram written into `bufs[i]` must be described by `meta[i]`. Implementations should
+    /// register `cx` for wakeup when returning `Pending`.
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>>;
+
+    /// The address this socket is reachable at
+    ///
+    /// Whether this is an IPv6 address determines whether IPv4 destinations are mapped into IPv6
+    /// before being passed to [`poll_send()`](DatagramSocket::poll_send).
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl DatagramSocket for UdpSocket {
+    fn poll_send(&self, cx: &mut Context, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
+        UdpSocket::poll_send(self, cx, transmits)
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        UdpSocket::poll_recv(self, cx, bufs, meta)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+/// Bidirectional mapping between carrier-specific addresses and synthetic [`SocketAddr`]s
+///
+/// Synthetic addresses are drawn from the IPv6 unique local range `fd00::/8`, so they never
+/// collide with addresses of routable hosts.
+pub struct AddressMap<A> {
+    to_socket: HashMap<A, SocketAddr>,
+    from_socket: HashMap<SocketAddr, A>,
+    next: u64,
+}
+
+impl<A> AddressMap<A>
+where
+    A: Hash + Eq + Clone,
+{
+    /// Create an empty map
+    pub fn new() -> Self {
+        Self {
+            to_socket: HashMap::new(),
+            from_socket: HashMap::new(),
+            next: 0,
+        }
+    }
+
+    /// Get the synthetic address for `addr`, assigning a new one if necessary
+    pub fn insert(&mut self, addr: A) -> SocketAddr {
+        if let Some(&x) = self.to_socket.get(&addr) {
+            return x;
+        }
+        let n = self.next;
+        self.next += 1;
+        let ip = Ipv6Addr::new(
+            0xfd00,
+            0,
+            0,
+            0,
+            (n >> 48) as u16,
+            (n >> 32) as u16,
+            (n >> 16) as u16,
+            n as u16,
+        );
+        let socket = SocketAddr::V6(SocketAddrV6::new(ip, 1, 0, 0));
+        self.to_socket.insert(addr.clone(), socket);
+        self.from_socket.insert(socket, addr);
+        socket
+    }
+
+    /// Look up the synthetic address previously assigned to `addr`
+    pub fn socket_addr(&self, addr: &A) -> Option<SocketAddr> {
+        self.to_socket.get(addr).copied()
+    }
+
+    /// Look up the carrier address a synthetic address was assigned to
+    pub fn get(&self, socket: &SocketAddr) -> Option<&A> {
+        self.from_socket.get(socket)
+    }
+
+    /// Forget `addr`, returning its synthetic address if it had one
+    ///
+    /// Synthetic addresses are never reused, so a peer reappearing afterwards is indistinguishable
+    /// from a newly seen one.
+    pub fn remove(&mut self, addr: &A) -> Option<SocketAddr> {
+        let socket = self.to_socket.remove(addr)?;
+        self.from_socket.remove(&socket);
+        Some(socket)
+    }
+
+    /// Number of addresses currently mapped
+    pub fn len(&self) -> usize {
+        self.to_socket.len()
+    }
+
+    /// Whether no addresses are currently mapped
+    pub fn is_empty(&self) -> bool {
+        self.to_socket.is_empty()
+    }
+}
+
+impl<A> Default for AddressMap<A>
+where
+    A: Hash + Eq + Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A> fmt::Debug for AddressMap<A>
+where
+    A: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.to_socket.iter()).finish()
+    }
+}
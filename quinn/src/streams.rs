@@ -2,6 +2,7 @@ use std::{
     future::Future,
     io,
     pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
     task::{Context, Poll},
 };
 
@@ -11,11 +12,13 @@ use futures::{
     io::{AsyncRead, AsyncWrite},
     ready, FutureExt,
 };
-use proto::{Chunk, ConnectionError, FinishError, StreamId};
+use once_cell::sync::OnceCell;
+use proto::{Chunk, ConnectionError, FinishError, RecvStreamStats, StreamId};
 use thiserror::Error;
 use tokio::io::ReadBuf;
+use tracing::Span;
 
-use crate::{connection::ConnectionRef, VarInt};
+use crate::{connection::ConnectionRef, extensions::Extensions, VarInt};
 
 /// A stream that can only be used to send data
 ///
@@ -32,18 +35,23 @@ where
     stream: StreamId,
     is_0rtt: bool,
     finishing: Option<oneshot::Receiver<Option<WriteError>>>,
+    /// Shared with the receiving half of a bidirectional stream, allocated on first use
+    extensions: OnceCell<Arc<Mutex<Extensions>>>,
+    span: Span,
 }
 
 impl<S> SendStream<S>
 where
     S: proto::crypto::Session,
 {
-    pub(crate) fn new(conn: ConnectionRef<S>, stream: StreamId, is_0rtt: bool) -> Self {
+    pub(crate) fn new(conn: ConnectionRef<S>, stream: StreamId, is_0rtt: bool, span: Span) -> Self {
         Self {
             conn,
             stream,
             is_0rtt,
             finishing: None,
+            extensions: OnceCell::new(),
+            span,
         }
     }
 
@@ -62,14 +70,23 @@ where
 
     fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<Result<usize, WriteError>> {
         use proto::WriteError::*;
+        let _guard = self.span.enter();
         let mut conn = self.conn.lock().unwrap();
         if self.is_0rtt {
-            conn.check_0rtt()
+            conn.check_0rtt(self.stream)
                 .map_err(|()| WriteError::ZeroRttRejected)?;
         }
         if let Some(ref x) = conn.error {
             return Poll::Ready(Err(WriteError::ConnectionClosed(x.clone())));
         }
+        let conn = &mut *conn;
+        if let Some(ref mut replay) = conn.replay {
+            // Data written before must go out first
+            if !replay.flush(&mut conn.inner, self.stream) {
+                conn.blocked_writers.insert(self.stream, cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
         let n = match conn.inner.write(self.stream, buf) {
             Ok(n) => n,
             Err(Blocked) => {
@@ -83,6 +100,9 @@ where
                 return Poll::Ready(Err(WriteError::UnknownStream));
             }
         };
+        if let Some(ref mut replay) = conn.replay {
+            replay.wrote(self.stream, &buf[..n]);
+        }
         conn.wake();
         Poll::Ready(Ok(n))
     }
@@ -97,16 +117,27 @@ where
 
     #[doc(hidden)]
     pub fn poll_finish(&mut self, cx: &mut Context) -> Poll<Result<(), WriteError>> {
+        let _guard = self.span.enter();
         let mut conn = self.conn.lock().unwrap();
         if self.is_0rtt {
-            conn.check_0rtt()
+            conn.check_0rtt(self.stream)
                 .map_err(|()| WriteError::ZeroRttRejected)?;
         }
         if self.finishing.is_none() {
+            let conn = &mut *conn;
+            if let Some(ref mut replay) = conn.replay {
+                if !replay.flush(&mut conn.inner, self.stream) {
+                    conn.blocked_writers.insert(self.stream, cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
             conn.inner.finish(self.stream).map_err(|e| match e {
                 FinishError::UnknownStream => WriteError::UnknownStream,
                 FinishError::Stopped(error_code) => WriteError::Stopped(error_code),
             })?;
+            if let Some(ref mut replay) = conn.replay {
+                replay.finished(self.stream);
+            }
             let (send, recv) = oneshot::channel();
             self.finishing = Some(recv);
             conn.finishing.insert(self.stream, send);
@@ -141,11 +172,15 @@ where
     /// previously transmitted data will no longer be retransmitted if lost. If an attempt has
     /// already been made to finish the stream, the peer may still receive all written data.
     pub fn reset(&mut self, error_code: VarInt) -> Result<(), UnknownStream> {
+        let _guard = self.span.enter();
         let mut conn = self.conn.lock().unwrap();
-        if self.is_0rtt && conn.check_0rtt().is_err() {
+        if self.is_0rtt && conn.check_0rtt(self.stream).is_err() {
             return Ok(());
         }
         conn.inner.reset(self.stream, error_code)?;
+        if let Some(ref mut replay) = conn.replay {
+            replay.reset(self.stream, error_code);
+        }
         conn.wake();
         Ok(())
     }
@@ -157,10 +192,11 @@ where
 
     #[doc(hidden)]
     pub fn poll_stopped(&mut self, cx: &mut Context) -> Poll<Result<VarInt, StoppedError>> {
+        let _guard = self.span.enter();
         let mut conn = self.conn.lock().unwrap();
 
         if self.is_0rtt {
-            conn.check_0rtt()
+            conn.check_0rtt(self.stream)
                 .map_err(|()| StoppedError::ZeroRttRejected)?;
         }
 
@@ -178,6 +214,43 @@ where
     pub fn id(&self) -> StreamId {
         self.stream
     }
+
+    /// Allow what's written to this stream in 0-RTT to be sent again if the server rejects 0-RTT
+    ///
+    /// Only affects streams opened before the handshake completes on connections from
+    /// [`Connecting::into_0rtt_with_replay()`], which must be marked before the server's answer is
+    /// known. Streams that aren't marked fail with `ZeroRttRejected` after a rejection, as on
+    /// connections from [`Connecting::into_0rtt()`]. Marks both halves of a bidirectional stream.
+    ///
+    /// Data written to a marked stream reaches the server twice if 0-RTT is rejected after the
+    /// server acted on it, so this is only for streams carrying idempotent requests.
+    ///
+    /// [`Connecting::into_0rtt_with_replay()`]: crate::generic::Connecting::into_0rtt_with_replay
+    /// [`Connecting::into_0rtt()`]: crate::generic::Connecting::into_0rtt
+    pub fn mark_idempotent(&mut self) {
+        if let Some(ref mut replay) = self.conn.lock().unwrap().replay {
+            replay.mark_idempotent(self.stream);
+        }
+    }
+
+    /// Application state attached to this stream
+    ///
+    /// Both halves of a bidirectional stream share the same extensions, which are allocated when
+    /// first used and dropped along with the last of them. The stream's other methods may be used
+    /// while the lock is held, but it must not be held across an `.await`.
+    pub fn extensions(&self) -> MutexGuard<'_, Extensions> {
+        self.extensions
+            .get_or_init(|| self.conn.stream_extensions(self.stream))
+            .lock()
+            .unwrap()
+    }
+
+    /// Application state attached to the connection this stream belongs to
+    ///
+    /// See [`Connection::extensions()`](crate::generic::Connection::extensions).
+    pub fn connection_extensions(&self) -> MutexGuard<'_, Extensions> {
+        self.conn.extensions()
+    }
 }
 
 impl<S> AsyncWrite for SendStream<S>
@@ -223,11 +296,19 @@ where
     S: proto::crypto::Session,
 {
     fn drop(&mut self) {
+        let _guard = self.span.enter();
         let mut conn = self.conn.lock().unwrap();
-        if conn.error.is_some() || (self.is_0rtt && conn.check_0rtt().is_err()) {
+        conn.release_stream_extensions(self.stream);
+        if conn.error.is_some() || (self.is_0rtt && conn.check_0rtt(self.stream).is_err()) {
             return;
         }
         if self.finishing.is_none() {
+            if let Some(ref mut replay) = conn.replay {
+                if replay.defer_finish(self.stream) {
+                    return;
+                }
+                replay.finished(self.stream);
+            }
             match conn.inner.finish(self.stream) {
                 Ok(()) => conn.wake(),
                 Err(FinishError::Stopped(reason)) => {
@@ -299,19 +380,27 @@ where
     conn: ConnectionRef<S>,
     stream: StreamId,
     is_0rtt: bool,
+    /// Whether 0-RTT data was seen on the stream, remembered past the stream's end
+    received_0rtt: bool,
     all_data_read: bool,
+    /// Shared with the sending half of a bidirectional stream, allocated on first use
+    extensions: OnceCell<Arc<Mutex<Extensions>>>,
+    span: Span,
 }
 
 impl<S> RecvStream<S>
 where
     S: proto::crypto::Session,
 {
-    pub(crate) fn new(conn: ConnectionRef<S>, stream: StreamId, is_0rtt: bool) -> Self {
+    pub(crate) fn new(conn: ConnectionRef<S>, stream: StreamId, is_0rtt: bool, span: Span) -> Self {
         Self {
             conn,
             stream,
             is_0rtt,
+            received_0rtt: false,
             all_data_read: false,
+            extensions: OnceCell::new(),
+            span,
         }
     }
 
@@ -437,16 +526,28 @@ where
     /// Discards unread data and notifies the peer to stop transmitting. Once stopped, further
     /// attempts to operate on a stream will yield `UnknownStream` errors.
     pub fn stop(&mut self, error_code: VarInt) -> Result<(), UnknownStream> {
+        let _guard = self.span.enter();
         let mut conn = self.conn.lock().unwrap();
-        if self.is_0rtt && conn.check_0rtt().is_err() {
+        if self.is_0rtt && conn.check_0rtt(self.stream).is_err() {
             return Ok(());
         }
         conn.inner.stop(self.stream, error_code)?;
+        if let Some(ref mut replay) = conn.replay {
+            replay.stopped(self.stream, error_code);
+        }
         conn.wake();
         self.all_data_read = true;
         Ok(())
     }
 
+    /// Statistics about data buffered for reassembly on this stream
+    ///
+    /// Fails once the stream has been fully read, stopped, or reset.
+    pub fn stats(&self) -> Result<RecvStreamStats, UnknownStream> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.inner.recv_stream_stats(self.stream)?)
+    }
+
     /// Check if this stream has been opened during 0-RTT.
     ///
     /// In which case any non-idempotent request should be considered dangerous at the application
@@ -455,11 +556,48 @@ where
         self.is_0rtt
     }
 
+    /// Whether any of the data received on this stream so far arrived in 0-RTT packets
+    ///
+    /// Servers can read such data as soon as the stream is accepted from a connection obtained
+    /// with [`Connecting::into_0rtt()`], before the handshake completes. It may have been replayed
+    /// by an attacker, so any request it carries should only be acted upon if it's idempotent.
+    ///
+    /// [`Connecting::into_0rtt()`]: crate::generic::Connecting::into_0rtt
+    pub fn received_0rtt(&self) -> bool {
+        self.received_0rtt
+            || self
+                .conn
+                .lock()
+                .unwrap()
+                .inner
+                .received_0rtt(self.stream)
+                .unwrap_or(false)
+    }
+
     /// Get the identity of this stream
     pub fn id(&self) -> StreamId {
         self.stream
     }
 
+    /// Application state attached to this stream
+    ///
+    /// Both halves of a bidirectional stream share the same extensions, which are allocated when
+    /// first used and dropped along with the last of them. The stream's other methods may be used
+    /// while the lock is held, but it must not be held across an `.await`.
+    pub fn extensions(&self) -> MutexGuard<'_, Extensions> {
+        self.extensions
+            .get_or_init(|| self.conn.stream_extensions(self.stream))
+            .lock()
+            .unwrap()
+    }
+
+    /// Application state attached to the connection this stream belongs to
+    ///
+    /// See [`Connection::extensions()`](crate::generic::Connection::extensions).
+    pub fn connection_extensions(&self) -> MutexGuard<'_, Extensions> {
+        self.conn.extensions()
+    }
+
     fn poll_read_generic<T, U>(
         &mut self,
         cx: &mut Context,
@@ -472,9 +610,15 @@ where
         ) -> Result<Option<U>, proto::ReadError>,
     {
         use proto::ReadError::*;
+        let _guard = self.span.enter();
         let mut conn = self.conn.lock().unwrap();
         if self.is_0rtt {
-            conn.check_0rtt().map_err(|()| ReadError::ZeroRttRejected)?;
+            conn.check_0rtt(self.stream)
+                .map_err(|()| ReadError::ZeroRttRejected)?;
+        }
+        // The stream is forgotten once all its data is read, so note this while we still can
+        if let Ok(true) = conn.inner.received_0rtt(self.stream) {
+            self.received_0rtt = true;
         }
         match read_fn(&mut conn, self.stream) {
             Ok(Some(u)) => {
@@ -600,13 +744,18 @@ where
     S: proto::crypto::Session,
 {
     fn drop(&mut self) {
+        let _guard = self.span.enter();
         let mut conn = self.conn.lock().unwrap();
-        if conn.error.is_some() || (self.is_0rtt && conn.check_0rtt().is_err()) {
+        conn.release_stream_extensions(self.stream);
+        if conn.error.is_some() || (self.is_0rtt && conn.check_0rtt(self.stream).is_err()) {
             return;
         }
         if !self.all_data_read {
             // Ignore UnknownStream errors
             let _ = conn.inner.stop(self.stream, 0u32.into());
+            if let Some(ref mut replay) = conn.replay {
+                replay.stopped(self.stream, 0u32.into());
+            }
             conn.wake();
         }
     }
@@ -642,6 +791,24 @@ pub enum ReadError {
     ZeroRttRejected,
 }
 
+impl ReadError {
+    /// The application error code the peer reset the stream with
+    pub fn error_code(&self) -> Option<VarInt> {
+        match *self {
+            ReadError::Reset(code) => Some(code),
+            _ => None,
+        }
+    }
+
+    /// The reason the connection was lost, if that's why the read failed
+    pub fn connection_error(&self) -> Option<&ConnectionError> {
+        match *self {
+            ReadError::ConnectionClosed(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 impl From<ReadError> for io::Error {
     fn from(x: ReadError) -> Self {
         use self::ReadError::*;
@@ -697,6 +864,34 @@ pub enum StoppedError {
     ZeroRttRejected,
 }
 
+impl WriteError {
+    /// The application error code the peer stopped the stream with
+    pub fn error_code(&self) -> Option<VarInt> {
+        match *self {
+            WriteError::Stopped(code) => Some(code),
+            _ => None,
+        }
+    }
+
+    /// The reason the connection was lost, if that's why the write failed
+    pub fn connection_error(&self) -> Option<&ConnectionError> {
+        match *self {
+            WriteError::ConnectionClosed(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl StoppedError {
+    /// The reason the connection was lost, if that's why monitoring failed
+    pub fn connection_error(&self) -> Option<&ConnectionError> {
+        match *self {
+            StoppedError::ConnectionClosed(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 impl From<WriteError> for io::Error {
     fn from(x: WriteError) -> Self {
         use self::WriteError::*;
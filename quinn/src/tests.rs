@@ -4,10 +4,11 @@ use std::{
     io,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
     str,
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
-use futures::{future, StreamExt};
+use bytes::Bytes;
+use futures::{future, FutureExt, StreamExt};
 use tokio::{
     runtime::{Builder, Runtime},
     time::{Duration, Instant},
@@ -16,8 +17,15 @@ use tracing::{info, info_span};
 use tracing_futures::Instrument as _;
 
 use super::{
-    ClientConfigBuilder, Endpoint, Incoming, NewConnection, RecvStream, SendStream,
-    ServerConfigBuilder,
+    masque,
+    memory::MemoryNetwork,
+    pcap::PcapWriter,
+    recv_pool::RecvPool,
+    transmit_queue::TransmitQueue,
+    webtransport::{self, DatagramRouter, SessionId, StreamHeader},
+    AddressMap, ClientConfigBuilder, ConnectionError, ConnectionMetrics, ConnectionPool,
+    DatagramSocket, Endpoint, EndpointMetrics, Incoming, LifecycleEvent, NewConnection, RecvMeta,
+    RecvStream, SendStream, ServerConfigBuilder,
 };
 
 #[test]
@@ -246,6 +254,42 @@ fn endpoint() -> (Endpoint, Incoming) {
     (x, y)
 }
 
+/// Construct a server endpoint listening on `network`, and a client endpoint that trusts it
+fn memory_endpoints(network: &MemoryNetwork) -> (Endpoint, Incoming, Endpoint, SocketAddr) {
+    memory_endpoints_with_protocols(network, &[])
+}
+
+/// Like `memory_endpoints`, with a server negotiating one of `protocols`
+fn memory_endpoints_with_protocols(
+    network: &MemoryNetwork,
+    protocols: &[&[u8]],
+) -> (Endpoint, Incoming, Endpoint, SocketAddr) {
+    let mut server_config = ServerConfigBuilder::default();
+    server_config.protocols(protocols);
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let key = crate::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+    let cert = crate::Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+    let cert_chain = crate::CertificateChain::from_certs(vec![cert.clone()]);
+    server_config.certificate(cert_chain, key).unwrap();
+    let mut server = Endpoint::builder();
+    server.listen(server_config.build());
+    let server_sock = network
+        .bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4433))
+        .unwrap();
+    let server_addr = server_sock.local_addr();
+    let (server, incoming) = server.with_datagram_socket(server_sock).unwrap();
+
+    let mut client_config = ClientConfigBuilder::default();
+    client_config.add_certificate_authority(cert).unwrap();
+    let mut client = Endpoint::builder();
+    client.default_client_config(client_config.build());
+    let client_sock = network
+        .bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+        .unwrap();
+    let (client, _) = client.with_datagram_socket(client_sock).unwrap();
+    (server, incoming, client, server_addr)
+}
+
 #[tokio::test]
 async fn zero_rtt() {
     let _guard = subscribe();
@@ -257,7 +301,12 @@ async fn zero_rtt() {
             mut uni_streams,
             connection,
             ..
-        } = incoming.into_0rtt().unwrap_or_else(|_| unreachable!()).0;
+        } = incoming
+            .accept()
+            .unwrap()
+            .into_0rtt()
+            .unwrap_or_else(|_| unreachable!())
+            .0;
         tokio::spawn(async move {
             while let Some(Ok(x)) = uni_streams.next().await {
                 let msg = x.read_to_end(usize::max_value()).await.unwrap();
@@ -436,6 +485,1252 @@ fn run_echo(client_addr: SocketAddr, server_addr: SocketAddr) {
     runtime.block_on(handle).unwrap();
 }
 
+#[tokio::test]
+async fn memory_unspecified_and_overflow() {
+    let _guard = subscribe();
+    let network = MemoryNetwork::new();
+    let receiver = network
+        .bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 4433))
+        .unwrap();
+    let sender = network
+        .bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+        .unwrap();
+    let destination = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4433);
+
+    // A wildcard bind receives datagrams for any address on its port, IPv4 ones included
+    let transmit = |contents: Vec<u8>, segment_size| crate::Transmit {
+        destination,
+        ecn: None,
+        contents,
+        segment_size,
+        src_ip: None,
+    };
+    future::poll_fn(|cx| sender.poll_send(cx, &[transmit(b"hello".to_vec(), None)]))
+        .await
+        .unwrap();
+    let mut buf = [0; 16];
+    let mut metas = [RecvMeta::default(); 1];
+    let len = future::poll_fn(|cx| {
+        receiver.poll_recv(cx, &mut [io::IoSliceMut::new(&mut buf)], &mut metas)
+    })
+    .await
+    .unwrap();
+    assert_eq!(len, 1);
+    assert_eq!(&buf[..metas[0].len], b"hello");
+    assert_eq!(metas[0].dst_ip, Some(destination.ip()));
+
+    // Datagrams beyond what the receiver has queued are dropped
+    let excess = 10;
+    let count = crate::memory::MAILBOX_CAPACITY + excess;
+    future::poll_fn(|cx| sender.poll_send(cx, &[transmit(vec![0; count], Some(1))]))
+        .await
+        .unwrap();
+    assert_eq!(network.datagrams_dropped(), excess as u64);
+}
+
+#[tokio::test(start_paused = true)]
+async fn echo_memory() {
+    let _guard = subscribe();
+    let network = MemoryNetwork::new();
+
+    let mut server_config = ServerConfigBuilder::default();
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let key = crate::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+    let cert = crate::Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+    let cert_chain = crate::CertificateChain::from_certs(vec![cert.clone()]);
+    server_config.certificate(cert_chain, key).unwrap();
+    let mut server = Endpoint::builder();
+    server.listen(server_config.build());
+    let server_sock = network
+        .bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4433))
+        .unwrap();
+    let server_addr = server_sock.local_addr();
+    let (server, mut server_incoming) = server.with_datagram_socket(server_sock).unwrap();
+
+    let mut client_config = ClientConfigBuilder::default();
+    client_config.add_certificate_authority(cert).unwrap();
+    let mut client = Endpoint::builder();
+    client.default_client_config(client_config.build());
+    let client_sock = network
+        .bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0))
+        .unwrap();
+    let (client, _) = client.with_datagram_socket(client_sock).unwrap();
+
+    let handle = tokio::spawn(async move {
+        let new_conn = server_incoming.next().await.unwrap().await.unwrap();
+        tokio::spawn(
+            new_conn
+                .bi_streams
+                .take_while(|x| future::ready(x.is_ok()))
+                .for_each(|s| echo(s.unwrap())),
+        );
+        server.wait_idle().await;
+    });
+
+    let connecting = client.connect(&server_addr, "localhost").unwrap();
+    let lifecycle = connecting.lifecycle_events();
+    let new_conn = connecting.await.expect("connect");
+    let (mut send, recv) = new_conn.connection.open_bi().await.expect("stream open");
+    send.write_all(b"foo").await.expect("write");
+    send.finish().await.expect("finish");
+    let data = recv.read_to_end(usize::max_value()).await.expect("read");
+    assert_eq!(&data[..], b"foo");
+    new_conn.connection.close(0u32.into(), b"done");
+    let events = lifecycle.collect::<Vec<_>>().await;
+    assert_eq!(events.len(), 2, "{:?}", events);
+    assert!(matches!(events[0], LifecycleEvent::HandshakeCompleted));
+    assert!(matches!(
+        events[1],
+        LifecycleEvent::Closed {
+            reason: ConnectionError::LocallyClosed
+        }
+    ));
+    client.wait_idle().await;
+    handle.await.unwrap();
+    assert!(network.datagrams_delivered() > 0);
+
+    let stats = client.stats();
+    assert_eq!(stats.handshaking, 0);
+    assert_eq!(stats.accepted_connections, 0);
+
+    let metrics = client.metrics();
+    assert_eq!(metrics.handshakes, 1);
+    assert_eq!(metrics.failed_handshakes, 0);
+    assert!(metrics.udp_tx_datagrams > 0);
+    assert!(metrics.udp_rx_bytes > 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn pcap_capture() {
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let _guard = subscribe();
+    let network = MemoryNetwork::new();
+    let file = Shared::default();
+    let capture = PcapWriter::new(file.clone()).unwrap();
+
+    let mut server_config = ServerConfigBuilder::default();
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let key = crate::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+    let cert = crate::Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+    let cert_chain = crate::CertificateChain::from_certs(vec![cert.clone()]);
+    server_config.certificate(cert_chain, key).unwrap();
+    let mut server = Endpoint::builder();
+    server.listen(server_config.build());
+    let server_sock = network
+        .bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4433))
+        .unwrap();
+    let server_addr = server_sock.local_addr();
+    let (server, mut server_incoming) = server.with_datagram_socket(server_sock).unwrap();
+
+    let mut client_config = ClientConfigBuilder::default();
+    client_config
+        .add_certificate_authority(cert)
+        .unwrap()
+        .key_log(Arc::new(capture.clone()));
+    let mut client = Endpoint::builder();
+    client
+        .default_client_config(client_config.build())
+        .capture(capture);
+    let client_sock = network
+        .bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0))
+        .unwrap();
+    let (client, _) = client.with_datagram_socket(client_sock).unwrap();
+
+    let handle = tokio::spawn(async move {
+        let mut new_conn = server_incoming.next().await.unwrap().await.unwrap();
+        // Ends once the client closes the connection
+        assert!(new_conn.uni_streams.next().await.unwrap().is_err());
+        server.wait_idle().await;
+    });
+    let new_conn = client
+        .connect(&server_addr, "localhost")
+        .unwrap()
+        .await
+        .expect("connect");
+    new_conn.connection.close(0u32.into(), b"done");
+    client.wait_idle().await;
+    handle.await.unwrap();
+
+    let data = file.0.lock().unwrap().clone();
+    let mut blocks = Vec::new();
+    let mut rest = &data[..];
+    while !rest.is_empty() {
+        let ty = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+        let len = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+        assert_eq!(len % 4, 0);
+        assert_eq!(&rest[len - 4..len], &rest[4..8], "trailing length mismatch");
+        blocks.push((ty, &rest[8..len - 4]));
+        rest = &rest[len..];
+    }
+    assert_eq!(blocks[0].0, 0x0A0D_0D0A);
+    assert_eq!(blocks[1].0, 0x0000_0001);
+
+    let secrets = blocks
+        .iter()
+        .filter(|(ty, _)| *ty == 0x0000_000A)
+        .map(|(_, body)| {
+            let len = u32::from_le_bytes([body[4], body[5], body[6], body[7]]) as usize;
+            str::from_utf8(&body[8..8 + len]).unwrap()
+        })
+        .collect::<String>();
+    assert!(secrets.contains("CLIENT_HANDSHAKE_TRAFFIC_SECRET "));
+    assert!(secrets.contains("CLIENT_TRAFFIC_SECRET_0 "));
+
+    let (mut sent, mut received) = (0, 0);
+    for (_, body) in blocks.iter().filter(|(ty, _)| *ty == 0x0000_0006) {
+        let len = u32::from_le_bytes([body[12], body[13], body[14], body[15]]) as usize;
+        let packet = &body[20..20 + len];
+        // The IPv6 client sends to the IPv4 server through a mapped address
+        assert_eq!(packet[0] >> 4, 6);
+        let udp = &packet[40..];
+        assert_eq!(u16::from_be_bytes([udp[4], udp[5]]) as usize, udp.len());
+        match (
+            u16::from_be_bytes([udp[0], udp[1]]),
+            u16::from_be_bytes([udp[2], udp[3]]),
+        ) {
+            (_, 4433) => sent += 1,
+            (4433, _) => received += 1,
+            x => panic!("unexpected ports {:?}", x),
+        }
+    }
+    assert!(sent > 0 && received > 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn connection_labels() {
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let log = Shared::default();
+    let writer = log.clone();
+    let sub = tracing_subscriber::FmtSubscriber::builder()
+        .with_max_level(tracing::Level::TRACE)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(sub);
+
+    let network = MemoryNetwork::new();
+    let mut server_config = ServerConfigBuilder::default();
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let key = crate::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+    let cert = crate::Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+    let cert_chain = crate::CertificateChain::from_certs(vec![cert.clone()]);
+    server_config.certificate(cert_chain, key).unwrap();
+    let mut server = Endpoint::builder();
+    server.listen(server_config.build());
+    let server_sock = network
+        .bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4433))
+        .unwrap();
+    let server_addr = server_sock.local_addr();
+    let (server, mut server_incoming) = server.with_datagram_socket(server_sock).unwrap();
+
+    let mut client_config = ClientConfigBuilder::default();
+    client_config.add_certificate_authority(cert).unwrap();
+    let client_sock = network
+        .bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+        .unwrap();
+    let (client, _) = Endpoint::builder()
+        .with_datagram_socket(client_sock)
+        .unwrap();
+
+    let handle = tokio::spawn(async move {
+        let connecting = server_incoming
+            .next()
+            .await
+            .unwrap()
+            .accept_labeled(None, "server-7")
+            .unwrap();
+        assert_eq!(connecting.label().as_deref(), Some("server-7"));
+        let mut new_conn = connecting.await.unwrap();
+        assert_eq!(new_conn.connection.label().as_deref(), Some("server-7"));
+        assert!(new_conn.uni_streams.next().await.unwrap().is_err());
+        server.wait_idle().await;
+    });
+    let new_conn = client
+        .connect_labeled(client_config.build(), &server_addr, "localhost", "client-7")
+        .unwrap()
+        .await
+        .expect("connect");
+    assert_eq!(new_conn.connection.label().as_deref(), Some("client-7"));
+    new_conn.connection.close(0u32.into(), b"done");
+    client.wait_idle().await;
+    handle.await.unwrap();
+
+    let output = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+    assert!(output.contains("label=\"client-7\""), "{}", output);
+    assert!(output.contains("label=\"server-7\""), "{}", output);
+}
+
+#[tokio::test(start_paused = true)]
+async fn shutdown() {
+    let _guard = subscribe();
+    let network = MemoryNetwork::new();
+    let (server, mut incoming, client, server_addr) = memory_endpoints(&network);
+
+    // Connections the application doesn't close are closed at the deadline
+    let first = client.connect(&server_addr, "localhost").unwrap();
+    let server_conn = incoming.next().await.unwrap().await.unwrap();
+    let first = first.await.unwrap();
+    let start = Instant::now();
+    assert_eq!(
+        server
+            .shutdown((start + Duration::from_secs(1)).into_std(), None)
+            .await,
+        1
+    );
+    assert!(Instant::now() >= start + Duration::from_secs(1));
+    assert!(incoming.next().await.is_none());
+    assert!(matches!(
+        first.uni_streams.into_future().await.0,
+        Some(Err(ConnectionError::ApplicationClosed(ref close))) if close.error_code == 0u32.into()
+    ));
+    drop(server_conn);
+
+    // Notified connections needn't wait for the deadline
+    let (server, mut incoming, client, server_addr) = memory_endpoints(&MemoryNetwork::new());
+    let second = client.connect(&server_addr, "localhost").unwrap();
+    let _server_conn = incoming.next().await.unwrap().await.unwrap();
+    let second = second.await.unwrap();
+    let start = Instant::now();
+    assert_eq!(
+        server
+            .shutdown(
+                (start + Duration::from_secs(60)).into_std(),
+                Some((7u32.into(), b"bye"))
+            )
+            .await,
+        0
+    );
+    assert!(Instant::now() < start + Duration::from_secs(60));
+    assert!(matches!(
+        second.uni_streams.into_future().await.0,
+        Some(Err(ConnectionError::ApplicationClosed(ref close)))
+            if close.error_code == 7u32.into() && &close.reason[..] == b"bye"
+    ));
+
+    // New connection attempts are refused
+    let refused = client.connect(&server_addr, "localhost").unwrap();
+    assert!(refused.await.is_err());
+}
+
+#[tokio::test(start_paused = true)]
+async fn wait_idle_timeout() {
+    let _guard = subscribe();
+    let network = MemoryNetwork::new();
+    let (server, mut incoming, client, server_addr) = memory_endpoints(&network);
+
+    let closed = client.connect(&server_addr, "localhost").unwrap();
+    let _closed_server = incoming.next().await.unwrap().await.unwrap();
+    let closed = closed.await.unwrap();
+    let kept = client.connect(&server_addr, "localhost").unwrap();
+    let _kept_server = incoming.next().await.unwrap().await.unwrap();
+    let kept = kept.await.unwrap();
+
+    closed.connection.close(0u32.into(), b"done");
+    let start = Instant::now();
+    let undrained = client.wait_idle_timeout(Duration::from_secs(5)).await;
+    assert!(Instant::now() >= start + Duration::from_secs(5));
+    assert_eq!(undrained.len(), 1);
+    assert_eq!(undrained[0].stable_id(), kept.connection.stable_id());
+    assert_eq!(undrained[0].remote_address(), server_addr);
+
+    kept.connection.close(0u32.into(), b"done");
+    assert!(client
+        .wait_idle_timeout(Duration::from_secs(5))
+        .await
+        .is_empty());
+    drop(server);
+}
+
+#[tokio::test(start_paused = true)]
+async fn close_after_flush() {
+    let _guard = subscribe();
+    let network = MemoryNetwork::new();
+    let (_server, mut incoming, client, server_addr) = memory_endpoints(&network);
+
+    const RESPONSE: &[u8] = &[0xab; 256 * 1024];
+    let handle = tokio::spawn(async move {
+        let new_conn = incoming.next().await.unwrap().await.unwrap();
+        let mut send = new_conn.connection.open_uni().await.unwrap();
+        send.write_all(RESPONSE).await.unwrap();
+        // Finishes the stream without waiting for it to be acknowledged
+        drop(send);
+        new_conn.connection.close_after_flush(7u32.into(), b"done");
+        new_conn
+    });
+    let mut new_conn = client
+        .connect(&server_addr, "localhost")
+        .unwrap()
+        .await
+        .unwrap();
+    let recv = new_conn.uni_streams.next().await.unwrap().unwrap();
+    let data = recv.read_to_end(usize::max_value()).await.unwrap();
+    assert_eq!(data.len(), RESPONSE.len());
+    assert!(matches!(
+        new_conn.uni_streams.next().await,
+        Some(Err(ConnectionError::ApplicationClosed(ref close))) if close.error_code == 7u32.into()
+    ));
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn incoming_triage() {
+    let _guard = subscribe();
+    let network = MemoryNetwork::new();
+    let (_server, mut incoming, client, server_addr) = memory_endpoints(&network);
+
+    let connecting = client.connect(&server_addr, "localhost").unwrap();
+    let attempt = incoming.next().await.unwrap();
+    assert_eq!(attempt.remote_address().ip(), Ipv4Addr::LOCALHOST);
+    assert!(!attempt.remote_address_validated());
+    assert_eq!(attempt.server_name(), Some("localhost"));
+    assert_eq!(attempt.alpn_protocols(), None);
+    attempt.retry().unwrap();
+
+    let attempt = incoming.next().await.unwrap();
+    assert!(attempt.remote_address_validated());
+    let attempt = attempt.retry().unwrap_err().into_incoming();
+    let _server_conn = attempt.accept().unwrap().await.unwrap();
+    let _client_conn = connecting.await.unwrap();
+
+    let refused = client.connect(&server_addr, "localhost").unwrap();
+    incoming.next().await.unwrap().refuse();
+    assert!(matches!(
+        refused.await,
+        Err(ConnectionError::ConnectionClosed(ref close))
+            if close.error_code == proto::TransportErrorCode::CONNECTION_REFUSED
+    ));
+}
+
+#[tokio::test]
+async fn accept_with_transport() {
+    let _guard = subscribe();
+    let network = MemoryNetwork::new();
+    let (_server, mut incoming, client, server_addr) = memory_endpoints(&network);
+
+    let connecting = client.connect(&server_addr, "localhost").unwrap();
+    let attempt = incoming.next().await.unwrap();
+    let mut transport = crate::TransportConfig::default();
+    transport.max_concurrent_uni_streams(1).unwrap();
+    let _server_conn = attempt
+        .accept_with(Arc::new(transport))
+        .unwrap()
+        .await
+        .unwrap();
+    let client_conn = connecting.await.unwrap();
+    let _send = client_conn.connection.open_uni().await.unwrap();
+    assert!(client_conn.connection.open_uni().now_or_never().is_none());
+}
+
+#[tokio::test]
+async fn runtime_stream_limit() {
+    let _guard = subscribe();
+    let network = MemoryNetwork::new();
+    let (_server, mut incoming, client, server_addr) = memory_endpoints(&network);
+
+    let connecting = client.connect(&server_addr, "localhost").unwrap();
+    let mut transport = crate::TransportConfig::default();
+    transport.max_concurrent_uni_streams(0).unwrap();
+    let server_conn = incoming
+        .next()
+        .await
+        .unwrap()
+        .accept_with(Arc::new(transport))
+        .unwrap()
+        .await
+        .unwrap();
+    let client_conn = connecting.await.unwrap();
+    assert!(client_conn.connection.open_uni().now_or_never().is_none());
+    server_conn
+        .connection
+        .set_max_concurrent_uni_streams(1u32.into());
+    let _send = client_conn.connection.open_uni().await.unwrap();
+}
+
+#[tokio::test]
+async fn connection_pool() {
+    let _guard = subscribe();
+    let network = MemoryNetwork::new();
+    let (_server, mut incoming, client, server_addr) =
+        memory_endpoints_with_protocols(&network, &[b"test", b"other"]);
+    let accepted = Arc::new(Mutex::new(Vec::new()));
+    tokio::spawn({
+        let accepted = accepted.clone();
+        async move {
+            while let Some(connecting) = incoming.next().await {
+                let new_conn = connecting.await.unwrap();
+                accepted.lock().unwrap().push(new_conn);
+            }
+        }
+    });
+
+    let offering = |protocols: &[&[u8]]| {
+        let mut config = client.default_client_config.clone();
+        Arc::make_mut(&mut config.crypto).alpn_protocols =
+            protocols.iter().map(|x| x.to_vec()).collect();
+        config
+    };
+    let (test, other) = (offering(&[b"test"]), offering(&[b"other"]));
+    let pool = ConnectionPool::new(client.clone(), Duration::from_millis(100));
+    let (a, b) = future::join(
+        pool.get(test.clone(), b"test", &server_addr, "localhost"),
+        pool.get(test.clone(), b"test", &server_addr, "localhost"),
+    )
+    .await;
+    let (a, b) = (a.unwrap(), b.unwrap());
+    assert_eq!(a.stable_id(), b.stable_id());
+
+    // The server prefers "test", which mustn't be cached as "other"
+    assert!(matches!(
+        pool.get(
+            offering(&[b"test", b"other"]),
+            b"other",
+            &server_addr,
+            "localhost"
+        )
+        .await,
+        Err(crate::PoolError::ProtocolMismatch)
+    ));
+    let c = pool
+        .get(other, b"other", &server_addr, "localhost")
+        .await
+        .unwrap();
+    assert_ne!(a.stable_id(), c.stable_id());
+
+    // A connection in use by tasks it was handed out to isn't evicted
+    for _ in 0..10 {
+        tokio::time::sleep(Duration::from_millis(25)).await;
+        let mut send = a.open_uni().await.unwrap();
+        send.write_all(b"busy").await.unwrap();
+        send.finish().await.unwrap();
+    }
+    let d = pool
+        .get(test.clone(), b"test", &server_addr, "localhost")
+        .await
+        .unwrap();
+    assert_eq!(a.stable_id(), d.stable_id());
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(accepted.lock().unwrap().len(), 3);
+    let e = pool
+        .get(test, b"test", &server_addr, "localhost")
+        .await
+        .unwrap();
+    assert_ne!(a.stable_id(), e.stable_id());
+}
+
+#[tokio::test]
+async fn copy_bidirectional() {
+    use crate::{CopyError, ReadError, ReadToEndError};
+    use tokio::io::AsyncWriteExt;
+
+    let _guard = subscribe();
+    let network = MemoryNetwork::new();
+    let (_server, mut incoming, client, server_addr) = memory_endpoints(&network);
+    let connecting = client.connect(&server_addr, "localhost").unwrap();
+    let server_conn = incoming.next().await.unwrap().await.unwrap();
+    let mut bi_streams = server_conn.bi_streams;
+    // Each stream is bridged to an in-memory pipe whose far end echoes
+    let server = tokio::spawn(async move {
+        let mut results = Vec::new();
+        while let Some(Ok((mut send, mut recv))) = bi_streams.next().await {
+            let (mut near, far) = tokio::io::duplex(64);
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = tokio::io::split(far);
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                let _ = writer.shutdown().await;
+            });
+            results.push(
+                crate::copy_bidirectional(&mut near, &mut send, &mut recv, 7u32.into()).await,
+            );
+            if results.len() == 2 {
+                break;
+            }
+        }
+        results
+    });
+    let client_conn = connecting.await.unwrap();
+
+    const MSG: &[u8] = &[0xab; 1000];
+    let (mut send, recv) = client_conn.connection.open_bi().await.unwrap();
+    send.write_all(MSG).await.unwrap();
+    send.finish().await.unwrap();
+    assert_eq!(&recv.read_to_end(usize::MAX).await.unwrap()[..], MSG);
+
+    let (mut send, recv) = client_conn.connection.open_bi().await.unwrap();
+    send.write_all(MSG).await.unwrap();
+    send.reset(3u32.into()).unwrap();
+    assert!(matches!(
+        recv.read_to_end(usize::MAX).await,
+        Err(ReadToEndError::Read(ReadError::Reset(code))) if code == 7u32.into()
+    ));
+
+    let results = server.await.unwrap();
+    assert_eq!(
+        results[0].as_ref().unwrap(),
+        &(MSG.len() as u64, MSG.len() as u64)
+    );
+    assert!(matches!(
+        results[1],
+        Err(CopyError::Read(ReadError::Reset(code))) if code == 3u32.into()
+    ));
+}
+
+#[tokio::test]
+async fn stream_extensions() {
+    #[derive(Debug, PartialEq)]
+    struct Tag(&'static str);
+
+    let _guard = subscribe();
+    let network = MemoryNetwork::new();
+    let (_server, mut incoming, client, server_addr) = memory_endpoints(&network);
+    let connecting = client.connect(&server_addr, "localhost").unwrap();
+    let mut server_conn = incoming.next().await.unwrap().await.unwrap();
+    let client_conn = connecting.await.unwrap();
+
+    let (mut send, recv) = client_conn.connection.open_bi().await.unwrap();
+    assert_eq!(send.extensions().insert(Tag("client")), None);
+    assert_eq!(recv.extensions().get::<Tag>(), Some(&Tag("client")));
+    send.write_all(b"hi").await.unwrap();
+
+    let uni = client_conn.connection.open_uni().await.unwrap();
+    assert_eq!(uni.extensions().get::<Tag>(), None);
+
+    let (send, recv) = server_conn.bi_streams.next().await.unwrap().unwrap();
+    assert_eq!(recv.extensions().get::<Tag>(), None);
+    recv.extensions().insert(Tag("server"));
+    assert_eq!(
+        send.extensions().insert(Tag("replaced")),
+        Some(Tag("server"))
+    );
+    assert_eq!(recv.extensions().remove::<Tag>(), Some(Tag("replaced")));
+    assert!(send.extensions().get::<Tag>().is_none());
+    send.extensions().insert(Tag("kept"));
+    drop(send);
+    assert_eq!(recv.extensions().get::<Tag>(), Some(&Tag("kept")));
+
+    // Values outlive the half they were attached through, even if the other never used them
+    let (send, recv) = client_conn.connection.open_bi().await.unwrap();
+    send.extensions().insert(Tag("finished"));
+    drop(send);
+    assert_eq!(recv.extensions().get::<Tag>(), Some(&Tag("finished")));
+}
+
+#[tokio::test]
+async fn connection_extensions() {
+    #[derive(Debug, PartialEq)]
+    struct User(&'static str);
+
+    let _guard = subscribe();
+    let network = MemoryNetwork::new();
+    let (_server, mut incoming, client, server_addr) = memory_endpoints(&network);
+    let client_connecting = client.connect(&server_addr, "localhost").unwrap();
+    client_connecting.extensions().insert(User("client"));
+    let server_connecting = incoming.next().await.unwrap().accept().unwrap();
+    server_connecting.extensions().insert(User("alice"));
+    let mut server_conn = server_connecting.await.unwrap();
+    let client_conn = client_connecting.await.unwrap();
+    assert_eq!(
+        client_conn.connection.extensions().get::<User>(),
+        Some(&User("client"))
+    );
+
+    let mut send = client_conn.connection.open_uni().await.unwrap();
+    assert_eq!(
+        send.connection_extensions().get::<User>(),
+        Some(&User("client"))
+    );
+    send.write_all(b"hi").await.unwrap();
+    send.finish().await.unwrap();
+
+    let recv = server_conn.uni_streams.next().await.unwrap().unwrap();
+    assert_eq!(
+        recv.connection_extensions().get::<User>(),
+        Some(&User("alice"))
+    );
+    // Every handle sees changes made through any other
+    recv.connection_extensions().insert(User("bob"));
+    assert_eq!(
+        server_conn.connection.extensions().get::<User>(),
+        Some(&User("bob"))
+    );
+}
+
+#[tokio::test]
+async fn path_info() {
+    use crate::AddressValidation;
+
+    let _guard = subscribe();
+    let network = MemoryNetwork::new();
+    let (_server, mut incoming, client, server_addr) = memory_endpoints(&network);
+    let connecting = client.connect(&server_addr, "localhost").unwrap();
+    let server_conn = incoming.next().await.unwrap().await.unwrap();
+    let client_conn = connecting.await.unwrap();
+
+    let info = client_conn.connection.path_info();
+    assert_eq!(info.remote, server_addr);
+    assert_eq!(info.address_validation, AddressValidation::Implicit);
+    assert_eq!(info.migrations, 0);
+    let info = server_conn.connection.path_info();
+    assert_eq!(info.remote, client.local_addr().unwrap());
+    assert_eq!(info.address_validation, AddressValidation::Implicit);
+    assert!(info.mtu >= 1200);
+}
+
+/// Session store that reports each session ticket put into it
+struct TicketSignal {
+    cache: Arc<rustls::ClientSessionMemoryCache>,
+    tickets: futures::channel::mpsc::UnboundedSender<()>,
+}
+
+impl rustls::StoresClientSessions for TicketSignal {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        // Key exchange hints are stored too, under a different prefix
+        if key.starts_with(b"session") {
+            let _ = self.tickets.unbounded_send(());
+        }
+        self.cache.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.cache.get(key)
+    }
+}
+
+#[tokio::test]
+async fn zero_rtt_replay() {
+    use crate::{WriteError, ZeroRttResult};
+
+    let _guard = subscribe();
+    let network = MemoryNetwork::new();
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let key = crate::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+    let cert = crate::Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+
+    // Two servers with the same certificate but separate session tickets, so that each rejects
+    // 0-RTT with tickets issued by the other
+    let (received_send, mut received) = futures::channel::mpsc::unbounded();
+    let mut server_addrs = Vec::new();
+    for port in &[4433, 4434] {
+        let mut server_config = ServerConfigBuilder::default();
+        let cert_chain = crate::CertificateChain::from_certs(vec![cert.clone()]);
+        server_config.certificate(cert_chain, key.clone()).unwrap();
+        let mut server = Endpoint::builder();
+        server.listen(server_config.build());
+        let sock = network
+            .bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), *port))
+            .unwrap();
+        server_addrs.push(sock.local_addr());
+        let (_, incoming) = server.with_datagram_socket(sock).unwrap();
+        let received_send = received_send.clone();
+        tokio::spawn(incoming.for_each(move |connecting| {
+            let received_send = received_send.clone();
+            async move {
+                let mut new_conn = connecting.await.unwrap();
+                while let Some(Ok(stream)) = new_conn.uni_streams.next().await {
+                    // Streams that weren't replayed are reset
+                    if let Ok(msg) = stream.read_to_end(usize::MAX).await {
+                        received_send.unbounded_send(msg).unwrap();
+                    }
+                }
+            }
+        }));
+    }
+
+    let mut client_config = ClientConfigBuilder::default();
+    client_config.add_certificate_authority(cert).unwrap();
+    let mut client_config = client_config.build();
+    let (tickets_send, mut tickets) = futures::channel::mpsc::unbounded();
+    Arc::make_mut(&mut client_config.crypto).session_persistence = Arc::new(TicketSignal {
+        cache: rustls::ClientSessionMemoryCache::new(32),
+        tickets: tickets_send,
+    });
+    let mut client = Endpoint::builder();
+    client.default_client_config(client_config);
+    let sock = network
+        .bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+        .unwrap();
+    let (client, _) = client.with_datagram_socket(sock).unwrap();
+
+    const MSG: &[u8] = b"idempotent";
+    let mut outcomes = Vec::new();
+    for &server_addr in &[server_addrs[0], server_addrs[1], server_addrs[1]] {
+        let (new_conn, outcome) = client
+            .connect(&server_addr, "localhost")
+            .unwrap()
+            .into_0rtt_with_replay();
+        let lifecycle = new_conn.connection.lifecycle_events();
+        let mut unmarked = new_conn.connection.open_uni().await.unwrap();
+        unmarked.write_all(b"unmarked").await.unwrap();
+        let mut s = new_conn.connection.open_uni().await.unwrap();
+        s.mark_idempotent();
+        s.write_all(MSG).await.unwrap();
+        s.finish().await.unwrap();
+        let unmarked = unmarked.finish().await;
+        outcomes.push(outcome.await);
+        let replayed = outcomes.last() == Some(&ZeroRttResult::Replayed);
+        if replayed {
+            assert_eq!(unmarked, Err(WriteError::ZeroRttRejected));
+        } else {
+            unmarked.unwrap();
+            assert_eq!(received.next().await.unwrap(), b"unmarked");
+        }
+        assert_eq!(received.next().await.unwrap(), MSG);
+        if outcomes.len() < 3 {
+            // The next connection relies on the server's NewSessionTicket
+            tickets.next().await.unwrap();
+        }
+        new_conn.connection.close(0u32.into(), b"done");
+        let rejected = lifecycle
+            .any(|x| future::ready(matches!(x, LifecycleEvent::ZeroRttRejected)))
+            .await;
+        assert_eq!(rejected, replayed);
+    }
+    assert_eq!(
+        outcomes,
+        [
+            ZeroRttResult::NotAttempted,
+            ZeroRttResult::Replayed,
+            ZeroRttResult::Accepted
+        ]
+    );
+}
+
+#[tokio::test]
+async fn webtransport_session() {
+    let _guard = subscribe();
+    let network = MemoryNetwork::new();
+    let (_server, mut incoming, client, server_addr) = memory_endpoints(&network);
+
+    let connecting = client.connect(&server_addr, "localhost").unwrap();
+    let server_conn = incoming.next().await.unwrap().await.unwrap();
+    let client_conn = connecting.await.unwrap();
+    // Stands in for the stream carrying the CONNECT request
+    let session = SessionId::from(crate::StreamId(0));
+    let client_session = webtransport::Session::new(client_conn.connection, session);
+
+    let (mut send, _) = client_session.open_bi().await.unwrap();
+    send.write_all(b"hello").await.unwrap();
+    let NewConnection {
+        mut bi_streams,
+        datagrams,
+        ..
+    } = server_conn;
+    let (_, mut recv) = bi_streams.next().await.unwrap().unwrap();
+    assert_eq!(
+        webtransport::read_bi_header(&mut recv).await.unwrap(),
+        StreamHeader::Session(session)
+    );
+    let mut buf = [0; 5];
+    recv.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+
+    let mut router = DatagramRouter::new();
+    let mut session_datagrams = router.register(session, 4);
+    client_session.send_datagram(b"ping").unwrap();
+    let datagram = datagrams.into_future().await.0.unwrap().unwrap();
+    assert!(router.route(datagram));
+    assert!(!router.route(Bytes::from_static(&[0x01, 0xff])));
+    assert_eq!(&session_datagrams.next().await.unwrap()[..], b"ping");
+}
+
+#[tokio::test]
+async fn masque_forward() {
+    let _guard = subscribe();
+    let network = MemoryNetwork::new();
+    let (_server, mut incoming, client, server_addr) = memory_endpoints(&network);
+
+    let connecting = client.connect(&server_addr, "localhost").unwrap();
+    let proxy_conn = incoming.next().await.unwrap().await.unwrap();
+    let client_conn = connecting.await.unwrap();
+    // Stands in for the stream carrying the CONNECT-UDP request
+    let request = SessionId::from(crate::StreamId(0));
+
+    let target = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let relay = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    relay.connect(target_addr).await.unwrap();
+    let mut proxy_router = DatagramRouter::new();
+    let proxy_datagrams = proxy_router.register(request, 4);
+    let mut proxy_incoming = proxy_conn.datagrams;
+    tokio::spawn(async move {
+        while let Some(Ok(datagram)) = proxy_incoming.next().await {
+            proxy_router.route(datagram);
+        }
+    });
+    let proxy_session = webtransport::Session::new(proxy_conn.connection, request);
+    tokio::spawn(async move { masque::forward(&proxy_session, proxy_datagrams, &relay).await });
+
+    let mut client_router = DatagramRouter::new();
+    let socket = masque::ProxiedSocket::new(
+        webtransport::Session::new(client_conn.connection, request),
+        client_router.register(request, 4),
+        target_addr,
+    );
+    let transmits = [crate::Transmit {
+        destination: target_addr,
+        ecn: None,
+        contents: b"hello".to_vec(),
+        segment_size: None,
+        src_ip: None,
+    }];
+    future::poll_fn(|cx| socket.poll_send(cx, &transmits))
+        .await
+        .unwrap();
+    let mut buf = [0; 16];
+    let (len, from) = target.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], b"hello");
+    target.send_to(b"world", from).await.unwrap();
+
+    let mut client_incoming = client_conn.datagrams;
+    client_router.route(client_incoming.next().await.unwrap().unwrap());
+    let mut metas = [RecvMeta::default(); 1];
+    let len = future::poll_fn(|cx| {
+        socket.poll_recv(cx, &mut [io::IoSliceMut::new(&mut buf)], &mut metas)
+    })
+    .await
+    .unwrap();
+    assert_eq!(len, 1);
+    assert_eq!(&buf[..metas[0].len], b"world");
+    assert_eq!(metas[0].addr, target_addr);
+
+    // Datagrams the tunnel can't carry fail like those exceeding a UDP socket's path MTU
+    let transmits = [crate::Transmit {
+        destination: target_addr,
+        ecn: None,
+        contents: vec![0; socket.max_payload_size().unwrap() + 1],
+        segment_size: None,
+        src_ip: None,
+    }];
+    let e = future::poll_fn(|cx| socket.poll_send(cx, &transmits))
+        .await
+        .unwrap_err();
+    assert!(crate::platform::is_too_large(&e));
+
+    // A batch stops short of a transmit whose segments are too large, without sending any of them
+    let max = socket.max_payload_size().unwrap();
+    let transmits = [
+        crate::Transmit {
+            destination: target_addr,
+            ecn: None,
+            contents: b"again".to_vec(),
+            segment_size: None,
+            src_ip: None,
+        },
+        crate::Transmit {
+            destination: target_addr,
+            ecn: None,
+            contents: vec![0; 2 * (max + 1)],
+            segment_size: Some(max + 1),
+            src_ip: None,
+        },
+    ];
+    let sent = future::poll_fn(|cx| socket.poll_send(cx, &transmits))
+        .await
+        .unwrap();
+    assert_eq!(sent, 1);
+    let len = target.recv(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], b"again");
+}
+
+#[tokio::test]
+async fn masque_handshake() {
+    let _guard = subscribe();
+    let network = MemoryNetwork::new();
+    let (_proxy, mut incoming, client, proxy_addr) = memory_endpoints(&network);
+
+    let connecting = client.connect(&proxy_addr, "localhost").unwrap();
+    let proxy_conn = incoming.next().await.unwrap().await.unwrap();
+    let client_conn = connecting.await.unwrap();
+    let request = SessionId::from(crate::StreamId(0));
+
+    // Only the proxy reaches the target over loopback, so the tunnelled path mustn't be taken for
+    // a local one
+    let mut transport = crate::TransportConfig::default();
+    transport.local_path_filter(|_| false);
+    let transport = Arc::new(transport);
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let key = crate::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+    let cert = crate::Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+    let mut server_config = ServerConfigBuilder::default();
+    server_config
+        .certificate(crate::CertificateChain::from_certs(vec![cert.clone()]), key)
+        .unwrap();
+    let mut server_config = server_config.build();
+    server_config.transport = transport.clone();
+    let mut target = Endpoint::builder();
+    target.listen(server_config);
+    let target_sock = network
+        .bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4434))
+        .unwrap();
+    let target_addr = target_sock.local_addr();
+    let (_target, mut target_incoming) = target.with_datagram_socket(target_sock).unwrap();
+
+    // Relays between the request's datagrams and the target, as `masque::forward()` would over UDP
+    let relay = network
+        .bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+        .unwrap();
+    let relay_addr = relay.local_addr();
+    let mut proxy_router = DatagramRouter::new();
+    let mut proxy_datagrams = proxy_router.register(request, 16).fuse();
+    let mut proxy_incoming = proxy_conn.datagrams;
+    tokio::spawn(async move {
+        while let Some(Ok(datagram)) = proxy_incoming.next().await {
+            proxy_router.route(datagram);
+        }
+    });
+    let proxy_session = webtransport::Session::new(proxy_conn.connection, request);
+    tokio::spawn(async move {
+        let mut buf = vec![0; 65527];
+        loop {
+            let mut metas = [RecvMeta::default(); 1];
+            futures::select! {
+                datagram = proxy_datagrams.next() => {
+                    let datagram = match datagram {
+                        Some(x) => x,
+                        None => return,
+                    };
+                    let transmit = crate::Transmit {
+                        destination: target_addr,
+                        ecn: None,
+                        // Strip the context ID
+                        contents: datagram[1..].to_vec(),
+                        segment_size: None,
+                        src_ip: None,
+                    };
+                    future::poll_fn(|cx| relay.poll_send(cx, std::slice::from_ref(&transmit)))
+                        .await
+                        .unwrap();
+                }
+                _ = future::poll_fn(|cx| {
+                    relay.poll_recv(cx, &mut [io::IoSliceMut::new(&mut buf)], &mut metas)
+                }).fuse() => {
+                    let mut datagram = vec![0];
+                    datagram.extend_from_slice(&buf[..metas[0].len]);
+                    let _ = proxy_session.send_datagram(&datagram);
+                }
+            }
+        }
+    });
+
+    let mut client_router = DatagramRouter::new();
+    let socket = masque::ProxiedSocket::new(
+        webtransport::Session::new(client_conn.connection, request),
+        client_router.register(request, 16),
+        target_addr,
+    );
+    assert!(socket.max_payload_size().unwrap() >= 1200);
+    let mut client_incoming = client_conn.datagrams;
+    tokio::spawn(async move {
+        while let Some(Ok(datagram)) = client_incoming.next().await {
+            client_router.route(datagram);
+        }
+    });
+
+    let mut client_config = ClientConfigBuilder::default();
+    client_config.add_certificate_authority(cert).unwrap();
+    let mut client_config = client_config.build();
+    client_config.transport = transport;
+    let mut tunnelled = Endpoint::builder();
+    tunnelled.default_client_config(client_config);
+    let (tunnelled, _) = tunnelled.with_datagram_socket(socket).unwrap();
+    let connecting = tunnelled.connect(&target_addr, "localhost").unwrap();
+    let (target_conn, tunnelled_conn) = future::join(
+        async { target_incoming.next().await.unwrap().await.unwrap() },
+        connecting,
+    )
+    .await;
+    let tunnelled_conn = tunnelled_conn.unwrap();
+    assert_eq!(target_conn.connection.remote_address(), relay_addr);
+    let mut send = tunnelled_conn.connection.open_uni().await.unwrap();
+    send.write_all(b"hello").await.unwrap();
+    send.finish().await.unwrap();
+    let mut target_uni = target_conn.uni_streams;
+    let recv = target_uni.next().await.unwrap().unwrap();
+    assert_eq!(&recv.read_to_end(16).await.unwrap()[..], b"hello");
+}
+
+#[cfg(feature = "tower")]
+#[tokio::test]
+async fn tower_service() {
+    use std::{
+        future::Future,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    struct Echo;
+
+    impl tower_service::Service<(SendStream, RecvStream)> for Echo {
+        type Response = ();
+        type Error = anyhow::Error;
+        type Future = Pin<Box<dyn Future<Output = Result<(), anyhow::Error>> + Send>>;
+
+        fn poll_ready(&mut self, _: &mut Context) -> Poll<Result<(), anyhow::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, (mut send, recv): (SendStream, RecvStream)) -> Self::Future {
+            Box::pin(async move {
+                let data = recv.read_to_end(usize::MAX).await?;
+                send.write_all(&data).await?;
+                send.finish().await?;
+                Ok(())
+            })
+        }
+    }
+
+    let _guard = subscribe();
+    let network = MemoryNetwork::new();
+    let (_server, mut incoming, client, server_addr) = memory_endpoints(&network);
+
+    let connecting = client.connect(&server_addr, "localhost").unwrap();
+    let server_conn = incoming.next().await.unwrap().await.unwrap();
+    let serve = tokio::spawn(crate::service::serve_bi_streams(
+        server_conn.bi_streams,
+        Echo,
+    ));
+    let client_conn = connecting.await.unwrap();
+    for request in &[&b"one"[..], b"two"] {
+        let (mut send, recv) = client_conn.connection.open_bi().await.unwrap();
+        send.write_all(request).await.unwrap();
+        send.finish().await.unwrap();
+        assert_eq!(&recv.read_to_end(usize::MAX).await.unwrap()[..], *request);
+    }
+    server_conn.connection.close(0u32.into(), b"");
+    serve.await.unwrap().unwrap();
+}
+
+#[test]
+fn prometheus_metrics() {
+    let metrics = EndpointMetrics {
+        active_connections: 2,
+        ptos: 5,
+        ..EndpointMetrics::default()
+    };
+    let mut out = String::new();
+    metrics.write_prometheus(&mut out, "quinn").unwrap();
+    assert!(out.starts_with(
+        "# HELP quinn_active_connections Connections currently being driven\n\
+         # TYPE quinn_active_connections gauge\n\
+         quinn_active_connections 2\n"
+    ));
+    assert!(out.contains("# TYPE quinn_ptos_total counter\nquinn_ptos_total 5\n"));
+}
+
+#[test]
+fn connection_prometheus_metrics() {
+    let a = ConnectionMetrics {
+        rtt: Duration::from_millis(25),
+        cwnd: 12000,
+        ..ConnectionMetrics::default()
+    };
+    let b = ConnectionMetrics {
+        lost_packets: 3,
+        ..ConnectionMetrics::default()
+    };
+    let mut out = String::new();
+    ConnectionMetrics::write_prometheus(&mut out, "quinn", &[("alice", a), ("\"bob\"", b)])
+        .unwrap();
+    assert!(out.starts_with(
+        "# HELP quinn_connection_rtt_seconds Estimated round-trip time\n\
+         # TYPE quinn_connection_rtt_seconds gauge\n\
+         quinn_connection_rtt_seconds{connection=\"alice\"} 0.025\n\
+         quinn_connection_rtt_seconds{connection=\"\\\"bob\\\"\"} 0\n"
+    ));
+    assert!(out.contains("quinn_connection_cwnd_bytes{connection=\"alice\"} 12000\n"));
+    assert!(out.contains("quinn_connection_lost_packets_total{connection=\"\\\"bob\\\"\"} 3\n"));
+}
+
+#[test]
+fn address_map() {
+    let mut map = AddressMap::new();
+    let a = map.insert("/tmp/a.sock");
+    let b = map.insert("/tmp/b.sock");
+    assert_ne!(a, b);
+    assert_eq!(map.insert("/tmp/a.sock"), a);
+    assert_eq!(map.get(&b), Some(&"/tmp/b.sock"));
+    assert_eq!(map.remove(&"/tmp/a.sock"), Some(a));
+    assert_eq!(map.get(&a), None);
+    assert_ne!(map.insert("/tmp/a.sock"), a);
+    assert_eq!(map.len(), 2);
+}
+
+#[test]
+fn recv_pool_reuse() {
+    let mut pool = RecvPool::new(1500);
+    let ptr = pool.iovs()[0].as_ptr();
+    let data = pool.take(0, 100);
+    assert_eq!(data.len(), 100);
+    assert_eq!(data.capacity(), 1500);
+    assert_eq!(data.as_ptr(), ptr);
+
+    // A block still referenced by a datagram isn't reused
+    let retained = data.freeze().slice(10..20);
+    assert_ne!(pool.iovs()[0].as_ptr(), ptr);
+    pool.take(0, 100);
+
+    // Once released, the next block is reclaimed
+    let ptr = pool.iovs()[0].as_ptr();
+    pool.take(0, 100);
+    assert_eq!(pool.iovs()[0].as_ptr(), ptr);
+    drop(retained);
+}
+
+#[test]
+fn transmit_queue_fairness() {
+    fn transmit(len: usize) -> proto::Transmit {
+        proto::Transmit {
+            destination: "[::1]:4433".parse().unwrap(),
+            ecn: None,
+            contents: vec![0; len],
+            segment_size: None,
+            src_ip: None,
+        }
+    }
+
+    let bulk = proto::ConnectionHandle(0);
+    let interactive = proto::ConnectionHandle(1);
+    let mut queue = TransmitQueue::new();
+    for _ in 0..10 {
+        queue.push(bulk, transmit(1200));
+    }
+    queue.push(interactive, transmit(100));
+    queue.push(interactive, transmit(100));
+
+    // The interactive connection's datagrams aren't stuck behind the bulk backlog
+    let mut order = Vec::new();
+    while let Some(t) = queue.pop() {
+        order.push(t.contents.len());
+    }
+    assert_eq!(order.len(), 12);
+    let first_interactive = order.iter().position(|&len| len == 100).unwrap();
+    assert!(first_interactive <= 1, "{:?}", order);
+    assert_eq!(order[..4].iter().filter(|&&len| len == 100).count(), 2);
+}
+
 async fn echo((mut send, recv): (SendStream, RecvStream)) {
     let data = recv
         .read_to_end(usize::max_value())
@@ -0,0 +1,103 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use proto::{ConnectionHandle, Transmit};
+
+use crate::platform::BATCH_SIZE;
+
+/// Bytes a connection may send per round before other connections get a turn
+///
+/// About one full-sized datagram, so that connections with data ready alternate packet by packet.
+const QUANTUM: usize = 1500;
+
+/// Most emptied batches kept by a [`BatchPool`]
+///
+/// Enough for the connections of a busy endpoint to transmit concurrently, without holding on to
+/// the memory of a past burst.
+const SPARE_BATCHES: usize = 64;
+
+/// Transmits from an endpoint's connections awaiting the socket, scheduled by deficit round robin
+///
+/// Each connection with queued transmits is visited in turn and may send up to `QUANTUM` bytes,
+/// carrying over any unused allowance to its next turn. A connection with a large backlog of bulk
+/// data therefore only delays others by about one datagram, rather than by its entire backlog.
+#[derive(Debug, Default)]
+pub(crate) struct TransmitQueue {
+    flows: HashMap<ConnectionHandle, Flow>,
+    /// Connections with queued transmits, in service order
+    active: VecDeque<ConnectionHandle>,
+}
+
+#[derive(Debug, Default)]
+struct Flow {
+    queue: VecDeque<Transmit>,
+    /// Bytes the connection may send before its turn ends
+    deficit: usize,
+}
+
+impl TransmitQueue {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a transmit produced by the connection `ch`
+    pub(crate) fn push(&mut self, ch: ConnectionHandle, transmit: Transmit) {
+        let flow = self.flows.entry(ch).or_default();
+        if flow.queue.is_empty() {
+            self.active.push_back(ch);
+        }
+        flow.queue.push_back(transmit);
+    }
+
+    /// Take the next transmit to send
+    pub(crate) fn pop(&mut self) -> Option<Transmit> {
+        loop {
+            let ch = *self.active.front()?;
+            let flow = self.flows.get_mut(&ch).unwrap();
+            let size = flow.queue.front().unwrap().contents.len();
+            if size > flow.deficit {
+                // Turn over; resume from the back of the line with a fresh allowance
+                flow.deficit += QUANTUM;
+                self.active.rotate_left(1);
+                continue;
+            }
+            flow.deficit -= size;
+            let transmit = flow.queue.pop_front();
+            if flow.queue.is_empty() {
+                // Idle connections don't accumulate allowance
+                self.flows.remove(&ch);
+                self.active.pop_front();
+            }
+            return transmit;
+        }
+    }
+}
+
+/// Batches of transmits emptied by an endpoint, for its connections to fill again
+///
+/// Connections hand their transmits to the endpoint a batch at a time; returning the batches here
+/// saves allocating a fresh one for each.
+#[derive(Debug, Default)]
+pub(crate) struct BatchPool(Mutex<Vec<Vec<Transmit>>>);
+
+impl BatchPool {
+    /// An empty batch with room for `BATCH_SIZE` transmits
+    pub(crate) fn get(&self) -> Vec<Transmit> {
+        self.0
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(BATCH_SIZE))
+    }
+
+    /// Make `batch`'s allocation available to the next `get`
+    pub(crate) fn put(&self, mut batch: Vec<Transmit>) {
+        batch.clear();
+        let mut spare = self.0.lock().unwrap();
+        if spare.len() < SPARE_BATCHES {
+            spare.push(batch);
+        }
+    }
+}
@@ -0,0 +1,299 @@
+//! Building blocks for WebTransport over HTTP/3
+//!
+//! A WebTransport session is established by an extended CONNECT request on a bidirectional
+//! stream, whose ID then identifies the session for its whole lifetime. Streams the session opens
+//! carry that ID in a short header, and its datagrams are prefixed with the "quarter stream ID" of
+//! the CONNECT stream, so that many sessions can share one connection.
+//!
+//! Negotiating the session, which requires an HTTP/3 layer, is left to the application. Once it
+//! has been established, a [`Session`] opens streams and sends datagrams on its behalf,
+//! [`read_uni_header()`] and [`read_bi_header()`] tell which session a stream from the peer
+//! belongs to, and a [`DatagramRouter`] hands each received datagram to its session.
+//!
+//! ```no_run
+//! # use futures::StreamExt;
+//! # use quinn::webtransport::{self, DatagramRouter, SessionId, StreamHeader};
+//! # async fn f(new_conn: quinn::NewConnection, connect_stream: quinn::StreamId) {
+//! let quinn::NewConnection { connection, mut uni_streams, mut datagrams, .. } = new_conn;
+//! let session = webtransport::Session::new(connection, SessionId::from(connect_stream));
+//! let mut router = DatagramRouter::new();
+//! let mut session_datagrams = router.register(session.id(), 16);
+//! tokio::spawn(async move {
+//!     while let Some(Ok(datagram)) = datagrams.next().await {
+//!         router.route(datagram);
+//!     }
+//! });
+//!
+//! let mut recv = uni_streams.next().await.unwrap().unwrap();
+//! match webtransport::read_uni_header(&mut recv).await.unwrap() {
+//!     StreamHeader::Session(id) if id == session.id() => { /* read from recv */ }
+//!     _ => { /* an HTTP/3 stream, or one for another session */ }
+//! }
+//! let _ = session_datagrams.next().await;
+//! # }
+//! ```
+
+use std::{
+    collections::HashMap,
+    fmt,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Bytes, BytesMut};
+use futures::{channel::mpsc, Stream};
+use proto::{coding::Codec, StreamId, VarInt};
+
+use crate::{
+    connection::{Connection, SendDatagramError},
+    streams::{ReadExactError, RecvStream, SendStream, WriteError},
+};
+
+/// HTTP/3 unidirectional stream type of a WebTransport stream
+pub const UNI_STREAM_TYPE: VarInt = VarInt::from_u32(0x54);
+/// HTTP/3 frame type opening a bidirectional WebTransport stream
+pub const BI_STREAM_SIGNAL: VarInt = VarInt::from_u32(0x41);
+
+/// Identifies a WebTransport session by the ID of the stream carrying its CONNECT request
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct SessionId(StreamId);
+
+impl SessionId {
+    /// The CONNECT stream's ID divided by four, as found at the start of HTTP/3 datagrams
+    pub fn quarter_stream_id(self) -> VarInt {
+        VarInt::from_u64(self.0 .0 / 4).unwrap()
+    }
+
+    /// Recover a session ID from the prefix of an HTTP/3 datagram
+    ///
+    /// CONNECT requests are always sent by the client on a bidirectional stream.
+    pub fn from_quarter_stream_id(x: VarInt) -> Self {
+        Self(StreamId(x.into_inner() * 4))
+    }
+
+    /// The ID of the stream carrying the session's CONNECT request
+    pub fn stream_id(self) -> StreamId {
+        self.0
+    }
+}
+
+impl From<StreamId> for SessionId {
+    fn from(x: StreamId) -> Self {
+        Self(x)
+    }
+}
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Opens streams and sends datagrams belonging to an established WebTransport session
+#[derive(Debug)]
+pub struct Session<S>
+where
+    S: proto::crypto::Session,
+{
+    connection: Connection<S>,
+    id: SessionId,
+}
+
+impl<S> Session<S>
+where
+    S: proto::crypto::Session + 'static,
+{
+    /// Act on behalf of session `id`, whose CONNECT request was exchanged on `connection`
+    pub fn new(connection: Connection<S>, id: SessionId) -> Self {
+        Self { connection, id }
+    }
+
+    /// The session this acts on behalf of
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    /// The connection carrying the session
+    pub fn connection(&self) -> &Connection<S> {
+        &self.connection
+    }
+
+    /// Open a unidirectional stream associated with the session
+    ///
+    /// The stream header is written before the stream is returned.
+    pub async fn open_uni(&self) -> Result<SendStream<S>, WriteError> {
+        let mut send = self
+            .connection
+            .open_uni()
+            .await
+            .map_err(WriteError::ConnectionClosed)?;
+        send.write_all(&self.header(UNI_STREAM_TYPE)).await?;
+        Ok(send)
+    }
+
+    /// Open a bidirectional stream associated with the session
+    ///
+    /// The stream header is written before the stream is returned.
+    pub async fn open_bi(&self) -> Result<(SendStream<S>, RecvStream<S>), WriteError> {
+        let (mut send, recv) = self
+            .connection
+            .open_bi()
+            .await
+            .map_err(WriteError::ConnectionClosed)?;
+        send.write_all(&self.header(BI_STREAM_SIGNAL)).await?;
+        Ok((send, recv))
+    }
+
+    /// Send `payload` as a datagram associated with the session
+    ///
+    /// See [`Connection::send_datagram()`].
+    pub fn send_datagram(&self, payload: &[u8]) -> Result<(), SendDatagramError> {
+        let prefix = self.id.quarter_stream_id();
+        let mut buf = BytesMut::with_capacity(prefix.size() + payload.len());
+        prefix.encode(&mut buf);
+        buf.extend_from_slice(payload);
+        self.connection.send_datagram(buf.freeze())
+    }
+
+    /// The largest payload that may currently be passed to [`send_datagram()`]
+    ///
+    /// See [`Connection::max_datagram_size()`].
+    ///
+    /// [`send_datagram()`]: Session::send_datagram
+    pub fn max_datagram_size(&self) -> Option<usize> {
+        let prefix = self.id.quarter_stream_id().size();
+        self.connection
+            .max_datagram_size()
+            .map(|x| x.saturating_sub(prefix))
+    }
+
+    fn header(&self, ty: VarInt) -> BytesMut {
+        let id = VarInt::from_u64(self.id.0 .0).unwrap();
+        let mut buf = BytesMut::with_capacity(ty.size() + id.size());
+        ty.encode(&mut buf);
+        id.encode(&mut buf);
+        buf
+    }
+}
+
+/// What a stream opened by the peer turned out to carry
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StreamHeader {
+    /// The stream belongs to the given WebTransport session, and its payload follows
+    Session(SessionId),
+    /// The stream is an ordinary HTTP/3 stream, which began with the given value
+    ///
+    /// For unidirectional streams this is the stream type, and for bidirectional streams the type
+    /// of the first frame.
+    Other(VarInt),
+}
+
+/// Read the header of a unidirectional stream opened by the peer
+pub async fn read_uni_header<S>(recv: &mut RecvStream<S>) -> Result<StreamHeader, ReadExactError>
+where
+    S: proto::crypto::Session,
+{
+    read_header(recv, UNI_STREAM_TYPE).await
+}
+
+/// Read the header of a bidirectional stream opened by the peer
+pub async fn read_bi_header<S>(recv: &mut RecvStream<S>) -> Result<StreamHeader, ReadExactError>
+where
+    S: proto::crypto::Session,
+{
+    read_header(recv, BI_STREAM_SIGNAL).await
+}
+
+async fn read_header<S>(
+    recv: &mut RecvStream<S>,
+    expected: VarInt,
+) -> Result<StreamHeader, ReadExactError>
+where
+    S: proto::crypto::Session,
+{
+    let ty = read_varint(recv).await?;
+    if ty != expected {
+        return Ok(StreamHeader::Other(ty));
+    }
+    let id = read_varint(recv).await?;
+    Ok(StreamHeader::Session(SessionId(StreamId(id.into_inner()))))
+}
+
+/// Read a single variable-length integer without consuming anything after it
+async fn read_varint<S>(recv: &mut RecvStream<S>) -> Result<VarInt, ReadExactError>
+where
+    S: proto::crypto::Session,
+{
+    let mut buf = [0; 8];
+    recv.read_exact(&mut buf[..1]).await?;
+    let len = 1 << (buf[0] >> 6);
+    recv.read_exact(&mut buf[1..len]).await?;
+    Ok(VarInt::decode(&mut &buf[..len]).unwrap())
+}
+
+/// Hands received HTTP/3 datagrams to the WebTransport sessions they belong to
+///
+/// Feed it every datagram received on a connection through [`route()`](Self::route).
+#[derive(Debug, Default)]
+pub struct DatagramRouter {
+    sessions: HashMap<SessionId, mpsc::Sender<Bytes>>,
+}
+
+impl DatagramRouter {
+    /// Create a router with no sessions
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start delivering the datagrams of `session`, buffering up to `capacity` of them
+    ///
+    /// Datagrams arriving while the buffer is full are dropped, as for the connection's own
+    /// receive buffer. Replaces any earlier registration of the same session.
+    pub fn register(&mut self, session: SessionId, capacity: usize) -> SessionDatagrams {
+        let (send, recv) = mpsc::channel(capacity);
+        self.sessions.insert(session, send);
+        SessionDatagrams(recv)
+    }
+
+    /// Stop delivering the datagrams of `session`, ending its [`SessionDatagrams`]
+    pub fn unregister(&mut self, session: SessionId) {
+        self.sessions.remove(&session);
+    }
+
+    /// Deliver `datagram` to the session it's addressed to, with the prefix removed
+    ///
+    /// Returns `false` if the datagram was malformed, addressed to an unknown session, or dropped
+    /// because the session isn't keeping up. Sessions whose [`SessionDatagrams`] was dropped are
+    /// unregistered.
+    pub fn route(&mut self, mut datagram: Bytes) -> bool {
+        let session = match VarInt::decode(&mut datagram) {
+            Ok(x) => SessionId::from_quarter_stream_id(x),
+            Err(_) => return false,
+        };
+        let send = match self.sessions.get_mut(&session) {
+            Some(x) => x,
+            None => return false,
+        };
+        match send.try_send(datagram) {
+            Ok(()) => true,
+            Err(e) => {
+                if e.is_disconnected() {
+                    self.sessions.remove(&session);
+                }
+                false
+            }
+        }
+    }
+}
+
+/// Stream of the payloads of datagrams belonging to a session, from a [`DatagramRouter`]
+#[derive(Debug)]
+pub struct SessionDatagrams(mpsc::Receiver<Bytes>);
+
+impl Stream for SessionDatagrams {
+    type Item = Bytes;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Bytes>> {
+        Pin::new(&mut self.0).poll_next(cx)
+    }
+}